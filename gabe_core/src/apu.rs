@@ -1,16 +1,18 @@
+use super::gb::StateError;
 use super::sink::*;
-use super::{mmu::Memory, util::bit::*};
+use super::state::{SaveState, StateReader, StateWriter};
+use super::{log_target, mmu::Memory, util::bit::*};
+use core::fmt::{self, Display, Formatter};
 
 // Use SAMPLE_RATE exported from lib to match
 const SAMPLE_RATE: u32 = super::SAMPLE_RATE;
 
-// 4.19 MHz / 65.536 KHz
-const SAMPLE_RATE_PERIOD: u32 = super::CLOCK_RATE / SAMPLE_RATE;
+const CLOCK_RATE: u32 = super::CLOCK_RATE;
 
 // 4.19 MHz / 512 Hz
 const FRAME_SEQ_PERIOD: u32 = 8192;
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct SquareChannel1 {
     /// Flag indicating if the internal DAC is enabled
     /// If false, no sound will be emitted, even on channel trigger
@@ -194,8 +196,15 @@ impl SquareChannel1 {
         self.extra_length = true;
     }
 
+    /// The channel status reported by NR52. Channel 1 is only active while it has been
+    /// triggered, has not been stopped by its length counter or by its sweep overflowing,
+    /// and its DAC is on.
+    fn is_active(&self) -> bool {
+        self.channel_enabled && self.dac_enabled
+    }
+
     fn get_amp(&self) -> f32 {
-        if self.dac_enabled && self.channel_enabled {
+        if self.is_active() {
             let pattern = match extract_bits(self.nr11_length_data, 7, 6) {
                 0x0 => 0b0000_0001, // 12.5%
                 0x1 => 0b1000_0001, // 25%
@@ -213,19 +222,19 @@ impl SquareChannel1 {
 
 impl Memory for SquareChannel1 {
     fn read_byte(&self, addr: u16) -> u8 {
-        assert!((0xFF10..=0xFF14).contains(&addr));
+        debug_assert!((0xFF10..=0xFF14).contains(&addr));
         match addr {
             0xFF10 => self.nr10_sweep_control | 0x80,
             0xFF11 => self.nr11_length_data | 0x3F,
             0xFF12 => self.nr12_volume_control,
             0xFF13 => 0xFF,
             0xFF14 => self.nr14_freq_high_control | 0xBF,
-            _ => unreachable!(),
+            _ => 0xFF,
         }
     }
 
     fn write_byte(&mut self, addr: u16, val: u8) {
-        assert!((0xFF10..=0xFF14).contains(&addr));
+        debug_assert!((0xFF10..=0xFF14).contains(&addr));
         match addr {
             0xFF10 => {
                 let old_direction = test_bit(self.nr10_sweep_control, 3);
@@ -312,12 +321,12 @@ impl Memory for SquareChannel1 {
                     }
                 }
             }
-            _ => unreachable!(),
+            _ => {}
         }
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct SquareChannel2 {
     /// Flag indicating if the internal DAC is enabled
     /// If false, no sound will be emitted, even on channel trigger
@@ -429,8 +438,14 @@ impl SquareChannel2 {
         self.extra_length = true;
     }
 
+    /// The channel status reported by NR52. Channel 2 is only active while it has been
+    /// triggered, has not been stopped by its length counter, and its DAC is on.
+    fn is_active(&self) -> bool {
+        self.channel_enabled && self.dac_enabled
+    }
+
     fn get_amp(&self) -> f32 {
-        if self.dac_enabled && self.channel_enabled {
+        if self.is_active() {
             let pattern = match extract_bits(self.nr21_length_data, 7, 6) {
                 0x0 => 0b0000_0001, // 12.5%
                 0x1 => 0b1000_0001, // 25%
@@ -449,18 +464,18 @@ impl SquareChannel2 {
 
 impl Memory for SquareChannel2 {
     fn read_byte(&self, addr: u16) -> u8 {
-        assert!((0xFF16..=0xFF19).contains(&addr));
+        debug_assert!((0xFF16..=0xFF19).contains(&addr));
         match addr {
             0xFF16 => self.nr21_length_data | 0x3F,
             0xFF17 => self.nr22_volume_control,
             0xFF18 => 0xFF,
             0xFF19 => self.nr24_freq_high_control | 0xBF,
-            _ => unreachable!(),
+            _ => 0xFF,
         }
     }
 
     fn write_byte(&mut self, addr: u16, val: u8) {
-        assert!((0xFF16..=0xFF19).contains(&addr));
+        debug_assert!((0xFF16..=0xFF19).contains(&addr));
         match addr {
             0xFF16 => {
                 self.nr21_length_data = val;
@@ -519,12 +534,12 @@ impl Memory for SquareChannel2 {
                     }
                 }
             }
-            _ => unreachable!(),
+            _ => {}
         }
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct WaveChannel {
     /// Flag indicating if the sound is currently playing
     /// Set to true on a NR34 b7 trigger write, and reported by NR52
@@ -621,8 +636,15 @@ impl WaveChannel {
         self.extra_length = true;
     }
 
+    /// The channel status reported by NR52. The wave channel is only active while it has
+    /// been triggered, has not been stopped by its length counter, and its DAC is on
+    /// through NR30.
+    fn is_active(&self) -> bool {
+        self.channel_enabled && test_bit(self.nr30_dac_enable, 7)
+    }
+
     fn get_amp(&self) -> f32 {
-        if test_bit(self.nr30_dac_enable, 7) {
+        if self.is_active() {
             let vol_shift = match extract_bits(self.nr32_output_level, 6, 5) {
                 0b00 => 4,
                 0b01 => 0,
@@ -639,7 +661,7 @@ impl WaveChannel {
 
 impl Memory for WaveChannel {
     fn read_byte(&self, addr: u16) -> u8 {
-        assert!((0xFF1A..=0xFF1E).contains(&addr) || (0xFF30..=0xFF3F).contains(&addr));
+        debug_assert!((0xFF1A..=0xFF1E).contains(&addr) || (0xFF30..=0xFF3F).contains(&addr));
         match addr {
             0xFF1A => self.nr30_dac_enable | 0x7F,
             0xFF1B => 0xFF,
@@ -647,12 +669,12 @@ impl Memory for WaveChannel {
             0xFF1D => 0xFF,
             0xFF1E => self.nr34_freq_high_control | 0xBF,
             0xFF30..=0xFF3F => self.wave_ram[(addr - 0xFF30) as usize],
-            _ => unreachable!(),
+            _ => 0xFF,
         }
     }
 
     fn write_byte(&mut self, addr: u16, val: u8) {
-        assert!((0xFF1A..=0xFF1E).contains(&addr) || (0xFF30..=0xFF3F).contains(&addr));
+        debug_assert!((0xFF1A..=0xFF1E).contains(&addr) || (0xFF30..=0xFF3F).contains(&addr));
         match addr {
             0xFF1A => {
                 self.nr30_dac_enable = val;
@@ -706,12 +728,12 @@ impl Memory for WaveChannel {
                 }
             }
             0xFF30..=0xFF3F => self.wave_ram[(addr - 0xFF30) as usize] = val,
-            _ => unreachable!(),
+            _ => {}
         }
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct NoiseChannel {
     /// Flag indicating if the sound is currently playing
     /// Set to true on a NR34 b7 trigger write, and reported by NR52
@@ -784,10 +806,12 @@ impl NoiseChannel {
     fn step_freq(&mut self) {
         // Check if the buffer needs to be updated with new samples to match the frequency
         if self.frequency_timer == 0 {
-            let output = !(self.lfsr & 0x1) ^ ((self.lfsr >> 1) & 0x1);
-            self.lfsr |= output << 15;
+            // Bits 0 and 1 are XNORed and fed back into bit 15, and in 7-bit width mode also
+            // replace bit 7, shortening the register to 7 stages
+            let feedback = !(self.lfsr ^ (self.lfsr >> 1)) & 0x1;
+            self.lfsr = (self.lfsr & 0x7FFF) | (feedback << 15);
             if test_bit(self.nr43_freq_rng, 3) {
-                self.lfsr |= output << 7;
+                self.lfsr = (self.lfsr & !(1 << 7)) | (feedback << 7);
             }
             self.lfsr >>= 1;
             self.frequency_timer = (self.divisor as u32) << extract_bits(self.nr43_freq_rng, 7, 4);
@@ -820,8 +844,14 @@ impl NoiseChannel {
         self.extra_length = true;
     }
 
+    /// The channel status reported by NR52. The noise channel is only active while it has
+    /// been triggered, has not been stopped by its length counter, and its DAC is on.
+    fn is_active(&self) -> bool {
+        self.channel_enabled && self.dac_enabled
+    }
+
     fn get_amp(&self) -> f32 {
-        if self.dac_enabled && self.channel_enabled {
+        if self.is_active() {
             convert_u4_to_f32_sample((!self.lfsr & 0x1) as u8 * 0xF)
                 * (self.current_volume as f32 / 15.0)
         } else {
@@ -832,18 +862,18 @@ impl NoiseChannel {
 
 impl Memory for NoiseChannel {
     fn read_byte(&self, addr: u16) -> u8 {
-        assert!((0xFF20..=0xFF23).contains(&addr) || (0xFF30..=0xFF3F).contains(&addr));
+        debug_assert!((0xFF20..=0xFF23).contains(&addr) || (0xFF30..=0xFF3F).contains(&addr));
         match addr {
             0xFF20 => 0xFF,
             0xFF21 => self.nr42_volume_control,
             0xFF22 => self.nr43_freq_rng,
             0xFF23 => self.nr44_channel_control | 0xBF,
-            _ => unreachable!(),
+            _ => 0xFF,
         }
     }
 
     fn write_byte(&mut self, addr: u16, val: u8) {
-        assert!((0xFF20..=0xFF23).contains(&addr) || (0xFF30..=0xFF3F).contains(&addr));
+        debug_assert!((0xFF20..=0xFF23).contains(&addr) || (0xFF30..=0xFF3F).contains(&addr));
         match addr {
             0xFF20 => {
                 self.nr41_length_timer = val & 0x3F;
@@ -909,11 +939,115 @@ impl Memory for NoiseChannel {
                     }
                 }
             }
-            _ => unreachable!(),
+            _ => {}
         }
     }
 }
 
+/// Snapshot of a sound channel's state, for debugging
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelState {
+    /// Whether the channel is playing, as reported by NR52
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    /// Current envelope volume, 0-15. For the wave channel, the NR32 output level 0-3 instead.
+    pub volume: u8,
+    /// The 11-bit period written to NRx3 and NRx4. For the noise channel, NR43 instead.
+    pub period: u16,
+    /// Rate the waveform repeats at in Hz. For the noise channel, the rate the LFSR is clocked at.
+    pub frequency: u32,
+    pub length_timer: u16,
+    /// Whether the channel is left out of the audio output, see `Apu::set_channel_enabled`
+    pub muted: bool,
+}
+
+/// Snapshot of the APU registers and channel states, for debugging.
+/// Displays as a table of the registers with their fields decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApuDebug {
+    /// NR10-NR52 at 0xFF10-0xFF26, as read by the CPU
+    pub registers: [u8; 0x17],
+    /// Channels 1-4, in order
+    pub channels: [ChannelState; 4],
+    pub wave_ram: [u8; 16],
+}
+
+impl ApuDebug {
+    fn register(&self, addr: u16) -> u8 {
+        self.registers[(addr - 0xFF10) as usize]
+    }
+}
+
+impl Display for ApuDebug {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        let nr50 = self.register(0xFF24);
+        let nr51 = self.register(0xFF25);
+        let nr52 = self.register(0xFF26);
+        writeln!(
+            f,
+            "NR50: {:02X}  Left: {}  Right: {}  Vin: {}/{}",
+            nr50,
+            extract_bits(nr50, 6, 4),
+            extract_bits(nr50, 2, 0),
+            on_off(test_bit(nr50, 7)),
+            on_off(test_bit(nr50, 3))
+        )?;
+        write!(f, "NR51: {:02X} ", nr51)?;
+        for channel in 0..4 {
+            let left = if test_bit(nr51, channel + 4) {
+                "L"
+            } else {
+                "-"
+            };
+            let right = if test_bit(nr51, channel) { "R" } else { "-" };
+            write!(f, " CH{}: {}{}", channel + 1, left, right)?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "NR52: {:02X}  Sound: {}",
+            nr52,
+            on_off(test_bit(nr52, 7))
+        )?;
+
+        let names = ["Square 1", "Square 2", "Wave", "Noise"];
+        let bases = [0xFF10, 0xFF15, 0xFF1A, 0xFF1F];
+        for (i, channel) in self.channels.iter().enumerate() {
+            writeln!(
+                f,
+                "CH{} {:<8}  {:<3}  DAC: {:<3}  Volume: {:>2}  Period: {:03X}  {:>6} Hz  Length: {}",
+                i + 1,
+                names[i],
+                on_off(channel.enabled),
+                on_off(channel.dac_enabled),
+                channel.volume,
+                channel.period,
+                channel.frequency,
+                channel.length_timer
+            )?;
+            if channel.muted {
+                writeln!(f, "    Muted")?;
+            }
+            write!(f, "   ")?;
+            for (n, addr) in (bases[i]..bases[i] + 5).enumerate() {
+                // NR20 and NR40 don't exist
+                if addr != 0xFF15 && addr != 0xFF1F {
+                    write!(f, " NR{}{}: {:02X}", i + 1, n, self.register(addr))?;
+                }
+            }
+            writeln!(f)?;
+        }
+        write!(f, "Wave RAM:")?;
+        for byte in self.wave_ram {
+            write!(f, " {:02X}", byte)?;
+        }
+        writeln!(f)
+    }
+}
+
+/// The full APU state is cloneable, so a snapshot restores the channels mid-waveform
+#[derive(Clone)]
 pub struct Apu {
     // Global Registers
     /// Channel control / ON-OFF / Volume (R/W)
@@ -970,6 +1104,21 @@ pub struct Apu {
     /// Also marks the generation of samples to the host device.
     frame_cycle: u8,
 
+    /// Rate in Hz that samples are generated at
+    sample_rate: u32,
+
+    /// Accumulates `sample_rate` every cycle, generating a sample each time it
+    /// passes `CLOCK_RATE`, so rates that don't evenly divide the clock don't drift
+    sample_accumulator: u32,
+
+    /// Whether samples are generated at all. The channels keep running without them,
+    /// so the registers read the same either way.
+    sample_output: bool,
+
+    /// Channels the host has muted, which are silenced in the output but otherwise
+    /// run as normal. Not part of the save state, like the other output settings.
+    channels_muted: [bool; 4],
+
     /// When any DAC is enabled, a high-pass filter capacitor is slowly applied
     /// to each of the two analog signals.
     _hpf_capacitor_l: f32,
@@ -983,7 +1132,7 @@ impl Apu {
             nr51_channel_pan: 0xF3,
             all_sound_on: true,
             square1: SquareChannel1 {
-                dac_enabled: true,
+                dac_enabled: false,
                 channel_enabled: false,
                 nr10_sweep_control: 0x80,
                 nr11_length_data: 0x00,
@@ -1004,7 +1153,7 @@ impl Apu {
                 extra_length: false,
             },
             square2: SquareChannel2 {
-                dac_enabled: true,
+                dac_enabled: false,
                 channel_enabled: false,
                 nr21_length_data: 0x00,
                 nr22_volume_control: 0x00,
@@ -1035,7 +1184,7 @@ impl Apu {
             },
             noise: NoiseChannel {
                 channel_enabled: false,
-                dac_enabled: true,
+                dac_enabled: false,
                 nr41_length_timer: 0x00,
                 nr42_volume_control: 0x00,
                 nr43_freq_rng: 0x00,
@@ -1052,101 +1201,210 @@ impl Apu {
             },
             cycle_count: 0,
             frame_cycle: 0,
+            sample_rate: SAMPLE_RATE,
+            sample_accumulator: 0,
+            sample_output: true,
+            channels_muted: [false; 4],
             _hpf_capacitor_l: 0.0,
             _hpf_capacitor_r: 0.0,
         }
     }
 
-    pub fn update(&mut self, cycles: u32, audio_sink: &mut dyn Sink<AudioFrame>) {
-        if self.all_sound_on {
-            for _ in 0..cycles {
-                self.cycle_count += 1;
-
-                self.square1.step_freq();
-                self.square2.step_freq();
-                self.wave.step_freq();
-                self.noise.step_freq();
-
-                if self.cycle_count >= FRAME_SEQ_PERIOD {
-                    // Increment the number of frame sequencer clocks
-                    self.cycle_count -= FRAME_SEQ_PERIOD;
-                    self.frame_cycle = (self.frame_cycle + 1) % 8;
-                    if [0, 2, 4, 6].contains(&self.frame_cycle) {
-                        // Update length counter if enabled
-                        self.square1.step_length();
-                        self.square2.step_length();
-                        self.wave.step_length();
-                        self.noise.step_length();
-                    }
-                    if [2, 6].contains(&self.frame_cycle) {
-                        // Update Freq Sweep
-                        self.square1.step_sweep();
-                    }
-                    if self.frame_cycle == 7 {
-                        // Update volume envelope
-                        self.square1.step_envelope();
-                        self.square2.step_envelope();
-                        self.noise.step_envelope();
-                    }
-                    if [1, 3, 5, 7].contains(&self.frame_cycle) {
-                        self.square1.extra_length = false;
-                        self.square2.extra_length = false;
-                        self.wave.extra_length = false;
-                        self.noise.extra_length = false;
-                    }
-                }
+    /// Sets the rate in Hz that samples are generated at, which can be any rate
+    /// up to the CPU clock rate. Rates of 0 or above the clock rate are clamped.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate.clamp(1, CLOCK_RATE);
+        self.sample_accumulator = 0;
+    }
 
-                if self.cycle_count % SAMPLE_RATE_PERIOD == 0 {
-                    // Reached period needed to generate a sample
-                    let left_amp = {
-                        let mut amp_acc: f32 = 0.0;
-                        if test_bit(self.nr51_channel_pan, 4) {
-                            amp_acc += self.square1.get_amp();
-                        }
-                        if test_bit(self.nr51_channel_pan, 5) {
-                            amp_acc += self.square2.get_amp();
-                        }
-                        if test_bit(self.nr51_channel_pan, 6) {
-                            amp_acc += self.wave.get_amp();
-                        }
-                        if test_bit(self.nr51_channel_pan, 7) {
-                            amp_acc += self.noise.get_amp();
-                        }
-                        amp_acc / 4.0
-                    };
-                    let right_amp = {
-                        let mut amp_acc: f32 = 0.0;
-                        if test_bit(self.nr51_channel_pan, 0) {
-                            amp_acc += self.square1.get_amp();
-                        }
-                        if test_bit(self.nr51_channel_pan, 1) {
-                            amp_acc += self.square2.get_amp();
-                        }
-                        if test_bit(self.nr51_channel_pan, 2) {
-                            amp_acc += self.wave.get_amp();
-                        }
-                        if test_bit(self.nr51_channel_pan, 4) {
-                            amp_acc += self.noise.get_amp();
-                        }
-                        amp_acc / 4.0
-                    };
-                    let left_vol =
-                        (extract_bits(self.nr50_output_control, 6, 4) as f32 + 1.0) / 8.0;
-                    let right_vol =
-                        (extract_bits(self.nr50_output_control, 2, 0) as f32 + 1.0) / 8.0;
-                    let left_output = left_amp * left_vol;
-                    let right_output = right_amp * right_vol;
-                    audio_sink.append(((left_output), (right_output)));
-                }
+    /// Stops or resumes generating samples, for running as fast as possible when
+    /// the audio isn't wanted
+    pub fn set_sample_output(&mut self, enabled: bool) {
+        self.sample_output = enabled;
+        self.sample_accumulator = 0;
+    }
+
+    /// Mutes or unmutes `channel`, 0-3 for channels 1-4. A muted channel outputs silence
+    /// but keeps running, so unmuting it picks up where it would have been. Other channel
+    /// numbers are ignored.
+    pub fn set_channel_enabled(&mut self, channel: usize, enabled: bool) {
+        if let Some(muted) = self.channels_muted.get_mut(channel) {
+            *muted = !enabled;
+        }
+    }
+
+    /// Whether `channel`, 0-3 for channels 1-4, is in the audio output. False for any other
+    /// channel number.
+    pub fn channel_enabled(&self, channel: usize) -> bool {
+        self.channels_muted
+            .get(channel)
+            .map_or(false, |muted| !muted)
+    }
+
+    /// Takes a snapshot of the registers and channel states for debugging
+    pub fn debug_state(&self) -> ApuDebug {
+        let mut registers = [0; 0x17];
+        for (addr, register) in (0xFF10..).zip(registers.iter_mut()) {
+            *register = self.read_byte(addr);
+        }
+        let square_period =
+            |low: u8, high: u8| ((extract_bits(high, 2, 0) as u16) << 8) | low as u16;
+        let period1 = square_period(
+            self.square1.nr13_frequency_low,
+            self.square1.nr14_freq_high_control,
+        );
+        let period2 = square_period(
+            self.square2.nr23_frequency_low,
+            self.square2.nr24_freq_high_control,
+        );
+        let period3 = square_period(
+            self.wave.nr33_frequency_low,
+            self.wave.nr34_freq_high_control,
+        );
+        ApuDebug {
+            registers,
+            channels: [
+                ChannelState {
+                    enabled: self.square1.is_active(),
+                    dac_enabled: self.square1.dac_enabled,
+                    volume: self.square1.current_volume,
+                    period: period1,
+                    frequency: 131_072 / (2048 - period1 as u32),
+                    length_timer: self.square1.length_timer as u16,
+                    muted: self.channels_muted[0],
+                },
+                ChannelState {
+                    enabled: self.square2.is_active(),
+                    dac_enabled: self.square2.dac_enabled,
+                    volume: self.square2.current_volume,
+                    period: period2,
+                    frequency: 131_072 / (2048 - period2 as u32),
+                    length_timer: self.square2.length_timer as u16,
+                    muted: self.channels_muted[1],
+                },
+                ChannelState {
+                    enabled: self.wave.is_active(),
+                    dac_enabled: test_bit(self.wave.nr30_dac_enable, 7),
+                    volume: extract_bits(self.wave.nr32_output_level, 6, 5),
+                    period: period3,
+                    frequency: 65_536 / (2048 - period3 as u32),
+                    length_timer: self.wave.length_timer,
+                    muted: self.channels_muted[2],
+                },
+                ChannelState {
+                    enabled: self.noise.is_active(),
+                    dac_enabled: self.noise.dac_enabled,
+                    volume: self.noise.current_volume,
+                    period: self.noise.nr43_freq_rng as u16,
+                    frequency: CLOCK_RATE
+                        / ((self.noise.divisor as u32)
+                            << extract_bits(self.noise.nr43_freq_rng, 7, 4)),
+                    length_timer: self.noise.length_timer,
+                    muted: self.channels_muted[3],
+                },
+            ],
+            wave_ram: self.wave.wave_ram,
+        }
+    }
+
+    /// Runs the APU for the given number of cycles, emitting a sample split by channel
+    /// at the sample rate. While sound is off the channels are stopped and the samples are
+    /// silent, so the stream stays continuous for frontends that pace on audio.
+    /// Nothing is emitted while sample output is off.
+    pub fn update(&mut self, cycles: u32, audio_sink: &mut dyn Sink<ChannelAudioFrame>) {
+        for _ in 0..cycles {
+            if self.all_sound_on {
+                self.step_channels();
+            }
+            if !self.sample_output {
+                continue;
+            }
+
+            self.sample_accumulator += self.sample_rate;
+            if self.sample_accumulator >= CLOCK_RATE {
+                // Reached period needed to generate a sample
+                self.sample_accumulator -= CLOCK_RATE;
+                let frame = if self.all_sound_on {
+                    self.mix_frame()
+                } else {
+                    [(0.0, 0.0); 4]
+                };
+                audio_sink.append(frame);
             }
         }
     }
 
+    /// Steps each channel and the frame sequencer by a single cycle
+    fn step_channels(&mut self) {
+        self.cycle_count += 1;
+
+        self.square1.step_freq();
+        self.square2.step_freq();
+        self.wave.step_freq();
+        self.noise.step_freq();
+
+        if self.cycle_count >= FRAME_SEQ_PERIOD {
+            // Increment the number of frame sequencer clocks
+            self.cycle_count -= FRAME_SEQ_PERIOD;
+            self.frame_cycle = (self.frame_cycle + 1) % 8;
+            if [0, 2, 4, 6].contains(&self.frame_cycle) {
+                // Update length counter if enabled
+                self.square1.step_length();
+                self.square2.step_length();
+                self.wave.step_length();
+                self.noise.step_length();
+            }
+            if [2, 6].contains(&self.frame_cycle) {
+                // Update Freq Sweep
+                self.square1.step_sweep();
+            }
+            if self.frame_cycle == 7 {
+                // Update volume envelope
+                self.square1.step_envelope();
+                self.square2.step_envelope();
+                self.noise.step_envelope();
+            }
+            if [1, 3, 5, 7].contains(&self.frame_cycle) {
+                self.square1.extra_length = false;
+                self.square2.extra_length = false;
+                self.wave.extra_length = false;
+                self.noise.extra_length = false;
+            }
+        }
+    }
+
+    /// Samples each channel, panned and scaled by the master volume
+    fn mix_frame(&self) -> ChannelAudioFrame {
+        let amps = [
+            self.square1.get_amp(),
+            self.square2.get_amp(),
+            self.wave.get_amp(),
+            self.noise.get_amp(),
+        ];
+        let left_vol = (extract_bits(self.nr50_output_control, 6, 4) as f32 + 1.0) / 8.0;
+        let right_vol = (extract_bits(self.nr50_output_control, 2, 0) as f32 + 1.0) / 8.0;
+        let mut frame: ChannelAudioFrame = [(0.0, 0.0); 4];
+        for (i, amp) in amps.iter().enumerate() {
+            if self.channels_muted[i] {
+                continue;
+            }
+            // NR51 bits 4-7 pan each channel to the left, bits 0-3 to the right.
+            // Each channel is a quarter of the final mix.
+            if test_bit(self.nr51_channel_pan, 4 + i as u8) {
+                frame[i].0 = amp / 4.0 * left_vol;
+            }
+            if test_bit(self.nr51_channel_pan, i as u8) {
+                frame[i].1 = amp / 4.0 * right_vol;
+            }
+        }
+        frame
+    }
+
     // TODO: no_std prevents the powf function, rework without math
     // fn high_pass_filter(&mut self, in_sample: f32, capacitor: f32) -> (f32, f32) {
     //     let mut out_sample = 0.0;
     //     let mut out_cap = 0.0;
-    //     let charge_factor = 0.999958f32.powf(SAMPLE_RATE_PERIOD as f32);
+    //     let charge_factor = 0.999958f32.powf((CLOCK_RATE / self.sample_rate) as f32);
     //     if self.square1.dac_enabled
     //         || self.square2.dac_enabled
     //         || test_bit(self.wave.nr30_dac_enable, 7)
@@ -1160,7 +1418,7 @@ impl Apu {
 
 impl Memory for Apu {
     fn read_byte(&self, addr: u16) -> u8 {
-        assert!((0xFF10..=0xFF3F).contains(&addr));
+        debug_assert!((0xFF10..=0xFF3F).contains(&addr));
         match addr {
             0xFF10..=0xFF14 => self.square1.read_byte(addr),
             0xFF16..=0xFF19 => self.square2.read_byte(addr),
@@ -1173,29 +1431,29 @@ impl Memory for Apu {
                 if self.all_sound_on {
                     ret = set_bit(ret, 7);
                 }
-                if self.square1.channel_enabled {
+                if self.square1.is_active() {
                     ret = set_bit(ret, 0);
                 }
-                if self.square2.channel_enabled {
+                if self.square2.is_active() {
                     ret = set_bit(ret, 1);
                 }
-                if self.wave.channel_enabled {
+                if self.wave.is_active() {
                     ret = set_bit(ret, 2);
                 }
-                if self.noise.channel_enabled {
+                if self.noise.is_active() {
                     ret = set_bit(ret, 3);
                 }
                 ret
             }
             0xFF30..=0xFF3F => self.wave.read_byte(addr),
             _ => {
-                debug!("Unassigned APU memory location {:04X}", addr);
+                debug!(target: log_target::APU, "Unassigned APU memory location {:04X}", addr);
                 0xFF
             }
         }
     }
     fn write_byte(&mut self, addr: u16, val: u8) {
-        assert!((0xFF10..=0xFF3F).contains(&addr));
+        debug_assert!((0xFF10..=0xFF3F).contains(&addr));
         if self.all_sound_on {
             match addr {
                 0xFF10..=0xFF14 => self.square1.write_byte(addr, val),
@@ -1208,35 +1466,259 @@ impl Memory for Apu {
                     self.all_sound_on = val & 0x80 != 0; // Only bit 7 is writable
                     if !self.all_sound_on {
                         // APU disabled, clear all registers
+                        // On DMG, length counters are unaffected by APU power
                         self.nr50_output_control = 0;
                         self.nr51_channel_pan = 0;
-                        self.square1 = SquareChannel1::default();
-                        self.square2 = SquareChannel2::default();
+                        self.square1 = SquareChannel1 {
+                            length_timer: self.square1.length_timer,
+                            ..Default::default()
+                        };
+                        self.square2 = SquareChannel2 {
+                            length_timer: self.square2.length_timer,
+                            ..Default::default()
+                        };
                         // Copy over wave ram, shouldn't be affected by APU power
                         let new_wave = WaveChannel {
                             wave_ram: self.wave.wave_ram,
+                            length_timer: self.wave.length_timer,
                             ..Default::default()
                         };
                         self.wave = new_wave;
-                        self.noise = NoiseChannel::default();
+                        self.noise = NoiseChannel {
+                            length_timer: self.noise.length_timer,
+                            ..Default::default()
+                        };
                     }
                 }
                 0xFF30..=0xFF3F => self.wave.write_byte(addr, val),
-                _ => debug!("Unassigned APU memory location {:04X}", addr),
+                _ => debug!(target: log_target::APU, "Unassigned APU memory location {:04X}", addr),
             }
         } else {
             // Most writes are ignored while APU is powered off
             match addr {
                 0xFF26 => {
                     self.all_sound_on = val & 0x80 != 0; // Only bit 7 is writable
+                    if self.all_sound_on {
+                        // Frame sequencer restarts so the next step is step 0
+                        self.cycle_count = 0;
+                        self.frame_cycle = 7;
+                    }
                 }
+                // On DMG, length counters can still be written while powered off
+                0xFF11 => self.square1.length_timer = 64 - extract_bits(val, 5, 0),
+                0xFF16 => self.square2.length_timer = 64 - extract_bits(val, 5, 0),
+                0xFF1B => self.wave.length_timer = 256 - val as u16,
+                0xFF20 => self.noise.length_timer = 64 - extract_bits(val, 5, 0) as u16,
                 0xFF30..=0xFF3F => self.wave.write_byte(addr, val),
-                _ => debug!("Writing to APU while powered off {:04X}", addr),
+                _ => {
+                    debug!(target: log_target::APU, "Writing to APU while powered off {:04X}", addr)
+                }
             }
         }
     }
 }
 
+impl SaveState for SquareChannel1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.dac_enabled);
+        w.bool(self.channel_enabled);
+        w.u8(self.nr10_sweep_control);
+        w.u8(self.nr11_length_data);
+        w.u8(self.nr12_volume_control);
+        w.u8(self.nr13_frequency_low);
+        w.u8(self.nr14_freq_high_control);
+        w.u32(self.frequency_timer);
+        w.u8(self.sweep_timer);
+        w.bool(self.sweep_enabled);
+        w.i32(self.sweep_shadow);
+        w.bool(self.sweep_occurred);
+        w.u8(self.current_volume);
+        w.bool(self.volume_increasing);
+        w.u8(self.envelope_timer);
+        w.u8(self.envelope_period);
+        w.u8(self.length_timer);
+        w.u8(self.wave_index as u8);
+        w.bool(self.extra_length);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.dac_enabled = r.bool()?;
+        self.channel_enabled = r.bool()?;
+        self.nr10_sweep_control = r.u8()?;
+        self.nr11_length_data = r.u8()?;
+        self.nr12_volume_control = r.u8()?;
+        self.nr13_frequency_low = r.u8()?;
+        self.nr14_freq_high_control = r.u8()?;
+        self.frequency_timer = r.u32()?;
+        self.sweep_timer = r.u8()?;
+        self.sweep_enabled = r.bool()?;
+        self.sweep_shadow = r.i32()?;
+        self.sweep_occurred = r.bool()?;
+        self.current_volume = r.u8()?;
+        self.volume_increasing = r.bool()?;
+        self.envelope_timer = r.u8()?;
+        self.envelope_period = r.u8()?;
+        self.length_timer = r.u8()?;
+        self.wave_index = load_index(r, 8)?;
+        self.extra_length = r.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for SquareChannel2 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.dac_enabled);
+        w.bool(self.channel_enabled);
+        w.u8(self.nr21_length_data);
+        w.u8(self.nr22_volume_control);
+        w.u8(self.nr23_frequency_low);
+        w.u8(self.nr24_freq_high_control);
+        w.u32(self.frequency_timer);
+        w.u8(self.current_volume);
+        w.bool(self.volume_increasing);
+        w.u8(self.envelope_timer);
+        w.u8(self.envelope_period);
+        w.u8(self.length_timer);
+        w.u8(self.wave_index as u8);
+        w.bool(self.extra_length);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.dac_enabled = r.bool()?;
+        self.channel_enabled = r.bool()?;
+        self.nr21_length_data = r.u8()?;
+        self.nr22_volume_control = r.u8()?;
+        self.nr23_frequency_low = r.u8()?;
+        self.nr24_freq_high_control = r.u8()?;
+        self.frequency_timer = r.u32()?;
+        self.current_volume = r.u8()?;
+        self.volume_increasing = r.bool()?;
+        self.envelope_timer = r.u8()?;
+        self.envelope_period = r.u8()?;
+        self.length_timer = r.u8()?;
+        self.wave_index = load_index(r, 8)?;
+        self.extra_length = r.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.channel_enabled);
+        w.u8(self.nr30_dac_enable);
+        w.u8(self.nr31_length_timer);
+        w.u8(self.nr32_output_level);
+        w.u8(self.nr33_frequency_low);
+        w.u8(self.nr34_freq_high_control);
+        w.u32(self.frequency_timer);
+        w.u16(self.length_timer);
+        w.u8(self.sample_buffer);
+        w.bytes(&self.wave_ram);
+        w.u8(self.wave_index as u8);
+        w.bool(self.extra_length);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.channel_enabled = r.bool()?;
+        self.nr30_dac_enable = r.u8()?;
+        self.nr31_length_timer = r.u8()?;
+        self.nr32_output_level = r.u8()?;
+        self.nr33_frequency_low = r.u8()?;
+        self.nr34_freq_high_control = r.u8()?;
+        self.frequency_timer = r.u32()?;
+        self.length_timer = r.u16()?;
+        self.sample_buffer = r.u8()?;
+        r.bytes_into(&mut self.wave_ram)?;
+        self.wave_index = load_index(r, 32)?;
+        self.extra_length = r.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.channel_enabled);
+        w.bool(self.dac_enabled);
+        w.u8(self.nr41_length_timer);
+        w.u8(self.nr42_volume_control);
+        w.u8(self.nr43_freq_rng);
+        w.u8(self.nr44_channel_control);
+        w.u32(self.frequency_timer);
+        w.u16(self.length_timer);
+        w.u8(self.current_volume);
+        w.bool(self.volume_increasing);
+        w.u8(self.envelope_timer);
+        w.u8(self.envelope_period);
+        w.u16(self.lfsr);
+        w.u8(self.divisor);
+        w.bool(self.extra_length);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.channel_enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.nr41_length_timer = r.u8()?;
+        self.nr42_volume_control = r.u8()?;
+        self.nr43_freq_rng = r.u8()?;
+        self.nr44_channel_control = r.u8()?;
+        self.frequency_timer = r.u32()?;
+        self.length_timer = r.u16()?;
+        self.current_volume = r.u8()?;
+        self.volume_increasing = r.bool()?;
+        self.envelope_timer = r.u8()?;
+        self.envelope_period = r.u8()?;
+        self.lfsr = r.u16()?;
+        self.divisor = r.u8()?;
+        // NR43 only selects divisors of 8 or more, and the period divides by it
+        if self.divisor == 0 {
+            return Err(StateError::Corrupt);
+        }
+        self.extra_length = r.bool()?;
+        Ok(())
+    }
+}
+
+/// Reads a position within a waveform `len` steps long
+fn load_index(r: &mut StateReader<'_>, len: usize) -> Result<usize, StateError> {
+    let index = r.u8()? as usize;
+    if index < len {
+        Ok(index)
+    } else {
+        Err(StateError::Corrupt)
+    }
+}
+
+impl SaveState for Apu {
+    /// The output settings, such as the sample rate, are left to the host
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.nr50_output_control);
+        w.u8(self.nr51_channel_pan);
+        w.bool(self.all_sound_on);
+        self.square1.save_state(w);
+        self.square2.save_state(w);
+        self.wave.save_state(w);
+        self.noise.save_state(w);
+        w.u32(self.cycle_count);
+        w.u8(self.frame_cycle);
+        w.u32(self.sample_accumulator);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.nr50_output_control = r.u8()?;
+        self.nr51_channel_pan = r.u8()?;
+        self.all_sound_on = r.bool()?;
+        self.square1.load_state(r)?;
+        self.square2.load_state(r)?;
+        self.wave.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.cycle_count = r.u32()?;
+        self.frame_cycle = load_index(r, 8)? as u8;
+        // Rates may differ between hosts, so keep the accumulator within this one's range
+        self.sample_accumulator = r.u32()? % CLOCK_RATE;
+        Ok(())
+    }
+}
+
 /// The channel DACs convert 4-bit unsigned digital signals to -1.0 to 1.0 analog signals.
 fn convert_u4_to_f32_sample(sample: u8) -> f32 {
     // Mask off upper nibble to make sure it's 4-bit
@@ -1244,3 +1726,330 @@ fn convert_u4_to_f32_sample(sample: u8) -> f32 {
 
     (sample as f32 / 7.5) - 1.0
 }
+
+#[cfg(test)]
+mod apu_tests {
+    use super::*;
+
+    struct CountingSink(usize);
+
+    impl Sink<ChannelAudioFrame> for CountingSink {
+        fn append(&mut self, _value: ChannelAudioFrame) {
+            self.0 += 1;
+        }
+    }
+
+    struct ChannelSink(Vec<ChannelAudioFrame>);
+
+    impl Sink<ChannelAudioFrame> for ChannelSink {
+        fn append(&mut self, value: ChannelAudioFrame) {
+            self.0.push(value);
+        }
+    }
+
+    struct TrackSink(Vec<AudioFrame>);
+
+    impl Sink<AudioFrame> for TrackSink {
+        fn append(&mut self, value: AudioFrame) {
+            self.0.push(value);
+        }
+    }
+
+    #[test]
+    fn debug_state_display() {
+        let mut apu = Apu::power_on();
+        // Channel 1 at period 0x783, 1048 Hz, full volume
+        apu.write_byte(0xFF12, 0xF0);
+        apu.write_byte(0xFF13, 0x83);
+        apu.write_byte(0xFF14, 0x87);
+        let state = apu.debug_state();
+        assert!(state.channels[0].enabled);
+        assert_eq!(state.channels[0].period, 0x783);
+        assert_eq!(state.channels[0].frequency, 1048);
+        assert!(!state.channels[1].enabled);
+
+        let text = format!("{}", state);
+        assert!(text.contains("CH1 Square 1  on"));
+        assert!(text.contains("Period: 783    1048 Hz"));
+        assert!(text.contains("CH2 Square 2  off"));
+        assert!(text.contains("NR12: F0"));
+        assert!(text.contains("NR52: F1  Sound: on"));
+    }
+
+    #[test]
+    fn nr52_dac_disable_clears_status() {
+        let mut apu = Apu::power_on();
+        // Turn on each DAC and trigger each channel
+        apu.write_byte(0xFF12, 0xF0);
+        apu.write_byte(0xFF14, 0x80);
+        apu.write_byte(0xFF17, 0xF0);
+        apu.write_byte(0xFF19, 0x80);
+        apu.write_byte(0xFF1A, 0x80);
+        apu.write_byte(0xFF1E, 0x80);
+        apu.write_byte(0xFF21, 0xF0);
+        apu.write_byte(0xFF23, 0x80);
+        assert_eq!(apu.read_byte(0xFF26), 0xFF);
+
+        // Disabling a DAC clears that channel's status bit without any clocking
+        apu.write_byte(0xFF12, 0x00);
+        assert_eq!(apu.read_byte(0xFF26), 0xFE);
+        apu.write_byte(0xFF1A, 0x00);
+        assert_eq!(apu.read_byte(0xFF26), 0xFA);
+
+        // Re-enabling the DAC doesn't restart the channel, only a trigger does
+        apu.write_byte(0xFF12, 0xF0);
+        assert_eq!(apu.read_byte(0xFF26), 0xFA);
+        apu.write_byte(0xFF14, 0x80);
+        assert_eq!(apu.read_byte(0xFF26), 0xFB);
+    }
+
+    #[test]
+    fn nr52_trigger_with_dac_off() {
+        let mut apu = Apu::power_on();
+        // Power-on NRx2 values leave the DACs off, so triggering does nothing
+        apu.write_byte(0xFF14, 0x80);
+        apu.write_byte(0xFF19, 0x80);
+        apu.write_byte(0xFF23, 0x80);
+        assert_eq!(apu.read_byte(0xFF26), 0xF0);
+    }
+
+    #[test]
+    fn nr52_length_expiry_clears_status() {
+        let mut apu = Apu::power_on();
+        apu.write_byte(0xFF17, 0xF0);
+        // Length of 1, length enabled, trigger
+        apu.write_byte(0xFF16, 0x3F);
+        apu.write_byte(0xFF19, 0xC0);
+        assert_eq!(apu.read_byte(0xFF26) & 0x2, 0x2);
+        // A full frame sequencer cycle is guaranteed to clock length
+        let mut sink = CountingSink(0);
+        apu.update(FRAME_SEQ_PERIOD * 8, &mut sink);
+        assert!(sink.0 > 0);
+        assert_eq!(apu.read_byte(0xFF26) & 0x2, 0x0);
+    }
+
+    #[test]
+    fn noise_lfsr_period() {
+        let lfsr_period = |nr43: u8| {
+            let mut noise = Apu::power_on().noise;
+            noise.nr43_freq_rng = nr43;
+            let mut shift = || {
+                noise.frequency_timer = 0;
+                noise.step_freq();
+                noise.lfsr
+            };
+            // Let any bits from before the switch to 7-bit width shift out
+            for _ in 0..16 {
+                shift();
+            }
+            let start = shift();
+            (1..=0x8000).find(|_| shift() == start).unwrap()
+        };
+        assert_eq!(lfsr_period(0x00), 32767);
+        assert_eq!(lfsr_period(0x08), 127);
+    }
+
+    #[test]
+    fn silent_frames_while_off() {
+        let mut apu = Apu::power_on();
+        apu.write_byte(0xFF26, 0x00);
+        let mut sink = ChannelSink(vec![]);
+        // An eighth of a second, 6000 samples at the default rate
+        apu.update(CLOCK_RATE / 8, &mut sink);
+        assert_eq!(sink.0.len(), SAMPLE_RATE as usize / 8);
+        assert!(sink.0.iter().all(|frame| *frame == [(0.0, 0.0); 4]));
+    }
+
+    #[test]
+    fn muted_channels() {
+        let mut apu = Apu::power_on();
+        // Channels 1 and 2 at full volume
+        apu.write_byte(0xFF12, 0xF0);
+        apu.write_byte(0xFF14, 0x87);
+        apu.write_byte(0xFF17, 0xF0);
+        apu.write_byte(0xFF19, 0x87);
+        apu.set_channel_enabled(0, false);
+        assert!(!apu.channel_enabled(0));
+        assert!(apu.debug_state().channels[0].muted);
+        assert!(format!("{}", apu.debug_state()).contains("Muted"));
+
+        let mut sink = ChannelSink(vec![]);
+        apu.update(FRAME_SEQ_PERIOD, &mut sink);
+        assert!(sink.0.iter().all(|frame| frame[0] == (0.0, 0.0)));
+        assert!(sink.0.iter().any(|frame| frame[1] != (0.0, 0.0)));
+        // The muted channel keeps playing
+        assert!(apu.debug_state().channels[0].enabled);
+
+        apu.set_channel_enabled(0, true);
+        let mut sink = ChannelSink(vec![]);
+        apu.update(FRAME_SEQ_PERIOD, &mut sink);
+        assert!(sink.0.iter().any(|frame| frame[0] != (0.0, 0.0)));
+
+        // There's no fifth channel
+        apu.set_channel_enabled(4, true);
+        assert!(!apu.channel_enabled(4));
+    }
+
+    #[test]
+    fn no_sample_output() {
+        let mut apu = Apu::power_on();
+        apu.set_sample_output(false);
+        apu.write_byte(0xFF17, 0xF0);
+        // Length of 1, length enabled, trigger
+        apu.write_byte(0xFF16, 0x3F);
+        apu.write_byte(0xFF19, 0xC0);
+        let mut sink = CountingSink(0);
+        apu.update(FRAME_SEQ_PERIOD * 8, &mut sink);
+        assert_eq!(sink.0, 0);
+        // The channels still ran
+        assert_eq!(apu.read_byte(0xFF26) & 0x2, 0x0);
+
+        apu.set_sample_output(true);
+        apu.update(FRAME_SEQ_PERIOD * 8, &mut sink);
+        assert_eq!(sink.0, 750);
+    }
+
+    #[test]
+    fn snapshot_continues_waveform() {
+        let mut apu = Apu::power_on();
+        // Channel 1 at 50% duty with a decreasing envelope, channel 3 playing wave RAM
+        apu.write_byte(0xFF11, 0x80);
+        apu.write_byte(0xFF12, 0xF3);
+        apu.write_byte(0xFF13, 0x40);
+        apu.write_byte(0xFF14, 0x87);
+        for addr in 0xFF30..=0xFF3F {
+            apu.write_byte(addr, (addr as u8) << 4 | 0x0F);
+        }
+        apu.write_byte(0xFF1A, 0x80);
+        apu.write_byte(0xFF1C, 0x20);
+        apu.write_byte(0xFF1D, 0x00);
+        apu.write_byte(0xFF1E, 0x87);
+        // Stop partway through a period and a frame sequencer step
+        apu.update(FRAME_SEQ_PERIOD * 3 + 1234, &mut NullSink);
+
+        let mut restored = apu.clone();
+        let mut expected = ChannelSink(vec![]);
+        let mut actual = ChannelSink(vec![]);
+        apu.update(FRAME_SEQ_PERIOD * 8, &mut expected);
+        restored.update(FRAME_SEQ_PERIOD * 8, &mut actual);
+        assert!(expected.0.iter().any(|frame| frame[0] != frame[2]));
+        assert_eq!(actual.0, expected.0);
+    }
+
+    #[test]
+    fn non_dividing_sample_rate() {
+        for rate in [44_100, 48_000, 96_000] {
+            let mut apu = Apu::power_on();
+            apu.set_sample_rate(rate);
+            let mut sink = CountingSink(0);
+            // 3 seconds, in uneven steps
+            let mut cycles = 0;
+            while cycles < CLOCK_RATE * 3 {
+                let step = (CLOCK_RATE * 3 - cycles).min(1234);
+                apu.update(step, &mut sink);
+                cycles += step;
+            }
+            assert!(sink.0.abs_diff(rate as usize * 3) <= 1, "{}", sink.0);
+        }
+
+        // Out of range rates are clamped, rather than stopping audio or panicking
+        let mut apu = Apu::power_on();
+        let mut sink = CountingSink(0);
+        apu.set_sample_rate(0);
+        apu.update(CLOCK_RATE, &mut sink);
+        assert_eq!(sink.0, 1);
+        apu.set_sample_rate(u32::MAX);
+        apu.update(1000, &mut sink);
+        assert_eq!(sink.0, 1001);
+    }
+
+    #[test]
+    fn i16_output() {
+        struct Collect<T>(Vec<T>);
+        impl<T> Sink<T> for Collect<T> {
+            fn append(&mut self, value: T) {
+                self.0.push(value);
+            }
+        }
+
+        // Identical APUs playing Square 1 and Square 2
+        let setup = || {
+            let mut apu = Apu::power_on();
+            apu.write_byte(0xFF24, 0x77);
+            apu.write_byte(0xFF12, 0xF0);
+            apu.write_byte(0xFF14, 0x87);
+            apu.write_byte(0xFF17, 0xA0);
+            apu.write_byte(0xFF19, 0x86);
+            apu
+        };
+        let mut f32_sink = Collect(Vec::new());
+        setup().update(FRAME_SEQ_PERIOD, &mut MixedAudioSink(&mut f32_sink));
+        let mut i16_sink = Collect(Vec::new());
+        setup().update(FRAME_SEQ_PERIOD, &mut I16AudioSink(&mut i16_sink));
+
+        assert_eq!(f32_sink.0.len(), i16_sink.0.len());
+        for (f, i) in f32_sink.0.iter().zip(i16_sink.0.iter()) {
+            assert_eq!((sample_to_i16(f.0), sample_to_i16(f.1)), *i);
+        }
+        assert!(i16_sink.0.iter().any(|i| i.0 != 0));
+
+        // Out of range samples are clamped
+        assert_eq!(sample_to_i16(1.0), i16::MAX);
+        assert_eq!(sample_to_i16(1.5), i16::MAX);
+        assert_eq!(sample_to_i16(-1.0), -i16::MAX);
+        assert_eq!(sample_to_i16(-3.0), -i16::MAX);
+        assert_eq!(sample_to_i16(0.0), 0);
+    }
+
+    #[test]
+    fn split_channel_tracks() {
+        let mut apu = Apu::power_on();
+        // Trigger Square 1 and Noise at full volume, leave Square 2 and Wave silent
+        apu.write_byte(0xFF11, 0x80);
+        apu.write_byte(0xFF12, 0xF0);
+        apu.write_byte(0xFF14, 0x87);
+        apu.write_byte(0xFF21, 0xF0);
+        apu.write_byte(0xFF23, 0x80);
+
+        let mut tracks = [
+            TrackSink(Vec::new()),
+            TrackSink(Vec::new()),
+            TrackSink(Vec::new()),
+            TrackSink(Vec::new()),
+        ];
+        {
+            let [sq1, sq2, wave, noise] = &mut tracks;
+            let mut sink = SplitAudioSink([sq1, sq2, wave, noise]);
+            apu.update(FRAME_SEQ_PERIOD, &mut sink);
+        }
+        let expected_len =
+            (FRAME_SEQ_PERIOD as u64 * SAMPLE_RATE as u64 / CLOCK_RATE as u64) as usize;
+        for track in tracks.iter() {
+            assert_eq!(track.0.len(), expected_len);
+        }
+        assert!(tracks[0].0.iter().any(|s| s.0 != 0.0));
+        assert!(tracks[1].0.iter().all(|s| *s == (0.0, 0.0)));
+        assert!(tracks[2].0.iter().all(|s| *s == (0.0, 0.0)));
+        assert!(tracks[3].0.iter().any(|s| s.0 != 0.0));
+    }
+
+    #[test]
+    fn zero_noise_divisor_state() {
+        let mut w = StateWriter::new();
+        Apu::power_on().save_state(&mut w);
+        let mut state = w.into_inner();
+        assert_eq!(
+            Apu::power_on().load_state(&mut StateReader::new(&state)),
+            Ok(())
+        );
+        // The noise divisor, followed by extra_length, cycle_count, frame_cycle and the
+        // sample accumulator
+        let divisor = state.len() - 11;
+        assert_eq!(state[divisor], 8);
+        state[divisor] = 0;
+        assert_eq!(
+            Apu::power_on().load_state(&mut StateReader::new(&state)),
+            Err(StateError::Corrupt)
+        );
+    }
+}
@@ -1,7 +1,9 @@
 use alloc::boxed::Box;
-use alloc::string::*;
 
+use super::super::gb::StateError;
+use super::super::log_target;
 use super::super::mmu::Memory;
+use super::super::state::{SaveState, StateReader, StateWriter};
 use super::{Cartridge, CartridgeError};
 
 const CART_ROM_START: usize = 0x0000;
@@ -25,33 +27,47 @@ impl Mbc0 {
 impl Memory for Mbc0 {
     fn read_byte(&self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x7FFF => self.rom[addr as usize - CART_ROM_START],
+            0x0000..=0x7FFF => self
+                .rom
+                .get(addr as usize - CART_ROM_START)
+                .copied()
+                .unwrap_or(0xFF),
+            // No RAM on the cartridge, nothing drives the bus
+            0xA000..=0xBFFF => 0xFF,
             _ => {
-                error!("Unassigned read to MBC0 location {:04X}", addr);
+                error!(target: log_target::CARTRIDGE, "Unassigned read to MBC0 location {:04X}", addr);
                 0xFF
             }
         }
     }
     fn write_byte(&mut self, addr: u16, val: u8) {
-        error!(
-            "Unassigned write to MBC0 location {:04X} of value {:02X}",
-            addr, val
-        );
+        match addr {
+            // Writes to where cartridge RAM would be are dropped
+            0xA000..=0xBFFF => {}
+            _ => error!(target: log_target::CARTRIDGE,
+                "Unassigned write to MBC0 location {:04X} of value {:02X}",
+                addr, val
+            ),
+        }
     }
 }
 
 impl Cartridge for Mbc0 {
     fn read_save_data(&mut self, _data: Box<[u8]>) -> Result<(), CartridgeError> {
         // No RAM file to write save to, do nothing
-        Err(CartridgeError::Unsupported(
-            "MBC0 does not support save file writing.".to_string(),
-        ))
+        Err(CartridgeError::NotBatteryBacked)
     }
 
     fn write_save_data(&self) -> Result<Box<[u8]>, CartridgeError> {
         // No RAM file to write save to, do nothing
-        Err(CartridgeError::Unsupported(
-            "MBC0 does not support save file writing.".to_string(),
-        ))
+        Err(CartridgeError::NotBatteryBacked)
+    }
+}
+
+impl SaveState for Mbc0 {
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader<'_>) -> Result<(), StateError> {
+        Ok(())
     }
 }
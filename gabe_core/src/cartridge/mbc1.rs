@@ -1,10 +1,10 @@
-use core::cmp::Ordering;
-
 use alloc::boxed::Box;
-use alloc::string::*;
 use alloc::vec::*;
 
+use super::super::gb::StateError;
+use super::super::log_target;
 use super::super::mmu::Memory;
+use super::super::state::{SaveState, StateReader, StateWriter};
 use super::{Cartridge, CartridgeError};
 
 // Maximum can support 2 MB worth of ROM banks, which is 0x7F = 128 16-Kb banks
@@ -57,6 +57,16 @@ impl Mbc1 {
             mode1_enabled: false,
         }
     }
+
+    /// Translates an address in 0xA000-0xBFFF to an index into `ram`, which may be out of bounds
+    fn ram_index(&self, addr: u16) -> usize {
+        if self.mode1_enabled {
+            (addr - 0xA000) as usize + 0x2000 * self.ram_bank as usize
+        } else {
+            // Without Mode 1, RAM always uses bank 0.
+            (addr - 0xA000) as usize
+        }
+    }
 }
 
 impl Memory for Mbc1 {
@@ -80,19 +90,14 @@ impl Memory for Mbc1 {
             }
             0xA000..=0xBFFF => {
                 if self.ram_enabled {
-                    if self.mode1_enabled {
-                        self.ram
-                            [((addr - 0xA000) as u32 + (0x2000u32 * self.ram_bank as u32)) as usize]
-                    } else {
-                        // Without Mode 1, RAM always uses bank 0.
-                        self.ram[(addr - 0xA000) as usize]
-                    }
+                    // Addresses past the end of a small RAM read as an open bus
+                    self.ram.get(self.ram_index(addr)).copied().unwrap_or(0xFF)
                 } else {
                     0xFF
                 }
             }
             _ => {
-                error!("Invalid cartridge read address {}", addr);
+                error!(target: log_target::CARTRIDGE, "Invalid cartridge read address {}", addr);
                 0
             }
         }
@@ -134,16 +139,13 @@ impl Memory for Mbc1 {
             }
             0xA000..=0xBFFF => {
                 if self.ram_enabled {
-                    if self.mode1_enabled {
-                        self.ram[((addr - 0xA000) as u32 + (0x2000u32 * self.ram_bank as u32))
-                            as usize] = val;
-                    } else {
-                        // Without Mode 1, RAM always uses bank 0.
-                        self.ram[(addr - 0xA000) as usize] = val;
+                    let index = self.ram_index(addr);
+                    if let Some(byte) = self.ram.get_mut(index) {
+                        *byte = val;
                     }
                 }
             }
-            _ => error!("Invalid cartridge write address {}", addr),
+            _ => error!(target: log_target::CARTRIDGE, "Invalid cartridge write address {}", addr),
         }
     }
 }
@@ -152,31 +154,9 @@ impl Cartridge for Mbc1 {
     fn read_save_data(&mut self, data: Box<[u8]>) -> Result<(), CartridgeError> {
         if self.has_battery && self.ram_bank_count >= 0x1 {
             // We have battery-backed RAM available to read from a file
-            // If we hit a read error, just propagate up, otherwise we succeed.
-            match data.len().cmp(&self.ram.len()) {
-                Ordering::Equal => {
-                    self.ram.copy_from_slice(data.as_ref());
-                    Ok(())
-                }
-                Ordering::Greater => {
-                    // Fill RAM with data until full
-                    for (i, v) in self.ram.iter_mut().enumerate() {
-                        *v = data[i];
-                    }
-                    Ok(())
-                }
-                Ordering::Less => {
-                    // Fill RAM with data until out of data
-                    for (i, v) in data.iter().enumerate() {
-                        self.ram[i] = *v;
-                    }
-                    Ok(())
-                }
-            }
+            super::load_ram(&mut self.ram, &data)
         } else {
-            Err(CartridgeError::Unsupported(
-                "Game doesn't support save files via battery-backed RAM.".to_string(),
-            ))
+            Err(CartridgeError::NotBatteryBacked)
         }
     }
 
@@ -186,9 +166,43 @@ impl Cartridge for Mbc1 {
             // Provide cloned RAM data as a pointer
             Ok(self.ram.clone())
         } else {
-            Err(CartridgeError::Unsupported(
-                "Game doesn't support save files via battery-backed RAM.".to_string(),
-            ))
+            Err(CartridgeError::NotBatteryBacked)
+        }
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    fn ram_bank(&self) -> Option<usize> {
+        if !self.ram_enabled {
+            None
+        } else if self.mode1_enabled {
+            Some(self.ram_bank as usize)
+        } else {
+            Some(0)
+        }
+    }
+}
+
+impl SaveState for Mbc1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_enabled);
+        w.bool(self.mode1_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        r.bytes_into(&mut self.ram)?;
+        self.rom_bank = r.u8()?;
+        if self.rom_bank >= self.rom_bank_count {
+            return Err(StateError::Corrupt);
         }
+        self.ram_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        self.mode1_enabled = r.bool()?;
+        Ok(())
     }
 }
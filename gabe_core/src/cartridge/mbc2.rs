@@ -1,10 +1,10 @@
-use core::cmp::Ordering;
-
 use alloc::boxed::Box;
-use alloc::string::ToString;
 use alloc::vec::*;
 
+use super::super::gb::StateError;
+use super::super::log_target;
 use super::super::mmu::Memory;
+use super::super::state::{SaveState, StateReader, StateWriter};
 use super::{Cartridge, CartridgeError};
 
 // Maximum can support 256 KB worth of ROM banks, which is 0x10 = 16 16-KB banks
@@ -65,7 +65,7 @@ impl Memory for Mbc2 {
                 }
             }
             _ => {
-                error!("Invalid cartridge read address {:X}", addr);
+                error!(target: log_target::CARTRIDGE, "Invalid cartridge read address {:X}", addr);
                 0
             }
         }
@@ -98,7 +98,9 @@ impl Memory for Mbc2 {
                     self.ram[((addr - 0xA000) & 0x1FF) as usize] = val & 0xF;
                 }
             }
-            _ => error!("Invalid cartridge write address {:X}", addr),
+            _ => {
+                error!(target: log_target::CARTRIDGE, "Invalid cartridge write address {:X}", addr)
+            }
         }
     }
 }
@@ -107,31 +109,9 @@ impl Cartridge for Mbc2 {
     fn read_save_data(&mut self, data: Box<[u8]>) -> Result<(), CartridgeError> {
         if self.has_battery {
             // We have battery-backed RAM available to read from a file
-            // If we hit a read error, just propagate up, otherwise we succeed.
-            match data.len().cmp(&self.ram.len()) {
-                Ordering::Equal => {
-                    self.ram.copy_from_slice(data.as_ref());
-                    Ok(())
-                }
-                Ordering::Greater => {
-                    // Fill RAM with data until full
-                    for (i, v) in self.ram.iter_mut().enumerate() {
-                        *v = data[i];
-                    }
-                    Ok(())
-                }
-                Ordering::Less => {
-                    // Fill RAM with data until out of data
-                    for (i, v) in data.iter().enumerate() {
-                        self.ram[i] = *v;
-                    }
-                    Ok(())
-                }
-            }
+            super::load_ram(&mut self.ram, &data)
         } else {
-            Err(CartridgeError::Unsupported(
-                "Game doesn't support save files via battery-backed RAM.".to_string(),
-            ))
+            Err(CartridgeError::NotBatteryBacked)
         }
     }
 
@@ -141,9 +121,38 @@ impl Cartridge for Mbc2 {
             // Provide cloned RAM data as a pointer
             Ok(self.ram.clone())
         } else {
-            Err(CartridgeError::Unsupported(
-                "Game doesn't support save files via battery-backed RAM.".to_string(),
-            ))
+            Err(CartridgeError::NotBatteryBacked)
+        }
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    fn ram_bank(&self) -> Option<usize> {
+        // Built-in RAM only has the single bank
+        if self.ram_enabled {
+            Some(0)
+        } else {
+            None
+        }
+    }
+}
+
+impl SaveState for Mbc2 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.rom_bank);
+        w.bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        r.bytes_into(&mut self.ram)?;
+        self.rom_bank = r.u8()?;
+        if self.rom_bank >= self.rom_bank_count {
+            return Err(StateError::Corrupt);
         }
+        self.ram_enabled = r.bool()?;
+        Ok(())
     }
 }
@@ -1,30 +1,209 @@
-use core::cmp::Ordering;
-
 use alloc::boxed::Box;
-use alloc::string::ToString;
 use alloc::vec::*;
 
+use super::super::gb::StateError;
+use super::super::log_target;
 use super::super::mmu::Memory;
+use super::super::state::{SaveState, StateReader, StateWriter};
 use super::{Cartridge, CartridgeError};
 
 // Maximum can support 2 MB worth of ROM banks, which is 0x7F = 128 16-Kb banks
 const MAX_ROM_SIZE: u32 = 0x20_0000;
 
+/// Cycles in one second of the RTC, which runs off its own crystal at the same pace
+const RTC_SECOND: u32 = super::super::CLOCK_RATE;
+
+/// Size of the clock appended to save data, in the layout most emulators share: the live
+/// then latched registers as 32-bit values, then the host time they were saved at
+const RTC_FOOTER_SIZE: usize = 48;
+/// Size of the same clock saved by older emulators, with a 32-bit host time
+const RTC_FOOTER_SIZE_32: usize = 44;
+
+// Indexes of the RTC registers, mapped by writing 0x08-0x0C to 0x4000-0x5FFF
+const SECONDS: usize = 0;
+const MINUTES: usize = 1;
+const HOURS: usize = 2;
+const DAY_LOW: usize = 3;
+const DAY_HIGH: usize = 4;
+
+/// Bits that exist in each of the RTC registers
+const RTC_MASKS: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+const HALT: u8 = 0x40;
+const DAY_CARRY: u8 = 0x80;
+
+/// The MBC3's Real-Time Clock, counting seconds up to 511 days
+#[derive(Default)]
+struct Rtc {
+    /// Seconds, minutes, hours, the low 8 bits of the day counter, then:
+    /// Bit 0 - Bit 8 of the day counter
+    /// Bit 6 - Halt, stopping the clock
+    /// Bit 7 - Day counter carry, set on overflow until cleared by a write
+    regs: [u8; 5],
+    /// Copy of `regs` taken when latched, which is what reads return
+    latched: [u8; 5],
+    /// Last value written to 0x6000-0x7FFF, writing 0x00 then 0x01 latches the clock
+    latch_write: u8,
+    /// Cycles into the current second
+    cycles: u32,
+    /// Host time, in seconds since the Unix epoch, that `regs` are correct for. Set when
+    /// synced, and moved on with each second emulated so saves made between syncs agree.
+    timestamp: u64,
+    /// Whether the clock has yet to catch up on the time since `timestamp`, after being
+    /// loaded from save data
+    catch_up: bool,
+}
+
+impl Rtc {
+    fn halted(&self) -> bool {
+        self.regs[DAY_HIGH] & HALT != 0
+    }
+
+    fn day(&self) -> u64 {
+        self.regs[DAY_LOW] as u64 | (self.regs[DAY_HIGH] as u64 & 0x1) << 8
+    }
+
+    /// Sets the day counter, setting the carry if `day` overflowed it
+    fn set_day(&mut self, day: u64) {
+        if day > 0x1FF {
+            self.regs[DAY_HIGH] |= DAY_CARRY;
+        }
+        self.regs[DAY_LOW] = day as u8;
+        self.regs[DAY_HIGH] = (self.regs[DAY_HIGH] & !0x1) | ((day >> 8) & 0x1) as u8;
+    }
+
+    fn update(&mut self, cycles: u32) {
+        if self.halted() {
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= RTC_SECOND {
+            self.cycles -= RTC_SECOND;
+            self.tick();
+            self.timestamp += 1;
+        }
+    }
+
+    /// Counts one second. Values written out of range count up to the most their register
+    /// holds, then wrap to 0 without carrying.
+    fn tick(&mut self) {
+        self.regs[SECONDS] = (self.regs[SECONDS] + 1) & RTC_MASKS[SECONDS];
+        if self.regs[SECONDS] != 60 {
+            return;
+        }
+        self.regs[SECONDS] = 0;
+        self.regs[MINUTES] = (self.regs[MINUTES] + 1) & RTC_MASKS[MINUTES];
+        if self.regs[MINUTES] != 60 {
+            return;
+        }
+        self.regs[MINUTES] = 0;
+        self.regs[HOURS] = (self.regs[HOURS] + 1) & RTC_MASKS[HOURS];
+        if self.regs[HOURS] != 24 {
+            return;
+        }
+        self.regs[HOURS] = 0;
+        self.set_day(self.day() + 1);
+    }
+
+    /// Counts `seconds` at once, for time passed while the emulator wasn't running
+    fn advance(&mut self, mut seconds: u64) {
+        if self.halted() {
+            return;
+        }
+        // Out of range values don't carry normally, so count through them a second at a time
+        while seconds > 0
+            && (self.regs[SECONDS] >= 60 || self.regs[MINUTES] >= 60 || self.regs[HOURS] >= 24)
+        {
+            self.tick();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+        let total = seconds
+            + self.regs[SECONDS] as u64
+            + self.regs[MINUTES] as u64 * 60
+            + self.regs[HOURS] as u64 * 3600;
+        self.regs[SECONDS] = (total % 60) as u8;
+        self.regs[MINUTES] = (total / 60 % 60) as u8;
+        self.regs[HOURS] = (total / 3600 % 24) as u8;
+        self.set_day(self.day() + total / 86400);
+    }
+
+    fn read(&self, reg: usize) -> u8 {
+        self.latched[reg]
+    }
+
+    /// Writes go to the live clock, and show up in reads straight away
+    fn write(&mut self, reg: usize, val: u8) {
+        let val = val & RTC_MASKS[reg];
+        self.regs[reg] = val;
+        self.latched[reg] = val;
+        if reg == SECONDS {
+            // Writing the seconds resets the divider counting towards the next one
+            self.cycles = 0;
+        }
+    }
+
+    fn latch(&mut self, val: u8) {
+        if self.latch_write == 0x00 && val == 0x01 {
+            self.latched = self.regs;
+        }
+        self.latch_write = val;
+    }
+
+    /// Returns whether the clock changed, along with the save data it's kept in
+    fn sync(&mut self, now: u64) -> bool {
+        let changed = self.catch_up || self.timestamp != now;
+        if self.catch_up {
+            self.advance(now.saturating_sub(self.timestamp));
+            self.catch_up = false;
+        }
+        self.timestamp = now;
+        changed
+    }
+
+    fn write_footer(&self, data: &mut Vec<u8>) {
+        for reg in self.regs.iter().chain(&self.latched) {
+            data.extend_from_slice(&(*reg as u32).to_le_bytes());
+        }
+        data.extend_from_slice(&self.timestamp.to_le_bytes());
+    }
+
+    /// Loads the clock from a footer of `RTC_FOOTER_SIZE` or `RTC_FOOTER_SIZE_32` bytes,
+    /// to catch up on the time since it was saved when next synced
+    fn read_footer(&mut self, footer: &[u8]) {
+        let (regs, timestamp) = footer.split_at(40);
+        for (i, reg) in regs.chunks_exact(4).enumerate() {
+            let val = u32::from_le_bytes(reg.try_into().unwrap()) as u8 & RTC_MASKS[i % 5];
+            if i < 5 {
+                self.regs[i] = val;
+            } else {
+                self.latched[i - 5] = val;
+            }
+        }
+        let mut time = [0; 8];
+        time[..timestamp.len()].copy_from_slice(timestamp);
+        self.timestamp = u64::from_le_bytes(time);
+        self.catch_up = true;
+    }
+}
+
 /// MBC3 cartridges can support up to 2 MB of ROM banks and/or 32 KB of RAM banks
 /// Requires to be provided the ROM and RAM size to calculate the number of
 /// ROM/RAM banks to support
-/// Also supports a Real-Time Clock
+/// Also supports a Real-Time Clock, which is kept in save data along with RAM
 pub struct Mbc3 {
     rom: Box<[u8]>,
     ram: Box<[u8]>,
     rom_bank: u8,
-    _rom_bank_count: u8,
+    rom_bank_count: u8,
     ram_bank: u8,
     ram_bank_count: u8,
     ram_enabled: bool,
     has_battery: bool,
-    _has_rtc: bool,
-    rtc_enabled: bool,
+    rtc: Option<Rtc>,
+    /// The RTC register mapped into 0xA000-0xBFFF in place of RAM, if any
+    rtc_register: Option<usize>,
 }
 
 impl Mbc3 {
@@ -53,22 +232,24 @@ impl Mbc3 {
             _ => panic!("Provided RAM Size unsupported for MBC3."),
         };
         let ram: Vec<u8> = vec![0; (0x2000u32 * ram_bank_count as u32) as usize];
-        if has_rtc {
-            error!("MBC3 RTC not implemented, clock info will not be provided.");
-        }
         Mbc3 {
             rom,
             ram: ram.into_boxed_slice(),
             rom_bank: 1,
             ram_bank: 0,
-            _rom_bank_count: rom_bank_count,
+            rom_bank_count,
             ram_bank_count,
             ram_enabled: false,
             has_battery,
-            _has_rtc: has_rtc,
-            rtc_enabled: false,
+            rtc: has_rtc.then(Rtc::default),
+            rtc_register: None,
         }
     }
+
+    /// Translates an address in 0xA000-0xBFFF to an index into `ram`, which may be out of bounds
+    fn ram_index(&self, addr: u16) -> usize {
+        (addr - 0xA000) as usize + 0x2000 * self.ram_bank as usize
+    }
 }
 
 impl Memory for Mbc3 {
@@ -81,18 +262,14 @@ impl Memory for Mbc3 {
             0x4000..=0x7FFF => {
                 self.rom[((addr - 0x4000) as u32 + (0x4000u32 * self.rom_bank as u32)) as usize]
             }
-            0xA000..=0xBFFF => {
-                if self.rtc_enabled {
-                    // TODO: Read RTC regs
-                    0x00
-                } else if self.ram_enabled {
-                    self.ram[((addr - 0xA000) as u32 + (0x2000u32 * self.ram_bank as u32)) as usize]
-                } else {
-                    0xFF
-                }
-            }
+            0xA000..=0xBFFF => match (&self.rtc, self.rtc_register) {
+                _ if !self.ram_enabled => 0xFF,
+                (Some(rtc), Some(reg)) => rtc.read(reg),
+                // Addresses past the end of a small RAM read as an open bus
+                _ => self.ram.get(self.ram_index(addr)).copied().unwrap_or(0xFF),
+            },
             _ => {
-                error!("Invalid cartridge read address {}", addr);
+                error!(target: log_target::CARTRIDGE, "Invalid cartridge read address {}", addr);
                 0
             }
         }
@@ -100,7 +277,9 @@ impl Memory for Mbc3 {
     fn write_byte(&mut self, addr: u16, val: u8) {
         match addr {
             0x0000..=0x1FFF => {
-                self.ram_enabled = ((val & 0xF) == 0x0A) && self.ram_bank_count != 0;
+                // Also enables access to the RTC
+                self.ram_enabled =
+                    ((val & 0xF) == 0x0A) && (self.ram_bank_count != 0 || self.rtc.is_some());
             }
             0x2000..=0x3FFF => {
                 if (val & 0x7F) == 0x0 {
@@ -109,67 +288,138 @@ impl Memory for Mbc3 {
                     self.rom_bank = val & 0x7F;
                 }
             }
-            0x4000..=0x5FFF => {
-                if self.ram_bank_count == 0x4 {
-                    // Using 32 KB of ram, select the RAM bank
-                    self.ram_bank = val & 0x3;
+            0x4000..=0x5FFF => match val {
+                0x08..=0x0C if self.rtc.is_some() => {
+                    self.rtc_register = Some((val - 0x08) as usize);
                 }
-            }
-            0xA000..=0xBFFF => {
-                if self.rtc_enabled {
-                    // TODO: RTC registers
-                } else if self.ram_enabled {
-                    self.ram
-                        [((addr - 0xA000) as u32 + (0x2000u32 * self.ram_bank as u32)) as usize] =
-                        val;
+                _ => {
+                    self.rtc_register = None;
+                    if self.ram_bank_count == 0x4 {
+                        // Using 32 KB of ram, select the RAM bank
+                        self.ram_bank = val & 0x3;
+                    }
+                }
+            },
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.latch(val);
                 }
             }
-            _ => error!("Invalid cartridge write address {}", addr),
+            0xA000..=0xBFFF => match (self.rtc.as_mut(), self.rtc_register) {
+                _ if !self.ram_enabled => {}
+                (Some(rtc), Some(reg)) => rtc.write(reg, val),
+                _ => {
+                    let index = self.ram_index(addr);
+                    if let Some(byte) = self.ram.get_mut(index) {
+                        *byte = val;
+                    }
+                }
+            },
+            _ => error!(target: log_target::CARTRIDGE, "Invalid cartridge write address {}", addr),
         }
     }
 }
 
 impl Cartridge for Mbc3 {
+    /// Save data may have the RTC's footer after RAM. Without one the clock keeps its time.
     fn read_save_data(&mut self, data: Box<[u8]>) -> Result<(), CartridgeError> {
-        if self.has_battery {
-            // We have battery-backed RAM available to read from a file
-            // If we hit a read error, just propagate up, otherwise we succeed.
-            match data.len().cmp(&self.ram.len()) {
-                Ordering::Equal => {
-                    self.ram.copy_from_slice(data.as_ref());
-                    Ok(())
-                }
-                Ordering::Greater => {
-                    // Fill RAM with data until full
-                    for (i, v) in self.ram.iter_mut().enumerate() {
-                        *v = data[i];
-                    }
-                    Ok(())
-                }
-                Ordering::Less => {
-                    // Fill RAM with data until out of data
-                    for (i, v) in data.iter().enumerate() {
-                        self.ram[i] = *v;
-                    }
-                    Ok(())
-                }
+        if !self.has_battery {
+            return Err(CartridgeError::NotBatteryBacked);
+        }
+        let (ram, footer) = data.split_at(self.ram.len().min(data.len()));
+        match (self.rtc.as_mut(), footer.len()) {
+            (_, 0) => {}
+            (Some(rtc), RTC_FOOTER_SIZE | RTC_FOOTER_SIZE_32) => rtc.read_footer(footer),
+            (rtc, _) => {
+                return Err(CartridgeError::SizeMismatch {
+                    expected: self.ram.len() + if rtc.is_some() { RTC_FOOTER_SIZE } else { 0 },
+                    got: data.len(),
+                })
             }
-        } else {
-            Err(CartridgeError::Unsupported(
-                "Game doesn't support save files via battery-backed RAM.".to_string(),
-            ))
         }
+        super::load_ram(&mut self.ram, ram)
     }
 
+    /// Provides RAM followed by the RTC's footer, if the cartridge has one
     fn write_save_data(&self) -> Result<Box<[u8]>, CartridgeError> {
-        if self.has_battery {
-            // We have battery-backed RAM available to maintain save data
-            // Provide cloned RAM data as a pointer
-            Ok(self.ram.clone())
+        if !self.has_battery {
+            return Err(CartridgeError::NotBatteryBacked);
+        }
+        let mut data = self.ram.to_vec();
+        if let Some(rtc) = &self.rtc {
+            rtc.write_footer(&mut data);
+        }
+        Ok(data.into_boxed_slice())
+    }
+
+    fn update(&mut self, cycles: u32) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.update(cycles);
+        }
+    }
+
+    fn sync_rtc(&mut self, now: u64) -> bool {
+        match &mut self.rtc {
+            Some(rtc) => rtc.sync(now),
+            None => false,
+        }
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    fn ram_bank(&self) -> Option<usize> {
+        if self.ram_enabled && self.ram_bank_count != 0 && self.rtc_register.is_none() {
+            Some(self.ram_bank as usize)
         } else {
-            Err(CartridgeError::Unsupported(
-                "Game doesn't support save files via battery-backed RAM.".to_string(),
-            ))
+            None
+        }
+    }
+}
+
+impl SaveState for Mbc3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_enabled);
+        w.u8(self.rtc_register.map_or(0xFF, |reg| reg as u8));
+        if let Some(rtc) = &self.rtc {
+            w.bytes(&rtc.regs);
+            w.bytes(&rtc.latched);
+            w.u8(rtc.latch_write);
+            w.u32(rtc.cycles);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        r.bytes_into(&mut self.ram)?;
+        self.rom_bank = r.u8()?;
+        if self.rom_bank >= self.rom_bank_count {
+            return Err(StateError::Corrupt);
+        }
+        self.ram_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        self.rtc_register = match (r.u8()?, &self.rtc) {
+            (0xFF, _) => None,
+            (reg @ 0..=4, Some(_)) => Some(reg as usize),
+            _ => return Err(StateError::Corrupt),
+        };
+        // The host time isn't part of the state, it's kept as last synced
+        if let Some(rtc) = self.rtc.as_mut() {
+            r.bytes_into(&mut rtc.regs)?;
+            r.bytes_into(&mut rtc.latched)?;
+            let in_range = |regs: &[u8; 5]| regs.iter().zip(RTC_MASKS).all(|(r, m)| r & !m == 0);
+            if !in_range(&rtc.regs) || !in_range(&rtc.latched) {
+                return Err(StateError::Corrupt);
+            }
+            rtc.latch_write = r.u8()?;
+            rtc.cycles = r.u32()?;
+            if rtc.cycles >= RTC_SECOND {
+                return Err(StateError::Corrupt);
+            }
         }
+        Ok(())
     }
 }
@@ -1,33 +1,252 @@
-pub mod mbc0;
-pub mod mbc1;
-pub mod mbc2;
-pub mod mbc3;
+pub(crate) mod mbc0;
+pub(crate) mod mbc1;
+pub(crate) mod mbc2;
+pub(crate) mod mbc3;
 
 use alloc::boxed::Box;
 use alloc::fmt;
 use alloc::string::String;
 
+use super::gb::CgbSupport;
+
+/// All known cartridge type bytes from the header at 0x147, with their names, whether they
+/// can be loaded and whether they have a battery
+const CARTRIDGE_TYPES: [(u8, &str, bool, bool); 28] = [
+    (0x00, "ROM ONLY", true, false),
+    (0x01, "MBC1", true, false),
+    (0x02, "MBC1+RAM", true, false),
+    (0x03, "MBC1+RAM+BATTERY", true, true),
+    (0x05, "MBC2", true, false),
+    (0x06, "MBC2+BATTERY", true, true),
+    (0x08, "ROM+RAM", false, false),
+    (0x09, "ROM+RAM+BATTERY", false, true),
+    (0x0B, "MMM01", false, false),
+    (0x0C, "MMM01+RAM", false, false),
+    (0x0D, "MMM01+RAM+BATTERY", false, true),
+    (0x0F, "MBC3+TIMER+BATTERY", true, true),
+    (0x10, "MBC3+TIMER+RAM+BATTERY", true, true),
+    (0x11, "MBC3", true, false),
+    (0x12, "MBC3+RAM", true, false),
+    (0x13, "MBC3+RAM+BATTERY", true, true),
+    (0x19, "MBC5", false, false),
+    (0x1A, "MBC5+RAM", false, false),
+    (0x1B, "MBC5+RAM+BATTERY", false, true),
+    (0x1C, "MBC5+RUMBLE", false, false),
+    (0x1D, "MBC5+RUMBLE+RAM", false, false),
+    (0x1E, "MBC5+RUMBLE+RAM+BATTERY", false, true),
+    (0x20, "MBC6", false, false),
+    (0x22, "MBC7+SENSOR+RUMBLE+RAM+BATTERY", false, true),
+    (0xFC, "POCKET CAMERA", false, false),
+    (0xFD, "BANDAI TAMA5", false, false),
+    (0xFE, "HuC3", false, false),
+    (0xFF, "HuC1+RAM+BATTERY", false, true),
+];
+
+/// How many of `CARTRIDGE_TYPES` can be loaded
+const SUPPORTED_COUNT: usize = 11;
+
+/// The cartridge types that can be loaded, picked out of `CARTRIDGE_TYPES`
+const SUPPORTED_TYPES: [(u8, &str); SUPPORTED_COUNT] = {
+    let mut types = [(0, ""); SUPPORTED_COUNT];
+    let (mut i, mut count) = (0, 0);
+    while i < CARTRIDGE_TYPES.len() {
+        let (cart_type, name, supported, _) = CARTRIDGE_TYPES[i];
+        if supported {
+            types[count] = (cart_type, name);
+            count += 1;
+        }
+        i += 1;
+    }
+    assert!(count == SUPPORTED_COUNT);
+    types
+};
+
+/// Lists the cartridge type bytes (header 0x147) that can be loaded, with human readable names
+pub fn supported_types() -> &'static [(u8, &'static str)] {
+    &SUPPORTED_TYPES
+}
+
+/// Looks up a cartridge type byte (header 0x147) in `CARTRIDGE_TYPES`
+fn find_type(cart_type: u8) -> Option<&'static (u8, &'static str, bool, bool)> {
+    CARTRIDGE_TYPES.iter().find(|(t, ..)| *t == cart_type)
+}
+
+/// Whether a cartridge type byte (header 0x147) includes a battery, which keeps cartridge RAM
+/// or the real time clock running while the Gameboy is off. Covers types that can't be loaded.
+pub fn has_battery(cart_type: u8) -> bool {
+    find_type(cart_type).map_or(false, |(.., battery)| *battery)
+}
+
+/// The end of the cartridge header, which every ROM must be at least this long to contain
+pub const HEADER_END: usize = 0x150;
+
+/// Where a game was sold, from the destination code at 0x14A
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Japan,
+    Overseas,
+}
+
+/// The publisher of a game. Older games give a one byte code at 0x14B, newer games set that
+/// to 0x33 and give two ASCII characters at 0x144-0x145 instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Licensee {
+    Old(u8),
+    New([u8; 2]),
+}
+
+/// The information about a game in its cartridge header at 0x0134-0x014F, for frontends to
+/// show or check before booting it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    /// Upper case ASCII, with any other characters replaced by '?'
+    pub title: String,
+    pub cgb_support: CgbSupport,
+    /// Whether the game uses Super Game Boy functions
+    pub sgb_support: bool,
+    /// The cartridge type byte at 0x147, naming the MBC and other hardware on the cartridge
+    pub cartridge_type: u8,
+    /// Size of the ROM in bytes, or `None` if the size code isn't known
+    pub rom_size: Option<usize>,
+    /// Size of the cartridge RAM in bytes, or `None` if the size code isn't known.
+    /// MBC2 has RAM built in, so reports 0 here.
+    pub ram_size: Option<usize>,
+    pub destination: Destination,
+    pub licensee: Licensee,
+    /// Mask ROM version number, usually 0
+    pub version: u8,
+    /// Checksum of 0x0134-0x014C stored at 0x014D, which the boot ROM refuses to run
+    /// a game without
+    pub header_checksum: u8,
+    /// Checksum calculated from the header bytes
+    calculated_checksum: u8,
+}
+
+impl CartridgeHeader {
+    /// Reads the header of a ROM, or returns `None` if the ROM is too short to contain one
+    pub fn parse(rom: &[u8]) -> Option<Self> {
+        if rom.len() < HEADER_END {
+            return None;
+        }
+        let cgb_support = CgbSupport::from_header_flag(rom[0x143]);
+        // The CGB flag takes the last byte of the title on newer games
+        let title_end = if cgb_support == CgbSupport::None {
+            0x144
+        } else {
+            0x143
+        };
+        let title = rom[0x134..title_end]
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| {
+                if c.is_ascii_graphic() || *c == b' ' {
+                    *c as char
+                } else {
+                    '?'
+                }
+            })
+            .collect::<String>()
+            .trim_end()
+            .into();
+        let licensee = match rom[0x14B] {
+            0x33 => Licensee::New([rom[0x144], rom[0x145]]),
+            code => Licensee::Old(code),
+        };
+        Some(CartridgeHeader {
+            title,
+            cgb_support,
+            // SGB functions are only enabled for games using the new licensee code
+            sgb_support: rom[0x146] == 0x03 && rom[0x14B] == 0x33,
+            cartridge_type: rom[0x147],
+            rom_size: (rom[0x148] <= 8).then(|| 0x8000 << rom[0x148]),
+            ram_size: match rom[0x149] {
+                0x0 | 0x1 => Some(0),
+                0x2 => Some(0x2000),
+                0x3 => Some(0x8000),
+                0x4 => Some(0x20000),
+                0x5 => Some(0x10000),
+                _ => None,
+            },
+            destination: if rom[0x14A] == 0 {
+                Destination::Japan
+            } else {
+                Destination::Overseas
+            },
+            licensee,
+            version: rom[0x14C],
+            header_checksum: rom[0x14D],
+            calculated_checksum: rom[0x134..=0x14C]
+                .iter()
+                .fold(0u8, |sum, b| sum.wrapping_sub(*b).wrapping_sub(1)),
+        })
+    }
+
+    /// The name of the cartridge type, e.g. "MBC1+RAM", or `None` for unknown types
+    pub fn cartridge_type_name(&self) -> Option<&'static str> {
+        find_type(self.cartridge_type).map(|(_, name, ..)| *name)
+    }
+
+    /// Whether the cartridge type can be emulated, see `supported_types`
+    pub fn is_supported(&self) -> bool {
+        supported_types()
+            .iter()
+            .any(|(t, _)| *t == self.cartridge_type)
+    }
+
+    pub fn has_battery(&self) -> bool {
+        has_battery(self.cartridge_type)
+    }
+
+    /// Whether the header checksum matches the header. Games with a bad checksum are locked
+    /// up by the boot ROM on hardware, though they run fine without one.
+    pub fn header_checksum_valid(&self) -> bool {
+        self.header_checksum == self.calculated_checksum
+    }
+}
+
 /// Error type representing possible errors when using cartridge functions.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CartridgeError {
-    /// The operation attempted is unsupported by the cartridge type
-    Unsupported(String),
+    /// Save data doesn't match the size of the cartridge's RAM
+    SizeMismatch { expected: usize, got: usize },
+    /// The cartridge has no battery-backed RAM to keep save data in
+    NotBatteryBacked,
 }
 
 impl fmt::Display for CartridgeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            CartridgeError::Unsupported(ref s) => {
-                write!(f, "Unsupported function attempted: {}", s)
+            CartridgeError::SizeMismatch { expected, got } => {
+                write!(
+                    f,
+                    "Save data is {} bytes, expected {} bytes of cartridge RAM",
+                    got, expected
+                )
+            }
+            CartridgeError::NotBatteryBacked => {
+                write!(f, "Game doesn't support save files via battery-backed RAM.")
             }
         }
     }
 }
 
+/// Copies save data into cartridge RAM, leaving RAM untouched if the sizes don't match
+fn load_ram(ram: &mut [u8], data: &[u8]) -> Result<(), CartridgeError> {
+    if ram.len() == data.len() {
+        ram.copy_from_slice(data);
+        Ok(())
+    } else {
+        Err(CartridgeError::SizeMismatch {
+            expected: ram.len(),
+            got: data.len(),
+        })
+    }
+}
+
 /// Trait representing the functionality that a Gameboy cartridge can perform for the rest of the system.
 /// Contains all possible functions for a cartridge, but different Memory Bank Controllers (MBCs) may not
 /// support any given function, in which case an error will be returned.
-pub trait Cartridge: super::mmu::Memory {
+pub trait Cartridge: super::mmu::Memory + super::state::SaveState {
     /// Writes the current content of the Cartridge's battery-backed RAM into the provided
     /// file location. If not supported by the cartridge or fails to write to the location,
     /// returns CartridgeError.
@@ -37,4 +256,305 @@ pub trait Cartridge: super::mmu::Memory {
     /// file location. If not supported by the cartridge or fails to write to the location,
     /// returns CartridgeError.
     fn write_save_data(&self) -> Result<Box<[u8]>, CartridgeError>;
+
+    /// Advances anything on the cartridge that keeps time, given the `cycles` run by the CPU
+    fn update(&mut self, _cycles: u32) {}
+
+    /// Tells the cartridge's real time clock the host's time, see `Gameboy::sync_rtc`.
+    /// Returns whether the clock changed, which changes the save data too.
+    fn sync_rtc(&mut self, _now: u64) -> bool {
+        false
+    }
+
+    /// The ROM bank currently mapped into 0x4000-0x7FFF
+    fn rom_bank(&self) -> usize {
+        1
+    }
+
+    /// The RAM bank currently mapped into 0xA000-0xBFFF, or None if cartridge RAM
+    /// is absent or disabled
+    fn ram_bank(&self) -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod cartridge_tests {
+    use super::mbc1::Mbc1;
+    use super::*;
+    use crate::gb::{Gameboy, GameboyError, StateError};
+    use crate::mmu::Memory;
+    use alloc::vec;
+
+    #[test]
+    fn supported_type_list() {
+        let types = supported_types();
+        assert!(types.contains(&(0x01, "MBC1")));
+        assert_eq!(types.len(), 11);
+        // MBC5
+        assert!(!types.iter().any(|(t, _)| *t == 0x19));
+
+        // The list agrees with which types actually load
+        for cart_type in 0..=0xFF {
+            let mut rom = vec![0; 0x8000];
+            rom[0x147] = cart_type;
+            // 8 KiB RAM
+            rom[0x149] = 0x02;
+            let result = Gameboy::power_on(rom.into_boxed_slice(), None);
+            if types.iter().any(|(t, _)| *t == cart_type) {
+                assert!(result.is_ok(), "{:02X} should load", cart_type);
+            } else {
+                assert!(matches!(
+                    result.err(),
+                    Some(GameboyError::UnsupportedMbc(t)) if t == cart_type
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn header() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x13C].copy_from_slice(b"TESTGAME");
+        rom[0x143] = 0x80;
+        rom[0x144..0x146].copy_from_slice(b"01");
+        rom[0x146] = 0x03;
+        rom[0x147] = 0x1B;
+        rom[0x148] = 0x02;
+        rom[0x149] = 0x03;
+        rom[0x14A] = 0x01;
+        rom[0x14B] = 0x33;
+        rom[0x14C] = 0x02;
+        rom[0x14D] = 0x53;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(
+            header,
+            CartridgeHeader {
+                title: "TESTGAME".into(),
+                cgb_support: CgbSupport::Enhanced,
+                sgb_support: true,
+                cartridge_type: 0x1B,
+                rom_size: Some(0x20000),
+                ram_size: Some(0x8000),
+                destination: Destination::Overseas,
+                licensee: Licensee::New(*b"01"),
+                version: 2,
+                header_checksum: 0x53,
+                calculated_checksum: 0x53,
+            }
+        );
+        assert!(header.header_checksum_valid());
+        assert_eq!(header.cartridge_type_name(), Some("MBC5+RAM+BATTERY"));
+        assert!(!header.is_supported());
+        assert!(header.has_battery());
+
+        // Older games use all 16 bytes for the title, and the old licensee code
+        rom[0x134..0x144].copy_from_slice(b"SIXTEEN CHARS \x01 ");
+        rom[0x14B] = 0x01;
+        rom[0x147] = 0x01;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "SIXTEEN CHARS ?");
+        assert_eq!(header.licensee, Licensee::Old(0x01));
+        assert!(!header.sgb_support);
+        assert!(!header.header_checksum_valid());
+        assert!(header.is_supported());
+        assert!(!header.has_battery());
+        assert!(has_battery(0xFF));
+        assert!(!has_battery(0x1C));
+        assert!(!has_battery(0x04));
+        // The battery column agrees with the names
+        for (cart_type, name, _, battery) in CARTRIDGE_TYPES {
+            assert_eq!(battery, name.ends_with("+BATTERY"), "{:02X}", cart_type);
+        }
+
+        let gb = Gameboy::power_on(rom.clone().into_boxed_slice(), None).unwrap();
+        assert_eq!(gb.cartridge_header(), &header);
+        assert_eq!(CartridgeHeader::parse(&rom[..0x14F]), None);
+        assert!(matches!(
+            Gameboy::power_on(rom[..0x100].into(), None),
+            Err(GameboyError::MissingHeader(0x100))
+        ));
+    }
+
+    #[test]
+    fn missing_ram_access() {
+        let mut cart = mbc0::Mbc0::power_on(vec![0; 0x8000].into_boxed_slice());
+        cart.write_byte(0xA000, 0x12);
+        assert_eq!(cart.read_byte(0xA000), 0xFF);
+        assert_eq!(cart.read_byte(0xBFFF), 0xFF);
+
+        // MBC1 and MBC3 without RAM, which refuse to enable it
+        let mut cart = Mbc1::power_on(vec![0; 0x8000].into_boxed_slice(), 0x0, 0x0, false);
+        cart.write_byte(0x0000, 0x0A);
+        cart.write_byte(0xA000, 0x12);
+        assert_eq!(cart.read_byte(0xA000), 0xFF);
+        let mut cart =
+            mbc3::Mbc3::power_on(vec![0; 0x8000].into_boxed_slice(), 0x0, 0x0, false, false);
+        cart.write_byte(0x0000, 0x0A);
+        cart.write_byte(0xA000, 0x12);
+        assert_eq!(cart.read_byte(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn save_data_size_mismatch() {
+        // 8 KiB of battery-backed RAM
+        let mut cart = Mbc1::power_on(vec![0; 0x8000].into_boxed_slice(), 0x0, 0x2, true);
+        let result = cart.read_save_data(vec![0xAA; 0x1000].into_boxed_slice());
+        assert!(matches!(
+            result,
+            Err(CartridgeError::SizeMismatch {
+                expected: 0x2000,
+                got: 0x1000
+            })
+        ));
+        assert_eq!(cart.write_save_data().unwrap()[0], 0x00);
+
+        cart.read_save_data(vec![0xAA; 0x2000].into_boxed_slice())
+            .unwrap();
+        assert_eq!(cart.write_save_data().unwrap()[0], 0xAA);
+
+        let mut cart = Mbc1::power_on(vec![0; 0x8000].into_boxed_slice(), 0x0, 0x2, false);
+        assert!(matches!(
+            cart.read_save_data(vec![0; 0x2000].into_boxed_slice()),
+            Err(CartridgeError::NotBatteryBacked)
+        ));
+
+        // Powering on with it fails rather than dropping the save
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let result = Gameboy::power_on(rom.into_boxed_slice(), Some(vec![0; 0x1000].into()));
+        assert_eq!(
+            result.err(),
+            Some(GameboyError::SaveData(CartridgeError::SizeMismatch {
+                expected: 0x2000,
+                got: 0x1000
+            }))
+        );
+    }
+
+    #[test]
+    fn rom_bank_out_of_range_state() {
+        use crate::state::{SaveState, StateReader, StateWriter};
+
+        // The ROM bank follows the RAM in each MBC's state, 32 KiB ROMs only have banks 0-1
+        fn load_with_bank(cart: &mut dyn SaveState, bank: u8) -> Result<(), StateError> {
+            let mut w = StateWriter::new();
+            cart.save_state(&mut w);
+            let mut state = w.into_inner();
+            let ram_len = u32::from_le_bytes(state[..4].try_into().unwrap()) as usize;
+            state[4 + ram_len] = bank;
+            cart.load_state(&mut StateReader::new(&state))
+        }
+        let rom = || vec![0; 0x8000].into_boxed_slice();
+        let mut mbc1 = Mbc1::power_on(rom(), 0x0, 0x2, false);
+        let mut mbc2 = mbc2::Mbc2::power_on(rom(), 0x0, false);
+        let mut mbc3 = mbc3::Mbc3::power_on(rom(), 0x0, 0x2, false, false);
+        let carts: [&mut dyn SaveState; 3] = [&mut mbc1, &mut mbc2, &mut mbc3];
+        for cart in carts {
+            assert_eq!(load_with_bank(cart, 1), Ok(()));
+            assert_eq!(load_with_bank(cart, 2), Err(StateError::Corrupt));
+            assert_eq!(load_with_bank(cart, 0x7F), Err(StateError::Corrupt));
+        }
+    }
+
+    /// MBC3 with the RTC and 8 KiB of battery-backed RAM, with RAM and the RTC enabled
+    fn rtc_cart() -> mbc3::Mbc3 {
+        let mut cart =
+            mbc3::Mbc3::power_on(vec![0; 0x8000].into_boxed_slice(), 0x0, 0x2, true, true);
+        cart.write_byte(0x0000, 0x0A);
+        cart
+    }
+
+    /// Latches the clock and reads back its seconds, minutes, hours, day low and day high
+    fn read_rtc(cart: &mut mbc3::Mbc3) -> [u8; 5] {
+        cart.write_byte(0x6000, 0x00);
+        cart.write_byte(0x6000, 0x01);
+        let mut regs = [0; 5];
+        for (i, reg) in regs.iter_mut().enumerate() {
+            cart.write_byte(0x4000, 0x08 + i as u8);
+            *reg = cart.read_byte(0xA000);
+        }
+        regs
+    }
+
+    fn write_rtc(cart: &mut mbc3::Mbc3, regs: [u8; 5]) {
+        for (i, reg) in regs.into_iter().enumerate() {
+            cart.write_byte(0x4000, 0x08 + i as u8);
+            cart.write_byte(0xA000, reg);
+        }
+    }
+
+    #[test]
+    fn rtc_clock() {
+        let mut cart = rtc_cart();
+        cart.write_byte(0xA000, 0x12);
+        write_rtc(&mut cart, [59, 59, 23, 0xFF, 0x00]);
+        assert_eq!(read_rtc(&mut cart), [59, 59, 23, 0xFF, 0x00]);
+        // RAM is still there once it's mapped back in
+        cart.write_byte(0x4000, 0x00);
+        assert_eq!(cart.read_byte(0xA000), 0x12);
+
+        // Reads hold the latched time while the clock runs
+        cart.update(crate::CLOCK_RATE - 4);
+        cart.write_byte(0x4000, 0x08);
+        assert_eq!(cart.read_byte(0xA000), 59);
+        cart.update(4);
+        assert_eq!(cart.read_byte(0xA000), 59);
+        // Only the day counter's high bit carries over
+        assert_eq!(read_rtc(&mut cart), [0, 0, 0, 0x00, 0x01]);
+
+        // The day counter overflows into the sticky carry flag
+        write_rtc(&mut cart, [59, 59, 23, 0xFF, 0x01]);
+        cart.update(crate::CLOCK_RATE);
+        assert_eq!(read_rtc(&mut cart), [0, 0, 0, 0x00, 0x80]);
+        cart.update(crate::CLOCK_RATE * 60);
+        assert_eq!(read_rtc(&mut cart), [0, 1, 0, 0x00, 0x80]);
+
+        // Halting stops the clock
+        write_rtc(&mut cart, [10, 0, 0, 0, 0x40]);
+        cart.update(crate::CLOCK_RATE * 5);
+        assert_eq!(read_rtc(&mut cart), [10, 0, 0, 0, 0x40]);
+
+        // Out of range values count up to the register's maximum, then wrap without carrying
+        write_rtc(&mut cart, [62, 5, 0, 0, 0]);
+        cart.update(crate::CLOCK_RATE * 2);
+        assert_eq!(read_rtc(&mut cart), [0, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rtc_save_data() {
+        let mut cart = rtc_cart();
+        cart.write_byte(0xA000, 0x12);
+        write_rtc(&mut cart, [30, 59, 23, 0x10, 0x00]);
+        cart.sync_rtc(1_000_000);
+        let data = cart.write_save_data().unwrap();
+        assert_eq!(data.len(), 0x2000 + 48);
+        assert_eq!(&data[0x2000..0x2004], &[30, 0, 0, 0]);
+        assert_eq!(&data[0x2000 + 40..], &1_000_000u64.to_le_bytes());
+
+        // Catching up on a day, an hour and a minute the emulator was closed, only once
+        let mut loaded = rtc_cart();
+        loaded.read_save_data(data.clone()).unwrap();
+        assert_eq!(loaded.read_byte(0xA000), 0x12);
+        loaded.sync_rtc(1_000_000 + 86400 + 3600 + 60);
+        loaded.sync_rtc(2_000_000);
+        assert_eq!(read_rtc(&mut loaded), [30, 0, 1, 0x12, 0x00]);
+
+        // Footers with a 32-bit timestamp, and save data without a footer
+        let mut loaded = rtc_cart();
+        loaded.read_save_data(data[..0x2000 + 44].into()).unwrap();
+        loaded.sync_rtc(1_000_030);
+        assert_eq!(read_rtc(&mut loaded), [0, 0, 0, 0x11, 0x00]);
+        let mut loaded = rtc_cart();
+        loaded.read_save_data(data[..0x2000].into()).unwrap();
+        assert_eq!(read_rtc(&mut loaded), [0, 0, 0, 0, 0]);
+        assert!(matches!(
+            loaded.read_save_data(data[..0x2000 + 10].into()),
+            Err(CartridgeError::SizeMismatch {
+                expected: 0x2030,
+                got: 0x200A
+            })
+        ));
+    }
 }
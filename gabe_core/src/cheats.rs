@@ -0,0 +1,190 @@
+//! Cheat codes, added with `Gameboy::add_cheat`. Game Genie codes patch bytes of the ROM as
+//! they're read, and GameShark codes write bytes to RAM at the start of each V-Blank.
+
+use alloc::fmt;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Error type representing why a cheat code could not be added
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheatError {
+    /// The code isn't a 6 or 9 digit Game Genie code or an 8 digit GameShark code
+    InvalidCode(String),
+    /// The Game Genie code patches an address outside the ROM
+    NotInRom(u16),
+    /// The GameShark code writes an address outside of cartridge and work RAM
+    NotInRam(u16),
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::InvalidCode(code) => write!(f, "\"{}\" is not a cheat code", code),
+            CheatError::NotInRom(addr) => {
+                write!(
+                    f,
+                    "Game Genie code patches {:04X}, outside of the ROM",
+                    addr
+                )
+            }
+            CheatError::NotInRam(addr) => {
+                write!(f, "GameShark code writes {:04X}, outside of the RAM", addr)
+            }
+        }
+    }
+}
+
+/// What a cheat code does, decoded by `CheatCode::parse`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheatCode {
+    /// Reads of `addr` in the ROM return `value` instead. With a `compare` value, only when
+    /// the ROM holds that value there, so the code only affects the bank it was made for.
+    GameGenie {
+        addr: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    /// `value` is written to `addr` each frame. `bank` is the RAM bank the code was made
+    /// for, which is ignored as only the DMG is emulated, so the write goes to the bank mapped.
+    GameShark { bank: u8, addr: u16, value: u8 },
+}
+
+impl CheatCode {
+    /// Decodes a Game Genie code, like "00A-17B-C49" or "00A-17B", or a GameShark code,
+    /// like "01FF16D0". Dashes and case are ignored.
+    pub fn parse(code: &str) -> Result<CheatCode, CheatError> {
+        let invalid = || CheatError::InvalidCode(String::from(code));
+        let digits = code
+            .chars()
+            .filter(|c| *c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        let byte = |i: usize| digits[i] << 4 | digits[i + 1];
+        match digits.len() {
+            6 | 9 => {
+                let addr = ((digits[5] ^ 0xF) as u16) << 12
+                    | (digits[2] as u16) << 8
+                    | (digits[3] as u16) << 4
+                    | digits[4] as u16;
+                if addr > 0x7FFF {
+                    return Err(CheatError::NotInRom(addr));
+                }
+                // The 8th digit is a check digit and ignored
+                let compare = (digits.len() == 9)
+                    .then(|| (digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA);
+                Ok(CheatCode::GameGenie {
+                    addr,
+                    value: byte(0),
+                    compare,
+                })
+            }
+            8 => {
+                let addr = u16::from_le_bytes([byte(4), byte(6)]);
+                // Cartridge RAM and WRAM, writing elsewhere could switch banks or poke IO
+                if !(0xA000..=0xDFFF).contains(&addr) {
+                    return Err(CheatError::NotInRam(addr));
+                }
+                Ok(CheatCode::GameShark {
+                    bank: byte(0),
+                    value: byte(2),
+                    addr,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A cheat code added to a `Gameboy`, see `Gameboy::cheats`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    /// The code as it was entered
+    pub text: String,
+    pub code: CheatCode,
+    pub enabled: bool,
+}
+
+/// Returns the byte read from the ROM at `addr` with any enabled Game Genie codes applied
+pub(crate) fn patch_rom(cheats: &[Cheat], addr: u16, value: u8) -> u8 {
+    cheats
+        .iter()
+        .filter(|cheat| cheat.enabled)
+        .find_map(|cheat| match cheat.code {
+            CheatCode::GameGenie {
+                addr: patched,
+                value: patch,
+                compare,
+            } if patched == addr && compare.map_or(true, |compare| compare == value) => Some(patch),
+            _ => None,
+        })
+        .unwrap_or(value)
+}
+
+#[cfg(test)]
+mod cheats_tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            CheatCode::parse("00A-17B-C49"),
+            Ok(CheatCode::GameGenie {
+                addr: 0x4A17,
+                value: 0x00,
+                compare: Some(0xC8),
+            })
+        );
+        assert_eq!(
+            CheatCode::parse("3e1-23f"),
+            Ok(CheatCode::GameGenie {
+                addr: 0x0123,
+                value: 0x3E,
+                compare: None,
+            })
+        );
+        assert_eq!(
+            CheatCode::parse("01FF16D0"),
+            Ok(CheatCode::GameShark {
+                bank: 0x01,
+                addr: 0xD016,
+                value: 0xFF,
+            })
+        );
+        assert_eq!(
+            CheatCode::parse("3E1-237"),
+            Err(CheatError::NotInRom(0x8123))
+        );
+        assert_eq!(
+            CheatCode::parse("01FF0020"),
+            Err(CheatError::NotInRam(0x2000))
+        );
+        assert_eq!(
+            CheatCode::parse("01FF40FF"),
+            Err(CheatError::NotInRam(0xFF40))
+        );
+        assert_eq!(
+            CheatCode::parse("01FF16D"),
+            Err(CheatError::InvalidCode(String::from("01FF16D")))
+        );
+        assert_eq!(
+            CheatCode::parse("0XA-17B"),
+            Err(CheatError::InvalidCode(String::from("0XA-17B")))
+        );
+    }
+
+    #[test]
+    fn patch() {
+        let cheat = |text: &str, enabled: bool| Cheat {
+            text: String::from(text),
+            code: CheatCode::parse(text).unwrap(),
+            enabled,
+        };
+        // 00 at 4A17 where it was C8, and 3E at 0123
+        let cheats = [cheat("00A-17B-C49", true), cheat("3E1-23F", false)];
+        assert_eq!(patch_rom(&cheats, 0x4A17, 0xC8), 0x00);
+        assert_eq!(patch_rom(&cheats, 0x4A17, 0xC7), 0xC7);
+        assert_eq!(patch_rom(&cheats, 0x4A18, 0xC8), 0xC8);
+        assert_eq!(patch_rom(&cheats, 0x0123, 0x01), 0x01);
+    }
+}
@@ -1,6 +1,11 @@
+use super::gb::StateError;
+use super::log_target;
 use super::mmu::InterruptKind;
 use super::mmu::Memory;
+use super::state::{SaveState, StateReader, StateWriter};
+use alloc::collections::VecDeque;
 use alloc::fmt::*;
+use alloc::vec::Vec;
 
 /// The register F holds flag information that are set by ALU
 /// operations. Conditional operations check these flags afterwards.
@@ -126,6 +131,20 @@ impl Registers {
     }
 }
 
+/// Cycles taken to dispatch an interrupt: two idle M-cycles, two to push PC and one to jump
+const INTERRUPT_CYCLES: u32 = 20;
+/// Extra cycles taken to leave HALT once an interrupt is pending
+const HALT_WAKE_CYCLES: u32 = 4;
+
+/// Interrupt handler addresses, in order of priority
+const INTERRUPT_VECTORS: [(InterruptKind, u16); 5] = [
+    (InterruptKind::VBlank, 0x40),
+    (InterruptKind::LcdStat, 0x48),
+    (InterruptKind::Timer, 0x50),
+    (InterruptKind::Serial, 0x58),
+    (InterruptKind::Joypad, 0x60),
+];
+
 /// Tables of opcode cycle counts.
 /// Skipped when running rustfmt
 #[rustfmt::skip]
@@ -182,6 +201,30 @@ pub struct Cpu {
     pub next_ime: bool,
     pub halted: bool,
     pub stopped: bool,
+    /// The PC and opcode of the illegal instruction that locked up the CPU, if one has.
+    /// A locked up CPU never executes anything again, not even interrupts.
+    pub lockup: Option<(u16, u8)>,
+    /// Treats illegal opcodes as NOPs instead of locking up, to see how far a buggy
+    /// program gets. Off by default, as hardware locks up.
+    pub skip_illegal_opcodes: bool,
+    /// History of executed instructions, only kept while tracing is enabled
+    trace: Option<TraceRing>,
+}
+
+/// Fixed-size history of the most recently executed (PC, opcode) pairs, oldest first
+#[derive(Clone)]
+struct TraceRing {
+    entries: VecDeque<(u16, u8)>,
+    depth: usize,
+}
+
+impl TraceRing {
+    fn push(&mut self, pc: u16, opcode: u8) {
+        if self.entries.len() == self.depth {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((pc, opcode));
+    }
 }
 
 impl Display for Cpu {
@@ -235,6 +278,18 @@ impl Cpu {
             next_ime: false,
             halted: false,
             stopped: false,
+            lockup: None,
+            skip_illegal_opcodes: false,
+            trace: None,
+        }
+    }
+
+    /// Initializes the CPU as it is when first powered, with every register cleared, to run
+    /// a boot ROM from 0x0000 rather than starting at the game's entry point
+    pub fn power_on_boot_rom() -> Self {
+        Cpu {
+            reg: Registers::default(),
+            ..Cpu::power_on()
         }
     }
 
@@ -242,80 +297,75 @@ impl Cpu {
         self.clone()
     }
 
+    /// Enables recording of the last `depth` executed instructions, or disables it with None.
+    /// Any previously recorded history is discarded.
+    pub fn set_trace_depth(&mut self, depth: Option<usize>) {
+        self.trace = depth.filter(|d| *d > 0).map(|depth| TraceRing {
+            entries: VecDeque::with_capacity(depth),
+            depth,
+        });
+    }
+
+    /// Returns the recorded (PC, opcode) pairs, oldest first. Empty if tracing is disabled.
+    pub fn recent_trace(&self) -> Vec<(u16, u8)> {
+        self.trace
+            .as_ref()
+            .map(|t| t.entries.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Services the highest priority interrupt that is both requested and enabled, if IME allows.
+    /// Also wakes the CPU from HALT on any pending interrupt, even with IME unset.
+    /// Returns the cycles spent, or None if the next instruction should run right away.
     fn check_interrupts(&mut self, mmu: &mut dyn Memory) -> Option<u32> {
         // Check if any enabled interrupts were requested
-        let mut interrupt_reqs = mmu.read_byte(0xFF0F);
-        let interrupt_enables = mmu.read_byte(0xFFFF);
-        let interrupt_result = (interrupt_reqs & interrupt_enables) & 0x1F;
-        if interrupt_result == 0x0 {
+        let pending = mmu.read_byte(0xFF0F) & mmu.read_byte(0xFFFF) & 0x1F;
+        if pending == 0x0 {
             // No interrupts were both requested and enabled
-            None
-        } else {
-            // If we're halted, exit on an interrupt
-            self.halted = false;
-            if !self.ime {
-                // No longer halted, exit if we cannot handle interrupts
-                None
-            } else {
-                if (interrupt_result & InterruptKind::VBlank as u8) != 0x0 {
-                    // V-Blank interrupt
-                    // Reset the request flag to the interrupt
-                    interrupt_reqs &= !(InterruptKind::VBlank as u8);
-                    mmu.write_byte(0xFF0F, interrupt_reqs);
-
-                    // Run CALL on V-Blank procedure
-                    self.stack_push(mmu, self.reg.pc);
-                    self.reg.pc = 0x40;
-                } else if (interrupt_result & InterruptKind::LcdStat as u8) != 0x0 {
-                    // LCD STAT Interrupt
-                    // Reset the request flag to the interrupt
-                    interrupt_reqs &= !(InterruptKind::LcdStat as u8);
-                    mmu.write_byte(0xFF0F, interrupt_reqs);
-
-                    // Run CALL on LCD Stat procedure
-                    self.stack_push(mmu, self.reg.pc);
-                    self.reg.pc = 0x48;
-                } else if (interrupt_result & InterruptKind::Timer as u8) != 0x0 {
-                    // Timer Interrupt
-                    // Reset the request flag to the interrupt
-                    interrupt_reqs &= !(InterruptKind::Timer as u8);
-                    mmu.write_byte(0xFF0F, interrupt_reqs);
-
-                    // Run CALL on Timer procedure
-                    self.stack_push(mmu, self.reg.pc);
-                    self.reg.pc = 0x50;
-                } else if (interrupt_result & InterruptKind::Serial as u8) != 0x0 {
-                    // Serial Interrupt
-                    // Reset the request flag to the interrupt
-                    interrupt_reqs &= !(InterruptKind::Serial as u8);
-                    mmu.write_byte(0xFF0F, interrupt_reqs);
-
-                    // Run CALL on Serial procedure
-                    self.stack_push(mmu, self.reg.pc);
-                    self.reg.pc = 0x58;
-                } else if (interrupt_result & InterruptKind::Joypad as u8) != 0x0 {
-                    // Joypad Interrupt
-                    // Reset the request flag to the interrupt
-                    interrupt_reqs &= !(InterruptKind::Joypad as u8);
-                    mmu.write_byte(0xFF0F, interrupt_reqs);
-
-                    // Run CALL on Joypad procedure
-                    self.stack_push(mmu, self.reg.pc);
-                    self.reg.pc = 0x60;
-                }
-                // We're executing a interrupt procedure, disable all interrupts and
-                // return cycles matching an interrupt service
-                self.ime = false;
-                self.next_ime = false;
-                Some(20)
-            }
+            return None;
+        }
+        // Leaving HALT takes an extra M-cycle before anything else happens
+        let wake_cycles = if self.halted { HALT_WAKE_CYCLES } else { 0 };
+        self.halted = false;
+        if !self.ime {
+            // No longer halted, continue with the next instruction if we cannot handle interrupts
+            return (wake_cycles != 0).then_some(wake_cycles);
         }
+        // We're executing a interrupt procedure, disable all interrupts
+        self.ime = false;
+        self.next_ime = false;
+
+        // After two idle M-cycles, PC is pushed high byte first. The interrupt to service is only
+        // picked after the high byte is written, so a push that overwrites IE at 0xFFFF can
+        // cancel the dispatch, in which case execution continues at 0x0000.
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        mmu.write_byte(self.reg.sp, (self.reg.pc >> 8) as u8);
+        let interrupt_reqs = mmu.read_byte(0xFF0F);
+        let pending = interrupt_reqs & mmu.read_byte(0xFFFF) & 0x1F;
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        mmu.write_byte(self.reg.sp, self.reg.pc as u8);
+
+        self.reg.pc = match INTERRUPT_VECTORS
+            .iter()
+            .find(|(kind, _)| pending & *kind as u8 != 0x0)
+        {
+            Some((kind, vector)) => {
+                // Reset the request flag to the interrupt
+                mmu.write_byte(0xFF0F, interrupt_reqs & !(*kind as u8));
+                *vector
+            }
+            None => 0x0000,
+        };
+        Some(INTERRUPT_CYCLES + wake_cycles)
     }
 
     /// Fetches a single instruction opcode, decodes the opcode to the
     /// appropriate function, and executes the functionality.
     /// Returns the number of cycles executed.
     pub fn tick(&mut self, mmu: &mut dyn Memory) -> u32 {
+        if self.lockup.is_some() {
+            return OPCODE_TABLE[0];
+        }
         if self.stopped {
             // Reset DIV
             mmu.write_byte(0xFF04, 0x0);
@@ -327,7 +377,7 @@ impl Cpu {
         if self.ime || self.halted {
             // If CPU is halted or IME is enabled, check if there's any interrupts to execute
             if let Some(c) = self.check_interrupts(mmu) {
-                // Running interrupt routine, return cycles
+                // Ran an interrupt dispatch or woke from HALT, return cycles
                 return c;
             }
         }
@@ -338,6 +388,10 @@ impl Cpu {
         }
 
         let mut opcode = self.imm(mmu);
+        trace!(target: log_target::CPU, "{:04X}: {:02X}", self.reg.pc.wrapping_sub(1), opcode);
+        if let Some(trace) = &mut self.trace {
+            trace.push(self.reg.pc.wrapping_sub(1), opcode);
+        }
         // Set the IME on the cycle after it changes, delaying any interrupt handling.
         self.ime = self.next_ime;
         let mut using_cb: bool = false;
@@ -735,16 +789,40 @@ impl Cpu {
             0x3D => self.reg.a = self.dec(self.reg.a),
 
             // INC r16
-            0x03 => self.reg.set_bc(self.reg.get_bc().wrapping_add(1)),
-            0x13 => self.reg.set_de(self.reg.get_de().wrapping_add(1)),
-            0x23 => self.reg.set_hl(self.reg.get_hl().wrapping_add(1)),
-            0x33 => self.reg.sp = self.reg.sp.wrapping_add(1),
+            0x03 => {
+                mmu.idu_access(self.reg.get_bc());
+                self.reg.set_bc(self.reg.get_bc().wrapping_add(1));
+            }
+            0x13 => {
+                mmu.idu_access(self.reg.get_de());
+                self.reg.set_de(self.reg.get_de().wrapping_add(1));
+            }
+            0x23 => {
+                mmu.idu_access(self.reg.get_hl());
+                self.reg.set_hl(self.reg.get_hl().wrapping_add(1));
+            }
+            0x33 => {
+                mmu.idu_access(self.reg.sp);
+                self.reg.sp = self.reg.sp.wrapping_add(1);
+            }
 
             // DEC r16
-            0x0B => self.reg.set_bc(self.reg.get_bc().wrapping_sub(1)),
-            0x1B => self.reg.set_de(self.reg.get_de().wrapping_sub(1)),
-            0x2B => self.reg.set_hl(self.reg.get_hl().wrapping_sub(1)),
-            0x3B => self.reg.sp = self.reg.sp.wrapping_sub(1),
+            0x0B => {
+                mmu.idu_access(self.reg.get_bc());
+                self.reg.set_bc(self.reg.get_bc().wrapping_sub(1));
+            }
+            0x1B => {
+                mmu.idu_access(self.reg.get_de());
+                self.reg.set_de(self.reg.get_de().wrapping_sub(1));
+            }
+            0x2B => {
+                mmu.idu_access(self.reg.get_hl());
+                self.reg.set_hl(self.reg.get_hl().wrapping_sub(1));
+            }
+            0x3B => {
+                mmu.idu_access(self.reg.sp);
+                self.reg.sp = self.reg.sp.wrapping_sub(1);
+            }
 
             // POP r16
             0xC1 => {
@@ -931,6 +1009,8 @@ impl Cpu {
             0xD9 => {
                 let a = self.stack_pop(mmu);
                 self.reg.pc = a;
+                // Unlike EI, RETI enables interrupts immediately
+                self.ime = true;
                 self.next_ime = true;
             }
 
@@ -1507,7 +1587,17 @@ impl Cpu {
                     0xFF => self.reg.a = self.set(self.reg.a, 7),
                 }
             }
-            _ => panic!("Unsupported or unimplemented opcode 0x{:X}", opcode),
+            _ => {
+                // The remaining opcodes are illegal, and hang the CPU
+                let pc = self.reg.pc.wrapping_sub(1);
+                if self.skip_illegal_opcodes {
+                    warn!(target: log_target::CPU, "Illegal opcode {:02X} at {:04X}, skipped", opcode, pc);
+                    cond_cycles = OPCODE_TABLE[0];
+                } else {
+                    error!(target: log_target::CPU, "Illegal opcode {:02X} at {:04X}, CPU locked up", opcode, pc);
+                    self.lockup = Some((pc, opcode));
+                }
+            }
         };
         if using_cb {
             OPCODE_CB_TABLE[opcode as usize]
@@ -1609,10 +1699,10 @@ impl Cpu {
     /// Adds a given 16-bit register value to the HL register.
     /// Flags:
     ///
-    /// - Z: Set to 0
+    /// - Z: Unaffected
     /// - N: Set to 0
-    /// - H: Set to 1 if bit 3 carries, 0 otherwise
-    /// - C: Set to 1 if bit 7 carries, 0 otherwise
+    /// - H: Set to 1 if bit 11 carries, 0 otherwise
+    /// - C: Set to 1 if bit 15 carries, 0 otherwise
     fn add_hl(&mut self, r: u16) {
         let hl = self.reg.get_hl();
         self.reg.set_flag(Flag::N, false);
@@ -1867,7 +1957,7 @@ impl Cpu {
         self.reg.set_flag(Flag::N, false);
         self.reg.set_flag(Flag::H, false);
         self.reg.set_flag(Flag::C, false);
-        (r >> 4) | (r << 4)
+        r.rotate_left(4)
     }
 
     /// Shift register `r` right into the Carry flag. Bit 7 set to 0.
@@ -1954,12 +2044,214 @@ impl Cpu {
     }
 }
 
+impl SaveState for Cpu {
+    fn save_state(&self, w: &mut StateWriter) {
+        let reg = &self.reg;
+        for v in [reg.a, reg.f, reg.b, reg.c, reg.d, reg.e, reg.h, reg.l] {
+            w.u8(v);
+        }
+        w.u16(reg.sp);
+        w.u16(reg.pc);
+        w.bool(self.ime);
+        w.bool(self.next_ime);
+        w.bool(self.halted);
+        w.bool(self.stopped);
+        w.bool(self.lockup.is_some());
+        let (pc, opcode) = self.lockup.unwrap_or_default();
+        w.u16(pc);
+        w.u8(opcode);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> core::result::Result<(), StateError> {
+        let reg = &mut self.reg;
+        for v in [
+            &mut reg.a, &mut reg.f, &mut reg.b, &mut reg.c, &mut reg.d, &mut reg.e, &mut reg.h,
+            &mut reg.l,
+        ] {
+            *v = r.u8()?;
+        }
+        reg.sp = r.u16()?;
+        reg.pc = r.u16()?;
+        self.ime = r.bool()?;
+        self.next_ime = r.bool()?;
+        self.halted = r.bool()?;
+        self.stopped = r.bool()?;
+        let locked_up = r.bool()?;
+        let lockup = (r.u16()?, r.u8()?);
+        self.lockup = locked_up.then_some(lockup);
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod cpu_tests {
 
-    use std::io::{BufReader, Read};
+    use std::io::BufReader;
 
     use super::*;
+
+    /// Flat 64 KiB of RAM with no memory-mapped behavior
+    struct TestRam {
+        ram: Box<[u8]>,
+    }
+
+    impl Memory for TestRam {
+        fn read_byte(&self, addr: u16) -> u8 {
+            self.ram[addr as usize]
+        }
+
+        fn write_byte(&mut self, addr: u16, val: u8) {
+            self.ram[addr as usize] = val
+        }
+    }
+
+    impl TestRam {
+        fn new() -> Self {
+            TestRam {
+                ram: vec![0; 0x10000].into_boxed_slice(),
+            }
+        }
+    }
+
+    #[test]
+    fn add_16bit_carries() {
+        let add_hl_hl = |hl: u16| {
+            // ADD HL, HL
+            let mut ram = TestRam::new();
+            ram.ram[0x100] = 0x29;
+            let mut cpu = Cpu::power_on();
+            cpu.reg.set_hl(hl);
+            cpu.tick(&mut ram);
+            cpu.reg
+        };
+        let reg = add_hl_hl(0x0FFF);
+        assert_eq!(reg.get_hl(), 0x1FFE);
+        assert!(reg.get_flag(Flag::H));
+        assert!(!reg.get_flag(Flag::C));
+        let reg = add_hl_hl(0xFFFF);
+        assert_eq!(reg.get_hl(), 0xFFFE);
+        assert!(reg.get_flag(Flag::H));
+        assert!(reg.get_flag(Flag::C));
+        // Carries out of the low byte don't count for ADD HL
+        let reg = add_hl_hl(0x00FF);
+        assert!(!reg.get_flag(Flag::H));
+        assert!(!reg.get_flag(Flag::C));
+
+        let add_sp = |sp: u16, r8: u8| {
+            // ADD SP, r8
+            let mut ram = TestRam::new();
+            ram.ram[0x100..0x102].copy_from_slice(&[0xE8, r8]);
+            let mut cpu = Cpu::power_on();
+            cpu.reg.sp = sp;
+            cpu.tick(&mut ram);
+            cpu.reg
+        };
+        let reg = add_sp(0x000F, 0x01);
+        assert_eq!(reg.sp, 0x0010);
+        assert!(reg.get_flag(Flag::H));
+        assert!(!reg.get_flag(Flag::C));
+        let reg = add_sp(0x00FF, 0x01);
+        assert!(reg.get_flag(Flag::H));
+        assert!(reg.get_flag(Flag::C));
+        // Carries are from the low byte even when adding a negative offset
+        let reg = add_sp(0xFFF8, 0xFF);
+        assert_eq!(reg.sp, 0xFFF7);
+        assert!(reg.get_flag(Flag::H));
+        assert!(reg.get_flag(Flag::C));
+        // and bit 11 carries don't count for ADD SP
+        let reg = add_sp(0x0FF0, 0x10);
+        assert_eq!(reg.sp, 0x1000);
+        assert!(!reg.get_flag(Flag::H));
+        assert!(reg.get_flag(Flag::C));
+    }
+
+    #[test]
+    fn interrupt_dispatch_cycles() {
+        let setup = |pending: InterruptKind| {
+            let mut ram = TestRam::new();
+            ram.ram[0xFFFF] = pending as u8;
+            ram.ram[0xFF0F] = pending as u8;
+            let mut cpu = Cpu::power_on();
+            cpu.reg.pc = 0x1234;
+            cpu.reg.sp = 0xD000;
+            cpu.ime = true;
+            (cpu, ram)
+        };
+        let (mut cpu, mut ram) = setup(InterruptKind::Timer);
+        assert_eq!(cpu.tick(&mut ram), INTERRUPT_CYCLES);
+        assert_eq!(INTERRUPT_CYCLES, 20);
+        assert_eq!(cpu.reg.pc, 0x50);
+        assert_eq!(cpu.reg.sp, 0xCFFE);
+        assert_eq!(ram.read_word(0xCFFE), 0x1234);
+        assert_eq!(ram.ram[0xFF0F], 0);
+        assert!(!cpu.ime);
+
+        // Waking from HALT takes one more M-cycle
+        let (mut cpu, mut ram) = setup(InterruptKind::Joypad);
+        cpu.halted = true;
+        assert_eq!(cpu.tick(&mut ram), 24);
+        assert_eq!(cpu.reg.pc, 0x60);
+
+        // Without IME, HALT is left without servicing the interrupt
+        let (mut cpu, mut ram) = setup(InterruptKind::VBlank);
+        cpu.ime = false;
+        cpu.halted = true;
+        assert_eq!(cpu.tick(&mut ram), 4);
+        assert!(!cpu.halted);
+        assert_eq!(cpu.reg.pc, 0x1234);
+        assert_eq!(ram.ram[0xFF0F], InterruptKind::VBlank as u8);
+
+        // Only the low 5 bits of IE and IF can request an interrupt
+        let (mut cpu, mut ram) = setup(InterruptKind::VBlank);
+        ram.ram[0xFFFF] = 0xE0;
+        ram.ram[0xFF0F] = 0xFF;
+        assert_eq!(cpu.tick(&mut ram), 4);
+        assert_eq!(cpu.reg.pc, 0x1235);
+
+        // Pushing the high byte of PC over IE cancels the dispatch
+        let (mut cpu, mut ram) = setup(InterruptKind::Serial);
+        cpu.reg.pc = 0x0034;
+        cpu.reg.sp = 0x0000;
+        assert_eq!(cpu.tick(&mut ram), 20);
+        assert_eq!(cpu.reg.pc, 0x0000);
+        assert_eq!(ram.ram[0xFF0F], InterruptKind::Serial as u8);
+    }
+
+    #[test]
+    fn ei_reti_ime_timing() {
+        // EI; NOP; NOP with a V-Blank interrupt pending
+        let mut ram = TestRam::new();
+        ram.ram[0x100..0x103].copy_from_slice(&[0xFB, 0x00, 0x00]);
+        ram.ram[0xFFFF] = InterruptKind::VBlank as u8;
+        ram.ram[0xFF0F] = InterruptKind::VBlank as u8;
+        let mut cpu = Cpu::power_on();
+        cpu.tick(&mut ram);
+        assert!(!cpu.ime);
+        // EI is delayed by an instruction, the NOP following it still runs
+        cpu.tick(&mut ram);
+        assert!(cpu.ime);
+        assert_eq!(cpu.reg.pc, 0x102);
+        assert_eq!(cpu.tick(&mut ram), 20);
+        assert_eq!(cpu.reg.pc, 0x40);
+        assert_eq!(ram.read_word(cpu.reg.sp), 0x102);
+
+        // RETI returning to 0x200 with a V-Blank interrupt pending
+        let mut ram = TestRam::new();
+        ram.ram[0x100] = 0xD9;
+        ram.ram[0xFFFF] = InterruptKind::VBlank as u8;
+        ram.ram[0xFF0F] = InterruptKind::VBlank as u8;
+        let mut cpu = Cpu::power_on();
+        cpu.reg.sp = 0xD000;
+        ram.write_word(0xD000, 0x200);
+        cpu.tick(&mut ram);
+        // RETI enables interrupts immediately, so the interrupt is serviced before the
+        // instruction at the return address
+        assert!(cpu.ime);
+        assert_eq!(cpu.reg.pc, 0x200);
+        assert_eq!(cpu.tick(&mut ram), 20);
+        assert_eq!(cpu.reg.pc, 0x40);
+        assert_eq!(ram.read_word(cpu.reg.sp), 0x200);
+    }
     #[test]
     fn register_read() {
         let reg = Registers::power_on();
@@ -2010,19 +2302,6 @@ mod cpu_tests {
         use std::path::PathBuf;
 
         // Define simple 64k RAM that impls Memory for tests
-        struct TestRam {
-            ram: Box<[u8]>,
-        }
-        impl Memory for TestRam {
-            fn read_byte(&self, addr: u16) -> u8 {
-                self.ram[addr as usize]
-            }
-
-            fn write_byte(&mut self, addr: u16, val: u8) {
-                self.ram[addr as usize] = val
-            }
-        }
-
         // Get path from top-level test folder
         let json_path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests/resources/sm83_json"]
             .iter()
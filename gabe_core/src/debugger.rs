@@ -0,0 +1,744 @@
+//! Breakpoints and watchpoints with conditions, managed through text commands so any frontend
+//! can offer a debugger prompt. Numbers are hex, with or without a `0x` or `$` prefix.
+//!
+//! - `break 4000 if a == 3F` stops before the instruction at 0x4000 runs, when A is 0x3F
+//! - `watch C000-C0FF`, `rwatch C000` and `awatch C000` stop after a write, a read or
+//!   either by the CPU within the range
+//! - `watch io FF40` or `watch io 40` watches an IO register
+//! - `delete 2` removes a breakpoint or watchpoint, `delete` removes them all
+//! - `info` lists them
+//! - `step 10` runs ten instructions, or one without a count, stopping early at a breakpoint
+//!   or watchpoint. Counts are decimal.
+//! - `scanline` runs until LY moves on to the next line, stopping early likewise
+//! - `logwrites C000 C0FF` records writes to the range without stopping, see
+//!   `Gameboy::log_writes`. `logwrites` lists those made since last listed, and
+//!   `logwrites off` stops recording.
+//!
+//! Conditions compare a register (`a`, `f`, ..., `af`, `bc`, `de`, `hl`, `sp`, `pc`), a byte of
+//! memory (`[FF44]`), or for watchpoints the `value` read or written, using `==`, `!=`, `<`,
+//! `<=`, `>` or `>=`.
+//!
+//! Commands that run the game discard its audio and frames, `Gameboy::frame_buffer` shows the
+//! screen as they left it.
+
+use alloc::fmt;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use super::gb::{Gameboy, WatchHit, WatchKind, SCANLINE_CYCLES};
+use super::sink::{AudioFrame, NullSink, Sink, VideoFrame};
+
+/// Error type representing why a debugger command was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DebugError {
+    UnknownCommand(String),
+    MissingArgument,
+    /// A command was given more arguments than it takes
+    TooManyArguments,
+    InvalidNumber(String),
+    InvalidCondition(String),
+    /// A count of instructions to run isn't a decimal number
+    InvalidCount(String),
+    /// `watch io` was given an address outside of the IO registers
+    NotIoRegister(u16),
+    NoSuchPoint(usize),
+    /// A range ends before it starts, given as (start, end)
+    ReversedRange(u16, u16),
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugError::UnknownCommand(cmd) => write!(f, "Unknown command \"{}\"", cmd),
+            DebugError::MissingArgument => write!(f, "Missing an address"),
+            DebugError::TooManyArguments => write!(f, "Too many arguments"),
+            DebugError::InvalidNumber(num) => write!(f, "\"{}\" isn't a hex number", num),
+            DebugError::InvalidCondition(cond) => write!(f, "Invalid condition \"{}\"", cond),
+            DebugError::InvalidCount(count) => {
+                write!(f, "\"{}\" isn't a number of instructions", count)
+            }
+            DebugError::NotIoRegister(addr) => {
+                write!(f, "0x{:04X} isn't an IO register", addr)
+            }
+            DebugError::NoSuchPoint(id) => write!(f, "No breakpoint or watchpoint {}", id),
+            DebugError::ReversedRange(start, end) => {
+                write!(f, "End {:04X} is before start {:04X}", end, start)
+            }
+        }
+    }
+}
+
+/// What a condition compares against its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+    Memory(u16),
+    /// The value read or written by the access that hit a watchpoint
+    Value,
+}
+
+const REGISTER_NAMES: [(&str, Operand); 14] = [
+    ("a", Operand::A),
+    ("f", Operand::F),
+    ("b", Operand::B),
+    ("c", Operand::C),
+    ("d", Operand::D),
+    ("e", Operand::E),
+    ("h", Operand::H),
+    ("l", Operand::L),
+    ("af", Operand::AF),
+    ("bc", Operand::BC),
+    ("de", Operand::DE),
+    ("hl", Operand::HL),
+    ("sp", Operand::SP),
+    ("pc", Operand::PC),
+];
+
+/// Comparison operators, ordered so two-character operators are matched first
+const COMPARISONS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+/// A condition that must hold for a breakpoint or watchpoint to stop the game
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Condition {
+    operand: Operand,
+    comparison: &'static str,
+    value: u16,
+}
+
+impl Condition {
+    fn parse(text: &str, watch: bool) -> Result<Self, DebugError> {
+        let invalid = || DebugError::InvalidCondition(text.to_string());
+        let (pos, comparison) = COMPARISONS
+            .iter()
+            .find_map(|op| text.find(op).map(|pos| (pos, *op)))
+            .ok_or_else(invalid)?;
+        let lhs = text[..pos].trim().to_ascii_lowercase();
+        let value = parse_number(text[pos + comparison.len()..].trim())?;
+        let operand = if let Some(addr) = lhs.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            Operand::Memory(parse_number(addr.trim())?)
+        } else if lhs == "value" && watch {
+            Operand::Value
+        } else {
+            REGISTER_NAMES
+                .iter()
+                .find(|(name, _)| *name == lhs)
+                .map(|(_, reg)| *reg)
+                .ok_or_else(invalid)?
+        };
+        Ok(Condition {
+            operand,
+            comparison,
+            value,
+        })
+    }
+
+    fn holds(&self, gb: &Gameboy, hit: Option<&WatchHit>) -> bool {
+        let reg = gb.registers();
+        let pair = |hi: u8, lo: u8| u16::from_be_bytes([hi, lo]);
+        let lhs = match self.operand {
+            Operand::A => reg.a as u16,
+            Operand::F => reg.f as u16,
+            Operand::B => reg.b as u16,
+            Operand::C => reg.c as u16,
+            Operand::D => reg.d as u16,
+            Operand::E => reg.e as u16,
+            Operand::H => reg.h as u16,
+            Operand::L => reg.l as u16,
+            Operand::AF => pair(reg.a, reg.f),
+            Operand::BC => pair(reg.b, reg.c),
+            Operand::DE => pair(reg.d, reg.e),
+            Operand::HL => pair(reg.h, reg.l),
+            Operand::SP => reg.sp,
+            Operand::PC => reg.pc,
+            Operand::Memory(addr) => {
+                gb.get_memory_range(addr as usize..addr as usize + 1)[0] as u16
+            }
+            Operand::Value => match hit {
+                Some(hit) => hit.value as u16,
+                None => return false,
+            },
+        };
+        match self.comparison {
+            "==" => lhs == self.value,
+            "!=" => lhs != self.value,
+            "<=" => lhs <= self.value,
+            ">=" => lhs >= self.value,
+            "<" => lhs < self.value,
+            _ => lhs > self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.operand {
+            Operand::Memory(addr) => write!(f, "[{:04X}]", addr)?,
+            Operand::Value => write!(f, "value")?,
+            operand => {
+                let (name, _) = REGISTER_NAMES.iter().find(|(_, r)| *r == operand).unwrap();
+                write!(f, "{}", name)?
+            }
+        }
+        write!(f, " {} 0x{:X}", self.comparison, self.value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Trap {
+    Breakpoint(u16),
+    Watchpoint(RangeInclusive<u16>, WatchKind),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Point {
+    id: usize,
+    trap: Trap,
+    condition: Option<Condition>,
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.trap {
+            Trap::Breakpoint(addr) => write!(f, "Breakpoint {} at 0x{:04X}", self.id, addr)?,
+            Trap::Watchpoint(range, kind) => {
+                let kind = match kind {
+                    WatchKind::Read => "Read watchpoint",
+                    WatchKind::Write => "Watchpoint",
+                    WatchKind::ReadWrite => "Access watchpoint",
+                };
+                write!(f, "{} {} on 0x{:04X}", kind, self.id, range.start())?;
+                if range.end() != range.start() {
+                    write!(f, "-0x{:04X}", range.end())?;
+                }
+            }
+        }
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why `Debugger::step` stopped the game
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The PC reached breakpoint `id`, and the instruction there hasn't run yet
+    Breakpoint { id: usize, pc: u16 },
+    /// The last instruction accessed memory watched by watchpoint `id`
+    Watchpoint { id: usize, hit: WatchHit },
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Breakpoint { id, pc } => write!(f, "Breakpoint {} hit at 0x{:04X}", id, pc),
+            Stop::Watchpoint { id, hit } => write!(
+                f,
+                "Watchpoint {} hit: 0x{:04X} {} 0x{:04X} (0x{:02X})",
+                id,
+                hit.pc,
+                if hit.write { "wrote" } else { "read" },
+                hit.addr,
+                hit.value
+            ),
+        }
+    }
+}
+
+/// Breakpoints and watchpoints on a `Gameboy`, numbered from 1 in the order they're added.
+/// Run the game with `step` in place of `Gameboy::step` so they're checked.
+#[derive(Default)]
+pub struct Debugger {
+    points: Vec<Point>,
+    next_id: usize,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs a command from the module documentation, returning a message describing what
+    /// it did
+    pub fn execute(&mut self, gb: &mut Gameboy, line: &str) -> Result<String, DebugError> {
+        let (command, condition) = match line.find(" if ") {
+            Some(pos) => (&line[..pos], Some(line[pos + 4..].trim())),
+            None => (line, None),
+        };
+        let mut args = command.split_whitespace();
+        let name = args.next().unwrap_or("");
+        let args: Vec<&str> = args.collect();
+        let kind = match name {
+            "break" | "b" => None,
+            "watch" => Some(WatchKind::Write),
+            "rwatch" => Some(WatchKind::Read),
+            "awatch" => Some(WatchKind::ReadWrite),
+            "delete" | "d" => return self.delete(gb, &args),
+            "info" | "i" => {
+                let lines: Vec<String> = self.points.iter().map(|p| p.to_string()).collect();
+                return Ok(lines.join("\n"));
+            }
+            "step" | "s" => {
+                let count = match args[..] {
+                    [] => 1,
+                    [count] => count
+                        .parse()
+                        .map_err(|_| DebugError::InvalidCount(count.to_string()))?,
+                    _ => return Err(DebugError::TooManyArguments),
+                };
+                return Ok(self.step_count(gb, count));
+            }
+            "scanline" | "line" => return Ok(self.step_scanline(gb)),
+            "logwrites" => return log_writes(gb, &args),
+            _ => return Err(DebugError::UnknownCommand(name.to_string())),
+        };
+        let condition = condition
+            .map(|cond| Condition::parse(cond, kind.is_some()))
+            .transpose()?;
+        let trap = match (kind, &args[..]) {
+            (None, [addr]) => Trap::Breakpoint(parse_number(addr)?),
+            (Some(kind), ["io", range]) => {
+                let range = parse_range(range)?;
+                Trap::Watchpoint(
+                    io_address(*range.start())?..=io_address(*range.end())?,
+                    kind,
+                )
+            }
+            (Some(kind), [range]) => Trap::Watchpoint(parse_range(range)?, kind),
+            (_, []) => return Err(DebugError::MissingArgument),
+            _ => return Err(DebugError::TooManyArguments),
+        };
+        if let Trap::Watchpoint(range, kind) = &trap {
+            gb.add_watchpoint(range.clone(), *kind);
+        }
+        self.next_id += 1;
+        let point = Point {
+            id: self.next_id,
+            trap,
+            condition,
+        };
+        let message = point.to_string();
+        self.points.push(point);
+        Ok(message)
+    }
+
+    fn delete(&mut self, gb: &mut Gameboy, args: &[&str]) -> Result<String, DebugError> {
+        let removed: Vec<Point> = match args {
+            [] => core::mem::take(&mut self.points),
+            [id] => {
+                let id = id
+                    .parse()
+                    .map_err(|_| DebugError::InvalidNumber(id.to_string()))?;
+                let pos = self
+                    .points
+                    .iter()
+                    .position(|p| p.id == id)
+                    .ok_or(DebugError::NoSuchPoint(id))?;
+                vec![self.points.remove(pos)]
+            }
+            _ => return Err(DebugError::TooManyArguments),
+        };
+        for point in &removed {
+            if let Trap::Watchpoint(range, kind) = &point.trap {
+                gb.remove_watchpoint(range.clone(), *kind);
+                // The Gameboy drops every watchpoint on the same range, so restore any others
+                let others = self.points.iter().filter(|p| p.trap == point.trap).count();
+                for _ in 0..others {
+                    gb.add_watchpoint(range.clone(), *kind);
+                }
+            }
+        }
+        Ok(format!("Deleted {}", removed.len()))
+    }
+
+    /// Runs up to `count` instructions, stopping early at a breakpoint or watchpoint, and
+    /// describes where the game stopped
+    fn step_count(&mut self, gb: &mut Gameboy, count: u32) -> String {
+        for ran in 1..=count {
+            if let Some(stop) = self.step(gb, &mut NullSink, &mut NullSink) {
+                return format!("{} after {} instructions\n{}", stop, ran, registers(gb));
+            }
+        }
+        format!("Ran {} instructions\n{}", count, registers(gb))
+    }
+
+    /// Runs until LY moves on to the next line like `Gameboy::step_scanline`, stopping early
+    /// at a breakpoint or watchpoint, and describes where the game stopped
+    fn step_scanline(&mut self, gb: &mut Gameboy) -> String {
+        let start = gb.current_scanline();
+        let mut cycles = 0;
+        while gb.current_scanline() == start && cycles < SCANLINE_CYCLES * 2 {
+            cycles += gb.step(&mut NullSink, &mut NullSink);
+            if let Some(stop) = self.check_stop(gb) {
+                return format!("{} on LY {}\n{}", stop, start, registers(gb));
+            }
+        }
+        format!(
+            "Ran {} cycles to LY {}\n{}",
+            cycles,
+            gb.current_scanline(),
+            registers(gb)
+        )
+    }
+
+    /// Runs one instruction like `Gameboy::step`, returning the breakpoint or watchpoint
+    /// that stopped the game, if any. Watch hits whose conditions fail are discarded.
+    pub fn step(
+        &mut self,
+        gb: &mut Gameboy,
+        video_sink: &mut dyn Sink<VideoFrame>,
+        audio_sink: &mut dyn Sink<AudioFrame>,
+    ) -> Option<Stop> {
+        gb.step(video_sink, audio_sink);
+        self.check_stop(gb)
+    }
+
+    /// Checks for a breakpoint or watchpoint stopping the game after an instruction ran
+    fn check_stop(&self, gb: &mut Gameboy) -> Option<Stop> {
+        // Conditions are checked against every hit, so one failing doesn't hide a later one
+        for hit in gb.take_watch_hits() {
+            let watched = self.points.iter().find(|p| match &p.trap {
+                Trap::Watchpoint(range, kind) => {
+                    range.contains(&hit.addr)
+                        && match kind {
+                            WatchKind::Read => !hit.write,
+                            WatchKind::Write => hit.write,
+                            WatchKind::ReadWrite => true,
+                        }
+                        && p.condition
+                            .as_ref()
+                            .map_or(true, |c| c.holds(gb, Some(&hit)))
+                }
+                Trap::Breakpoint(_) => false,
+            });
+            if let Some(point) = watched {
+                return Some(Stop::Watchpoint { id: point.id, hit });
+            }
+        }
+        let pc = gb.get_pc();
+        self.points
+            .iter()
+            .find(|p| {
+                p.trap == Trap::Breakpoint(pc)
+                    && p.condition.as_ref().map_or(true, |c| c.holds(gb, None))
+            })
+            .map(|p| Stop::Breakpoint { id: p.id, pc })
+    }
+}
+
+/// Starts or stops recording writes, or lists those recorded
+fn log_writes(gb: &mut Gameboy, args: &[&str]) -> Result<String, DebugError> {
+    let range = match *args {
+        [] => {
+            let lines: Vec<String> = gb
+                .take_write_log()
+                .iter()
+                .map(|w| format!("0x{:04X} wrote 0x{:02X} to 0x{:04X}", w.pc, w.value, w.addr))
+                .collect();
+            return Ok(lines.join("\n"));
+        }
+        ["off"] => {
+            gb.log_writes(None);
+            return Ok("Stopped logging writes".to_string());
+        }
+        [range] => parse_range(range)?,
+        [start, end] => address_range(parse_number(start)?, parse_number(end)?)?,
+        _ => return Err(DebugError::TooManyArguments),
+    };
+    let message = format!(
+        "Logging writes to 0x{:04X}-0x{:04X}",
+        range.start(),
+        range.end()
+    );
+    gb.log_writes(Some(range));
+    Ok(message)
+}
+
+/// The CPU registers on one line, for describing where the game stopped
+fn registers(gb: &Gameboy) -> String {
+    let reg = gb.registers();
+    format!(
+        "PC: {:04X} SP: {:04X} AF: {:02X}{:02X} BC: {:02X}{:02X} DE: {:02X}{:02X} HL: {:02X}{:02X}",
+        reg.pc, reg.sp, reg.a, reg.f, reg.b, reg.c, reg.d, reg.e, reg.h, reg.l
+    )
+}
+
+fn parse_number(text: &str) -> Result<u16, DebugError> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_prefix('$'))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| DebugError::InvalidNumber(text.to_string()))
+}
+
+/// Parses a single address or an inclusive range such as `C000-C0FF`
+fn parse_range(text: &str) -> Result<RangeInclusive<u16>, DebugError> {
+    match text.split_once('-') {
+        Some((start, end)) => address_range(parse_number(start)?, parse_number(end)?),
+        None => parse_number(text).map(|addr| addr..=addr),
+    }
+}
+
+fn address_range(start: u16, end: u16) -> Result<RangeInclusive<u16>, DebugError> {
+    if end < start {
+        return Err(DebugError::ReversedRange(start, end));
+    }
+    Ok(start..=end)
+}
+
+/// Expands an IO register given by its offset from 0xFF00, or passes through a full address
+fn io_address(addr: u16) -> Result<u16, DebugError> {
+    match addr {
+        0x00..=0x7F => Ok(0xFF00 + addr),
+        0xFF00..=0xFF7F | 0xFFFF => Ok(addr),
+        _ => Err(DebugError::NotIoRegister(addr)),
+    }
+}
+
+#[cfg(test)]
+mod debugger_tests {
+    use super::*;
+    use crate::sink::NullSink;
+
+    fn run_to_stop(debugger: &mut Debugger, gb: &mut Gameboy) -> Stop {
+        for _ in 0..1000 {
+            if let Some(stop) = debugger.step(gb, &mut NullSink, &mut NullSink) {
+                return stop;
+            }
+        }
+        panic!("Game never stopped");
+    }
+
+    #[test]
+    fn conditions() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x100..0x109].copy_from_slice(&[
+            0x3E, 0x3C, // LD A, $3C
+            0x3C, // INC A
+            0xE0, 0x80, // LDH ($80), A
+            0xE0, 0x42, // LDH ($42), A
+            0x18, 0xF9, // JR -7
+        ]);
+        let mut gb = Gameboy::power_on(rom.into_boxed_slice(), None).unwrap();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(
+            debugger.execute(&mut gb, "break 0x102 if a == 3F"),
+            Ok("Breakpoint 1 at 0x0102 if a == 0x3F".to_string())
+        );
+        assert_eq!(
+            run_to_stop(&mut debugger, &mut gb),
+            Stop::Breakpoint { id: 1, pc: 0x0102 }
+        );
+        assert_eq!(gb.registers().a, 0x3F);
+
+        debugger.execute(&mut gb, "delete 1").unwrap();
+        debugger.execute(&mut gb, "rwatch io 42").unwrap();
+        assert_eq!(
+            debugger.execute(&mut gb, "watch io FF42 if value >= $41"),
+            Ok("Watchpoint 3 on 0xFF42 if value >= 0x41".to_string())
+        );
+        debugger
+            .execute(&mut gb, "awatch FF80-FF81 if [ff80] == 0")
+            .unwrap();
+        assert_eq!(
+            run_to_stop(&mut debugger, &mut gb),
+            Stop::Watchpoint {
+                id: 3,
+                hit: WatchHit {
+                    pc: 0x0105,
+                    addr: 0xFF42,
+                    value: 0x41,
+                    write: true
+                }
+            }
+        );
+        assert_eq!(
+            debugger.execute(&mut gb, "info").unwrap(),
+            "Read watchpoint 2 on 0xFF42\n\
+             Watchpoint 3 on 0xFF42 if value >= 0x41\n\
+             Access watchpoint 4 on 0xFF80-0xFF81 if [FF80] == 0x0"
+        );
+        assert_eq!(
+            debugger.execute(&mut gb, "delete"),
+            Ok("Deleted 3".to_string())
+        );
+        for _ in 0..100 {
+            assert_eq!(debugger.step(&mut gb, &mut NullSink, &mut NullSink), None);
+        }
+    }
+
+    #[test]
+    fn condition_on_later_hit() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x100..0x10A].copy_from_slice(&[
+            0x31, 0x10, 0xC0, // LD SP, $C010
+            0x01, 0x34, 0x12, // LD BC, $1234
+            0xC5, // PUSH BC, writing 0x12 then 0x34
+            0x18, 0xFE, // JR -2
+            0x00,
+        ]);
+        let mut gb = Gameboy::power_on(rom.into_boxed_slice(), None).unwrap();
+        let mut debugger = Debugger::new();
+        debugger
+            .execute(&mut gb, "watch C00E-C00F if value == 34")
+            .unwrap();
+        // The first write of the PUSH fails the condition, the second still stops the game
+        assert_eq!(
+            run_to_stop(&mut debugger, &mut gb),
+            Stop::Watchpoint {
+                id: 1,
+                hit: WatchHit {
+                    pc: 0x0106,
+                    addr: 0xC00E,
+                    value: 0x34,
+                    write: true
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn step_count() {
+        // NOPs all the way
+        let mut gb = Gameboy::power_on(vec![0x00; 0x8000].into_boxed_slice(), None).unwrap();
+        let mut debugger = Debugger::new();
+        let message = debugger.execute(&mut gb, "step 10").unwrap();
+        assert_eq!(gb.get_pc(), 0x10A);
+        assert!(message.starts_with("Ran 10 instructions\nPC: 010A SP: FFFE"));
+        debugger.execute(&mut gb, "s").unwrap();
+        assert_eq!(gb.get_pc(), 0x10B);
+
+        // Stopping early at a breakpoint
+        debugger.execute(&mut gb, "break 110").unwrap();
+        let message = debugger.execute(&mut gb, "step 10").unwrap();
+        assert_eq!(gb.get_pc(), 0x110);
+        assert!(message.starts_with("Breakpoint 1 hit at 0x0110 after 5 instructions\n"));
+    }
+
+    #[test]
+    fn step_scanline() {
+        let mut gb = Gameboy::power_on(vec![0x00; 0x8000].into_boxed_slice(), None).unwrap();
+        let mut debugger = Debugger::new();
+        let start = gb.current_scanline();
+        let message = debugger.execute(&mut gb, "scanline").unwrap();
+        assert_eq!(gb.current_scanline(), (start + 1) % 154);
+        assert!(message.starts_with("Ran "));
+
+        // Stopping early at a breakpoint, still on the same line
+        let pc = gb.get_pc();
+        debugger
+            .execute(&mut gb, &format!("break {:X}", pc + 3))
+            .unwrap();
+        let message = debugger.execute(&mut gb, "line").unwrap();
+        assert_eq!(gb.get_pc(), pc + 3);
+        assert_eq!(gb.current_scanline(), (start + 1) % 154);
+        assert!(message.starts_with("Breakpoint 1 hit"));
+    }
+
+    #[test]
+    fn log_writes() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x100..0x10B].copy_from_slice(&[
+            0x3E, 0x12, // LD A, $12
+            0xEA, 0x00, 0xC0, // LD ($C000), A
+            0x3C, // INC A
+            0xEA, 0x01, 0xC0, // LD ($C001), A
+            0x18, 0xFE, // JR -2
+        ]);
+        let rom = rom.into_boxed_slice();
+        let mut gb = Gameboy::power_on(rom.clone(), None).unwrap();
+        let mut debugger = Debugger::new();
+        assert_eq!(
+            debugger.execute(&mut gb, "logwrites C000 C0FF"),
+            Ok("Logging writes to 0xC000-0xC0FF".to_string())
+        );
+        debugger.execute(&mut gb, "step 6").unwrap();
+        assert_eq!(
+            debugger.execute(&mut gb, "logwrites"),
+            Ok("0x0102 wrote 0x12 to 0xC000\n0x0106 wrote 0x13 to 0xC001".to_string())
+        );
+        // Listing takes the writes, and the loop writes nothing more
+        debugger.execute(&mut gb, "step 6").unwrap();
+        assert_eq!(debugger.execute(&mut gb, "logwrites"), Ok(String::new()));
+
+        // A single address
+        let mut gb = Gameboy::power_on(rom, None).unwrap();
+        debugger.execute(&mut gb, "logwrites C001").unwrap();
+        debugger.execute(&mut gb, "step 6").unwrap();
+        assert_eq!(
+            debugger.execute(&mut gb, "logwrites"),
+            Ok("0x0106 wrote 0x13 to 0xC001".to_string())
+        );
+        debugger.execute(&mut gb, "logwrites off").unwrap();
+        assert!(gb.take_write_log().is_empty());
+
+        // Reversed ranges are rejected rather than logging nothing
+        assert_eq!(
+            debugger.execute(&mut gb, "logwrites C0FF C000"),
+            Err(DebugError::ReversedRange(0xC0FF, 0xC000))
+        );
+        assert_eq!(
+            debugger.execute(&mut gb, "logwrites C0FF-C000"),
+            Err(DebugError::ReversedRange(0xC0FF, 0xC000))
+        );
+        assert_eq!(
+            DebugError::ReversedRange(0xC0FF, 0xC000).to_string(),
+            "End C000 is before start C0FF"
+        );
+    }
+
+    #[test]
+    fn command_errors() {
+        let mut gb = Gameboy::power_on(vec![0x00; 0x8000].into_boxed_slice(), None).unwrap();
+        let mut debugger = Debugger::new();
+        let mut run = |line: &str| debugger.execute(&mut gb, line).unwrap_err();
+        assert_eq!(
+            run("frob 10"),
+            DebugError::UnknownCommand("frob".to_string())
+        );
+        assert_eq!(run("break"), DebugError::MissingArgument);
+        assert_eq!(
+            run("break C0G0"),
+            DebugError::InvalidNumber("C0G0".to_string())
+        );
+        assert_eq!(run("watch io 90"), DebugError::NotIoRegister(0x90));
+        assert_eq!(
+            run("break 100 if q == 1"),
+            DebugError::InvalidCondition("q == 1".to_string())
+        );
+        // There's no value read or written at a breakpoint
+        assert_eq!(
+            run("break 100 if value == 1"),
+            DebugError::InvalidCondition("value == 1".to_string())
+        );
+        assert_eq!(run("delete 9"), DebugError::NoSuchPoint(9));
+        assert_eq!(
+            run("step 0x10"),
+            DebugError::InvalidCount("0x10".to_string())
+        );
+        assert_eq!(run("step 1 2"), DebugError::TooManyArguments);
+        assert_eq!(run("delete 1 2"), DebugError::TooManyArguments);
+        assert_eq!(run("watch C000 C001"), DebugError::TooManyArguments);
+        assert_eq!(run("break 100 200"), DebugError::TooManyArguments);
+        assert_eq!(
+            run("logwrites C000 C001 C002"),
+            DebugError::TooManyArguments
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use alloc::string::*;
 use alloc::vec::*;
+use core::ops::Range;
 
 /// Given a block of code separated into u8 values, interpret each byte as a valid Gameboy opcode,
 /// and convert it and its operands into a human-readable mnemonic.
@@ -1813,6 +1814,41 @@ pub fn disassemble_block(data: &[u8], pc: u16) -> Vec<(u16, String)> {
     ret
 }
 
+/// Disassembles a block like `disassemble_block`, except that bytes at addresses within any of
+/// `data_regions` are rendered as `db` directives rather than decoded. Decoding restarts at the
+/// end of each data region, so data can't desync the code that follows it.
+pub fn disassemble_block_with_data(
+    data: &[u8],
+    pc: u16,
+    data_regions: &[Range<u16>],
+) -> Vec<(u16, String)> {
+    let is_data = |offset: usize| {
+        let addr = (pc as usize + offset) as u16;
+        data_regions.iter().any(|r| r.contains(&addr))
+    };
+    let mut ret: Vec<(u16, String)> = vec![];
+    let mut start = 0;
+    while start < data.len() {
+        let in_data = is_data(start);
+        let end = (start..data.len())
+            .find(|offset| is_data(*offset) != in_data)
+            .unwrap_or(data.len());
+        let block_pc = (pc as usize + start) as u16;
+        if in_data {
+            for (i, byte) in data[start..end].iter().enumerate() {
+                ret.push((
+                    block_pc + i as u16,
+                    format!("{:02X}:\t db ${:02X}", byte, byte),
+                ));
+            }
+        } else {
+            ret.append(&mut disassemble_block(&data[start..end], block_pc));
+        }
+        start = end;
+    }
+    ret
+}
+
 /// Returns a String representation of the
 pub fn get_opcode(opcode: u8) -> String {
     OPCODE_STRINGS[opcode as usize].to_string()
@@ -2102,6 +2138,28 @@ const OPCODE_SIZE: [usize; 256] = [
 
 #[cfg(test)]
 mod disassemble_tests {
+    use super::*;
+
     #[test]
     fn interrupt_requests() {}
+
+    #[test]
+    fn data_regions() {
+        // LD A, $3E; a 2-byte table; NOP
+        let block = [0x3E, 0x3E, 0x01, 0xFA, 0x00];
+        let code = disassemble_block(&block, 0x200);
+        // Decoded naively, the table is taken as LD BC,$00FA and swallows the NOP
+        assert_eq!(code.len(), 2);
+
+        // Regions outside the block have no effect
+        let lines = disassemble_block_with_data(&block, 0x200, &[0x202..0x204, 0x4000..0x4100]);
+        let addrs: Vec<u16> = lines.iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(addrs, [0x200, 0x202, 0x203, 0x204]);
+        assert!(lines[0].1.contains("ld a,$3E"));
+        assert!(lines[1].1.ends_with("db $01"));
+        assert!(lines[2].1.ends_with("db $FA"));
+        assert!(lines[3].1.contains("nop"));
+
+        assert_eq!(disassemble_block_with_data(&block, 0x200, &[]), code);
+    }
 }
@@ -1,17 +1,88 @@
+use super::apu;
+use super::cartridge::{CartridgeError, CartridgeHeader, HEADER_END};
+use super::cheats::{Cheat, CheatCode, CheatError};
 use super::cpu;
+use super::link::SerialLink;
 use super::mmu;
 use super::mmu::Memory;
+use super::movie::{Movie, MovieError, MovieStatus};
+use super::rewind::RewindBuffer;
 use super::sink::*;
+use super::state::{SaveState, StateReader, StateWriter};
+use super::storage::SaveStorage;
+use super::util::hash::fnv1a_64;
 
 use alloc::boxed::*;
+use alloc::collections::VecDeque;
+use alloc::fmt;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+/// Number of cycles the PPU spends on each scanline
+pub const SCANLINE_CYCLES: u32 = 456;
+
+/// Number of cycles in a frame, 154 scanlines including V-Blank
+pub const FRAME_CYCLES: u32 = SCANLINE_CYCLES * 154;
+
+/// Most audio frames kept by `step_buffered` before the oldest are dropped,
+/// one second's worth at the default sample rate
+pub const MAX_BUFFERED_AUDIO: usize = super::SAMPLE_RATE as usize;
+
+/// Number of writes kept by `Gameboy::log_writes` before the oldest are dropped
+pub const MAX_WRITE_LOG: usize = 0x10000;
+
+/// Frames waited after battery-backed RAM is stored before storing it again, about a second
+pub const SAVE_FLUSH_FRAMES: u64 = 60;
+
+/// Size of the DMG boot ROM, mapped over 0x0000-0x00FF
+pub const BOOT_ROM_SIZE: usize = 0x100;
+
+/// Identifies the start of a save state
+const STATE_MAGIC: [u8; 4] = *b"GABE";
+
+/// Version of the save state format written by `Gameboy::save_state`, bumped whenever it
+/// changes so older states are rejected rather than misread
+pub const STATE_VERSION: u16 = 4;
 
 pub struct Gameboy {
     cpu: cpu::Cpu,
     mmu: mmu::Mmu,
+    /// Maximum number of cycles allowed to pass without a V-Blank before the watchdog expires
+    watchdog_limit: Option<u64>,
+    /// Cycles run since the PPU last entered V-Blank
+    cycles_since_vblank: u64,
+    /// Audio produced by `step_buffered`, waiting for `take_audio`
+    audio_buffer: AudioBuffer,
+    /// Hash of the ROM, so save states are only loaded into the game they came from
+    rom_hash: u64,
+    /// Save state taken when the CPU locked up, for `last_crash`
+    crash_state: Option<Vec<u8>>,
+    /// States taken each V-Blank for `rewind`
+    rewind: RewindBuffer,
+    header: CartridgeHeader,
+    /// Number of times the PPU has entered V-Blank since power on
+    frame_count: u64,
+    movie: Option<MovieSession>,
+    /// Where battery-backed RAM is written back to, see `set_save_storage`
+    save_storage: Option<Box<dyn SaveStorage>>,
+    /// The frame count when battery-backed RAM was last stored
+    last_save_flush: u64,
+    /// Whether host time is ignored, see `GameboyBuilder::deterministic`
+    deterministic: bool,
+}
+
+/// A movie being recorded or played by a `Gameboy`. Movie input changes at the start of
+/// V-Blank, so the game sees exactly the same input on every playback.
+enum MovieSession {
+    /// Recording, with the buttons the host has set held from the next frame
+    Recording { movie: Movie, keys: u8 },
+    /// Playing, with `frame` the index of the next frame to play
+    Playing { movie: Movie, frame: usize },
 }
 
 /// The supported input states for the Joypad.
 /// User provides a combined mask of these values during each step call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GbKeys {
     Right = 0,
     Left = 1,
@@ -23,8 +94,465 @@ pub enum GbKeys {
     Start = 7,
 }
 
+/// Builder for a `Gameboy`, for configuring options that must be decided before power on
+pub struct GameboyBuilder {
+    rom_data: Box<[u8]>,
+    save_data: Option<Box<[u8]>>,
+    deterministic: bool,
+    watchdog: Option<u64>,
+    trace_depth: Option<usize>,
+    audio: bool,
+    reject_cgb_only: bool,
+    skip_illegal_opcodes: bool,
+    boot_rom: Option<Box<[u8]>>,
+    save_storage: Option<Box<dyn SaveStorage>>,
+}
+
+impl GameboyBuilder {
+    pub fn new(rom_data: Box<[u8]>) -> Self {
+        GameboyBuilder {
+            rom_data,
+            save_data: None,
+            deterministic: false,
+            watchdog: None,
+            trace_depth: None,
+            audio: true,
+            reject_cgb_only: false,
+            skip_illegal_opcodes: false,
+            boot_rom: None,
+            save_storage: None,
+        }
+    }
+
+    /// Cartridge RAM contents to load on power on, if the cartridge supports it
+    pub fn save_data(mut self, save_data: Box<[u8]>) -> Self {
+        self.save_data = Some(save_data);
+        self
+    }
+
+    /// Loads cartridge RAM from `storage` on power on, unless given `save_data`, and writes it
+    /// back there as the game changes it, see `Gameboy::set_save_storage`. Building fails
+    /// with `GameboyError::SaveData` if the save data doesn't fit, so it's never overwritten.
+    pub fn save_storage(mut self, storage: Box<dyn SaveStorage>) -> Self {
+        self.save_storage = Some(storage);
+        self
+    }
+
+    /// Guarantees identical results for identical inputs on any machine, for snapshot tests.
+    /// All RAM, including cartridge RAM, starts zeroed, so building fails with
+    /// `GameboyError::DeterministicSaveData` if given save data or storage.
+    /// Anything that would normally follow host time is instead derived from emulated cycles,
+    /// so the cartridge's real time clock starts at zero and `Gameboy::sync_rtc` is ignored.
+    pub fn deterministic(mut self) -> Self {
+        self.deterministic = true;
+        self
+    }
+
+    /// Enables the watchdog, see `Gameboy::set_watchdog`
+    pub fn watchdog(mut self, max_cycles: u64) -> Self {
+        self.watchdog = Some(max_cycles);
+        self
+    }
+
+    /// Enables the instruction trace, see `Gameboy::set_trace_depth`
+    pub fn trace(mut self, depth: usize) -> Self {
+        self.trace_depth = Some(depth);
+        self
+    }
+
+    /// Skips generating audio for headless runs, see `Gameboy::set_audio_output`
+    pub fn without_audio(mut self) -> Self {
+        self.audio = false;
+        self
+    }
+
+    /// Fails to build with `GameboyError::RequiresCgb` for games that only run on the CGB,
+    /// rather than running them with the wrong hardware. Only the DMG is emulated, so by
+    /// default they're run anyway, as some do show a message saying they need a CGB.
+    pub fn reject_cgb_only(mut self) -> Self {
+        self.reject_cgb_only = true;
+        self
+    }
+
+    /// Skips illegal opcodes rather than locking up, see `Gameboy::set_skip_illegal_opcodes`
+    pub fn skip_illegal_opcodes(mut self) -> Self {
+        self.skip_illegal_opcodes = true;
+        self
+    }
+
+    /// Runs a DMG boot ROM from 0x0000 on power on, scrolling in the logo before starting the
+    /// game, rather than starting the game with the registers as the boot ROM leaves them.
+    /// Must be `BOOT_ROM_SIZE` bytes.
+    pub fn boot_rom(mut self, boot_rom: Box<[u8]>) -> Self {
+        self.boot_rom = Some(boot_rom);
+        self
+    }
+
+    pub fn build(self) -> Result<Gameboy, GameboyError> {
+        if self.reject_cgb_only {
+            let flag = self.rom_data.get(0x143).copied().unwrap_or(0);
+            if CgbSupport::from_header_flag(flag) == CgbSupport::Only {
+                return Err(GameboyError::RequiresCgb);
+            }
+        }
+        if self.deterministic && (self.save_data.is_some() || self.save_storage.is_some()) {
+            return Err(GameboyError::DeterministicSaveData);
+        }
+        let mut save_storage = self.save_storage;
+        let save_data = self.save_data.or_else(|| {
+            let data = save_storage.as_mut()?.load()?;
+            Some(data.into_boxed_slice())
+        });
+        if let Some(boot_rom) = &self.boot_rom {
+            if boot_rom.len() != BOOT_ROM_SIZE {
+                return Err(GameboyError::InvalidBootRom(boot_rom.len()));
+            }
+        }
+        let mut gb = Gameboy::power_on(self.rom_data, save_data)?;
+        if let Some(boot_rom) = self.boot_rom {
+            gb.cpu = cpu::Cpu::power_on_boot_rom();
+            gb.mmu.map_boot_rom(boot_rom);
+        }
+        gb.set_watchdog(self.watchdog);
+        gb.set_trace_depth(self.trace_depth);
+        gb.set_audio_output(self.audio);
+        gb.set_skip_illegal_opcodes(self.skip_illegal_opcodes);
+        gb.set_save_storage(save_storage);
+        gb.deterministic = self.deterministic;
+        Ok(gb)
+    }
+}
+
+/// Error type representing why a save state could not be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    /// The data doesn't start like a save state
+    NotAState,
+    /// The save state was written by a different version of the format
+    UnsupportedVersion(u16),
+    /// The save state was taken while running a different ROM
+    WrongGame,
+    /// The save state is truncated or contains values that couldn't have been saved
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::NotAState => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "Save state is version {}, only version {} is supported",
+                version, STATE_VERSION
+            ),
+            StateError::WrongGame => write!(f, "Save state is for a different game"),
+            StateError::Corrupt => write!(f, "Save state is corrupt"),
+        }
+    }
+}
+
+/// Error type representing why a ROM could not be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameboyError {
+    /// The cartridge type byte at 0x147 names an MBC that isn't supported
+    UnsupportedMbc(u8),
+    /// The CGB flag at 0x143 of the header marks a CGB-only game, which was rejected by
+    /// `GameboyBuilder::reject_cgb_only`
+    RequiresCgb,
+    /// The boot ROM given to `GameboyBuilder::boot_rom` isn't `BOOT_ROM_SIZE` bytes
+    InvalidBootRom(usize),
+    /// The ROM is too short to contain a cartridge header
+    MissingHeader(usize),
+    /// The save data given doesn't fit the cartridge's RAM, so wasn't loaded. Frontends
+    /// should keep the save it came from rather than overwrite it with blank RAM.
+    SaveData(CartridgeError),
+    /// Save data or storage was given to a `GameboyBuilder::deterministic` build, whose
+    /// cartridge RAM always starts zeroed
+    DeterministicSaveData,
+}
+
+impl fmt::Display for GameboyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GameboyError::UnsupportedMbc(mbc) => {
+                write!(f, "Unsupported cartridge type (MBC) 0x{:02X}", mbc)
+            }
+            GameboyError::RequiresCgb => write!(f, "Game requires a Game Boy Color"),
+            GameboyError::InvalidBootRom(len) => write!(
+                f,
+                "Boot ROM is {} bytes, expected {} bytes",
+                len, BOOT_ROM_SIZE
+            ),
+            GameboyError::MissingHeader(len) => write!(
+                f,
+                "ROM is {} bytes, too short to contain a cartridge header ({} bytes)",
+                len, HEADER_END
+            ),
+            GameboyError::SaveData(e) => write!(f, "{}", e),
+            GameboyError::DeterministicSaveData => {
+                write!(f, "Deterministic runs can't load save data")
+            }
+        }
+    }
+}
+
+/// How a ROM uses CGB features, from the CGB flag at 0x143 of the header.
+/// Only DMG hardware is emulated, so CGB features are never used either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+    /// A DMG game. On older ROMs 0x143 is part of the title instead.
+    None,
+    /// Uses CGB features when present, but also runs on the DMG (0x80)
+    Enhanced,
+    /// Only runs on the CGB (0xC0)
+    Only,
+}
+
+impl CgbSupport {
+    pub fn from_header_flag(flag: u8) -> Self {
+        match flag & 0xC0 {
+            0xC0 => CgbSupport::Only,
+            0x80 => CgbSupport::Enhanced,
+            _ => CgbSupport::None,
+        }
+    }
+}
+
+/// Subsystems that can be frozen with `Gameboy::freeze_subsystem`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Apu = 0,
+    /// The PPU, which also stops frames from being produced
+    Ppu = 1,
+    /// DIV and TIMA
+    Timer = 2,
+}
+
+/// Palette selection for rendering tile data with `Gameboy::dump_tiles`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TilePalette {
+    /// BG palette, BGP
+    Bgp,
+    /// Object palette 0, OBP0
+    Obp0,
+    /// Object palette 1, OBP1
+    Obp1,
+}
+
+/// An OBJ's entry in OAM, decoded, see `Gameboy::oam_entries`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OamEntry {
+    /// Y position on screen plus 16, as stored, so 0 puts the OBJ out of sight above the screen
+    pub y: u8,
+    /// X position on screen plus 8
+    pub x: u8,
+    /// Tile number from 0x8000. For 8x16 OBJs bit 0 is ignored, the top tile being even.
+    pub tile: u8,
+    /// Whether BG and window color numbers 1-3 are drawn over the OBJ
+    pub behind_bg: bool,
+    pub flip_y: bool,
+    pub flip_x: bool,
+    /// `TilePalette::Obp0` or `TilePalette::Obp1`
+    pub palette: TilePalette,
+}
+
+/// Where the PPU draws the background, window and OBJs from, going by LCDC and the scroll
+/// and window position registers, see `Gameboy::background_layout`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackgroundLayout {
+    /// Position in the background map of the screen's top left corner, SCX
+    pub scroll_x: u8,
+    /// SCY
+    pub scroll_y: u8,
+    /// X position of the window on screen plus 7, WX
+    pub window_x: u8,
+    /// WY
+    pub window_y: u8,
+    pub bg_enabled: bool,
+    pub window_enabled: bool,
+    pub objs_enabled: bool,
+    /// Tile map used by the background, false for 0x9800-0x9BFF and true for 0x9C00-0x9FFF
+    pub bg_map_select: bool,
+    /// Tile map used by the window, false for 0x9800-0x9BFF and true for 0x9C00-0x9FFF
+    pub window_map_select: bool,
+    /// Tile data used by the background and window, false for 0x8800-0x97FF addressed with
+    /// signed tile numbers and true for 0x8000-0x8FFF
+    pub tile_data_select: bool,
+    /// Whether OBJs are 8x16 rather than 8x8
+    pub tall_objs: bool,
+}
+
+/// Description of one region of the CPU's address space, see `Gameboy::memory_map`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub name: &'static str,
+    /// First address of the region
+    pub start: u16,
+    /// Last address of the region, inclusive
+    pub end: u16,
+    /// The bank currently mapped into the region, for banked regions
+    pub bank: Option<usize>,
+    /// Whether the CPU can currently read from the region on hardware
+    pub readable: bool,
+    /// Whether the CPU can currently write to the region on hardware. Writes to ROM are
+    /// taken by the MBC as bank control, so ROM is never reported as writable.
+    pub writable: bool,
+}
+
+/// Returned when a run helper reaches its cycle limit before its stop condition is met
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
+
+/// What the CPU was doing when it locked up, for bug reports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashReport {
+    /// Address of the illegal instruction
+    pub pc: u16,
+    pub opcode: u8,
+    /// The instructions leading up to the crash, as returned by `Gameboy::recent_trace`.
+    /// Empty unless tracing was enabled.
+    pub trace: Vec<(u16, u8)>,
+    /// Save state from right after the illegal instruction, see `Gameboy::load_state`
+    pub state: Vec<u8>,
+}
+
+/// A write recorded by `Gameboy::log_writes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteRecord {
+    /// Address of the instruction that wrote
+    pub pc: u16,
+    pub addr: u16,
+    pub value: u8,
+}
+
+/// Callbacks from inside the emulator as it runs, for scripting. Each is called with the
+/// emulator mid-instruction, so they can only record what happened for the host to act on
+/// once the step returns. See `Gameboy::set_hooks`.
+pub trait Hooks {
+    /// Called as the CPU reads `value` from `addr`, including opcode fetches
+    fn on_read(&mut self, _addr: u16, _value: u8) {}
+    /// Called as the CPU writes `value` to `addr`, before the write takes effect
+    fn on_write(&mut self, _addr: u16, _value: u8) {}
+    /// Called as the PPU enters V-Blank, after any movie input for the frame is applied
+    fn on_frame(&mut self) {}
+}
+
+/// Which accesses by the CPU a watchpoint traps, see `Gameboy::add_watchpoint`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// An access by the CPU that hit a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Address of the instruction that made the access
+    pub pc: u16,
+    pub addr: u16,
+    /// The value read or written
+    pub value: u8,
+    pub write: bool,
+}
+
+/// The first difference between two save states found by `Gameboy::diff_states`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateDiff {
+    /// A CPU register or flag, such as "A", "SP" or "IME", with its value in each state
+    Register { name: &'static str, a: u16, b: u16 },
+    /// A byte of memory, in the region named like in `Gameboy::memory_map`. `offset` is from
+    /// the start of the region, counting on through the banks of banked regions.
+    Memory {
+        region: &'static str,
+        offset: usize,
+        a: u8,
+        b: u8,
+    },
+    /// A sound channel, 0-3 for channels 1-4, see `apu::ChannelState`
+    ApuChannel(usize),
+    /// State not covered by the above, such as timer and PPU counters
+    Other,
+}
+
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateDiff::Register { name, a, b } => {
+                write!(f, "Register {}: 0x{:02X} != 0x{:02X}", name, a, b)
+            }
+            StateDiff::Memory {
+                region,
+                offset,
+                a,
+                b,
+            } => write!(
+                f,
+                "{} at offset 0x{:04X}: 0x{:02X} != 0x{:02X}",
+                region, offset, a, b
+            ),
+            StateDiff::ApuChannel(channel) => write!(f, "Sound channel {}", channel + 1),
+            StateDiff::Other => write!(f, "Internal state"),
+        }
+    }
+}
+
+/// The parts of a loaded state compared by `Gameboy::diff_states`, in the order compared
+struct StateSnapshot {
+    registers: [(&'static str, u16); 12],
+    memory: Vec<(&'static str, Vec<u8>)>,
+    channels: [apu::ChannelState; 4],
+}
+
+impl StateSnapshot {
+    fn take(gb: &Gameboy) -> Self {
+        let reg = &gb.cpu.reg;
+        StateSnapshot {
+            registers: [
+                ("A", reg.a as u16),
+                ("F", reg.f as u16),
+                ("B", reg.b as u16),
+                ("C", reg.c as u16),
+                ("D", reg.d as u16),
+                ("E", reg.e as u16),
+                ("H", reg.h as u16),
+                ("L", reg.l as u16),
+                ("SP", reg.sp),
+                ("PC", reg.pc),
+                ("IME", gb.cpu.ime as u16),
+                ("HALT", gb.cpu.halted as u16),
+            ],
+            memory: gb.mmu.stored_memory(),
+            channels: gb.mmu.apu_debug_state().channels,
+        }
+    }
+
+    fn diff(&self, other: &Self) -> Option<StateDiff> {
+        let registers = self.registers.iter().zip(&other.registers);
+        if let Some(((name, a), (_, b))) = registers.into_iter().find(|(a, b)| a != b) {
+            return Some(StateDiff::Register { name, a: *a, b: *b });
+        }
+        for ((region, a), (_, b)) in self.memory.iter().zip(&other.memory) {
+            if let Some((offset, (a, b))) = a.iter().zip(b).enumerate().find(|(_, (a, b))| a != b) {
+                return Some(StateDiff::Memory {
+                    region,
+                    offset,
+                    a: *a,
+                    b: *b,
+                });
+            }
+        }
+        let channels = self.channels.iter().zip(&other.channels);
+        if let Some(channel) = channels.into_iter().position(|(a, b)| a != b) {
+            return Some(StateDiff::ApuChannel(channel));
+        }
+        None
+    }
+}
+
 pub struct GbDebug {
     pub cpu_data: cpu::Cpu,
+    pub apu_data: apu::ApuDebug,
     pub ie_data: u8,
     pub if_data: u8,
     pub vram_lcdc: u8,
@@ -32,15 +560,87 @@ pub struct GbDebug {
     pub vram_ly: u8,
 }
 
+/// Queues audio frames, dropping the oldest once `MAX_BUFFERED_AUDIO` are queued
+#[derive(Default)]
+struct AudioBuffer(VecDeque<AudioFrame>);
+
+impl Sink<AudioFrame> for AudioBuffer {
+    fn append(&mut self, value: AudioFrame) {
+        if self.0.len() == MAX_BUFFERED_AUDIO {
+            self.0.pop_front();
+        }
+        self.0.push_back(value);
+    }
+}
+
+/// Passes frames on to `inner`, either all of them or only the last of `remaining`
+struct SkippingVideoSink<'a> {
+    inner: &'a mut dyn Sink<VideoFrame>,
+    remaining: u32,
+    skip: bool,
+}
+
+impl Sink<VideoFrame> for SkippingVideoSink<'_> {
+    fn append(&mut self, value: VideoFrame) {
+        self.remaining = self.remaining.saturating_sub(1);
+        if !self.skip || self.remaining == 0 {
+            self.inner.append(value);
+        }
+    }
+}
+
+/// Passes on one in every `every` audio frames to `inner`
+struct DecimatingAudioSink<'a> {
+    inner: &'a mut dyn Sink<AudioFrame>,
+    every: u32,
+    count: u32,
+}
+
+impl Sink<AudioFrame> for DecimatingAudioSink<'_> {
+    fn append(&mut self, value: AudioFrame) {
+        if self.count == 0 {
+            self.inner.append(value);
+        }
+        self.count = (self.count + 1) % self.every;
+    }
+}
+
+/// Holds on to the last frame it was given
+struct LatestFrameSink(Option<VideoFrame>);
+
+impl Sink<VideoFrame> for LatestFrameSink {
+    fn append(&mut self, value: VideoFrame) {
+        self.0 = Some(value);
+    }
+}
+
 impl Gameboy {
     /// Initializes Gameboy state to begin emulation on provided
-    /// binary file
-    pub fn power_on(rom_data: Box<[u8]>, save_data: Option<Box<[u8]>>) -> Self {
-        let mmu = mmu::Mmu::power_on(rom_data, save_data);
-        Gameboy {
+    /// binary file. Returns an error if the cartridge type in the header isn't supported.
+    pub fn power_on(
+        rom_data: Box<[u8]>,
+        save_data: Option<Box<[u8]>>,
+    ) -> Result<Self, GameboyError> {
+        let header =
+            CartridgeHeader::parse(&rom_data).ok_or(GameboyError::MissingHeader(rom_data.len()))?;
+        let rom_hash = fnv1a_64(&rom_data);
+        let mmu = mmu::Mmu::power_on(rom_data, save_data)?;
+        Ok(Gameboy {
             cpu: cpu::Cpu::power_on(),
             mmu,
-        }
+            watchdog_limit: None,
+            cycles_since_vblank: 0,
+            audio_buffer: AudioBuffer::default(),
+            rom_hash,
+            crash_state: None,
+            rewind: RewindBuffer::new(0),
+            header,
+            frame_count: 0,
+            movie: None,
+            save_storage: None,
+            last_save_flush: 0,
+            deterministic: false,
+        })
     }
 
     /// Executes one CPU instruction and updates the other
@@ -51,55 +651,2109 @@ impl Gameboy {
         video_sink: &mut dyn Sink<VideoFrame>,
         audio_sink: &mut dyn Sink<AudioFrame>,
     ) -> u32 {
+        self.step_channels(video_sink, &mut MixedAudioSink(audio_sink))
+    }
+
+    /// Same as `step`, but audio is kept in an internal buffer for the host to collect
+    /// with `take_audio` on its own schedule. Only the latest `MAX_BUFFERED_AUDIO` frames
+    /// are kept if it isn't collected.
+    pub fn step_buffered(&mut self, video_sink: &mut dyn Sink<VideoFrame>) -> u32 {
+        let mut audio_buffer = core::mem::take(&mut self.audio_buffer);
+        let cycles = self.step(video_sink, &mut audio_buffer);
+        self.audio_buffer = audio_buffer;
+        cycles
+    }
+
+    /// Returns the audio produced by `step_buffered` since the last call, oldest first
+    pub fn take_audio(&mut self) -> Vec<AudioFrame> {
+        self.audio_buffer.0.drain(..).collect()
+    }
+
+    /// Same as `step`, but audio is mixed into signed 16-bit samples rather than f32,
+    /// for hosts that want to skip a conversion pass
+    pub fn step_i16(
+        &mut self,
+        video_sink: &mut dyn Sink<VideoFrame>,
+        audio_sink: &mut dyn Sink<AudioFrameI16>,
+    ) -> u32 {
+        self.step_channels(video_sink, &mut I16AudioSink(audio_sink))
+    }
+
+    /// Same as `step`, but audio is provided separately for each APU channel rather than
+    /// mixed together, allowing each channel to be recorded to its own track.
+    /// Use `SplitAudioSink` to send each channel to a separate `AudioFrame` Sink.
+    pub fn step_channels(
+        &mut self,
+        video_sink: &mut dyn Sink<VideoFrame>,
+        audio_sink: &mut dyn Sink<ChannelAudioFrame>,
+    ) -> u32 {
+        let prev_mode = self.mmu.ppu_mode();
+        self.mmu.set_instruction_pc(self.cpu.reg.pc);
+        self.mmu.set_cpu_access(true);
         let cycles = self.cpu.tick(&mut self.mmu);
+        self.mmu.set_cpu_access(false);
 
         // Update memory
-        self.mmu.update(cycles, video_sink, audio_sink);
+        if self.cpu.stopped {
+            // Nothing but the joypad runs until STOP is ended by a button press
+            self.mmu.update_stopped();
+        } else {
+            self.mmu.update(cycles, video_sink, audio_sink);
+        }
+
+        if prev_mode != 1 && self.mmu.ppu_mode() == 1 {
+            self.cycles_since_vblank = 0;
+            self.frame_count += 1;
+            self.advance_movie();
+            self.mmu.apply_ram_cheats();
+            if self.frame_count.saturating_sub(self.last_save_flush) >= SAVE_FLUSH_FRAMES {
+                self.flush_save_data();
+            }
+            if let Some(hooks) = self.mmu.hooks.get_mut() {
+                hooks.on_frame();
+            }
+            if self.rewind.capacity() > 0 {
+                self.rewind.push(self.save_state());
+            }
+        } else {
+            self.cycles_since_vblank += cycles as u64;
+        }
+        if self.cpu.lockup.is_some() && self.crash_state.is_none() {
+            self.crash_state = Some(self.save_state());
+        }
         cycles
     }
 
-    pub fn update_key_state(&mut self, key: GbKeys, pressed: bool) {
-        self.mmu.joypad.set_key_pressed(key, pressed);
+    /// Captures the full state of the system, to resume from later with `load_state`.
+    /// Options set by the host, such as the sample rate or watchdog, aren't included.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        for b in STATE_MAGIC {
+            w.u8(b);
+        }
+        w.u16(STATE_VERSION);
+        w.u64(self.rom_hash);
+        self.cpu.save_state(&mut w);
+        self.mmu.save_state(&mut w);
+        w.u64(self.cycles_since_vblank);
+        w.u64(self.frame_count);
+        w.into_inner()
     }
 
-    pub fn get_save_data(&self) -> Option<Box<[u8]>> {
-        let result = self.mmu.cart.write_save_data();
+    /// Restores a state captured by `save_state` while running the same ROM. On error the
+    /// running state is left unchanged.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = self.state_body(data)?;
+        let backup = self.save_state();
+        let result = self.load_body(&mut r);
         match result {
-            Ok(d) => Some(d),
-            Err(_) => None,
+            Ok(()) => {
+                self.crash_state = self.cpu.lockup.map(|_| data.to_vec());
+                self.seek_movie();
+                self.mmu.save_dirty |= self.mmu.has_battery;
+            }
+            Err(_) => {
+                let mut r = StateReader::new(&backup[STATE_MAGIC.len() + 2 + 8..]);
+                self.load_body(&mut r)
+                    .expect("Restoring the state from before a failed load");
+            }
         }
+        result
     }
 
-    pub fn poll_serial(&mut self) -> Option<u8> {
-        if self.mmu.read_byte(0xFF02) == 0x81 {
-            // Output ready
-            // Clear SC to ACK
-            self.mmu.write_byte(0xFF02, 0x01);
-            Some(self.mmu.read_byte(0xFF01))
-        } else {
-            None
+    /// Compares two save states of the running game field by field, for tracking down
+    /// desyncs. Returns the first difference found, checking the CPU registers, then memory,
+    /// then the sound channels, or `None` if the states are the same. The running state is
+    /// left as it was.
+    pub fn diff_states(&mut self, a: &[u8], b: &[u8]) -> Result<Option<StateDiff>, StateError> {
+        let snapshot_a = self.inspect_state(a, StateSnapshot::take)?;
+        let snapshot_b = self.inspect_state(b, StateSnapshot::take)?;
+        let diff = snapshot_a.diff(&snapshot_b);
+        Ok(diff.or_else(|| (a != b).then_some(StateDiff::Other)))
+    }
+
+    /// Calls `f` with a save state loaded, then puts the running state back. Unlike
+    /// `load_state`, movies and the crash state are left alone.
+    fn inspect_state<T>(&mut self, data: &[u8], f: impl Fn(&Self) -> T) -> Result<T, StateError> {
+        let mut r = self.state_body(data)?;
+        let backup = self.save_state();
+        let result = self.load_body(&mut r).map(|()| f(self));
+        let mut r = StateReader::new(&backup[STATE_MAGIC.len() + 2 + 8..]);
+        self.load_body(&mut r)
+            .expect("Restoring the state from before inspecting another");
+        result
+    }
+
+    /// Checks the header of a save state, returning a reader for the rest
+    fn state_body<'a>(&self, data: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        if !data.starts_with(&STATE_MAGIC) {
+            return Err(StateError::NotAState);
+        }
+        let mut r = StateReader::new(&data[STATE_MAGIC.len()..]);
+        let version = r.u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
         }
+        if r.u64()? != self.rom_hash {
+            return Err(StateError::WrongGame);
+        }
+        Ok(r)
     }
 
-    pub fn get_debug_state(&self) -> GbDebug {
-        GbDebug {
-            cpu_data: self.cpu.get_debug_data(),
-            if_data: self.mmu.read_byte(0xFF0F),
-            ie_data: self.mmu.read_byte(0xFFFF),
-            vram_lcdc: self.mmu.read_byte(0xFF40),
-            vram_stat: self.mmu.read_byte(0xFF41),
-            vram_ly: self.mmu.read_byte(0xFF44),
+    /// Loads the parts of a save state after its header
+    fn load_body(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.cpu.load_state(r)?;
+        self.mmu.load_state(r)?;
+        self.cycles_since_vblank = r.u64()?;
+        self.frame_count = r.u64()?;
+        r.finish()
+    }
+
+    /// Starts recording a movie from the current state, replacing any movie being recorded or
+    /// played. Until it's stopped, buttons set with `update_key_state` are recorded and take
+    /// effect at the start of the next V-Blank rather than straight away, so nothing happens
+    /// while the LCD is off. Loading a save state, including by `rewind`, rolls the recording
+    /// back to the frame the state was taken on, so a section can be re-recorded.
+    pub fn start_movie_recording(&mut self) {
+        let movie = Movie {
+            frames: Vec::new(),
+            rerecords: 0,
+            start_state: self.save_state(),
+            start_frame: self.frame_count,
+            rom_hash: self.rom_hash,
+        };
+        let keys = self.mmu.joypad.held_keys();
+        self.movie = Some(MovieSession::Recording { movie, keys });
+    }
+
+    /// Loads the state `movie` starts from and plays it back, replacing any movie being
+    /// recorded or played. Buttons set with `update_key_state` are ignored until every frame
+    /// has been played. Loading a save state taken during the movie continues playback from
+    /// the frame it was taken on.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), MovieError> {
+        if movie.rom_hash != self.rom_hash {
+            return Err(MovieError::WrongGame);
         }
+        self.movie = None;
+        self.load_state(&movie.start_state)
+            .map_err(MovieError::StartState)?;
+        self.movie = Some(MovieSession::Playing { movie, frame: 0 });
+        Ok(())
     }
 
-    /// Returns the current program counter of the CPU
-    pub fn get_pc(&self) -> u16 {
-        self.cpu.reg.pc
+    /// Stops recording or playing a movie, returning it. Input is left with the host.
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        match self.movie.take()? {
+            MovieSession::Recording { movie, .. } | MovieSession::Playing { movie, .. } => {
+                Some(movie)
+            }
+        }
     }
 
-    /// Returns a boxed slice of u8 values contained within the given range of usize values.
-    /// Only returns values as read via the CPU, so forbidden or fixed reads will not be bypassed
-    pub fn get_memory_range(&self, range: core::ops::Range<usize>) -> Box<[u8]> {
-        self.mmu.get_memory_range(range).into_boxed_slice()
+    /// Whether a movie is being recorded or played, and how far through it is
+    pub fn movie_status(&self) -> MovieStatus {
+        match &self.movie {
+            None => MovieStatus::Inactive,
+            Some(MovieSession::Recording { movie, .. }) => {
+                MovieStatus::Recording { frame: movie.len() }
+            }
+            Some(MovieSession::Playing { movie, frame }) => MovieStatus::Playing {
+                frame: *frame,
+                length: movie.len(),
+            },
+        }
+    }
+
+    /// Records or plays the next frame of the movie, at the start of V-Blank
+    fn advance_movie(&mut self) {
+        match &mut self.movie {
+            Some(MovieSession::Recording { movie, keys }) => {
+                movie.frames.push(*keys);
+                self.mmu.joypad.set_held_keys(*keys);
+            }
+            Some(MovieSession::Playing { movie, frame }) => {
+                if let Some(&keys) = movie.frames.get(*frame) {
+                    self.mmu.joypad.set_held_keys(keys);
+                    *frame += 1;
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Moves the movie to the frame a newly loaded state was taken on. A recording is cut
+    /// short there, or padded with released buttons for a state taken after its end.
+    fn seek_movie(&mut self) {
+        match &mut self.movie {
+            Some(MovieSession::Recording { movie, keys }) => {
+                let frame = self.frame_count.saturating_sub(movie.start_frame) as usize;
+                movie.frames.resize(frame, 0);
+                movie.rerecords += 1;
+                *keys = self.mmu.joypad.held_keys();
+            }
+            Some(MovieSession::Playing { movie, frame }) => {
+                let seeked = self.frame_count.saturating_sub(movie.start_frame) as usize;
+                *frame = seeked.min(movie.len());
+            }
+            None => {}
+        }
+    }
+
+    /// Keeps a state from each of the last `frames` frames for `rewind`, discarding any kept
+    /// so far. 0 stops keeping them, which is the default.
+    pub fn set_rewind_frames(&mut self, frames: usize) {
+        self.rewind = RewindBuffer::new(frames);
+    }
+
+    /// Number of frames that can currently be rewound
+    pub fn rewind_frames_available(&self) -> usize {
+        self.rewind.len()
+    }
+
+    /// Steps back through recent play, restoring the state from the start of the V-Blank
+    /// `frames` frames ago. Stops at the oldest state kept if there are fewer than that.
+    /// Returns the number of frames rewound.
+    pub fn rewind(&mut self, frames: usize) -> usize {
+        if frames == 0 {
+            return 0;
+        }
+        let rewound = frames.min(self.rewind.len());
+        if let Some(state) = self.rewind.pop(frames) {
+            self.load_state(&state)
+                .expect("Loading a state taken from the running game");
+        }
+        rewound
+    }
+
+    /// Sets the watchdog, which expires once `max_cycles` cycles pass without the PPU entering
+    /// V-Blank, such as when a ROM spins forever with the LCD off. `None` disables it.
+    /// Run helpers stop once it expires, and `watchdog_expired` reports it for custom loops.
+    pub fn set_watchdog(&mut self, max_cycles: Option<u64>) {
+        self.watchdog_limit = max_cycles;
+        self.cycles_since_vblank = 0;
+    }
+
+    /// Returns true if the watchdog is set and its cycle limit has passed without a V-Blank
+    pub fn watchdog_expired(&self) -> bool {
+        self.watchdog_limit
+            .map_or(false, |limit| self.cycles_since_vblank >= limit)
+    }
+
+    /// Steps the emulator until the byte at `addr` reads as `value`, discarding all video
+    /// and audio output. Returns the number of cycles consumed, or `Timeout` if `max_cycles`
+    /// elapse or the watchdog expires first. The condition is checked before each step, so a
+    /// match at the start returns 0.
+    pub fn run_until_mem(&mut self, addr: u16, value: u8, max_cycles: u64) -> Result<u64, Timeout> {
+        let mut video_sink = NullSink;
+        let mut audio_sink = NullSink;
+        let mut cycles: u64 = 0;
+        loop {
+            if self.mmu.read_byte(addr) == value {
+                return Ok(cycles);
+            }
+            if cycles >= max_cycles || self.watchdog_expired() {
+                return Err(Timeout);
+            }
+            cycles += self.step(&mut video_sink, &mut audio_sink) as u64;
+        }
+    }
+
+    /// Enables or disables emulation of the DMG OAM corruption bug, where INC/DEC of a
+    /// 16-bit register pointing into 0xFE00-0xFEFF during OAM scan corrupts OAM.
+    /// Disabled by default.
+    pub fn set_oam_bug_enabled(&mut self, enabled: bool) {
+        self.mmu.set_oam_bug_enabled(enabled);
+    }
+
+    /// Enables or disables the hardware limit of 10 sprites per scanline. Disabling it is an
+    /// inaccurate enhancement that draws every sprite, so games that flicker sprites to get
+    /// around the limit show them all. Enabled by default.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.mmu.set_sprite_limit(enabled);
+    }
+
+    /// Steps the emulator until LY moves on to the next scanline, including the wrap from
+    /// the last V-Blank line back to 0. Returns the number of cycles run, about 456.
+    /// While the LCD is off LY never changes, so this gives up after two scanlines' worth.
+    pub fn step_scanline(
+        &mut self,
+        video_sink: &mut dyn Sink<VideoFrame>,
+        audio_sink: &mut dyn Sink<AudioFrame>,
+    ) -> u32 {
+        let start = self.current_scanline();
+        let mut cycles = 0;
+        while self.current_scanline() == start && cycles < SCANLINE_CYCLES * 2 {
+            cycles += self.step(video_sink, audio_sink);
+        }
+        cycles
+    }
+
+    /// Runs `frames` frames as fast as possible, for fast-forwarding. With `skip_video`, only
+    /// the last frame is sent to `video_sink`. Audio is thinned out to a single frame's worth
+    /// by keeping one in every `frames` samples, so it keeps pace with real time at a higher
+    /// pitch instead of piling up. No frames come while the LCD is off, so this gives up after
+    /// `frames` frames' worth of cycles. Returns the number of cycles run.
+    pub fn emulate_frames(
+        &mut self,
+        frames: u32,
+        skip_video: bool,
+        video_sink: &mut dyn Sink<VideoFrame>,
+        audio_sink: &mut dyn Sink<AudioFrame>,
+    ) -> u64 {
+        let mut video_sink = SkippingVideoSink {
+            inner: video_sink,
+            remaining: frames,
+            skip: skip_video,
+        };
+        let mut audio_sink = DecimatingAudioSink {
+            inner: audio_sink,
+            every: frames.max(1),
+            count: 0,
+        };
+        let limit = frames as u64 * FRAME_CYCLES as u64;
+        let mut cycles = 0;
+        while video_sink.remaining > 0 && cycles < limit {
+            cycles += self.step(&mut video_sink, &mut audio_sink) as u64;
+        }
+        cycles
+    }
+
+    /// Steps the emulator until it produces a frame that isn't a single solid color, for
+    /// capturing thumbnails past the blank startup screen. Audio is discarded. Returns the
+    /// frame, or `None` if `max_frames` frames complete without drawing anything.
+    pub fn run_to_first_frame(&mut self, max_frames: u32) -> Option<VideoFrame> {
+        let mut video_sink = LatestFrameSink(None);
+        let mut audio_sink = NullSink;
+        let mut frames = 0;
+        while frames < max_frames {
+            self.step(&mut video_sink, &mut audio_sink);
+            if let Some(frame) = video_sink.0.take() {
+                frames += 1;
+                // Compare whole RGB pixels against the first one
+                if frame.chunks(3).any(|p| p != &frame[0..3]) {
+                    return Some(frame);
+                }
+            }
+        }
+        None
+    }
+
+    /// Sets the rate in Hz that audio samples are generated at. Defaults to `SAMPLE_RATE`,
+    /// but any rate up to `CLOCK_RATE` can be used to match the host's audio device. Rates
+    /// outside that are clamped to it, with 0 treated as 1.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.mmu.set_sample_rate(sample_rate);
+    }
+
+    /// Stops or resumes generating audio. While stopped no audio frames are sent to the
+    /// audio sink and no time is spent mixing them, but the APU keeps running, so games
+    /// behave the same either way.
+    pub fn set_audio_output(&mut self, enabled: bool) {
+        self.mmu.set_sample_output(enabled);
+    }
+
+    /// Mutes or unmutes a sound channel, 0-3 for channels 1-4, in both the mixed and
+    /// per-channel audio. Muted channels keep running, so games behave the same either way.
+    /// All channels are enabled by default, and the setting isn't part of save states.
+    /// Channel numbers other than 0-3 are ignored.
+    pub fn set_channel_enabled(&mut self, channel: usize, enabled: bool) {
+        self.mmu.set_channel_enabled(channel, enabled);
+    }
+
+    /// Whether a sound channel, 0-3 for channels 1-4, is in the audio output. False for
+    /// channel numbers other than 0-3.
+    pub fn channel_enabled(&self, channel: usize) -> bool {
+        self.mmu.channel_enabled(channel)
+    }
+
+    /// Takes a snapshot of the APU registers, channel states and wave RAM, for showing
+    /// alongside the audio. Cheaper than `get_debug_state` when only the APU is wanted.
+    pub fn apu_debug_state(&self) -> apu::ApuDebug {
+        self.mmu.apu_debug_state()
+    }
+
+    pub fn update_key_state(&mut self, key: GbKeys, pressed: bool) {
+        match &mut self.movie {
+            Some(MovieSession::Recording { keys, .. }) => {
+                let bit = 1 << key as u8;
+                if pressed {
+                    *keys |= bit;
+                } else {
+                    *keys &= !bit;
+                }
+            }
+            Some(MovieSession::Playing { movie, frame }) if *frame < movie.len() => {}
+            _ => self.mmu.joypad.set_key_pressed(key, pressed),
+        }
+    }
+
+    /// The buttons the game currently sees held down, one bit per button indexed by `GbKeys`.
+    /// Useful for showing the input on screen.
+    pub fn pressed_keys(&self) -> u8 {
+        self.mmu.joypad.pressed_keys()
+    }
+
+    /// Debugging aid that stops a subsystem from being stepped while the rest of the system,
+    /// including the CPU, keeps running. Freezing one subsystem at a time helps rule it out as
+    /// the source of a bug. A frozen subsystem's registers can still be read and written.
+    /// Freezing the PPU stops V-Blanks, so it will also trip the watchdog if one is set.
+    pub fn freeze_subsystem(&mut self, subsystem: Subsystem, frozen: bool) {
+        self.mmu.freeze_subsystem(subsystem, frozen);
+    }
+
+    /// The timer's internal 16-bit counter, whose upper byte is DIV. Its lower bits decide when
+    /// DIV and TIMA next increment, so saving it along with the registers is needed to
+    /// restore the timer exactly.
+    pub fn timer_counter(&self) -> u16 {
+        self.mmu.timer_counter()
+    }
+
+    /// Restores the timer's internal counter, as returned by `timer_counter`. Unlike writing
+    /// DIV, this has no side effects on TIMA.
+    pub fn set_timer_counter(&mut self, counter: u16) {
+        self.mmu.set_timer_counter(counter);
+    }
+
+    /// Whether the loaded ROM makes use of CGB features, for frontends to warn about
+    /// CGB-only games
+    pub fn cgb_support(&self) -> CgbSupport {
+        self.mmu.cgb_support
+    }
+
+    /// The game's cartridge header, as read at power on
+    pub fn cartridge_header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+
+    /// Whether the cartridge has a battery, going by its type in the header. Only these
+    /// cartridges keep their RAM while off, so frontends only need save files for them.
+    pub fn has_battery(&self) -> bool {
+        self.mmu.has_battery
+    }
+
+    /// Returns the contents of battery-backed cartridge RAM, or `None` if the cartridge has
+    /// none. Performs no I/O, so frontends can store it wherever suits them.
+    pub fn extract_save_data(&self) -> Option<Vec<u8>> {
+        if !self.mmu.has_battery {
+            return None;
+        }
+        self.mmu.cart.write_save_data().ok().map(Vec::from)
+    }
+
+    #[deprecated(note = "use `extract_save_data` instead")]
+    pub fn get_save_data(&self) -> Option<Box<[u8]>> {
+        self.extract_save_data().map(Vec::into_boxed_slice)
+    }
+
+    /// Replaces the contents of battery-backed cartridge RAM with save data, as returned by
+    /// `extract_save_data`. RAM is left untouched if the data is the wrong size, or if the
+    /// cartridge has no battery.
+    pub fn insert_save_data(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        if !self.mmu.has_battery {
+            return Err(CartridgeError::NotBatteryBacked);
+        }
+        self.mmu.cart.read_save_data(data.into())?;
+        self.mmu.save_dirty = true;
+        Ok(())
+    }
+
+    /// Sets where battery-backed RAM is written back to, replacing and returning any storage
+    /// already set. `None` leaves saving to the frontend, with `extract_save_data`. Nothing
+    /// is stored for cartridges without a battery.
+    pub fn set_save_storage(
+        &mut self,
+        storage: Option<Box<dyn SaveStorage>>,
+    ) -> Option<Box<dyn SaveStorage>> {
+        self.flush_save_data();
+        core::mem::replace(&mut self.save_storage, storage)
+    }
+
+    /// Writes battery-backed RAM to the save storage, if it's changed since it was last
+    /// written. Happens by itself at the start of V-Blank at most every `SAVE_FLUSH_FRAMES`
+    /// frames while the game writes to it, and when the `Gameboy` is dropped.
+    pub fn flush_save_data(&mut self) {
+        if !self.mmu.save_dirty {
+            return;
+        }
+        if let Some(storage) = &mut self.save_storage {
+            if let Ok(data) = self.mmu.cart.write_save_data() {
+                storage.store(&data);
+            }
+            self.mmu.save_dirty = false;
+            self.last_save_flush = self.frame_count;
+        }
+    }
+
+    /// Tells the cartridge's real time clock, if it has one, the host's time in seconds since
+    /// the Unix epoch. The clock runs with emulation, and save data keeps the time it was last
+    /// synced plus the seconds emulated since, so the first sync after loading it catches up
+    /// on just the time the emulator was closed. Call this after loading save data and before `extract_save_data`.
+    /// Ignored when built with `GameboyBuilder::deterministic`, so the clock only runs with
+    /// emulation.
+    pub fn sync_rtc(&mut self, now: u64) {
+        if self.deterministic {
+            return;
+        }
+        // The time synced is part of the save data, so it needs storing when it changes
+        if self.mmu.cart.sync_rtc(now) {
+            self.mmu.save_dirty = true;
+        }
+    }
+
+    /// Returns the byte last sent over the serial port using the internal clock, once each.
+    /// Unless a peer is connected with `connect_serial`, the transfer itself completes
+    /// receiving 0xFF.
+    pub fn poll_serial(&mut self) -> Option<u8> {
+        self.mmu.take_serial_output()
+    }
+
+    /// Connects the link cable to a peer, or disconnects it with `None`
+    pub fn connect_serial(&mut self, link: Option<Box<dyn SerialLink>>) {
+        self.mmu.connect_serial(link);
+    }
+
+    /// Lengthens transfers this side clocks by `cycles`, so the peer's reply has time to
+    /// arrive over a link with that much latency. Both ends see the same transfer timing as
+    /// long as the reply arrives in time. 0 by default, for links without latency.
+    pub fn set_serial_latency(&mut self, cycles: u32) {
+        self.mmu.set_serial_latency(cycles);
+    }
+
+    /// Holds transfers this side clocks until the peer's reply arrives, however late, so
+    /// neither side races ahead of the other. Disabled by default, so a missing peer reads
+    /// as 0xFF like on hardware.
+    pub fn set_serial_wait_for_peer(&mut self, wait: bool) {
+        self.mmu.set_serial_wait_for_peer(wait);
+    }
+
+    pub fn get_debug_state(&self) -> GbDebug {
+        GbDebug {
+            cpu_data: self.cpu.get_debug_data(),
+            apu_data: self.mmu.apu_debug_state(),
+            if_data: self.mmu.read_byte(0xFF0F),
+            ie_data: self.mmu.read_byte(0xFFFF),
+            vram_lcdc: self.mmu.read_byte(0xFF40),
+            vram_stat: self.mmu.read_byte(0xFF41),
+            vram_ly: self.mmu.read_byte(0xFF44),
+        }
+    }
+
+    /// Keeps a history of the last `depth` executed instructions for post-mortem debugging,
+    /// or disables it with None. Disabled by default, as it costs a little on every instruction.
+    pub fn set_trace_depth(&mut self, depth: Option<usize>) {
+        self.cpu.set_trace_depth(depth);
+    }
+
+    /// Returns the (PC, opcode) of the most recently executed instructions, oldest first.
+    /// CB-prefixed instructions are recorded with the 0xCB prefix as their opcode.
+    /// Empty unless tracing was enabled with `set_trace_depth`.
+    pub fn recent_trace(&self) -> Vec<(u16, u8)> {
+        self.cpu.recent_trace()
+    }
+
+    /// Returns what led up to the CPU locking up on an illegal opcode, if it has.
+    /// The system keeps running with the CPU hung, as on hardware.
+    pub fn last_crash(&self) -> Option<CrashReport> {
+        self.cpu.lockup.map(|(pc, opcode)| CrashReport {
+            pc,
+            opcode,
+            trace: self.recent_trace(),
+            state: self.crash_state.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Logs illegal opcodes and executes them as NOPs instead of locking up, for debugging
+    /// homebrew. Disabled by default, as hardware locks up.
+    pub fn set_skip_illegal_opcodes(&mut self, enabled: bool) {
+        self.cpu.skip_illegal_opcodes = enabled;
+    }
+
+    /// Records every write to `range` along with the instruction that made it, to find what
+    /// code touches a variable, or stops recording with `None`. Unlike a breakpoint nothing
+    /// halts, the writes are collected with `take_write_log`. Any uncollected writes are
+    /// discarded, and only the latest `MAX_WRITE_LOG` are kept.
+    pub fn log_writes(&mut self, range: Option<RangeInclusive<u16>>) {
+        self.mmu.log_writes(range);
+    }
+
+    /// Returns the writes recorded since the last call, oldest first
+    pub fn take_write_log(&mut self) -> Vec<WriteRecord> {
+        self.mmu.take_write_log()
+    }
+
+    /// Installs callbacks for accesses to memory by the CPU and the start of each frame,
+    /// replacing and returning any already installed. `None` removes them.
+    pub fn set_hooks(&mut self, hooks: Option<Box<dyn Hooks>>) -> Option<Box<dyn Hooks>> {
+        self.mmu.hooks.replace(hooks)
+    }
+
+    /// Adds a Game Genie or GameShark cheat code, enabled, returning its index in `cheats`.
+    /// Game Genie codes change what's read from the ROM, and GameShark codes write to RAM at
+    /// the start of each V-Blank. Cheats aren't part of save states.
+    pub fn add_cheat(&mut self, code: &str) -> Result<usize, CheatError> {
+        let cheat = Cheat {
+            text: code.into(),
+            code: CheatCode::parse(code)?,
+            enabled: true,
+        };
+        self.mmu.cheats.push(cheat);
+        Ok(self.mmu.cheats.len() - 1)
+    }
+
+    /// Removes the cheat at `index` in `cheats`, returning it
+    pub fn remove_cheat(&mut self, index: usize) -> Option<Cheat> {
+        (index < self.mmu.cheats.len()).then(|| self.mmu.cheats.remove(index))
+    }
+
+    /// Turns the cheat at `index` in `cheats` on or off, returning false if there isn't one
+    pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.mmu.cheats.get_mut(index) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The cheat codes added, in the order they were added
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.mmu.cheats
+    }
+
+    /// Traps reads and/or writes to `range` by the CPU, to find what code touches a variable.
+    /// The instruction making the access still completes, then `take_watch_hit` reports it.
+    /// Accesses by DMA, debuggers and the MMU itself are ignored.
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) {
+        self.mmu.add_watchpoint(range, kind);
+    }
+
+    /// Removes a watchpoint added with the same range and kind, returning whether there was one
+    pub fn remove_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) -> bool {
+        self.mmu.remove_watchpoint(range, kind)
+    }
+
+    /// Returns the first watchpoint hit since the last call, if any. Check it after each
+    /// step to stop at the instruction responsible.
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.mmu.take_watch_hit()
+    }
+
+    /// Returns every watchpoint hit since the last call, oldest first, for debuggers that
+    /// filter hits further. Only the first few are kept if they go untaken.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        self.mmu.take_watch_hits()
+    }
+
+    /// Writes a byte as the CPU would, with the same side effects, for debuggers to poke
+    /// at memory. Doesn't hit watchpoints.
+    pub fn write_memory(&mut self, addr: u16, val: u8) {
+        self.mmu.write_byte(addr, val);
+    }
+
+    /// The CPU's registers, for the debugger integrations within the crate
+    pub(crate) fn registers(&self) -> &cpu::Registers {
+        &self.cpu.reg
+    }
+
+    #[cfg(feature = "gdb")]
+    pub(crate) fn registers_mut(&mut self) -> &mut cpu::Registers {
+        &mut self.cpu.reg
+    }
+
+    /// Whether the CPU has locked up on an illegal opcode
+    #[cfg(feature = "gdb")]
+    pub(crate) fn locked_up(&self) -> bool {
+        self.cpu.lockup.is_some()
+    }
+
+    /// Returns the current program counter of the CPU
+    pub fn get_pc(&self) -> u16 {
+        self.cpu.reg.pc
+    }
+
+    /// Estimates the cycles until the next event that interrupts the game: the start of
+    /// V-Blank, a timer overflow, or a serial transfer completing. Frontends running their own
+    /// loop can step this many cycles before checking on the emulator again. Assumes the game
+    /// doesn't reconfigure the hardware in the meantime, as writing the timer registers in
+    /// particular can bring an overflow forward. Returns `None` if nothing is scheduled.
+    pub fn cycles_until_next_event(&self) -> Option<u32> {
+        self.mmu.cycles_until_next_event()
+    }
+
+    /// Returns the current PPU mode as reported in STAT: 0 for H-Blank, 1 for V-Blank,
+    /// 2 for OAM search, and 3 for drawing
+    pub fn ppu_mode(&self) -> u8 {
+        self.mmu.ppu_mode()
+    }
+
+    /// Returns the scanline the PPU is currently on, 0-153, as reported by LY.
+    /// Lines 144-153 are V-Blank.
+    pub fn current_scanline(&self) -> u8 {
+        self.mmu.current_scanline()
+    }
+
+    /// Summarizes the CPU's address space as it currently stands: each region's range, the
+    /// bank mapped into it, and whether it's accessible given the current PPU mode, OAM DMA,
+    /// and cartridge RAM enable.
+    pub fn memory_map(&self) -> Vec<MemoryRegion> {
+        self.mmu.memory_map()
+    }
+
+    /// Returns a snapshot of the IO registers at 0xFF00-0xFF7F, indexed by offset from 0xFF00.
+    /// Unused bits and unmapped registers read back as 1s, as they would to the CPU.
+    pub fn io_registers(&self) -> [u8; 0x80] {
+        self.mmu.io_registers()
+    }
+
+    /// Writes a single IO register, as if the CPU had written it, but without being blocked
+    /// by an active OAM DMA. Writes to addresses outside of 0xFF00-0xFF7F are ignored.
+    pub fn write_io_register(&mut self, addr: u16, val: u8) {
+        self.mmu.write_io_register(addr, val);
+    }
+
+    /// The screen as drawn so far, 160x144 pixels of 3 bytes each for red, green and blue.
+    /// Unlike frames sent to the video sink, this can be read at any time, such as while
+    /// paused. It holds the last completed frame during V-Blank, and mid-frame the lines above
+    /// the current scanline are already from the next.
+    pub fn frame_buffer(&self) -> &[u8] {
+        self.mmu.screen()
+    }
+
+    /// Returns a hash of the current screen contents, for comparing rendered output in tests.
+    /// The hash is stable across machines and builds.
+    pub fn frame_hash(&self) -> u64 {
+        fnv1a_64(self.mmu.screen())
+    }
+
+    /// Like `frame_hash`, but hashes each pixel's 2-bit color number from before the BGP,
+    /// OBP0 and OBP1 palettes were applied, so it doesn't change along with the palettes.
+    pub fn frame_index_hash(&self) -> u64 {
+        fnv1a_64(self.mmu.screen_indices())
+    }
+
+    /// Renders every tile in the given VRAM bank into a 128x192 RGB buffer, 16 tiles per row,
+    /// using the chosen palette. Only DMG mode is emulated, which has a single VRAM bank, so any
+    /// bank other than 0 returns `None`.
+    pub fn dump_tiles(&self, bank: u8, palette: TilePalette) -> Option<VideoFrame> {
+        if bank == 0 {
+            Some(self.mmu.dump_tiles(palette))
+        } else {
+            None
+        }
+    }
+
+    /// Renders one of the two 32x32 background tile maps into a 256x256 RGB buffer.
+    /// `map_select` false dumps the map at 0x9800-0x9BFF, true dumps the map at 0x9C00-0x9FFF.
+    pub fn dump_tilemap(&self, map_select: bool) -> VideoFrame {
+        self.mmu.dump_tilemap(map_select)
+    }
+
+    /// Borrows the raw contents of the given VRAM bank, 0x8000-0x9FFF, for external renderers.
+    /// Unlike `get_memory_range`, reads aren't blocked during mode 3. Only DMG mode is emulated,
+    /// which has a single VRAM bank, so any bank other than 0 returns `None`.
+    pub fn vram(&self, bank: u8) -> Option<&[u8]> {
+        (bank == 0).then(|| self.mmu.vram())
+    }
+
+    /// Borrows the raw contents of OAM, 0xFE00-0xFE9F, 4 bytes for each of the 40 OBJs.
+    /// Unlike `get_memory_range`, reads aren't blocked during OAM DMA or modes 2 and 3.
+    pub fn oam(&self) -> &[u8] {
+        self.mmu.oam()
+    }
+
+    /// Decodes the 40 OBJs in OAM, for sprite viewers
+    pub fn oam_entries(&self) -> Vec<OamEntry> {
+        self.mmu.oam_entries()
+    }
+
+    /// The color numbers 0-3 of the 8x8 pixels of tile `index` in VRAM, row by row, where
+    /// tile 0 is at 0x8000 and tile 383 the last at 0x97F0. Returns `None` past tile 383.
+    pub fn tile_pixels(&self, index: usize) -> Option<[u8; 64]> {
+        (index < 384).then(|| self.mmu.tile_pixels(index))
+    }
+
+    /// Where the background, window and OBJs are currently drawn from, for tile map viewers
+    pub fn background_layout(&self) -> BackgroundLayout {
+        self.mmu.background_layout()
+    }
+
+    /// Returns a boxed slice of u8 values contained within the given range of usize values.
+    /// Only returns values as read via the CPU, so forbidden or fixed reads will not be bypassed
+    pub fn get_memory_range(&self, range: core::ops::Range<usize>) -> Box<[u8]> {
+        self.mmu.get_memory_range(range).into_boxed_slice()
+    }
+}
+
+impl Drop for Gameboy {
+    /// Writes back any changes to battery-backed RAM, so closing the game doesn't lose them
+    fn drop(&mut self) {
+        self.flush_save_data();
+    }
+}
+
+#[cfg(test)]
+mod gb_tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Builds a 32 KiB ROM-only cartridge with `program` placed at the entry point
+    fn test_rom(program: &[u8]) -> Box<[u8]> {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        rom.into_boxed_slice()
+    }
+
+    /// Counts the frames appended to it, of whichever kind
+    struct Count(usize);
+
+    impl Sink<VideoFrame> for Count {
+        fn append(&mut self, _value: VideoFrame) {
+            self.0 += 1;
+        }
+    }
+
+    impl Sink<AudioFrame> for Count {
+        fn append(&mut self, _value: AudioFrame) {
+            self.0 += 1;
+        }
+    }
+
+    impl Sink<ChannelAudioFrame> for Count {
+        fn append(&mut self, _value: ChannelAudioFrame) {
+            self.0 += 1;
+        }
+    }
+
+    #[test]
+    fn cgb_support_flag() {
+        let support = |flag: u8| {
+            let mut rom = test_rom(&[]);
+            rom[0x143] = flag;
+            Gameboy::power_on(rom, None).unwrap().cgb_support()
+        };
+        assert_eq!(support(0x00), CgbSupport::None);
+        // Last character of a DMG game's title
+        assert_eq!(support(b'X'), CgbSupport::None);
+        assert_eq!(support(0x80), CgbSupport::Enhanced);
+        assert_eq!(support(0xC0), CgbSupport::Only);
+    }
+
+    #[test]
+    fn reject_cgb_only() {
+        let build = |flag: u8| {
+            let mut rom = test_rom(&[]);
+            rom[0x143] = flag;
+            GameboyBuilder::new(rom).reject_cgb_only().build().err()
+        };
+        assert_eq!(build(0xC0), Some(GameboyError::RequiresCgb));
+        assert_eq!(build(0x80), None);
+        assert_eq!(build(0x00), None);
+
+        // Run anyway by default
+        let mut rom = test_rom(&[]);
+        rom[0x143] = 0xC0;
+        assert!(GameboyBuilder::new(rom).build().is_ok());
+    }
+
+    #[test]
+    fn palette_independent_hash() {
+        let run = |bgp: u8| {
+            // LD A, bgp; LDH ($47), A; JR -2
+            let mut gb =
+                Gameboy::power_on(test_rom(&[0x3E, bgp, 0xE0, 0x47, 0x18, 0xFE]), None).unwrap();
+            let mut video_sink = LatestFrameSink(None);
+            while video_sink.0.is_none() {
+                gb.step(&mut video_sink, &mut NullSink);
+            }
+            (gb.frame_hash(), gb.frame_index_hash())
+        };
+        let (hash, index_hash) = run(0xFC);
+        let (inverted_hash, inverted_index_hash) = run(0x03);
+        assert_ne!(hash, inverted_hash);
+        assert_eq!(index_hash, inverted_index_hash);
+    }
+
+    #[test]
+    fn illegal_opcode_crash() {
+        // NOP; XOR A; illegal 0xD3
+        let mut gb = GameboyBuilder::new(test_rom(&[0x00, 0xAF, 0xD3, 0x00]))
+            .trace(4)
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            gb.step(&mut NullSink, &mut NullSink);
+        }
+        assert_eq!(gb.last_crash(), None);
+        gb.step(&mut NullSink, &mut NullSink);
+        let report = gb.last_crash().unwrap();
+        assert_eq!((report.pc, report.opcode), (0x102, 0xD3));
+        assert_eq!(report.trace, [(0x100, 0x00), (0x101, 0xAF), (0x102, 0xD3)]);
+
+        // Hung for good, while the rest of the system runs on
+        let ly = gb.current_scanline();
+        for _ in 0..1000 {
+            assert_eq!(gb.step(&mut NullSink, &mut NullSink), 4);
+        }
+        assert_eq!(gb.get_pc(), 0x103);
+        assert_ne!(gb.current_scanline(), ly);
+        assert_eq!(gb.last_crash(), Some(report.clone()));
+
+        // The state from the moment of the crash can be restored for debugging
+        let mut restored = Gameboy::power_on(test_rom(&[0x00, 0xAF, 0xD3, 0x00]), None).unwrap();
+        restored.load_state(&report.state).unwrap();
+        assert_eq!(restored.get_pc(), 0x103);
+        assert_eq!(restored.current_scanline(), ly);
+        assert_eq!(restored.last_crash().unwrap().state, report.state);
+    }
+
+    #[test]
+    fn skip_illegal_opcodes() {
+        // XOR A; illegal 0xD3; INC A; illegal 0xFC; INC A
+        let mut gb = GameboyBuilder::new(test_rom(&[0xAF, 0xD3, 0x3C, 0xFC, 0x3C]))
+            .skip_illegal_opcodes()
+            .build()
+            .unwrap();
+        gb.step(&mut NullSink, &mut NullSink);
+        assert_eq!(gb.step(&mut NullSink, &mut NullSink), 4);
+        assert_eq!(gb.get_pc(), 0x102);
+        for _ in 0..3 {
+            gb.step(&mut NullSink, &mut NullSink);
+        }
+        assert_eq!(gb.get_pc(), 0x105);
+        assert_eq!(gb.get_debug_state().cpu_data.reg.a, 2);
+        assert_eq!(gb.last_crash(), None);
+    }
+
+    #[test]
+    fn write_log() {
+        // LD HL, $C100; LD (HL), $12; INC HL; LD (HL), $34; LD ($C0FF), A; LD ($C102), A
+        let program = [
+            0x21, 0x00, 0xC1, 0x36, 0x12, 0x23, 0x36, 0x34, 0xEA, 0xFF, 0xC0, 0xEA, 0x02, 0xC1,
+        ];
+        let mut gb = Gameboy::power_on(test_rom(&program), None).unwrap();
+        gb.log_writes(Some(0xC100..=0xC101));
+        for _ in 0..6 {
+            gb.step(&mut NullSink, &mut NullSink);
+        }
+        assert_eq!(
+            gb.take_write_log(),
+            [
+                WriteRecord {
+                    pc: 0x103,
+                    addr: 0xC100,
+                    value: 0x12
+                },
+                WriteRecord {
+                    pc: 0x106,
+                    addr: 0xC101,
+                    value: 0x34
+                },
+            ]
+        );
+        assert!(gb.take_write_log().is_empty());
+    }
+
+    #[test]
+    fn save_data_round_trip() {
+        // LD A, $0A; LD ($0000), A; LD A, $5A; LD ($A123), A; JR -2
+        let program = [
+            0x3E, 0x0A, 0xEA, 0x00, 0x00, 0x3E, 0x5A, 0xEA, 0x23, 0xA1, 0x18, 0xFE,
+        ];
+        let mut rom = test_rom(&program);
+        // MBC1+RAM+BATTERY, 8 KiB RAM
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let mut gb = Gameboy::power_on(rom.clone(), None).unwrap();
+        gb.run_until_mem(0xA123, 0x5A, 1000).unwrap();
+        let data = gb.extract_save_data().unwrap();
+        assert_eq!(data.len(), 0x2000);
+        assert_eq!(data[0x123], 0x5A);
+
+        let mut fresh = Gameboy::power_on(rom, None).unwrap();
+        assert_ne!(fresh.extract_save_data().unwrap(), data);
+        fresh.insert_save_data(&data).unwrap();
+        assert_eq!(fresh.extract_save_data().unwrap(), data);
+        assert!(matches!(
+            fresh.insert_save_data(&data[..0x1000]),
+            Err(CartridgeError::SizeMismatch { .. })
+        ));
+        assert_eq!(fresh.extract_save_data().unwrap(), data);
+
+        let mut no_ram = Gameboy::power_on(test_rom(&program), None).unwrap();
+        assert_eq!(no_ram.extract_save_data(), None);
+        assert!(no_ram.insert_save_data(&data).is_err());
+
+        // RAM without a battery isn't save data
+        let mut rom = test_rom(&program);
+        rom[0x147] = 0x02;
+        rom[0x149] = 0x02;
+        let mut no_battery = Gameboy::power_on(rom, None).unwrap();
+        assert_eq!(no_battery.extract_save_data(), None);
+        assert_eq!(
+            no_battery.insert_save_data(&data),
+            Err(CartridgeError::NotBatteryBacked)
+        );
+    }
+
+    #[test]
+    fn save_storage() {
+        use crate::storage::MemoryStorage;
+
+        // LD A, $0A; LD ($0000), A; LD A, $5A; LD ($A123), A; JR -2
+        let program = [
+            0x3E, 0x0A, 0xEA, 0x00, 0x00, 0x3E, 0x5A, 0xEA, 0x23, 0xA1, 0x18, 0xFE,
+        ];
+        let mut rom = test_rom(&program);
+        // MBC1+RAM+BATTERY, 8 KiB RAM
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let storage = MemoryStorage::new(None);
+        let mut gb = GameboyBuilder::new(rom.clone())
+            .save_storage(Box::new(storage.clone()))
+            .build()
+            .unwrap();
+        gb.emulate_frames(1, true, &mut NullSink, &mut NullSink);
+        assert_eq!(storage.data(), None);
+        // Stored once the game's been writing for a while
+        gb.emulate_frames(SAVE_FLUSH_FRAMES as u32, true, &mut NullSink, &mut NullSink);
+        assert_eq!(storage.data().unwrap()[0x123], 0x5A);
+
+        // And as it's dropped
+        gb.write_memory(0xA124, 0x07);
+        drop(gb);
+        let data = storage.data().unwrap();
+        assert_eq!((data[0x123], data[0x124]), (0x5A, 0x07));
+
+        // Loaded on power on
+        let gb = GameboyBuilder::new(rom.clone())
+            .save_storage(Box::new(storage.clone()))
+            .build()
+            .unwrap();
+        assert_eq!(gb.extract_save_data().unwrap(), data);
+        drop(gb);
+        let result = GameboyBuilder::new(rom.clone())
+            .save_storage(Box::new(storage.clone()))
+            .deterministic()
+            .build();
+        assert_eq!(result.err(), Some(GameboyError::DeterministicSaveData));
+        assert_eq!(storage.data().unwrap(), data);
+
+        // Save data that doesn't fit is never written back over
+        let storage = MemoryStorage::new(Some(data[..0x1000].to_vec()));
+        let result = GameboyBuilder::new(rom)
+            .save_storage(Box::new(storage.clone()))
+            .build();
+        assert!(matches!(
+            result.err(),
+            Some(GameboyError::SaveData(CartridgeError::SizeMismatch { .. }))
+        ));
+        assert_eq!(storage.data().unwrap(), &data[..0x1000]);
+    }
+
+    #[test]
+    fn sync_rtc_storage() {
+        use crate::storage::MemoryStorage;
+
+        let build = |cart_type: u8, storage: &MemoryStorage| {
+            // JR -2
+            let mut rom = test_rom(&[0x18, 0xFE]);
+            rom[0x147] = cart_type;
+            rom[0x149] = 0x02;
+            GameboyBuilder::new(rom)
+                .save_storage(Box::new(storage.clone()))
+                .build()
+                .unwrap()
+        };
+        // MBC1+RAM+BATTERY has no clock, so syncing leaves its save alone
+        let storage = MemoryStorage::new(None);
+        let mut gb = build(0x03, &storage);
+        gb.sync_rtc(1_000_000);
+        drop(gb);
+        assert_eq!(storage.data(), None);
+
+        // MBC3+TIMER+RAM+BATTERY stores the time synced, but only when it changes
+        let storage = MemoryStorage::new(None);
+        let mut gb = build(0x10, &storage);
+        gb.sync_rtc(1_000_000);
+        gb.flush_save_data();
+        assert!(storage.data().is_some());
+        let unchanged = MemoryStorage::new(None);
+        gb.set_save_storage(Some(Box::new(unchanged.clone())));
+        gb.sync_rtc(1_000_000);
+        drop(gb);
+        assert_eq!(unchanged.data(), None);
+
+        // Saves made between syncs store the time the clock is correct for, so reloading
+        // doesn't count the seconds emulated since the last sync twice
+        let storage = MemoryStorage::new(None);
+        let mut gb = build(0x10, &storage);
+        gb.sync_rtc(1_000_000);
+        // Three seconds, flushed a second in by itself
+        gb.emulate_frames(180, true, &mut NullSink, &mut NullSink);
+        gb.write_memory(0x0000, 0x0A);
+        gb.write_memory(0xA000, 0x12);
+        // Then again as it's dropped, without syncing
+        drop(gb);
+        let mut gb = build(0x10, &storage);
+        gb.sync_rtc(1_000_010);
+        gb.write_memory(0x0000, 0x0A);
+        gb.write_memory(0x4000, 0x08);
+        gb.write_memory(0x6000, 0x00);
+        gb.write_memory(0x6000, 0x01);
+        assert_eq!(gb.get_memory_range(0xA000..0xA001)[0], 10);
+
+        // Deterministic runs keep the clock to emulated time
+        let mut rom = test_rom(&[0x18, 0xFE]);
+        rom[0x147] = 0x10;
+        rom[0x149] = 0x02;
+        let mut gb = GameboyBuilder::new(rom).deterministic().build().unwrap();
+        let data = gb.extract_save_data().unwrap();
+        gb.sync_rtc(1_000_000);
+        assert_eq!(gb.extract_save_data().unwrap(), data);
+    }
+
+    /// Enables the timer and a sound channel, then counts up in WRAM forever
+    const BUSY_PROGRAM: [u8; 22] = [
+        0x3E, 0x05, 0xE0, 0x07, // LD A, $05; LDH ($07), A
+        0x3E, 0x80, 0xE0, 0x26, // LD A, $80; LDH ($26), A
+        0x3E, 0xF0, 0xE0, 0x12, // LD A, $F0; LDH ($12), A
+        0x3E, 0x87, 0xE0, 0x14, // LD A, $87; LDH ($14), A
+        0x3C, 0xEA, 0x00, 0xC0, 0x18, 0xFA, // INC A; LD ($C000), A; JR -6
+    ];
+
+    /// Keeps a hash of each frame
+    #[derive(Debug, Default, PartialEq)]
+    struct FrameHashes(Vec<u64>);
+
+    impl Sink<VideoFrame> for FrameHashes {
+        fn append(&mut self, value: VideoFrame) {
+            self.0.push(fnv1a_64(&value));
+        }
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut gb = Gameboy::power_on(test_rom(&BUSY_PROGRAM), None).unwrap();
+        let run = |gb: &mut Gameboy| {
+            let mut frames = FrameHashes::default();
+            for _ in 0..20_000 {
+                gb.step(&mut frames, &mut NullSink);
+            }
+            (frames, gb.save_state())
+        };
+        run(&mut gb);
+        let state = gb.save_state();
+        let (frames, end) = run(&mut gb);
+        assert!(!frames.0.is_empty());
+
+        gb.load_state(&state).unwrap();
+        assert_eq!(gb.save_state(), state);
+        assert_eq!(run(&mut gb), (frames, end.clone()));
+
+        // Into a fresh instance of the same game too
+        let mut fresh = Gameboy::power_on(test_rom(&BUSY_PROGRAM), None).unwrap();
+        fresh.load_state(&state).unwrap();
+        assert_eq!(run(&mut fresh).1, end);
+    }
+
+    #[test]
+    fn rewind() {
+        let mut gb = Gameboy::power_on(test_rom(&BUSY_PROGRAM), None).unwrap();
+        assert_eq!(gb.rewind(1), 0);
+        gb.set_rewind_frames(10);
+        // The state right after each V-Blank starts, which is what's kept
+        let mut states = vec![];
+        while states.len() < 15 {
+            gb.step(&mut NullSink, &mut NullSink);
+            if gb.cycles_since_vblank == 0 {
+                states.push(gb.save_state());
+            }
+        }
+        assert_eq!(gb.rewind_frames_available(), 10);
+
+        assert_eq!(gb.rewind(3), 3);
+        assert_eq!(gb.save_state(), states[12]);
+        assert_eq!(gb.rewind(1), 1);
+        assert_eq!(gb.save_state(), states[11]);
+        // Running on keeps the new frames
+        while gb.rewind_frames_available() == 6 {
+            gb.step(&mut NullSink, &mut NullSink);
+        }
+        assert_eq!(gb.rewind(1), 1);
+        assert_eq!(gb.save_state(), states[12]);
+        assert_eq!(gb.rewind(100), 6);
+        assert_eq!(gb.save_state(), states[5]);
+        assert_eq!(gb.rewind(1), 0);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut gb = Gameboy::power_on(test_rom(&BUSY_PROGRAM), None).unwrap();
+        gb.add_watchpoint(0xFF26..=0xFF26, WatchKind::ReadWrite);
+        gb.add_watchpoint(0xC000..=0xC001, WatchKind::Write);
+        gb.add_watchpoint(0xC000..=0xC000, WatchKind::Read);
+        let run_to_hit = |gb: &mut Gameboy| {
+            for _ in 0..100 {
+                gb.step(&mut NullSink, &mut NullSink);
+                if let Some(hit) = gb.take_watch_hit() {
+                    return hit;
+                }
+            }
+            panic!("No watchpoint hit");
+        };
+        let hit = run_to_hit(&mut gb);
+        assert_eq!(
+            hit,
+            WatchHit {
+                pc: 0x0106,
+                addr: 0xFF26,
+                value: 0x80,
+                write: true
+            }
+        );
+        // Stopped at the instruction after
+        assert_eq!(gb.get_pc(), 0x0108);
+        assert!(gb.remove_watchpoint(0xFF26..=0xFF26, WatchKind::ReadWrite));
+        assert!(!gb.remove_watchpoint(0xFF26..=0xFF26, WatchKind::ReadWrite));
+
+        let hit = run_to_hit(&mut gb);
+        assert_eq!((hit.pc, hit.addr, hit.value), (0x0111, 0xC000, 0x88));
+        // Debugger writes are ignored
+        gb.write_memory(0xC001, 0x12);
+        assert_eq!(gb.take_watch_hit(), None);
+        assert_eq!(gb.get_memory_range(0xC001..0xC002)[0], 0x12);
+    }
+
+    #[test]
+    fn hooks() {
+        use alloc::rc::Rc;
+        use core::cell::RefCell;
+
+        #[derive(Default)]
+        struct Log {
+            writes: Vec<(u16, u8)>,
+            reads: usize,
+            frames: usize,
+        }
+        struct Recorder(Rc<RefCell<Log>>);
+        impl Hooks for Recorder {
+            fn on_read(&mut self, _addr: u16, _value: u8) {
+                self.0.borrow_mut().reads += 1;
+            }
+            fn on_write(&mut self, addr: u16, value: u8) {
+                self.0.borrow_mut().writes.push((addr, value));
+            }
+            fn on_frame(&mut self) {
+                self.0.borrow_mut().frames += 1;
+            }
+        }
+
+        // LD A, $5A; LD ($C001), A; JR -2
+        let program = [0x3E, 0x5A, 0xEA, 0x01, 0xC0, 0x18, 0xFE];
+        let mut gb = Gameboy::power_on(test_rom(&program), None).unwrap();
+        let log = Rc::new(RefCell::new(Log::default()));
+        assert!(gb
+            .set_hooks(Some(Box::new(Recorder(log.clone()))))
+            .is_none());
+        for _ in 0..3 {
+            gb.step(&mut NullSink, &mut NullSink);
+        }
+        // Opcodes and operands
+        assert_eq!(log.borrow().reads, 2 + 3 + 2);
+        assert_eq!(log.borrow().writes, [(0xC001, 0x5A)]);
+        // Debugger accesses aren't the CPU's
+        gb.write_memory(0xC002, 1);
+        assert_eq!(log.borrow().writes.len(), 1);
+
+        gb.emulate_frames(2, false, &mut NullSink, &mut NullSink);
+        assert_eq!(log.borrow().frames, 2);
+        assert!(gb.set_hooks(None).is_some());
+    }
+
+    #[test]
+    fn cheats() {
+        // LD A, ($0150); LD ($C000), A; JR -8
+        let mut rom = test_rom(&[0xFA, 0x50, 0x01, 0xEA, 0x00, 0xC0, 0x18, 0xF8]);
+        rom[0x150] = 0x11;
+        let mut gb = Gameboy::power_on(rom, None).unwrap();
+        let run = |gb: &mut Gameboy| {
+            gb.emulate_frames(1, true, &mut NullSink, &mut NullSink);
+            gb.get_memory_range(0xC000..0xC011)
+        };
+        assert_eq!(run(&mut gb)[0], 0x11);
+
+        // 22 at 0150 where the ROM holds 11, and 77 written to C010
+        assert_eq!(gb.add_cheat("221-50F-A0E"), Ok(0));
+        assert_eq!(gb.add_cheat("017710C0"), Ok(1));
+        assert!(gb.add_cheat("221-50F-A").is_err());
+        let ram = run(&mut gb);
+        assert_eq!((ram[0], ram[0x10]), (0x22, 0x77));
+
+        assert!(gb.set_cheat_enabled(0, false));
+        assert!(!gb.set_cheat_enabled(2, false));
+        assert_eq!(run(&mut gb)[0], 0x11);
+        // Compares against a value other than 11, so has no effect
+        assert_eq!(gb.add_cheat("221-50F-A0F"), Ok(2));
+        assert_eq!(run(&mut gb)[0], 0x11);
+
+        assert_eq!(
+            gb.remove_cheat(2).map(|cheat| cheat.text),
+            Some("221-50F-A0F".into())
+        );
+        assert!(gb.remove_cheat(2).is_none());
+        assert_eq!(gb.cheats().len(), 2);
+        assert!(!gb.cheats()[0].enabled);
+    }
+
+    #[test]
+    fn frame_buffer() {
+        let mut gb = Gameboy::power_on(test_rom(&BUSY_PROGRAM), None).unwrap();
+        let mut video = LatestFrameSink(None);
+        gb.emulate_frames(2, false, &mut video, &mut NullSink);
+        let frame = video.0.unwrap();
+        assert_eq!(frame.len(), 160 * 144 * 3);
+        assert_eq!(gb.frame_buffer(), &frame[..]);
+    }
+
+    #[test]
+    fn movie() {
+        // Sums the action buttons read each loop into $C000:
+        // LD A, $10; LDH ($00), A; LDH A, ($00); ADD A, C; LD C, A; LD ($C000), A; JR -13
+        let program = [
+            0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0x81, 0x4F, 0xEA, 0x00, 0xC0, 0x18, 0xF3,
+        ];
+        let mut gb = Gameboy::power_on(test_rom(&program), None).unwrap();
+        gb.emulate_frames(3, false, &mut NullSink, &mut NullSink);
+
+        gb.start_movie_recording();
+        for frame in 0..30 {
+            gb.update_key_state(GbKeys::A, (5..10).contains(&frame));
+            gb.update_key_state(GbKeys::Start, (12..20).contains(&frame));
+            gb.emulate_frames(1, false, &mut NullSink, &mut NullSink);
+        }
+        assert_eq!(gb.movie_status(), MovieStatus::Recording { frame: 30 });
+        let recorded = gb.save_state();
+        let movie = gb.stop_movie().unwrap();
+        assert_eq!(movie.frames[4..7], [0, 0x10, 0x10]);
+        assert_eq!(movie.frames[19..21], [0x80, 0]);
+
+        // Played back from the reloaded file, host input is ignored
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        gb.play_movie(movie.clone()).unwrap();
+        for _ in 0..30 {
+            gb.update_key_state(GbKeys::B, true);
+            gb.emulate_frames(1, false, &mut NullSink, &mut NullSink);
+        }
+        assert_eq!(
+            gb.movie_status(),
+            MovieStatus::Playing {
+                frame: 30,
+                length: 30
+            }
+        );
+        assert_eq!(gb.save_state(), recorded);
+
+        // Loading a state while recording rolls the recording back to it
+        gb.play_movie(movie).unwrap();
+        gb.start_movie_recording();
+        gb.emulate_frames(10, false, &mut NullSink, &mut NullSink);
+        let state = gb.save_state();
+        gb.update_key_state(GbKeys::A, true);
+        gb.emulate_frames(5, false, &mut NullSink, &mut NullSink);
+        gb.load_state(&state).unwrap();
+        assert_eq!(gb.movie_status(), MovieStatus::Recording { frame: 10 });
+        let movie = gb.stop_movie().unwrap();
+        assert_eq!(movie.rerecords, 1);
+        assert!(movie.frames.iter().all(|&keys| keys == 0));
+
+        let mut other = Gameboy::power_on(test_rom(&BUSY_PROGRAM), None).unwrap();
+        assert_eq!(other.play_movie(movie), Err(MovieError::WrongGame));
+    }
+
+    #[test]
+    fn emulate_frames() {
+        let mut gb = Gameboy::power_on(test_rom(&BUSY_PROGRAM), None).unwrap();
+        // Line up with the start of a frame
+        gb.emulate_frames(1, false, &mut NullSink, &mut NullSink);
+
+        let (mut video, mut audio) = (Count(0), Count(0));
+        let cycles = gb.emulate_frames(4, true, &mut video, &mut audio);
+        assert!(cycles.abs_diff(4 * FRAME_CYCLES as u64) < 24);
+        assert_eq!(video.0, 1);
+        // One frame's worth of samples
+        let samples_per_frame =
+            FRAME_CYCLES as f64 * crate::SAMPLE_RATE as f64 / crate::CLOCK_RATE as f64;
+        assert!((audio.0 as f64 - samples_per_frame).abs() < 2.0);
+
+        let mut video = Count(0);
+        gb.emulate_frames(3, false, &mut video, &mut NullSink);
+        assert_eq!(video.0, 3);
+
+        // LD A, $00; LDH ($40), A; JR -2
+        let mut gb =
+            Gameboy::power_on(test_rom(&[0x3E, 0x00, 0xE0, 0x40, 0x18, 0xFE]), None).unwrap();
+        let mut video = Count(0);
+        let cycles = gb.emulate_frames(2, false, &mut video, &mut NullSink);
+        assert_eq!(video.0, 0);
+        assert!(cycles >= 2 * FRAME_CYCLES as u64 && cycles < 2 * FRAME_CYCLES as u64 + 24);
+    }
+
+    #[test]
+    fn boot_rom() {
+        let mut boot_rom = vec![0x00; BOOT_ROM_SIZE];
+        boot_rom[..10].copy_from_slice(&[
+            0x31, 0xFE, 0xFF, // LD SP, $FFFE
+            0x3E, 0x91, 0xE0, 0x40, // LD A, $91; LDH ($40), A
+            0xC3, 0xFC, 0x00, // JP $00FC
+        ]);
+        // Unmaps itself as its last instruction, running on into the game at 0x0100
+        boot_rom[0xFC..].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        let rom = test_rom(&[0x18, 0xFE]);
+        let mut gb = GameboyBuilder::new(rom.clone())
+            .boot_rom(boot_rom.into_boxed_slice())
+            .build()
+            .unwrap();
+        assert_eq!(gb.get_pc(), 0x0000);
+        assert_eq!(gb.io_registers()[0x40], 0x00);
+        assert_eq!(gb.io_registers()[0x04], 0x00);
+        assert_eq!(gb.get_memory_range(0..1)[0], 0x31);
+        for _ in 0..6 {
+            gb.step(&mut NullSink, &mut NullSink);
+        }
+        assert_eq!(gb.get_pc(), 0x0100);
+        assert_eq!(gb.io_registers()[0x40], 0x91);
+        assert_eq!(gb.get_memory_range(0..1)[0], rom[0]);
+
+        assert!(matches!(
+            GameboyBuilder::new(rom)
+                .boot_rom(vec![0; 0x900].into())
+                .build(),
+            Err(GameboyError::InvalidBootRom(0x900))
+        ));
+    }
+
+    #[test]
+    fn save_state_errors() {
+        let mut gb = Gameboy::power_on(test_rom(&BUSY_PROGRAM), None).unwrap();
+        for _ in 0..1000 {
+            gb.step(&mut NullSink, &mut NullSink);
+        }
+        let state = gb.save_state();
+        for _ in 0..1000 {
+            gb.step(&mut NullSink, &mut NullSink);
+        }
+        let before = gb.save_state();
+
+        assert_eq!(gb.load_state(b"GAB"), Err(StateError::NotAState));
+        let mut newer = state.clone();
+        newer[4..6].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            gb.load_state(&newer),
+            Err(StateError::UnsupportedVersion(STATE_VERSION + 1))
+        );
+        let mut other = Gameboy::power_on(test_rom(&[0x18, 0xFE]), None).unwrap();
+        assert_eq!(other.load_state(&state), Err(StateError::WrongGame));
+        assert_eq!(
+            gb.load_state(&state[..state.len() - 1]),
+            Err(StateError::Corrupt)
+        );
+        let mut extra = state.clone();
+        extra.push(0);
+        assert_eq!(gb.load_state(&extra), Err(StateError::Corrupt));
+        // A failed load leaves the running state alone
+        assert_eq!(gb.save_state(), before);
+    }
+
+    #[test]
+    fn state_diff() {
+        let mut gb = Gameboy::power_on(test_rom(&BUSY_PROGRAM), None).unwrap();
+        for _ in 0..1000 {
+            gb.step(&mut NullSink, &mut NullSink);
+        }
+        let state = gb.save_state();
+        let a = gb.cpu.reg.a;
+        gb.cpu.reg.a = a ^ 0xFF;
+        let changed_a = gb.save_state();
+        gb.write_memory(0xC123, !gb.get_memory_range(0xC123..0xC124)[0]);
+        let changed_wram = gb.save_state();
+
+        let current = gb.save_state();
+        assert_eq!(gb.diff_states(&state, &state), Ok(None));
+        assert_eq!(
+            gb.diff_states(&state, &changed_a),
+            Ok(Some(StateDiff::Register {
+                name: "A",
+                a: a as u16,
+                b: (a ^ 0xFF) as u16
+            }))
+        );
+        assert!(matches!(
+            gb.diff_states(&changed_a, &changed_wram),
+            Ok(Some(StateDiff::Memory {
+                region: "WRAM",
+                offset: 0x123,
+                ..
+            }))
+        ));
+        assert_eq!(gb.diff_states(&state, b"GAB"), Err(StateError::NotAState));
+        // The running state is left alone
+        assert_eq!(gb.save_state(), current);
+    }
+
+    #[test]
+    fn next_event() {
+        // DI; JR -2
+        let mut gb = Gameboy::power_on(test_rom(&[0xF3, 0x18, 0xFE]), None).unwrap();
+        let frame = gb.cycles_until_next_event().unwrap();
+        assert!(frame <= 70224);
+
+        // Only the timer left, overflowing after 16 increments 16 cycles apart. IF bit 2
+        // is the timer interrupt.
+        gb.write_io_register(0xFF40, 0x00);
+        gb.write_io_register(0xFF0F, 0x00);
+        gb.write_io_register(0xFF05, 0xF0);
+        gb.write_io_register(0xFF07, 0x05);
+        let estimate = gb.cycles_until_next_event().unwrap();
+        assert!(estimate > 15 * 16 && estimate <= 16 * 16);
+        let mut cycles = 0;
+        while gb.mmu.read_byte(0xFF0F) & 0x04 == 0 {
+            cycles += gb.step(&mut NullSink, &mut NullSink);
+        }
+        assert!(estimate <= cycles, "{} > {}", estimate, cycles);
+        // Within one instruction
+        assert!(cycles - estimate < 24);
+
+        gb.write_io_register(0xFF07, 0x00);
+        assert_eq!(gb.cycles_until_next_event(), None);
+    }
+
+    #[test]
+    fn without_audio() {
+        // Channel frames are only mixed to be sent to the sink, so counting them counts mixing
+        fn mixed_in_frame(gb: &mut Gameboy) -> (usize, u32) {
+            let mut sink = Count(0);
+            let mut cycles = 0;
+            while cycles < 70224 {
+                cycles += gb.step_channels(&mut NullSink, &mut sink);
+            }
+            (sink.0, cycles)
+        }
+
+        // JR -2
+        let mut gb = GameboyBuilder::new(test_rom(&[0x18, 0xFE]))
+            .without_audio()
+            .build()
+            .unwrap();
+        assert_eq!(gb.io_registers()[0x26] & 0x80, 0x80, "sound should be on");
+        let (mixed_off, _) = mixed_in_frame(&mut gb);
+        let mut cycles = 0;
+        while cycles < 70224 {
+            cycles += gb.step_buffered(&mut NullSink);
+        }
+        assert!(gb.take_audio().is_empty());
+
+        gb.set_audio_output(true);
+        let (mixed_on, cycles) = mixed_in_frame(&mut gb);
+        let expected = cycles as u64 * crate::SAMPLE_RATE as u64 / crate::CLOCK_RATE as u64;
+        assert_eq!(mixed_on - mixed_off, expected as usize);
+        assert_eq!(mixed_off, 0);
+    }
+
+    #[test]
+    fn output_sample_rate() {
+        // JR -2
+        let mut gb = Gameboy::power_on(test_rom(&[0x18, 0xFE]), None).unwrap();
+        let mut sink = Count(0);
+        let mut cycles = 0;
+        while cycles < crate::CLOCK_RATE {
+            cycles += gb.step(&mut NullSink, &mut sink);
+        }
+        // A second's worth of audio, give or take the instruction run over
+        assert!(sink.0.abs_diff(48_000) <= 1, "{}", sink.0);
+    }
+
+    #[test]
+    fn buffered_audio() {
+        // JR -2
+        let mut gb = Gameboy::power_on(test_rom(&[0x18, 0xFE]), None).unwrap();
+        let mut cycles = 0;
+        while cycles < 70224 {
+            cycles += gb.step_buffered(&mut NullSink);
+        }
+        let expected = cycles as u64 * crate::SAMPLE_RATE as u64 / crate::CLOCK_RATE as u64;
+        assert_eq!(gb.take_audio().len(), expected as usize);
+        assert!(gb.take_audio().is_empty());
+
+        // Two seconds without collecting only keeps the latest second
+        let mut cycles = 0;
+        while cycles < crate::CLOCK_RATE * 2 {
+            cycles += gb.step_buffered(&mut NullSink);
+        }
+        assert_eq!(gb.take_audio().len(), MAX_BUFFERED_AUDIO);
+    }
+
+    #[test]
+    fn battery_cartridges() {
+        let has_battery = |cart_type: u8| {
+            let mut rom = test_rom(&[]);
+            rom[0x147] = cart_type;
+            // 8 KiB RAM
+            rom[0x149] = 0x02;
+            Gameboy::power_on(rom, None).unwrap().has_battery()
+        };
+        // MBC3+RAM+BATTERY
+        assert!(has_battery(0x13));
+        // ROM ONLY
+        assert!(!has_battery(0x00));
+        // MBC1+RAM
+        assert!(!has_battery(0x02));
+        // MBC3+TIMER+BATTERY, battery without RAM
+        assert!(has_battery(0x0F));
+    }
+
+    #[test]
+    fn raw_vram_and_oam() {
+        let mut gb = Gameboy::power_on(
+            test_rom(&[
+                0xAF, // XOR A
+                0xE0, 0x40, // LDH ($40), A ; LCD off so VRAM is writable
+                0x21, 0x10, 0x80, // LD HL, $8010
+                0x3E, 0x3C, // LD A, $3C
+                0x22, // LD (HL+), A
+                0x3E, 0x7E, // LD A, $7E
+                0x22, // LD (HL+), A
+                0x21, 0x00, 0xFE, // LD HL, $FE00
+                0x3E, 0x50, // LD A, $50
+                0x22, // LD (HL+), A
+                0x18, 0xFE, // JR -2
+            ]),
+            None,
+        )
+        .unwrap();
+        for _ in 0..12 {
+            gb.step(&mut NullSink, &mut NullSink);
+        }
+        let vram = gb.vram(0).unwrap();
+        assert_eq!(vram.len(), 0x2000);
+        assert_eq!(vram[0x10..0x12], [0x3C, 0x7E]);
+        assert_eq!(vram[0x12], 0x00);
+        assert!(gb.vram(1).is_none());
+        assert_eq!(gb.oam().len(), 0xA0);
+        assert_eq!(gb.oam()[0], 0x50);
+    }
+
+    #[test]
+    fn frozen_timer() {
+        // NOP; JR -3
+        let mut gb = GameboyBuilder::new(test_rom(&[0x00, 0x18, 0xFD]))
+            .trace(4)
+            .build()
+            .unwrap();
+        let div = |gb: &Gameboy| gb.io_registers()[0x04];
+        gb.freeze_subsystem(Subsystem::Timer, true);
+        let start = div(&gb);
+        let mut cycles = 0;
+        while cycles < 2048 {
+            cycles += gb.step(&mut NullSink, &mut NullSink);
+        }
+        assert_eq!(div(&gb), start);
+        // The CPU kept running the loop
+        assert_eq!(gb.recent_trace().len(), 4);
+
+        gb.freeze_subsystem(Subsystem::Timer, false);
+        let mut cycles = 0;
+        while cycles < 2048 {
+            cycles += gb.step(&mut NullSink, &mut NullSink);
+        }
+        assert_ne!(div(&gb), start);
+    }
+
+    #[test]
+    fn timer_counter_restore() {
+        // LD A, $05; LDH ($07), A to start TIMA at 16 cycles per tick, then NOPs
+        let rom = test_rom(&[0x3E, 0x05, 0xE0, 0x07]);
+        let start = |restore: Option<(u16, u8)>| {
+            let mut gb = Gameboy::power_on(rom.clone(), None).unwrap();
+            gb.step(&mut NullSink, &mut NullSink);
+            gb.step(&mut NullSink, &mut NullSink);
+            if let Some((counter, tima)) = restore {
+                gb.set_timer_counter(counter);
+                gb.write_io_register(0xFF05, tima);
+            }
+            gb
+        };
+        let timer = |gb: &Gameboy| (gb.timer_counter(), gb.io_registers()[0x05]);
+
+        let mut gb = start(None);
+        // Partway through a scanline
+        for _ in 0..1001 {
+            gb.step(&mut NullSink, &mut NullSink);
+        }
+        assert_eq!(gb.io_registers()[0x04], (gb.timer_counter() >> 8) as u8);
+        let (counter, tima) = timer(&gb);
+        let mut restored = start(Some((counter, tima)));
+        // Only the visible registers restored
+        let mut registers_only = start(Some((counter & 0xFF00, tima)));
+        let mut drifted = false;
+        for _ in 0..64 {
+            gb.step(&mut NullSink, &mut NullSink);
+            restored.step(&mut NullSink, &mut NullSink);
+            registers_only.step(&mut NullSink, &mut NullSink);
+            assert_eq!(timer(&restored), timer(&gb));
+            drifted |= registers_only.io_registers()[0x05] != gb.io_registers()[0x05];
+        }
+        assert!(drifted);
+    }
+
+    #[test]
+    fn trace_ring() {
+        // NOP; LD A, $01; INC A; LD B, A; JR -2
+        let program = [0x00, 0x3E, 0x01, 0x3C, 0x47, 0x18, 0xFE];
+        let mut gb = GameboyBuilder::new(test_rom(&program))
+            .trace(4)
+            .build()
+            .unwrap();
+        let mut video_sink = NullSink;
+        let mut audio_sink = NullSink;
+        for _ in 0..3 {
+            gb.step(&mut video_sink, &mut audio_sink);
+        }
+        assert_eq!(
+            gb.recent_trace(),
+            vec![(0x100, 0x00), (0x101, 0x3E), (0x103, 0x3C)]
+        );
+        for _ in 0..3 {
+            gb.step(&mut video_sink, &mut audio_sink);
+        }
+        assert_eq!(
+            gb.recent_trace(),
+            vec![(0x103, 0x3C), (0x104, 0x47), (0x105, 0x18), (0x105, 0x18)]
+        );
+
+        gb.set_trace_depth(None);
+        gb.step(&mut video_sink, &mut audio_sink);
+        assert!(gb.recent_trace().is_empty());
+    }
+
+    #[test]
+    fn unsupported_mbc() {
+        let mut rom = test_rom(&[0x18, 0xFE]);
+        // MBC7
+        rom[0x147] = 0x22;
+        let err = Gameboy::power_on(rom, None).err();
+        assert_eq!(err, Some(GameboyError::UnsupportedMbc(0x22)));
+        assert_eq!(
+            err.unwrap().to_string(),
+            "Unsupported cartridge type (MBC) 0x22"
+        );
+    }
+
+    #[test]
+    fn run_until_mem_match() {
+        // LD A, $42; LD ($C000), A; JR -2
+        let rom = test_rom(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+        let mut gb = Gameboy::power_on(rom, None).unwrap();
+        let cycles = gb.run_until_mem(0xC000, 0x42, 1000).unwrap();
+        // LD A, d8 (8) + LD (a16), A (16)
+        assert_eq!(cycles, 24);
+        assert_eq!(gb.get_memory_range(0xC000..0xC001)[0], 0x42);
+    }
+
+    #[test]
+    fn oam_bug_toggle() {
+        // Wait for LY 1 and the start of OAM scan, then INC HL with HL pointing into OAM
+        // LD HL, $FE00; LD A, ($FF44); CP 1; JR NZ, -6; INC HL; JR -2
+        let program = [
+            0x21, 0x00, 0xFE, 0xF0, 0x44, 0xFE, 0x01, 0x20, 0xFA, 0x23, 0x18, 0xFE,
+        ];
+        let run = |enabled: bool| {
+            let mut gb = Gameboy::power_on(test_rom(&program), None).unwrap();
+            gb.set_oam_bug_enabled(enabled);
+            for i in 0..0xA0u16 {
+                gb.mmu.write_byte(0xFE00 + i, i as u8);
+            }
+            gb.run_until_mem(0xFF44, 0x01, 100_000).unwrap();
+            gb.run_until_mem(0xFF44, 0x02, 1_000).unwrap();
+            gb.get_memory_range(0xFE00..0xFEA0)
+        };
+        let untouched: alloc::vec::Vec<u8> = (0..0xA0).collect();
+        assert_eq!(&run(false)[..], &untouched[..]);
+        assert_ne!(&run(true)[..], &untouched[..]);
+    }
+
+    #[test]
+    fn ppu_mode_and_scanline() {
+        // JR -2
+        let mut gb = Gameboy::power_on(test_rom(&[0x18, 0xFE]), None).unwrap();
+        let mut video_sink = NullSink;
+        let mut audio_sink = NullSink;
+        assert_eq!(gb.current_scanline(), 0);
+
+        // Record the order modes are entered over the first few lines
+        let mut modes = vec![gb.ppu_mode()];
+        while gb.current_scanline() < 3 {
+            gb.step(&mut video_sink, &mut audio_sink);
+            if *modes.last().unwrap() != gb.ppu_mode() {
+                modes.push(gb.ppu_mode());
+            }
+        }
+        assert_eq!(&modes[..], &[1, 2, 3, 0, 2, 3, 0, 2, 3, 0, 2]);
+
+        // Run into V-Blank
+        while gb.current_scanline() < 144 {
+            gb.step(&mut video_sink, &mut audio_sink);
+        }
+        assert_eq!(gb.ppu_mode(), 1);
+    }
+
+    #[test]
+    fn step_scanline() {
+        // JR -2
+        let mut gb = Gameboy::power_on(test_rom(&[0x18, 0xFE]), None).unwrap();
+        let mut video_sink = NullSink;
+        let mut audio_sink = NullSink;
+        gb.step_scanline(&mut video_sink, &mut audio_sink);
+        for line in 1..10 {
+            assert_eq!(gb.current_scanline(), line);
+            let cycles = gb.step_scanline(&mut video_sink, &mut audio_sink);
+            assert_eq!(gb.current_scanline(), line + 1);
+            // Instructions can overrun the end of the line by a few cycles
+            assert!((440..=472).contains(&cycles), "{} cycles", cycles);
+        }
+        while gb.current_scanline() != 153 {
+            gb.step_scanline(&mut video_sink, &mut audio_sink);
+        }
+        gb.step_scanline(&mut video_sink, &mut audio_sink);
+        assert_eq!(gb.current_scanline(), 0);
+    }
+
+    #[test]
+    fn first_frame_with_graphics() {
+        // Fill the top half of tile 0 with color 3, then spin
+        // LD HL, $8000; LD A, $FF; LD B, 8; LD (HL+), A; DEC B; JR NZ, -4; JR -2
+        let program = [
+            0x21, 0x00, 0x80, 0x3E, 0xFF, 0x06, 0x08, 0x22, 0x05, 0x20, 0xFC, 0x18, 0xFE,
+        ];
+        let mut gb = Gameboy::power_on(test_rom(&program), None).unwrap();
+        let frame = gb.run_to_first_frame(10).unwrap();
+        assert!(frame.chunks(3).any(|p| p != &frame[0..3]));
+        // Top row of the screen is tile 0's first line
+        assert_eq!(&frame[0..3], &[0, 0, 0]);
+
+        // Nothing drawn
+        let mut gb = Gameboy::power_on(test_rom(&[0x18, 0xFE]), None).unwrap();
+        assert!(gb.run_to_first_frame(3).is_none());
+    }
+
+    #[test]
+    fn stop_until_button_press() {
+        // STOP; JR -2
+        let mut gb = Gameboy::power_on(test_rom(&[0x10, 0x00, 0x18, 0xFE]), None).unwrap();
+        let mut video_sink = Count(0);
+        let mut audio_sink = NullSink;
+        gb.step(&mut video_sink, &mut audio_sink);
+        assert!(gb.cpu.stopped);
+
+        // Run for more than a frame, nothing should advance
+        let scanline = gb.current_scanline();
+        for _ in 0..20_000 {
+            gb.step(&mut video_sink, &mut audio_sink);
+        }
+        assert!(gb.cpu.stopped);
+        assert_eq!(gb.current_scanline(), scanline);
+        assert_eq!(video_sink.0, 0);
+        // DIV is held in reset
+        assert_eq!(gb.get_memory_range(0xFF04..0xFF05)[0], 0);
+
+        // Action buttons are selected on power on, pressing A wakes the CPU
+        gb.update_key_state(GbKeys::A, true);
+        for _ in 0..2 {
+            gb.step(&mut video_sink, &mut audio_sink);
+        }
+        assert!(!gb.cpu.stopped);
+        assert!(gb.get_pc() >= 0x101);
+        while video_sink.0 == 0 {
+            gb.step(&mut video_sink, &mut audio_sink);
+        }
+        assert_ne!(gb.current_scanline(), scanline);
+    }
+
+    #[test]
+    fn io_register_snapshot() {
+        // JR -2
+        let mut gb = Gameboy::power_on(test_rom(&[0x18, 0xFE]), None).unwrap();
+        gb.write_io_register(0xFF06, 0xAB);
+        gb.write_io_register(0xFF07, 0x05);
+        gb.write_io_register(0xFF47, 0x1B);
+        let regs = gb.io_registers();
+        assert_eq!(regs[0x40], gb.get_memory_range(0xFF40..0xFF41)[0]);
+        assert_eq!(regs[0x40] & 0x80, 0x80);
+        assert_eq!(regs[0x06], 0xAB);
+        // Upper bits of TAC and IF always read back as set
+        assert_eq!(regs[0x07], 0xFD);
+        assert_eq!(regs[0x0F] & 0xE0, 0xE0);
+        assert_eq!(regs[0x47], 0x1B);
+        // Unmapped registers
+        assert_eq!(regs[0x03], 0xFF);
+        assert_eq!(regs[0x7F], 0xFF);
+
+        // The timer picks up the written TMA on overflow
+        gb.write_io_register(0xFF05, 0xFF);
+        let mut video_sink = NullSink;
+        let mut audio_sink = NullSink;
+        for _ in 0..8 {
+            gb.step(&mut video_sink, &mut audio_sink);
+        }
+        assert!(gb.io_registers()[0x05] >= 0xAB);
+        assert_eq!(gb.io_registers()[0x0F] & 0x04, 0x04);
+
+        // Only IO registers are written
+        let (wram, ie) = (
+            gb.get_memory_range(0xC000..0xC001),
+            gb.get_memory_range(0xFFFF..0x10000),
+        );
+        gb.write_io_register(0xC000, !wram[0]);
+        gb.write_io_register(0xFFFF, !ie[0]);
+        assert_eq!(gb.get_memory_range(0xC000..0xC001), wram);
+        assert_eq!(gb.get_memory_range(0xFFFF..0x10000), ie);
+
+        gb.write_io_register(0xFF40, 0x00);
+        assert_eq!(gb.io_registers()[0x40], 0x00);
+        assert_eq!(gb.ppu_mode(), 0);
+    }
+
+    #[test]
+    fn memory_map_summary() {
+        // 64 KiB MBC1 cartridge
+        // LD A, 2; LD ($2000), A; JR -2
+        let mut rom = vec![0x00; 0x10000];
+        rom[0x100..0x107].copy_from_slice(&[0x3E, 0x02, 0xEA, 0x00, 0x20, 0x18, 0xFE]);
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x01;
+        let mut gb = Gameboy::power_on(rom.into_boxed_slice(), None).unwrap();
+        let region = |gb: &Gameboy, start: u16| {
+            gb.memory_map()
+                .into_iter()
+                .find(|r| r.start == start)
+                .unwrap()
+        };
+        assert_eq!(region(&gb, 0x4000).bank, Some(1));
+        // No RAM on this cartridge
+        assert!(!region(&gb, 0xA000).readable);
+
+        let mut video_sink = NullSink;
+        let mut audio_sink = NullSink;
+        while gb.ppu_mode() != 3 {
+            gb.step(&mut video_sink, &mut audio_sink);
+        }
+        assert_eq!(region(&gb, 0x4000).bank, Some(2));
+        let vram = region(&gb, 0x8000);
+        assert_eq!(vram.end, 0x9FFF);
+        assert!(!vram.readable && !vram.writable);
+        assert!(!region(&gb, 0xFE00).readable);
+        assert!(region(&gb, 0xC000).readable);
+
+        while gb.ppu_mode() != 0 {
+            gb.step(&mut video_sink, &mut audio_sink);
+        }
+        assert!(region(&gb, 0x8000).writable);
+        assert!(region(&gb, 0xFE00).writable);
+
+        // Regions cover the whole address space in order
+        let map = gb.memory_map();
+        assert_eq!(map[0].start, 0x0000);
+        assert_eq!(map.last().unwrap().end, 0xFFFF);
+        for pair in map.windows(2) {
+            assert_eq!(pair[0].end as u32 + 1, pair[1].start as u32);
+        }
+    }
+
+    #[test]
+    fn watchdog_without_vblank() {
+        // Turn off the LCD and spin forever
+        // XOR A; LDH ($40), A; JR -2
+        let rom = test_rom(&[0xAF, 0xE0, 0x40, 0x18, 0xFE]);
+        let mut gb = GameboyBuilder::new(rom).watchdog(100_000).build().unwrap();
+        let mut video_sink = NullSink;
+        let mut audio_sink = NullSink;
+        let mut cycles: u64 = 0;
+        while !gb.watchdog_expired() {
+            cycles += gb.step(&mut video_sink, &mut audio_sink) as u64;
+            assert!(cycles <= 100_000 + 24);
+        }
+        assert!(cycles >= 100_000);
+        // Run helpers bail out once it expires
+        assert_eq!(gb.run_until_mem(0xC000, 0x42, u64::MAX), Err(Timeout));
+
+        // Spinning with the LCD on still reaches V-Blank every frame
+        let mut gb = GameboyBuilder::new(test_rom(&[0x18, 0xFE]))
+            .watchdog(100_000)
+            .build()
+            .unwrap();
+        for _ in 0..100_000 {
+            gb.step(&mut video_sink, &mut audio_sink);
+            assert!(!gb.watchdog_expired());
+        }
+    }
+
+    #[test]
+    fn dump_tiles_banks() {
+        let gb = Gameboy::power_on(test_rom(&[0x18, 0xFE]), None).unwrap();
+        let tiles = gb.dump_tiles(0, TilePalette::Bgp).unwrap();
+        assert_eq!(tiles.len(), 3 * 128 * 192);
+        // DMG only has VRAM bank 0
+        assert!(gb.dump_tiles(1, TilePalette::Bgp).is_none());
+    }
+
+    #[test]
+    fn vram_introspection() {
+        let mut gb = Gameboy::power_on(test_rom(&[0x18, 0xFE]), None).unwrap();
+        // OBJ 1 at (16, 8) on screen using tile 5, behind the BG, flipped across and with OBP1
+        for (i, value) in [0x18, 0x10, 0x05, 0xB0].into_iter().enumerate() {
+            gb.write_memory(0xFE04 + i as u16, value);
+        }
+        // Tile 1's first row is color 1, and the second starts with color 2
+        gb.write_memory(0x8010, 0xFF);
+        gb.write_memory(0x8013, 0x80);
+        gb.write_memory(0xFF43, 0x12);
+        gb.write_memory(0xFF4B, 0x07);
+
+        let objs = gb.oam_entries();
+        assert_eq!(objs.len(), 40);
+        assert_eq!(
+            objs[1],
+            OamEntry {
+                y: 0x18,
+                x: 0x10,
+                tile: 0x05,
+                behind_bg: true,
+                flip_y: false,
+                flip_x: true,
+                palette: TilePalette::Obp1,
+            }
+        );
+        assert_eq!(objs[0].palette, TilePalette::Obp0);
+
+        let tile = gb.tile_pixels(1).unwrap();
+        assert_eq!(tile[..8], [1; 8]);
+        assert_eq!(tile[8..10], [2, 0]);
+        assert_eq!(gb.tile_pixels(0).unwrap(), [0; 64]);
+        assert!(gb.tile_pixels(383).is_some());
+        assert!(gb.tile_pixels(384).is_none());
+
+        let layout = gb.background_layout();
+        assert_eq!((layout.scroll_x, layout.scroll_y), (0x12, 0));
+        assert_eq!(layout.window_x, 0x07);
+        // As the boot ROM leaves LCDC
+        assert!(layout.bg_enabled && layout.tile_data_select && !layout.window_enabled);
+    }
+
+    #[test]
+    fn run_until_mem_timeout() {
+        // JR -2
+        let rom = test_rom(&[0x18, 0xFE]);
+        let mut gb = Gameboy::power_on(rom, None).unwrap();
+        assert_eq!(gb.run_until_mem(0xC000, 0x42, 1000), Err(Timeout));
     }
 }
@@ -0,0 +1,486 @@
+//! Debugging over the GDB remote serial protocol, using `gdbstub`. Enabled with the `gdb`
+//! feature.
+//!
+//! GDB has no SM83 target, so the CPU is described as the Z80 it's derived from, which
+//! `gdb-multiarch` (GDB 11 or later) supports. Only the registers the two share are given.
+//! Hosts provide the connection, e.g. a `TcpStream` with gdbstub's `std` feature enabled:
+//!
+//! ```ignore
+//! let (stream, _) = std::net::TcpListener::bind("127.0.0.1:9001")?.accept()?;
+//! let mut target = GdbTarget::new(gameboy);
+//! run_gdb_server(&mut target, stream)?;
+//! ```
+
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use gdbstub::arch::Arch;
+use gdbstub::common::Signal;
+use gdbstub::conn::{Connection, ConnectionExt};
+use gdbstub::stub::run_blocking::{BlockingEventLoop, Event, WaitForStopReasonError};
+use gdbstub::stub::{DisconnectReason, GdbStub, GdbStubError, SingleThreadStopReason};
+use gdbstub::target::ext::base::singlethread::{
+    SingleThreadBase, SingleThreadResume, SingleThreadResumeOps, SingleThreadSingleStep,
+    SingleThreadSingleStepOps,
+};
+use gdbstub::target::ext::base::BaseOps;
+use gdbstub::target::ext::breakpoints::{
+    Breakpoints, BreakpointsOps, HwWatchpoint, HwWatchpointOps, SwBreakpoint, SwBreakpointOps,
+};
+use gdbstub::target::{Target, TargetResult};
+
+use super::gb::{Gameboy, WatchKind};
+use super::sink::NullSink;
+
+/// Instructions run between checks for GDB interrupting a continue
+const POLL_INTERVAL: u32 = 1024;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>z80</architecture>
+  <feature name="org.gnu.gdb.z80.cpu">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="data_ptr"/>
+    <reg name="de" bitsize="16" type="data_ptr"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>"#;
+
+/// The SM83 CPU as GDB sees it
+pub enum Sm83 {}
+
+impl Arch for Sm83 {
+    type Usize = u16;
+    type Registers = Sm83Registers;
+    type BreakpointKind = usize;
+    type RegId = ();
+
+    fn target_description_xml() -> Option<&'static str> {
+        Some(TARGET_XML)
+    }
+}
+
+/// Register pairs in the order of `TARGET_XML`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Sm83Registers {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+impl Sm83Registers {
+    fn as_array(&self) -> [u16; 6] {
+        [self.af, self.bc, self.de, self.hl, self.sp, self.pc]
+    }
+}
+
+impl gdbstub::arch::Registers for Sm83Registers {
+    type ProgramCounter = u16;
+
+    fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        for reg in self.as_array() {
+            for b in reg.to_le_bytes() {
+                write_byte(Some(b));
+            }
+        }
+    }
+
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.len() != 12 {
+            return Err(());
+        }
+        let mut regs = bytes
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+        let mut next = || regs.next().unwrap();
+        *self = Sm83Registers {
+            af: next(),
+            bc: next(),
+            de: next(),
+            hl: next(),
+            sp: next(),
+            pc: next(),
+        };
+        Ok(())
+    }
+}
+
+/// What the game does when GDB resumes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExecMode {
+    Step,
+    Continue,
+}
+
+/// Why the game stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunEvent {
+    Stopped(SingleThreadStopReason<u16>),
+    /// GDB sent something, such as an interrupt, while the game ran
+    IncomingData,
+}
+
+/// A `Gameboy` being debugged by GDB. The game runs headless, with video and audio
+/// discarded, while GDB is connected.
+pub struct GdbTarget {
+    gb: Gameboy,
+    breakpoints: Vec<u16>,
+    exec_mode: ExecMode,
+}
+
+impl GdbTarget {
+    pub fn new(gb: Gameboy) -> Self {
+        GdbTarget {
+            gb,
+            breakpoints: Vec::new(),
+            exec_mode: ExecMode::Continue,
+        }
+    }
+
+    pub fn gameboy(&mut self) -> &mut Gameboy {
+        &mut self.gb
+    }
+
+    pub fn into_inner(self) -> Gameboy {
+        self.gb
+    }
+
+    /// Runs one instruction, returning why the game stopped if it did
+    fn step(&mut self) -> Option<SingleThreadStopReason<u16>> {
+        self.gb.step(&mut NullSink, &mut NullSink);
+        if let Some(hit) = self.gb.take_watch_hit() {
+            let kind = if hit.write {
+                gdbstub::target::ext::breakpoints::WatchKind::Write
+            } else {
+                gdbstub::target::ext::breakpoints::WatchKind::Read
+            };
+            return Some(SingleThreadStopReason::Watch {
+                tid: (),
+                kind,
+                addr: hit.addr,
+            });
+        }
+        if self.gb.locked_up() {
+            return Some(SingleThreadStopReason::Signal(Signal::SIGILL));
+        }
+        if self.breakpoints.contains(&self.gb.get_pc()) {
+            return Some(SingleThreadStopReason::SwBreak(()));
+        }
+        None
+    }
+
+    /// Runs as GDB asked, until the game stops or `incoming_data` reports GDB sent something
+    fn run(&mut self, mut incoming_data: impl FnMut() -> bool) -> RunEvent {
+        if self.exec_mode == ExecMode::Step {
+            return RunEvent::Stopped(self.step().unwrap_or(SingleThreadStopReason::DoneStep));
+        }
+        let mut steps = 0;
+        loop {
+            if let Some(reason) = self.step() {
+                return RunEvent::Stopped(reason);
+            }
+            steps += 1;
+            if steps % POLL_INTERVAL == 0 && incoming_data() {
+                return RunEvent::IncomingData;
+            }
+        }
+    }
+}
+
+impl Target for GdbTarget {
+    type Arch = Sm83;
+    type Error = &'static str;
+
+    fn base_ops(&mut self) -> BaseOps<'_, Sm83, &'static str> {
+        BaseOps::SingleThread(self)
+    }
+
+    fn support_breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadBase for GdbTarget {
+    fn read_registers(&mut self, regs: &mut Sm83Registers) -> TargetResult<(), Self> {
+        let reg = self.gb.registers_mut();
+        *regs = Sm83Registers {
+            af: u16::from_be_bytes([reg.a, reg.f]),
+            bc: u16::from_be_bytes([reg.b, reg.c]),
+            de: u16::from_be_bytes([reg.d, reg.e]),
+            hl: u16::from_be_bytes([reg.h, reg.l]),
+            sp: reg.sp,
+            pc: reg.pc,
+        };
+        Ok(())
+    }
+
+    fn write_registers(&mut self, regs: &Sm83Registers) -> TargetResult<(), Self> {
+        let reg = self.gb.registers_mut();
+        [reg.a, reg.f] = regs.af.to_be_bytes();
+        // The low nibble of F doesn't exist
+        reg.f &= 0xF0;
+        [reg.b, reg.c] = regs.bc.to_be_bytes();
+        [reg.d, reg.e] = regs.de.to_be_bytes();
+        [reg.h, reg.l] = regs.hl.to_be_bytes();
+        reg.sp = regs.sp;
+        reg.pc = regs.pc;
+        Ok(())
+    }
+
+    fn read_addrs(&mut self, start_addr: u16, data: &mut [u8]) -> TargetResult<usize, Self> {
+        // Reads stop at the end of the address space rather than wrapping around
+        let start = start_addr as usize;
+        let end = (start + data.len()).min(0x10000);
+        let bytes = self.gb.get_memory_range(start..end);
+        data[..bytes.len()].copy_from_slice(&bytes);
+        Ok(bytes.len())
+    }
+
+    fn write_addrs(&mut self, start_addr: u16, data: &[u8]) -> TargetResult<(), Self> {
+        for (addr, val) in (start_addr..=0xFFFF).zip(data) {
+            self.gb.write_memory(addr, *val);
+        }
+        Ok(())
+    }
+
+    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadResume for GdbTarget {
+    fn resume(&mut self, signal: Option<Signal>) -> Result<(), &'static str> {
+        if signal.is_some() {
+            return Err("Signals can't be delivered to a Gameboy");
+        }
+        self.exec_mode = ExecMode::Continue;
+        Ok(())
+    }
+
+    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadSingleStep for GdbTarget {
+    fn step(&mut self, signal: Option<Signal>) -> Result<(), &'static str> {
+        if signal.is_some() {
+            return Err("Signals can't be delivered to a Gameboy");
+        }
+        self.exec_mode = ExecMode::Step;
+        Ok(())
+    }
+}
+
+impl Breakpoints for GdbTarget {
+    fn support_sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_hw_watchpoint(&mut self) -> Option<HwWatchpointOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SwBreakpoint for GdbTarget {
+    fn add_sw_breakpoint(&mut self, addr: u16, _kind: usize) -> TargetResult<bool, Self> {
+        self.breakpoints.push(addr);
+        Ok(true)
+    }
+
+    fn remove_sw_breakpoint(&mut self, addr: u16, _kind: usize) -> TargetResult<bool, Self> {
+        match self.breakpoints.iter().position(|b| *b == addr) {
+            Some(i) => {
+                self.breakpoints.remove(i);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// The range of a watchpoint GDB asked for, or `None` for an empty one
+fn watch_range(addr: u16, len: u16) -> Option<core::ops::RangeInclusive<u16>> {
+    let last = addr.saturating_add(len.checked_sub(1)?);
+    Some(addr..=last)
+}
+
+fn watch_kind(kind: gdbstub::target::ext::breakpoints::WatchKind) -> WatchKind {
+    match kind {
+        gdbstub::target::ext::breakpoints::WatchKind::Read => WatchKind::Read,
+        gdbstub::target::ext::breakpoints::WatchKind::Write => WatchKind::Write,
+        gdbstub::target::ext::breakpoints::WatchKind::ReadWrite => WatchKind::ReadWrite,
+    }
+}
+
+impl HwWatchpoint for GdbTarget {
+    fn add_hw_watchpoint(
+        &mut self,
+        addr: u16,
+        len: u16,
+        kind: gdbstub::target::ext::breakpoints::WatchKind,
+    ) -> TargetResult<bool, Self> {
+        let Some(range) = watch_range(addr, len) else {
+            return Ok(false);
+        };
+        self.gb.add_watchpoint(range, watch_kind(kind));
+        Ok(true)
+    }
+
+    fn remove_hw_watchpoint(
+        &mut self,
+        addr: u16,
+        len: u16,
+        kind: gdbstub::target::ext::breakpoints::WatchKind,
+    ) -> TargetResult<bool, Self> {
+        let Some(range) = watch_range(addr, len) else {
+            return Ok(false);
+        };
+        Ok(self.gb.remove_watchpoint(range, watch_kind(kind)))
+    }
+}
+
+/// Runs a `GdbTarget` between GDB's commands, for `run_gdb_server`
+pub struct GdbEventLoop<C>(PhantomData<C>);
+
+impl<C: ConnectionExt> BlockingEventLoop for GdbEventLoop<C> {
+    type Target = GdbTarget;
+    type Connection = C;
+    type StopReason = SingleThreadStopReason<u16>;
+
+    #[allow(clippy::type_complexity)]
+    fn wait_for_stop_reason(
+        target: &mut GdbTarget,
+        conn: &mut C,
+    ) -> Result<
+        Event<SingleThreadStopReason<u16>>,
+        WaitForStopReasonError<&'static str, <C as Connection>::Error>,
+    > {
+        // Connection errors are treated as GDB having sent something, to be read below
+        match target.run(|| conn.peek().map_or(true, |b| b.is_some())) {
+            RunEvent::Stopped(reason) => Ok(Event::TargetStopped(reason)),
+            RunEvent::IncomingData => {
+                let byte = conn.read().map_err(WaitForStopReasonError::Connection)?;
+                Ok(Event::IncomingData(byte))
+            }
+        }
+    }
+
+    fn on_interrupt(
+        _target: &mut GdbTarget,
+    ) -> Result<Option<SingleThreadStopReason<u16>>, &'static str> {
+        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+    }
+}
+
+/// Serves GDB over `conn` until it detaches or the connection fails
+pub fn run_gdb_server<C: ConnectionExt>(
+    target: &mut GdbTarget,
+    conn: C,
+) -> Result<DisconnectReason, GdbStubError<&'static str, C::Error>> {
+    GdbStub::new(conn).run_blocking::<GdbEventLoop<C>>(target)
+}
+
+#[cfg(test)]
+mod gdb_tests {
+    use super::*;
+    use alloc::rc::Rc;
+    use alloc::string::String;
+    use alloc::vec;
+    use core::cell::RefCell;
+
+    /// Plays GDB's side of a session from a script of packets, recording the replies
+    struct ScriptedConnection {
+        input: Vec<u8>,
+        pos: usize,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl ScriptedConnection {
+        fn new(packets: &[&str]) -> Self {
+            let mut input = Vec::new();
+            for packet in packets {
+                if *packet == "\x03" {
+                    input.push(0x03);
+                    continue;
+                }
+                let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+                input.extend(format!("${}#{:02x}", packet, checksum).bytes());
+            }
+            ScriptedConnection {
+                input,
+                pos: 0,
+                output: Rc::default(),
+            }
+        }
+    }
+
+    impl Connection for ScriptedConnection {
+        type Error = &'static str;
+
+        fn write(&mut self, byte: u8) -> Result<(), &'static str> {
+            self.output.borrow_mut().push(byte);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), &'static str> {
+            Ok(())
+        }
+    }
+
+    impl ConnectionExt for ScriptedConnection {
+        fn read(&mut self) -> Result<u8, &'static str> {
+            let byte = self.peek()?.ok_or("Script finished")?;
+            self.pos += 1;
+            Ok(byte)
+        }
+
+        fn peek(&mut self) -> Result<Option<u8>, &'static str> {
+            Ok(self.input.get(self.pos).copied())
+        }
+    }
+
+    #[test]
+    fn session() {
+        let mut rom = vec![0x00; 0x8000];
+        // INC A; JR -3
+        rom[0x100..0x103].copy_from_slice(&[0x3C, 0x18, 0xFD]);
+        let gb = Gameboy::power_on(rom.into_boxed_slice(), None).unwrap();
+        let mut target = GdbTarget::new(gb);
+        let conn = ScriptedConnection::new(&[
+            "?", "g", "Z0,101,1", "c", "m100,3", "z0,101,1", "s", "c", "\x03", "D",
+        ]);
+        let output = conn.output.clone();
+        let reason = run_gdb_server(&mut target, conn).unwrap();
+        assert_eq!(reason, DisconnectReason::Disconnect);
+        let output = String::from_utf8(output.take()).unwrap();
+        let replies: Vec<&str> = output
+            .split('$')
+            .skip(1)
+            .map(|reply| reply.split('#').next().unwrap())
+            .collect();
+
+        // AF, BC, DE, HL, SP and PC after the DMG boot ROM, little-endian
+        assert_eq!(replies[1], "b0011300d8004d01feff0001");
+        assert_eq!(replies[2], "OK");
+        assert_eq!(replies[3], "T05thread:01;swbreak:;");
+        assert_eq!(replies[4], "3c18fd");
+        assert_eq!(replies[5], "OK");
+        // Single step, then interrupted
+        assert_eq!(replies[6], "S05");
+        assert_eq!(replies[7], "S02");
+        assert_eq!(replies[8], "OK");
+        let gb = target.into_inner();
+        assert_eq!(gb.get_pc() & !1, 0x0100);
+    }
+}
@@ -1,6 +1,7 @@
-use super::gb::GbKeys;
+use super::gb::{GbKeys, StateError};
 use super::mmu::InterruptKind;
 use super::mmu::Memory;
+use super::state::{SaveState, StateReader, StateWriter};
 
 /// The eight Game Boy action/direction buttons are arranged as a 2x4 matrix.
 /// Select either action or direction buttons by writing to this register, then read out the bits 0-3.
@@ -46,11 +47,31 @@ impl Joypad {
     pub fn set_key_pressed(&mut self, key: GbKeys, pressed: bool) {
         self.keys_pressed[key as usize] = pressed;
     }
+
+    /// Sets every button at once from a mask with one bit per button indexed by `GbKeys`
+    pub fn set_held_keys(&mut self, keys: u8) {
+        for (i, pressed) in self.keys_pressed.iter_mut().enumerate() {
+            *pressed = keys & (1 << i) != 0;
+        }
+    }
+
+    /// The buttons set as held, which the game sees from the next update
+    pub fn held_keys(&self) -> u8 {
+        self.keys_pressed
+            .iter()
+            .enumerate()
+            .fold(0, |keys, (i, pressed)| keys | (*pressed as u8) << i)
+    }
+
+    /// The buttons held down as of the last update, one bit per button indexed by `GbKeys`
+    pub fn pressed_keys(&self) -> u8 {
+        !self.state
+    }
 }
 
 impl Memory for Joypad {
     fn read_byte(&self, addr: u16) -> u8 {
-        assert!(addr == 0xFF00);
+        debug_assert!(addr == 0xFF00);
         if self.using_directions {
             // Return directional pad values
             (self.state | 0b1111_0000) & 0b1110_1111
@@ -60,7 +81,7 @@ impl Memory for Joypad {
         }
     }
     fn write_byte(&mut self, addr: u16, val: u8) {
-        assert!(addr == 0xFF00);
+        debug_assert!(addr == 0xFF00);
         // Only write the bit 4/5 into the register, mask everything else off
         match (val >> 4) & 0b11 {
             0b00 | 0b10 => self.using_directions = true,
@@ -70,6 +91,25 @@ impl Memory for Joypad {
     }
 }
 
+impl SaveState for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.state);
+        w.bool(self.using_directions);
+        for pressed in self.keys_pressed {
+            w.bool(pressed);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.state = r.u8()?;
+        self.using_directions = r.bool()?;
+        for pressed in self.keys_pressed.iter_mut() {
+            *pressed = r.bool()?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod joypad_tests {
     use crate::mmu::Memory;
@@ -108,6 +148,14 @@ mod joypad_tests {
         joy.set_key_pressed(GbKeys::Down, false);
         assert!(joy.update().is_none());
         assert_eq!(joy.read_byte(0xFF00), 0b1101_1111);
+        assert_eq!(joy.pressed_keys(), 0);
+
+        joy.set_key_pressed(GbKeys::Start, true);
+        joy.set_key_pressed(GbKeys::Left, true);
+        // Not visible until the next update
+        assert_eq!(joy.pressed_keys(), 0);
+        joy.update();
+        assert_eq!(joy.pressed_keys(), 0b1000_0010);
     }
 
     #[test]
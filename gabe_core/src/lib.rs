@@ -7,19 +7,45 @@ extern crate alloc;
 extern crate log;
 
 mod apu;
-mod cartridge;
+pub mod cartridge;
+pub mod cheats;
 mod cpu;
+pub mod debugger;
 pub mod disassemble;
 pub mod gb;
+#[cfg(feature = "gdb")]
+pub mod gdb;
 mod joypad;
+pub mod link;
 mod mmu;
+pub mod movie;
+pub mod patch;
+pub mod recording;
+mod rewind;
 mod serial;
 pub mod sink;
+mod state;
+pub mod storage;
 mod timer;
 mod util;
 mod vram;
 mod wram;
 
+/// Targets of the `log` records from each part of the emulator, so one can be enabled without
+/// flooding the output with the others, e.g. `RUST_LOG=gabe::cpu=trace` with `env_logger`
+pub mod log_target {
+    pub const CPU: &str = "gabe::cpu";
+    pub const APU: &str = "gabe::apu";
+    pub const MMU: &str = "gabe::mmu";
+    pub const VRAM: &str = "gabe::vram";
+    pub const WRAM: &str = "gabe::wram";
+    pub const TIMER: &str = "gabe::timer";
+    pub const CARTRIDGE: &str = "gabe::cartridge";
+}
+
 pub const CLOCK_RATE: u32 = 4_194_304;
 pub const CGB_CLOCK_RATE: u32 = CLOCK_RATE * 2;
-pub const SAMPLE_RATE: u32 = CLOCK_RATE / 16; // 262.144 KHz sample rate
+/// Default rate in Hz that audio frames are emitted at, which most audio devices play
+/// natively. The channels are still stepped at `CLOCK_RATE` and sampled at this rate;
+/// use `Gameboy::set_sample_rate` to pick another.
+pub const SAMPLE_RATE: u32 = 48_000;
@@ -0,0 +1,13 @@
+/// The other end of a link cable, connected with `Gameboy::connect_serial`.
+///
+/// Each transfer swaps a byte with the peer: the side providing the clock sends its byte
+/// when the transfer starts and receives the peer's in reply, while the side using the
+/// external clock replies as soon as the peer's byte arrives. Transports over a network
+/// deliver bytes late, see `Gameboy::set_serial_latency` and
+/// `Gameboy::set_serial_wait_for_peer` to keep both ends in agreement.
+pub trait SerialLink {
+    /// Sends the byte shifted out by this end
+    fn send(&mut self, byte: u8);
+    /// Returns the next byte shifted out by the peer, or `None` if none has arrived yet
+    fn receive(&mut self) -> Option<u8>;
+}
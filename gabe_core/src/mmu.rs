@@ -1,15 +1,29 @@
 use alloc::boxed::*;
+use alloc::collections::VecDeque;
 use alloc::vec::*;
+use core::cell::RefCell;
+use core::ops::RangeInclusive;
 
-use super::apu::Apu;
-use super::cartridge::Cartridge;
+use super::apu::{Apu, ApuDebug};
+use super::cartridge::{has_battery, Cartridge, CartridgeError};
+use super::cheats::{self, Cheat, CheatCode};
+use super::gb::{
+    BackgroundLayout, CgbSupport, GameboyError, Hooks, MemoryRegion, OamEntry, StateError,
+    Subsystem, TilePalette, WatchHit, WatchKind, WriteRecord, MAX_WRITE_LOG,
+};
 use super::joypad::Joypad;
+use super::link::SerialLink;
+use super::log_target;
 use super::serial::Serial;
 use super::sink::*;
+use super::state::{SaveState, StateReader, StateWriter};
 use super::timer::Timer;
 use super::vram::Vram;
 use super::wram::Wram;
 
+/// Watchpoint hits kept until taken, more than one instruction's worth of accesses
+const MAX_WATCH_HITS: usize = 8;
+
 /// The possible states of a DMA transfer running within the MMU. Until a write is performed
 /// at 0xFF46, the state will always be `Stopped`. Once a valid write at 0xFF46 occurs, the
 /// state is set to `Starting` to begin during the next MMU update at the provided u8 value.
@@ -30,7 +44,7 @@ enum DmaState {
 ///
 /// Order represents the priority of interrupt execution when multiple
 /// interrupts are enabled and requested at once.
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InterruptKind {
     /// Vertical Blank interrupt whenever the LCD enters the V-Blank period.
     /// (INT 0x40)
@@ -59,6 +73,10 @@ pub trait Memory {
         (u16::from(self.read_byte(addr))) | (u16::from(self.read_byte(addr + 1)) << 8)
     }
     fn write_byte(&mut self, addr: u16, val: u8);
+    /// Called when the CPU's 16-bit increment/decrement unit places `addr` on the address
+    /// bus without reading or writing it, as in INC rr and DEC rr. Does nothing by default,
+    /// only used to emulate the DMG OAM corruption bug.
+    fn idu_access(&mut self, _addr: u16) {}
     fn write_word(&mut self, addr: u16, val: u16) {
         self.write_byte(addr, (val & 0xFF) as u8);
         self.write_byte(addr + 1, (val >> 8) as u8);
@@ -80,89 +98,138 @@ pub struct Mmu {
     serial: Serial,
     hram: [u8; 0x7F],
     intf: u8,
+    /// IE. Only the low 5 bits enable interrupts, but all 8 are stored and read back.
     ie: u8,
     dma_state: DmaState,
     previous_dma: u8,
+    oam_bug_enabled: bool,
+    pub cgb_support: CgbSupport,
+    pub has_battery: bool,
+    /// Boot ROM mapped over 0x0000-0x00FF until a write to 0xFF50 unmaps it
+    boot_rom: Option<Box<[u8]>>,
+    /// Subsystems skipped by `update`, indexed by `Subsystem`
+    frozen: [bool; 3],
+    /// Address of the instruction being executed, to attribute logged writes to
+    instruction_pc: u16,
+    /// Writes to the range being logged, see `Gameboy::log_writes`
+    write_log: Option<(RangeInclusive<u16>, VecDeque<WriteRecord>)>,
+    /// Ranges whose accesses by the CPU are trapped, see `Gameboy::add_watchpoint`
+    watchpoints: Vec<(RangeInclusive<u16>, WatchKind)>,
+    /// Set while the CPU executes an instruction, so only its accesses hit watchpoints and
+    /// not those of debuggers or the MMU itself
+    cpu_access: bool,
+    /// The watchpoint hits since last taken, oldest first and at most `MAX_WATCH_HITS`.
+    /// Reads only borrow the MMU, so it's a RefCell.
+    watch_hits: RefCell<Vec<WatchHit>>,
+    /// Called on each access by the CPU, see `Gameboy::set_hooks`. Reads only borrow the
+    /// MMU, so it's a RefCell.
+    pub hooks: RefCell<Option<Box<dyn Hooks>>>,
+    /// Cheat codes patching the ROM and RAM, see `Gameboy::add_cheat`
+    pub cheats: Vec<Cheat>,
+    /// Set when battery-backed RAM may have changed since it was last stored, see
+    /// `Gameboy::flush_save_data`
+    pub save_dirty: bool,
 }
 
 impl Mmu {
     /// Initializes the MMU with the given ROM path.
     /// Opens the given file and reads cartridge header information to find
     /// the MBC type.
-    pub fn power_on(rom_data: Box<[u8]>, save_data: Option<Box<[u8]>>) -> Self {
+    pub fn power_on(
+        rom_data: Box<[u8]>,
+        save_data: Option<Box<[u8]>>,
+    ) -> Result<Self, GameboyError> {
         use super::cartridge::mbc0::Mbc0;
         use super::cartridge::mbc1::Mbc1;
         use super::cartridge::mbc2::Mbc2;
         use super::cartridge::mbc3::Mbc3;
 
-        let title =
-            core::str::from_utf8(&rom_data[0x134..0x13F]).map_or_else(|_| "Invalid Title", |v| v);
+        let title = core::str::from_utf8(&rom_data[0x134..0x13F]).unwrap_or("Invalid Title");
         let rom_size = rom_data[0x148];
         let ram_size = rom_data[0x149];
-        info!("Cartridge Info:");
-        info!("\tTitle: {}", title);
-        info!("\tROM Size: {} KiB", 32 * (1 << rom_size));
+        info!(target: log_target::MMU, "Cartridge Info:");
+        info!(target: log_target::MMU, "\tTitle: {}", title);
+        info!(target: log_target::MMU, "\tROM Size: {} KiB", 32 * (1 << rom_size));
         match ram_size {
-            0x0 | 0x1 => info!("\tRAM Size: None"),
-            0x2 => info!("\tRAM Size: 8 KiB"),
-            0x3 => info!("\tRAM Size: 32 KiB"),
-            0x4 => info!("\tRAM Size: 128 KiB"),
-            0x5 => info!("\tRAM Size: 64 KiB"),
-            _ => info!("\tRAM Size: Unknown"),
+            0x0 | 0x1 => info!(target: log_target::MMU, "\tRAM Size: None"),
+            0x2 => info!(target: log_target::MMU, "\tRAM Size: 8 KiB"),
+            0x3 => info!(target: log_target::MMU, "\tRAM Size: 32 KiB"),
+            0x4 => info!(target: log_target::MMU, "\tRAM Size: 128 KiB"),
+            0x5 => info!(target: log_target::MMU, "\tRAM Size: 64 KiB"),
+            _ => info!(target: log_target::MMU, "\tRAM Size: Unknown"),
         };
+        let cgb_support = CgbSupport::from_header_flag(rom_data[0x143]);
+        match cgb_support {
+            CgbSupport::None => {}
+            CgbSupport::Enhanced => {
+                info!(target: log_target::MMU, "\tCGB enhanced, running in DMG mode")
+            }
+            CgbSupport::Only => {
+                warn!(target: log_target::MMU, "\tCGB only, may not run in DMG mode")
+            }
+        }
+        let has_battery = has_battery(rom_data[0x147]);
         let mut cart: Box<dyn Cartridge> = match rom_data[0x147] {
             0x00 => {
-                info!("\tMBC Type: MBC0/No MBC.");
+                info!(target: log_target::MMU, "\tMBC Type: MBC0/No MBC.");
                 Box::new(Mbc0::power_on(rom_data))
             }
             0x01 => {
-                info!("\tMBC Type: MBC1 w/o RAM");
+                info!(target: log_target::MMU, "\tMBC Type: MBC1 w/o RAM");
                 Box::new(Mbc1::power_on(rom_data, rom_size, 0, false))
             }
             0x02 => {
-                info!("\tMBC Type: MBC1 w/ RAM");
+                info!(target: log_target::MMU, "\tMBC Type: MBC1 w/ RAM");
                 Box::new(Mbc1::power_on(rom_data, rom_size, ram_size, false))
             }
             0x03 => {
-                info!("\tMBC Type: MBC1 w/ RAM and Battery");
+                info!(target: log_target::MMU, "\tMBC Type: MBC1 w/ RAM and Battery");
                 Box::new(Mbc1::power_on(rom_data, rom_size, ram_size, true))
             }
             0x05 => {
-                info!("\tMBC Type: MBC2");
+                info!(target: log_target::MMU, "\tMBC Type: MBC2");
                 Box::new(Mbc2::power_on(rom_data, rom_size, false))
             }
             0x06 => {
-                info!("\tMBC Type: MBC2 w/ Battery");
+                info!(target: log_target::MMU, "\tMBC Type: MBC2 w/ Battery");
                 Box::new(Mbc2::power_on(rom_data, rom_size, true))
             }
             0x0F => {
-                info!("\tMBC Type: MBC3 w/ RTC + Battery");
+                info!(target: log_target::MMU, "\tMBC Type: MBC3 w/ RTC + Battery");
                 Box::new(Mbc3::power_on(rom_data, rom_size, 0, true, true))
             }
             0x10 => {
-                info!("\tMBC Type: MBC3 w/ RTC + RAM + Battery");
+                info!(target: log_target::MMU, "\tMBC Type: MBC3 w/ RTC + RAM + Battery");
                 Box::new(Mbc3::power_on(rom_data, rom_size, ram_size, true, true))
             }
             0x11 => {
-                info!("\tMBC Type: MBC3");
+                info!(target: log_target::MMU, "\tMBC Type: MBC3");
                 Box::new(Mbc3::power_on(rom_data, rom_size, 0, false, false))
             }
             0x12 => {
-                info!("\tMBC Type: MBC3 w/ RAM");
+                info!(target: log_target::MMU, "\tMBC Type: MBC3 w/ RAM");
                 Box::new(Mbc3::power_on(rom_data, rom_size, ram_size, false, false))
             }
             0x13 => {
-                info!("\tMBC Type: MBC3 w/ RAM + Battery");
+                info!(target: log_target::MMU, "\tMBC Type: MBC3 w/ RAM + Battery");
                 Box::new(Mbc3::power_on(rom_data, rom_size, ram_size, true, false))
             }
-            _ => unimplemented!("MBC value {:02X} not supported!", rom_data[0x147]),
+            mbc => {
+                error!(target: log_target::MMU, "\tMBC Type: {:02X} not supported!", mbc);
+                return Err(GameboyError::UnsupportedMbc(mbc));
+            }
         };
         if let Some(data) = save_data {
-            if let Err(e) = cart.read_save_data(data) {
-                info!("Save file will not be written: {}", e);
+            match cart.read_save_data(data) {
+                Ok(()) => {}
+                // Nothing is kept for carts without a battery, so there's nothing to lose
+                Err(e @ CartridgeError::NotBatteryBacked) => {
+                    warn!(target: log_target::MMU, "Save data not loaded: {}", e)
+                }
+                Err(e) => return Err(GameboyError::SaveData(e)),
             }
         }
-        Mmu {
+        Ok(Mmu {
             cart,
             apu: Apu::power_on(),
             vram: Vram::power_on(),
@@ -175,7 +242,20 @@ impl Mmu {
             ie: 0x00,
             dma_state: DmaState::Stopped,
             previous_dma: 0xFF,
-        }
+            oam_bug_enabled: false,
+            cgb_support,
+            has_battery,
+            boot_rom: None,
+            frozen: [false; 3],
+            instruction_pc: 0,
+            write_log: None,
+            watchpoints: Vec::new(),
+            cpu_access: false,
+            watch_hits: RefCell::new(Vec::new()),
+            hooks: RefCell::new(None),
+            cheats: Vec::new(),
+            save_dirty: false,
+        })
     }
 
     /// Updates all memory components to align with the number of cycles
@@ -188,31 +268,189 @@ impl Mmu {
         &mut self,
         cycles: u32,
         video_sink: &mut dyn Sink<VideoFrame>,
-        audio_sink: &mut dyn Sink<AudioFrame>,
+        audio_sink: &mut dyn Sink<ChannelAudioFrame>,
     ) {
         if self.dma_state != DmaState::Stopped {
             self.dma_state = self.run_dma(cycles);
         }
+        // Update the cartridge's clock, if it has one
+        self.cart.update(cycles);
+
         // Update APU
-        self.apu.update(cycles, audio_sink);
+        if !self.frozen[Subsystem::Apu as usize] {
+            self.apu.update(cycles, audio_sink);
+        }
 
         // Update Joypad
         if let Some(i) = self.joypad.update() {
             self.request_interrupt(i);
         }
 
-        // Update Timers
-        if let Some(i) = self.timer.update(cycles) {
+        // Update Serial
+        if let Some(i) = self.serial.update(cycles) {
             self.request_interrupt(i);
         }
+
+        // Update Timers
+        if !self.frozen[Subsystem::Timer as usize] {
+            if let Some(i) = self.timer.update(cycles) {
+                self.request_interrupt(i);
+            }
+        }
         // Update VRAM
-        if let Some(i) = self.vram.update(cycles, video_sink) {
-            for interrupt in i {
-                self.request_interrupt(interrupt);
+        if !self.frozen[Subsystem::Ppu as usize] {
+            if let Some(i) = self.vram.update(cycles, video_sink) {
+                for interrupt in i {
+                    self.request_interrupt(interrupt);
+                }
             }
         }
     }
 
+    /// Cycles until the next V-Blank, timer overflow or serial transfer completion,
+    /// see `Gameboy::cycles_until_next_event`
+    pub fn cycles_until_next_event(&self) -> Option<u32> {
+        let mut events = [
+            self.vram.cycles_until_vblank(),
+            self.timer.cycles_until_overflow(),
+            self.serial.cycles_until_complete(),
+        ];
+        if self.frozen[Subsystem::Ppu as usize] {
+            events[0] = None;
+        }
+        if self.frozen[Subsystem::Timer as usize] {
+            events[1] = None;
+        }
+        events.into_iter().flatten().min()
+    }
+
+    /// Maps `boot_rom` over 0x0000-0x00FF until it's unmapped through 0xFF50, and returns the
+    /// registers it sets up to how they are at power on: the LCD and APU off and DIV at 0
+    pub fn map_boot_rom(&mut self, boot_rom: Box<[u8]>) {
+        self.write_byte(0xFF40, 0x00);
+        self.write_byte(0xFF26, 0x00);
+        self.write_byte(0xFF47, 0x00);
+        self.timer.set_counter(0);
+        self.boot_rom = Some(boot_rom);
+    }
+
+    pub fn set_instruction_pc(&mut self, pc: u16) {
+        self.instruction_pc = pc;
+    }
+
+    /// Marks the start or end of the CPU executing an instruction, see `cpu_access`
+    pub fn set_cpu_access(&mut self, cpu_access: bool) {
+        self.cpu_access = cpu_access;
+    }
+
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) {
+        self.watchpoints.push((range, kind));
+    }
+
+    pub fn remove_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != (range.clone(), kind));
+        self.watchpoints.len() != len
+    }
+
+    /// Writes the values of the enabled GameShark codes, at the start of each V-Blank
+    pub fn apply_ram_cheats(&mut self) {
+        for i in 0..self.cheats.len() {
+            if let Cheat {
+                code: CheatCode::GameShark { addr, value, .. },
+                enabled: true,
+                ..
+            } = self.cheats[i]
+            {
+                self.write_mapped(addr, value);
+            }
+        }
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.take_watch_hits().into_iter().next()
+    }
+
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        core::mem::take(self.watch_hits.get_mut())
+    }
+
+    /// Records an access by the CPU if it hits a watchpoint, keeping only the first few hits
+    fn check_watchpoints(&self, addr: u16, value: u8, write: bool) {
+        if !self.cpu_access
+            || self.watchpoints.is_empty()
+            || self.watch_hits.borrow().len() >= MAX_WATCH_HITS
+        {
+            return;
+        }
+        let hit = self.watchpoints.iter().any(|(range, kind)| {
+            range.contains(&addr)
+                && match kind {
+                    WatchKind::Read => !write,
+                    WatchKind::Write => write,
+                    WatchKind::ReadWrite => true,
+                }
+        });
+        if hit {
+            self.watch_hits.borrow_mut().push(WatchHit {
+                pc: self.instruction_pc,
+                addr,
+                value,
+                write,
+            });
+        }
+    }
+
+    pub fn log_writes(&mut self, range: Option<RangeInclusive<u16>>) {
+        self.write_log = range.map(|range| (range, VecDeque::new()));
+    }
+
+    pub fn take_write_log(&mut self) -> Vec<WriteRecord> {
+        self.write_log
+            .as_mut()
+            .map(|(_, records)| records.drain(..).collect())
+            .unwrap_or_default()
+    }
+
+    /// Takes the byte last sent over the serial port, see `Serial::take_output`
+    pub fn take_serial_output(&mut self) -> Option<u8> {
+        self.serial.take_output()
+    }
+
+    pub fn connect_serial(&mut self, link: Option<Box<dyn SerialLink>>) {
+        self.serial.connect(link);
+    }
+
+    pub fn set_serial_latency(&mut self, cycles: u32) {
+        self.serial.set_latency(cycles);
+    }
+
+    pub fn set_serial_wait_for_peer(&mut self, wait: bool) {
+        self.serial.set_wait_for_peer(wait);
+    }
+
+    pub fn timer_counter(&self) -> u16 {
+        self.timer.counter()
+    }
+
+    pub fn set_timer_counter(&mut self, counter: u16) {
+        self.timer.set_counter(counter);
+    }
+
+    /// Stops or resumes updating the given subsystem, see `Gameboy::freeze_subsystem`
+    pub fn freeze_subsystem(&mut self, subsystem: Subsystem, frozen: bool) {
+        self.frozen[subsystem as usize] = frozen;
+    }
+
+    /// Used in place of `update` while the CPU is in STOP mode. The system clock is halted,
+    /// so DMA, the APU, timers and the LCD are all frozen, and only the joypad is watched
+    /// for the button press that ends STOP.
+    pub fn update_stopped(&mut self) {
+        if let Some(i) = self.joypad.update() {
+            self.request_interrupt(i);
+        }
+    }
+
     /// Takes the given Interrupt enum value, and sets the corresponding bit
     /// in the IF register. CPU will run interrupt handler on next fetch cycle.
     pub fn request_interrupt(&mut self, int: InterruptKind) {
@@ -236,6 +474,177 @@ impl Mmu {
         vec
     }
 
+    /// Enables or disables emulation of the DMG OAM corruption bug. Disabled by default.
+    pub fn set_oam_bug_enabled(&mut self, enabled: bool) {
+        self.oam_bug_enabled = enabled;
+    }
+
+    /// Enables or disables the limit of 10 OBJs per scanline. Enabled by default.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.vram.set_sprite_limit(enabled);
+    }
+
+    /// Builds the summary of the address space returned by `Gameboy::memory_map`
+    pub fn memory_map(&self) -> Vec<MemoryRegion> {
+        // During OAM DMA only HRAM is accessible to the CPU
+        let dma_free = self.dma_state == DmaState::Stopped;
+        let mode = self.vram.mode();
+        let vram_access = dma_free && mode != 3;
+        let oam_access = dma_free && mode != 2 && mode != 3;
+        let cart_ram = self.cart.ram_bank();
+        let region = |name, start, end, bank, readable, writable| MemoryRegion {
+            name,
+            start,
+            end,
+            bank,
+            readable,
+            writable,
+        };
+        vec![
+            region("ROM Bank 00", 0x0000, 0x3FFF, Some(0), dma_free, false),
+            region(
+                "ROM Bank NN",
+                0x4000,
+                0x7FFF,
+                Some(self.cart.rom_bank()),
+                dma_free,
+                false,
+            ),
+            region("VRAM", 0x8000, 0x9FFF, Some(0), vram_access, vram_access),
+            region(
+                "Cartridge RAM",
+                0xA000,
+                0xBFFF,
+                cart_ram,
+                dma_free && cart_ram.is_some(),
+                dma_free && cart_ram.is_some(),
+            ),
+            region("WRAM", 0xC000, 0xDFFF, Some(0), dma_free, dma_free),
+            region("Echo RAM", 0xE000, 0xFDFF, None, dma_free, dma_free),
+            region("OAM", 0xFE00, 0xFE9F, None, oam_access, oam_access),
+            region("Unusable", 0xFEA0, 0xFEFF, None, false, false),
+            region("I/O Registers", 0xFF00, 0xFF7F, None, dma_free, dma_free),
+            region("HRAM", 0xFF80, 0xFFFE, None, true, true),
+            region("IE Register", 0xFFFF, 0xFFFF, None, dma_free, dma_free),
+        ]
+    }
+
+    /// Returns a snapshot of the IO registers at 0xFF00-0xFF7F as the CPU would read them,
+    /// with unused bits and unmapped registers reading back as 1s. Unlike regular reads, this
+    /// is not blocked by an active DMA transfer.
+    pub fn io_registers(&self) -> [u8; 0x80] {
+        let mut regs = [0xFF; 0x80];
+        for (addr, reg) in (0xFF00u16..=0xFF7F).zip(regs.iter_mut()) {
+            *reg = match addr {
+                0xFF00 | 0xFF01 | 0xFF02 | 0xFF04..=0xFF06 => self.read_io(addr),
+                0xFF07 => self.read_io(addr) | 0xF8,
+                0xFF0F => self.read_io(addr) | 0xE0,
+                0xFF10..=0xFF14
+                | 0xFF16..=0xFF1E
+                | 0xFF20..=0xFF26
+                | 0xFF30..=0xFF3F
+                | 0xFF40..=0xFF4B => self.read_io(addr),
+                _ => 0xFF,
+            };
+        }
+        regs
+    }
+
+    /// Writes a single IO register in 0xFF00-0xFF7F directly to its device, regardless of
+    /// any active DMA transfer.
+    pub fn write_io_register(&mut self, addr: u16, val: u8) {
+        if (0xFF00..=0xFF7F).contains(&addr) {
+            self.write_io(addr, val);
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.set_sample_rate(sample_rate);
+    }
+
+    pub fn set_sample_output(&mut self, enabled: bool) {
+        self.apu.set_sample_output(enabled);
+    }
+
+    pub fn apu_debug_state(&self) -> ApuDebug {
+        self.apu.debug_state()
+    }
+
+    pub fn set_channel_enabled(&mut self, channel: usize, enabled: bool) {
+        self.apu.set_channel_enabled(channel, enabled);
+    }
+
+    pub fn channel_enabled(&self, channel: usize) -> bool {
+        self.apu.channel_enabled(channel)
+    }
+
+    /// Returns the current PPU mode, 0-3
+    pub fn ppu_mode(&self) -> u8 {
+        self.vram.mode()
+    }
+
+    /// Returns the scanline currently being processed by the PPU
+    pub fn current_scanline(&self) -> u8 {
+        self.vram.ly()
+    }
+
+    /// Returns the RGB screen data currently held by the PPU
+    pub fn screen(&self) -> &[u8] {
+        self.vram.screen()
+    }
+
+    pub fn screen_indices(&self) -> &[u8] {
+        self.vram.screen_indices()
+    }
+
+    /// Debug function. Renders all tile data, see `Vram::dump_tiles`.
+    pub fn dump_tiles(&self, palette: TilePalette) -> VideoFrame {
+        self.vram.dump_tiles(palette)
+    }
+
+    /// The raw VRAM bytes, see `Gameboy::vram`
+    pub fn vram(&self) -> &[u8] {
+        self.vram.memory()
+    }
+
+    /// The raw OAM bytes, see `Gameboy::oam`
+    pub fn oam(&self) -> &[u8] {
+        self.vram.oam()
+    }
+
+    /// The memory kept in save states, as stored rather than as the CPU would read it, named
+    /// like in `memory_map`. Cartridge RAM is only included for cartridges with a battery.
+    pub fn stored_memory(&self) -> Vec<(&'static str, Vec<u8>)> {
+        let mut regions = vec![("VRAM", self.vram.memory().to_vec())];
+        if let Ok(data) = self.cart.write_save_data() {
+            regions.push(("Cartridge RAM", data.into_vec()));
+        }
+        let wram = (0xC000..=0xDFFF).map(|addr| self.wram.read_byte(addr));
+        regions.push(("WRAM", wram.collect()));
+        regions.push(("OAM", self.vram.oam().to_vec()));
+        regions.push(("I/O Registers", self.io_registers().to_vec()));
+        regions.push(("HRAM", self.hram.to_vec()));
+        regions.push(("IE Register", vec![self.ie]));
+        regions
+    }
+
+    /// Debug function. Renders the selected background tile map, see `Vram::dump_tilemap`.
+    pub fn dump_tilemap(&self, map_select: bool) -> VideoFrame {
+        self.vram.dump_tilemap(map_select)
+    }
+
+    pub fn oam_entries(&self) -> Vec<OamEntry> {
+        self.vram.oam_entries()
+    }
+
+    pub fn tile_pixels(&self, index: usize) -> [u8; 64] {
+        self.vram.tile_pixels(index)
+    }
+
+    pub fn background_layout(&self) -> BackgroundLayout {
+        self.vram.background_layout()
+    }
+
     /// Run the DMA for the remaining
     /// 671 cycles roughly needed for full DMA transfer.
     /// It takes about 160 us for a full DMA, which is a little more than
@@ -265,7 +674,7 @@ impl Mmu {
                     let src_addr = addr + i as u16;
                     if src_addr & 0xFF >= 0xA0 {
                         // DMA complete, return Stopped
-                        trace!("DMA Transfer complete.");
+                        trace!(target: log_target::MMU, "DMA Transfer complete.");
                         return DmaState::Stopped;
                     } else {
                         let val = match src_addr {
@@ -285,13 +694,55 @@ impl Mmu {
         }
     }
 
+    /// Routes a read within the IO register range 0xFF00-0xFF7F to its device
+    fn read_io(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF00 => self.joypad.read_byte(addr),
+            0xFF01..=0xFF02 => self.serial.read_byte(addr),
+            0xFF04..=0xFF07 => self.timer.read_byte(addr),
+            0xFF0F => self.intf,
+            0xFF10..=0xFF3F => self.apu.read_byte(addr),
+            0xFF46 => self.previous_dma,
+            // Boot ROM disable. Not readable on the DMG, and reads back as open bus
+            // whether or not a boot ROM is mapped.
+            0xFF50 => 0xFF,
+            0xFF40..=0xFF6F => self.vram.read_byte(addr),
+            _ => self.unassigned_read(addr),
+        }
+    }
+
+    /// Routes a write within the IO register range 0xFF00-0xFF7F to its device
+    fn write_io(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF00 => self.joypad.write_byte(addr, val),
+            0xFF01..=0xFF02 => self.serial.write_byte(addr, val),
+            0xFF04..=0xFF07 => self.timer.write_byte(addr, val),
+            0xFF0F => self.intf = val,
+            0xFF10..=0xFF3F => self.apu.write_byte(addr, val),
+            0xFF46 => {
+                trace!(target: log_target::MMU, "Beginning DMA Transfer at {:2X}00...", val);
+                self.dma_state = DmaState::Starting(val);
+                self.previous_dma = val;
+            }
+            0xFF50 => {
+                // Any write with bit 0 set unmaps the boot ROM until the next power on.
+                // Writes are harmless when no boot ROM is mapped.
+                if val & 0x1 != 0 && self.boot_rom.take().is_some() {
+                    info!(target: log_target::MMU, "Boot ROM unmapped.");
+                }
+            }
+            0xFF40..=0xFF6F => self.vram.write_byte(addr, val),
+            _ => self.unassigned_write(addr, val),
+        }
+    }
+
     fn unassigned_read(&self, addr: u16) -> u8 {
-        error!("Memory Read at unassigned location {:4X}", addr);
+        error!(target: log_target::MMU, "Memory Read at unassigned location {:4X}", addr);
         0xFF
     }
 
     fn unassigned_write(&mut self, addr: u16, val: u8) {
-        error!(
+        error!(target: log_target::MMU,
             "Memory Write at unassigned location {:4X} of value {:2X}",
             addr, val
         );
@@ -300,53 +751,83 @@ impl Mmu {
 
 impl Memory for Mmu {
     fn read_byte(&self, addr: u16) -> u8 {
+        let value = self.read_mapped(addr);
+        self.check_watchpoints(addr, value, false);
+        if self.cpu_access {
+            if let Some(hooks) = self.hooks.borrow_mut().as_mut() {
+                hooks.on_read(addr, value);
+            }
+        }
+        value
+    }
+    fn write_byte(&mut self, addr: u16, val: u8) {
+        self.check_watchpoints(addr, val, true);
+        if self.cpu_access {
+            if let Some(hooks) = self.hooks.get_mut() {
+                hooks.on_write(addr, val);
+            }
+        }
+        self.write_mapped(addr, val);
+    }
+    fn idu_access(&mut self, addr: u16) {
+        // Only DMG hardware corrupts OAM, which is the only mode gabe runs in
+        if self.oam_bug_enabled && (0xFE00..=0xFEFF).contains(&addr) {
+            self.vram.oam_bug_write();
+        }
+    }
+}
+
+impl Mmu {
+    fn read_mapped(&self, addr: u16) -> u8 {
         if self.dma_state != DmaState::Stopped && !(0xFF80..=0xFFFE).contains(&addr) {
-            warn!(
+            warn!(target: log_target::MMU,
                 "CPU attempting read at {:4X} during DMA, returning 0xFF",
                 addr
             );
             0xFF
         } else {
             match addr {
-                0x0000..=0x7FFF => self.cart.read_byte(addr),
+                0x0000..=0x00FF if self.boot_rom.is_some() => {
+                    self.boot_rom.as_ref().unwrap()[addr as usize]
+                }
+                0x0000..=0x7FFF => cheats::patch_rom(&self.cheats, addr, self.cart.read_byte(addr)),
                 0x8000..=0x9FFF => self.vram.read_byte(addr),
                 0xA000..=0xBFFF => self.cart.read_byte(addr),
                 0xC000..=0xFDFF => self.wram.read_byte(addr),
                 0xFE00..=0xFE9F => self.vram.read_byte(addr),
-                0xFF00 => self.joypad.read_byte(addr),
-                0xFF01..=0xFF02 => self.serial.read_byte(addr),
-                0xFF04..=0xFF07 => self.timer.read_byte(addr),
-                0xFF0F => self.intf,
-                0xFF10..=0xFF3F => self.apu.read_byte(addr),
-                0xFF46 => self.previous_dma,
-                0xFF40..=0xFF6F => self.vram.read_byte(addr),
+                0xFF00..=0xFF7F => self.read_io(addr),
                 0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize],
                 0xFFFF => self.ie,
                 _ => self.unassigned_read(addr),
             }
         }
     }
-    fn write_byte(&mut self, addr: u16, val: u8) {
+    fn write_mapped(&mut self, addr: u16, val: u8) {
         if self.dma_state != DmaState::Stopped && !(0xFF80..=0xFFFE).contains(&addr) {
-            warn!("CPU attempting write at {:4X} during DMA, ignoring.", addr);
+            warn!(target: log_target::MMU, "CPU attempting write at {:4X} during DMA, ignoring.", addr);
         } else {
+            if let Some((range, records)) = self.write_log.as_mut() {
+                if range.contains(&addr) {
+                    if records.len() == MAX_WRITE_LOG {
+                        records.pop_front();
+                    }
+                    records.push_back(WriteRecord {
+                        pc: self.instruction_pc,
+                        addr,
+                        value: val,
+                    });
+                }
+            }
             match addr {
                 0x0000..=0x7FFF => self.cart.write_byte(addr, val),
                 0x8000..=0x9FFF => self.vram.write_byte(addr, val),
-                0xA000..=0xBFFF => self.cart.write_byte(addr, val),
+                0xA000..=0xBFFF => {
+                    self.save_dirty |= self.has_battery;
+                    self.cart.write_byte(addr, val)
+                }
                 0xC000..=0xFDFF => self.wram.write_byte(addr, val),
                 0xFE00..=0xFE9F => self.vram.write_byte(addr, val),
-                0xFF00 => self.joypad.write_byte(addr, val),
-                0xFF01..=0xFF02 => self.serial.write_byte(addr, val),
-                0xFF04..=0xFF07 => self.timer.write_byte(addr, val),
-                0xFF0F => self.intf = val,
-                0xFF10..=0xFF3F => self.apu.write_byte(addr, val),
-                0xFF46 => {
-                    trace!("Beginning DMA Transfer at {:2X}00...", val);
-                    self.dma_state = DmaState::Starting(val);
-                    self.previous_dma = val;
-                }
-                0xFF40..=0xFF6F => self.vram.write_byte(addr, val),
+                0xFF00..=0xFF7F => self.write_io(addr, val),
                 0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize] = val,
                 0xFFFF => self.ie = val,
                 _ => self.unassigned_write(addr, val),
@@ -355,8 +836,102 @@ impl Memory for Mmu {
     }
 }
 
+impl SaveState for Mmu {
+    /// Options such as frozen subsystems and the OAM bug are left as set by the host
+    fn save_state(&self, w: &mut StateWriter) {
+        self.cart.save_state(w);
+        self.apu.save_state(w);
+        self.vram.save_state(w);
+        self.wram.save_state(w);
+        self.timer.save_state(w);
+        self.joypad.save_state(w);
+        self.serial.save_state(w);
+        w.bytes(&self.hram);
+        w.u8(self.intf);
+        w.u8(self.ie);
+        match self.dma_state {
+            DmaState::Stopped => {
+                w.u8(0);
+                w.u16(0);
+            }
+            DmaState::Starting(high) => {
+                w.u8(1);
+                w.u16(high as u16);
+            }
+            DmaState::Running(addr) => {
+                w.u8(2);
+                w.u16(addr);
+            }
+        }
+        w.u8(self.previous_dma);
+        w.bytes(self.boot_rom.as_deref().unwrap_or_default());
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.cart.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.vram.load_state(r)?;
+        self.wram.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.serial.load_state(r)?;
+        r.bytes_into(&mut self.hram)?;
+        self.intf = r.u8()?;
+        self.ie = r.u8()?;
+        self.dma_state = match (r.u8()?, r.u16()?) {
+            (0, _) => DmaState::Stopped,
+            (1, high) => DmaState::Starting(high as u8),
+            (2, addr) => DmaState::Running(addr),
+            _ => return Err(StateError::Corrupt),
+        };
+        self.previous_dma = r.u8()?;
+        // The boot ROM stays mapped until the game unmaps it, so it's kept along with the state
+        self.boot_rom = match r.bytes()? {
+            [] => None,
+            boot_rom if boot_rom.len() == 0x100 => Some(boot_rom.into()),
+            _ => return Err(StateError::Corrupt),
+        };
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod mmu_tests {
+    use super::*;
+    use alloc::vec;
+
     #[test]
     fn interrupt_requests() {}
+
+    #[test]
+    fn ie_upper_bits() {
+        let mut mmu = Mmu::power_on(vec![0x00; 0x8000].into_boxed_slice(), None).unwrap();
+        // All 8 bits of IE are kept, unlike IF whose upper bits always read set
+        mmu.write_byte(0xFFFF, 0xFF);
+        assert_eq!(mmu.read_byte(0xFFFF), 0xFF);
+        mmu.write_byte(0xFFFF, 0xA0);
+        assert_eq!(mmu.read_byte(0xFFFF), 0xA0);
+    }
+
+    #[test]
+    fn boot_rom_disable() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0000..0x0004].copy_from_slice(&[0xC3, 0x00, 0x01, 0x00]);
+        let mut mmu = Mmu::power_on(rom.into_boxed_slice(), None).unwrap();
+        // No boot ROM, writes are accepted and ignored
+        mmu.write_byte(0xFF50, 0x01);
+        assert_eq!(mmu.read_byte(0xFF50), 0xFF);
+        assert_eq!(mmu.read_byte(0x0000), 0xC3);
+
+        mmu.boot_rom = Some(vec![0x31; 0x100].into_boxed_slice());
+        assert_eq!(mmu.read_byte(0x0000), 0x31);
+        assert_eq!(mmu.read_byte(0x00FF), 0x31);
+        assert_eq!(mmu.read_byte(0x0100), 0x00);
+        // Bit 0 clear leaves it mapped
+        mmu.write_byte(0xFF50, 0x00);
+        assert_eq!(mmu.read_byte(0x0000), 0x31);
+        mmu.write_byte(0xFF50, 0x01);
+        assert_eq!(mmu.get_memory_range(0..4), [0xC3, 0x00, 0x01, 0x00]);
+        assert_eq!(mmu.read_byte(0xFF50), 0xFF);
+    }
 }
@@ -0,0 +1,153 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::gb::StateError;
+use super::state::{StateReader, StateWriter};
+
+/// Identifies the start of a movie file
+const MOVIE_MAGIC: [u8; 4] = *b"GBMV";
+
+/// Version of the movie format written by `Movie::to_bytes`
+pub const MOVIE_VERSION: u16 = 1;
+
+/// The buttons held each frame of a recording, along with the save state it starts from, so
+/// it plays back exactly the same way every time. Recorded and played by `Gameboy` with
+/// `start_movie_recording` and `play_movie`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    /// Buttons held each frame, one bit per button indexed by `GbKeys`
+    pub frames: Vec<u8>,
+    /// Number of times the recording was rewound by loading a save state
+    pub rerecords: u32,
+    /// Save state the recording starts from
+    pub(crate) start_state: Vec<u8>,
+    /// The `Gameboy` frame count at `start_state`, so states loaded while recording or playing
+    /// can be matched up with a frame of the movie
+    pub(crate) start_frame: u64,
+    /// Hash of the ROM it was recorded on
+    pub(crate) rom_hash: u64,
+}
+
+/// Error type representing why a movie could not be read or played
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieError {
+    /// The data doesn't start like a movie
+    NotAMovie,
+    /// The movie was written by a different version of the format
+    UnsupportedVersion(u16),
+    /// The movie was recorded on a different ROM
+    WrongGame,
+    /// The movie is truncated or has data after its end
+    Corrupt,
+    /// The save state the movie starts from couldn't be loaded
+    StartState(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::NotAMovie => write!(f, "Not a movie"),
+            MovieError::UnsupportedVersion(version) => write!(
+                f,
+                "Movie is version {}, only version {} is supported",
+                version, MOVIE_VERSION
+            ),
+            MovieError::WrongGame => write!(f, "Movie is for a different game"),
+            MovieError::Corrupt => write!(f, "Movie is corrupt"),
+            MovieError::StartState(e) => write!(f, "Movie's starting state: {}", e),
+        }
+    }
+}
+
+impl Movie {
+    /// Length of the movie in frames
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Serializes the movie, to save to a file and read back with `from_bytes`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        for b in MOVIE_MAGIC {
+            w.u8(b);
+        }
+        w.u16(MOVIE_VERSION);
+        w.u64(self.rom_hash);
+        w.u64(self.start_frame);
+        w.u32(self.rerecords);
+        w.bytes(&self.start_state);
+        w.bytes(&self.frames);
+        w.into_inner()
+    }
+
+    /// Reads a movie written by `to_bytes`
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        if !data.starts_with(&MOVIE_MAGIC) {
+            return Err(MovieError::NotAMovie);
+        }
+        let mut r = StateReader::new(&data[MOVIE_MAGIC.len()..]);
+        let version = r.u16().map_err(|_| MovieError::Corrupt)?;
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        Movie::read_body(&mut r).map_err(|_| MovieError::Corrupt)
+    }
+
+    fn read_body(r: &mut StateReader<'_>) -> Result<Movie, StateError> {
+        let movie = Movie {
+            rom_hash: r.u64()?,
+            start_frame: r.u64()?,
+            rerecords: r.u32()?,
+            start_state: r.bytes()?.to_vec(),
+            frames: r.bytes()?.to_vec(),
+        };
+        r.finish()?;
+        Ok(movie)
+    }
+}
+
+/// Where a `Gameboy` is with its movie, from `Gameboy::movie_status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieStatus {
+    /// No movie is being recorded or played
+    Inactive,
+    /// Recording, with `frame` frames recorded so far
+    Recording { frame: usize },
+    /// Playing, with `frame` of `length` frames played. Input is back with the host once all
+    /// frames have been played.
+    Playing { frame: usize, length: usize },
+}
+
+#[cfg(test)]
+mod movie_tests {
+    use super::*;
+
+    #[test]
+    fn bytes() {
+        let movie = Movie {
+            frames: vec![0, 0x80, 0x81, 0],
+            rerecords: 3,
+            start_state: vec![1, 2, 3],
+            start_frame: 42,
+            rom_hash: 0x1234,
+        };
+        let data = movie.to_bytes();
+        assert_eq!(Movie::from_bytes(&data), Ok(movie));
+
+        assert_eq!(Movie::from_bytes(b"GABE"), Err(MovieError::NotAMovie));
+        assert_eq!(
+            Movie::from_bytes(&data[..data.len() - 1]),
+            Err(MovieError::Corrupt)
+        );
+        let mut newer = data.clone();
+        newer[4] += 1;
+        assert_eq!(
+            Movie::from_bytes(&newer),
+            Err(MovieError::UnsupportedVersion(MOVIE_VERSION + 1))
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! ROM patching, applied to the ROM data before it's handed to `Gameboy::power_on`.
+//! Currently only the IPS format is supported.
+
+use alloc::boxed::Box;
+use alloc::fmt;
+
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+
+/// Error type representing possible errors when applying a patch
+#[derive(Debug, PartialEq, Eq)]
+pub enum PatchError {
+    /// The patch doesn't start with the expected header for its format
+    InvalidHeader,
+    /// The patch ended in the middle of a record, or without an end marker
+    Truncated,
+    /// A record writes past the end of the ROM, given as (offset, length)
+    OutOfBounds(usize, usize),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchError::InvalidHeader => write!(f, "Patch has an invalid header"),
+            PatchError::Truncated => write!(f, "Patch data ended unexpectedly"),
+            PatchError::OutOfBounds(offset, len) => write!(
+                f,
+                "Patch record at {:06X} of length {} is outside of the ROM",
+                offset, len
+            ),
+        }
+    }
+}
+
+/// Applies an IPS patch to `rom_data`, returning the patched ROM.
+///
+/// IPS patches are a "PATCH" header followed by records of a 3-byte offset and 2-byte length,
+/// then that many bytes to write. A length of 0 marks an RLE record, with a 2-byte run length
+/// and a single byte to repeat. Records end with "EOF". All values are big-endian.
+/// Records must lie within the original ROM, as the ROM size is fixed by the cartridge header.
+/// The optional truncation extension after "EOF" is ignored.
+pub fn apply_ips(rom_data: &[u8], patch: &[u8]) -> Result<Box<[u8]>, PatchError> {
+    if !patch.starts_with(IPS_HEADER) {
+        return Err(PatchError::InvalidHeader);
+    }
+    let mut rom = Box::<[u8]>::from(rom_data);
+    let mut pos = IPS_HEADER.len();
+
+    // Reads `len` bytes from the patch as a big-endian value
+    let read = |pos: &mut usize, len: usize| -> Result<usize, PatchError> {
+        let bytes = patch.get(*pos..*pos + len).ok_or(PatchError::Truncated)?;
+        *pos += len;
+        Ok(bytes.iter().fold(0, |acc, &b| (acc << 8) | b as usize))
+    };
+
+    loop {
+        if patch.get(pos..pos + IPS_EOF.len()) == Some(IPS_EOF) {
+            return Ok(rom);
+        }
+        let offset = read(&mut pos, 3)?;
+        let len = read(&mut pos, 2)?;
+        if len == 0 {
+            // RLE record
+            let run_len = read(&mut pos, 2)?;
+            let value = read(&mut pos, 1)? as u8;
+            rom.get_mut(offset..offset + run_len)
+                .ok_or(PatchError::OutOfBounds(offset, run_len))?
+                .fill(value);
+        } else {
+            let data = patch.get(pos..pos + len).ok_or(PatchError::Truncated)?;
+            pos += len;
+            rom.get_mut(offset..offset + len)
+                .ok_or(PatchError::OutOfBounds(offset, len))?
+                .copy_from_slice(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod patch_tests {
+    use super::*;
+
+    #[test]
+    fn ips_records() {
+        let rom = [0u8; 0x200];
+        let mut patch = b"PATCH".to_vec();
+        // Write 3 bytes at 0x150
+        patch.extend_from_slice(&[0x00, 0x01, 0x50, 0x00, 0x03, 0xAA, 0xBB, 0xCC]);
+        // Fill 4 bytes at 0x1F0 with 0x77
+        patch.extend_from_slice(&[0x00, 0x01, 0xF0, 0x00, 0x00, 0x00, 0x04, 0x77]);
+        patch.extend_from_slice(b"EOF");
+
+        let patched = apply_ips(&rom, &patch).unwrap();
+        assert_eq!(patched.len(), rom.len());
+        assert_eq!(&patched[0x150..0x153], &[0xAA, 0xBB, 0xCC]);
+        assert_eq!(&patched[0x1F0..0x1F4], &[0x77; 4]);
+        assert_eq!(patched[0x14F], 0x00);
+        assert_eq!(patched[0x153], 0x00);
+        assert_eq!(patched[0x1F4], 0x00);
+    }
+
+    #[test]
+    fn ips_errors() {
+        let rom = [0u8; 0x200];
+        assert_eq!(apply_ips(&rom, b"PACTH"), Err(PatchError::InvalidHeader));
+        // Missing EOF
+        assert_eq!(apply_ips(&rom, b"PATCH"), Err(PatchError::Truncated));
+        // Record data cut short
+        let patch = b"PATCH\x00\x01\x00\x00\x04\x01\x02EOF";
+        assert_eq!(apply_ips(&rom, patch), Err(PatchError::Truncated));
+        // Past the end of the ROM
+        let patch = b"PATCH\x00\x01\xFF\x00\x02\x01\x02EOF";
+        assert_eq!(
+            apply_ips(&rom, patch),
+            Err(PatchError::OutOfBounds(0x1FF, 2))
+        );
+    }
+}
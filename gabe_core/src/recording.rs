@@ -0,0 +1,441 @@
+use alloc::vec::Vec;
+
+use super::gb::FRAME_CYCLES;
+use super::sink::{AudioFrame, Sink, VideoFrame};
+use super::CLOCK_RATE;
+
+/// Width of frames from the PPU
+const WIDTH: usize = 160;
+/// Height of frames from the PPU
+const HEIGHT: usize = 144;
+
+/// Shortest delay in centiseconds a GIF frame can have. Browsers show frames with shorter
+/// delays for 100ms instead, so frames are dropped to keep every delay at least this long.
+const MIN_DELAY: u64 = 2;
+
+/// Largest code the LZW compression in a GIF can use, which is 12 bits
+const MAX_CODE: usize = 0xFFF;
+
+/// A video sink that encodes the frames it's given into an animated GIF, timed to play at
+/// the Game Boy's 59.73 frames per second. GIF frame delays are whole centiseconds and can't
+/// be shorter than `MIN_DELAY`, so each frame is held for as long as it would have been on
+/// screen and frames that come too soon after the last are dropped.
+/// Frames are compressed as they arrive, with up to 256 colors each.
+pub struct RecordingSink {
+    gif: Vec<u8>,
+    /// Frame shown from `pending_start` onwards, written once it's known how long it lasts
+    pending: Option<VideoFrame>,
+    /// Time in centiseconds the pending frame started, rounded
+    pending_start: u64,
+    /// Number of frames given so far, including those dropped
+    frames: u64,
+    /// Frames after which the sink is full and ignores any more, or `None` for no limit
+    max_frames: Option<u64>,
+}
+
+impl Default for RecordingSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&(WIDTH as u16).to_le_bytes());
+        gif.extend_from_slice(&(HEIGHT as u16).to_le_bytes());
+        // No global color table, each frame has its own. Background color 0, square pixels.
+        gif.extend_from_slice(&[0x00, 0x00, 0x00]);
+        // Loop forever
+        gif.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+        RecordingSink {
+            gif,
+            pending: None,
+            pending_start: 0,
+            frames: 0,
+            max_frames: None,
+        }
+    }
+
+    /// Creates a sink that stops taking frames after `seconds` seconds of play
+    pub fn with_seconds(seconds: u32) -> Self {
+        let mut sink = Self::new();
+        sink.max_frames = Some(seconds as u64 * CLOCK_RATE as u64 / FRAME_CYCLES as u64);
+        sink
+    }
+
+    /// Number of frames given so far, including any dropped to keep the timing
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Whether the time limit given to `with_seconds` has been reached
+    pub fn is_full(&self) -> bool {
+        self.max_frames.map_or(false, |max| self.frames >= max)
+    }
+
+    /// Writes out the last frame and returns the finished GIF
+    pub fn finish(mut self) -> Vec<u8> {
+        if let Some(frame) = self.pending.take() {
+            let delay = (frame_time(self.frames) - self.pending_start).max(MIN_DELAY);
+            self.write_frame(&frame, delay);
+        }
+        self.gif.push(0x3B);
+        self.gif
+    }
+
+    /// Appends a frame shown for `delay` centiseconds
+    fn write_frame(&mut self, frame: &[u8], delay: u64) {
+        let (palette, indices) = index_colors(frame);
+        // Enough bits per pixel to cover the palette, at least 1
+        let bits = (usize::BITS - (palette.len() - 1).leading_zeros()).max(1);
+
+        // Graphic control extension, setting the delay and leaving the frame in place
+        self.gif.extend_from_slice(&[0x21, 0xF9, 0x04, 0x04]);
+        self.gif
+            .extend_from_slice(&(delay.min(u16::MAX as u64) as u16).to_le_bytes());
+        self.gif.extend_from_slice(&[0x00, 0x00]);
+
+        // Image descriptor covering the whole screen, with a local color table
+        self.gif.push(0x2C);
+        for v in [0, 0, WIDTH as u16, HEIGHT as u16] {
+            self.gif.extend_from_slice(&v.to_le_bytes());
+        }
+        self.gif.push(0x80 | (bits - 1) as u8);
+        for i in 0..1 << bits {
+            self.gif
+                .extend_from_slice(&palette.get(i).copied().unwrap_or_default());
+        }
+
+        // The LZW code size can't be less than 2, even with a 2 color palette
+        let min_code_size = bits.max(2);
+        self.gif.push(min_code_size as u8);
+        for block in lzw_compress(&indices, min_code_size).chunks(255) {
+            self.gif.push(block.len() as u8);
+            self.gif.extend_from_slice(block);
+        }
+        self.gif.push(0x00);
+    }
+}
+
+impl Sink<VideoFrame> for RecordingSink {
+    fn append(&mut self, value: VideoFrame) {
+        if self.is_full() {
+            return;
+        }
+        let start = frame_time(self.frames);
+        self.frames += 1;
+        match self.pending.take() {
+            None => {
+                self.pending = Some(value);
+                self.pending_start = start;
+            }
+            Some(pending) if start - self.pending_start >= MIN_DELAY => {
+                self.write_frame(&pending, start - self.pending_start);
+                self.pending = Some(value);
+                self.pending_start = start;
+            }
+            // Too soon to show another frame
+            Some(pending) => self.pending = Some(pending),
+        }
+    }
+}
+
+/// An audio sink that stores the samples it's given as a stereo WAV file of 32-bit floats,
+/// exactly as the APU output them, for comparing captures sample by sample
+pub struct WavSink {
+    /// Samples so far, little-endian and interleaved left then right
+    data: Vec<u8>,
+    sample_rate: u32,
+}
+
+impl WavSink {
+    /// Creates a sink for audio output at `sample_rate`, which should match the rate given
+    /// to `Gameboy::set_sample_rate`
+    pub fn new(sample_rate: u32) -> Self {
+        WavSink {
+            data: Vec::new(),
+            sample_rate,
+        }
+    }
+
+    /// Number of frames given so far
+    pub fn frames(&self) -> usize {
+        self.data.len() / 8
+    }
+
+    /// Returns the finished WAV file
+    pub fn finish(self) -> Vec<u8> {
+        let mut wav = b"RIFF".to_vec();
+        let riff_len = 4 + (8 + 18) + (8 + 4) + (8 + self.data.len());
+        wav.extend_from_slice(&(riff_len as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+
+        // IEEE float format, 2 channels of 4 byte samples, with no extra format data
+        wav.extend_from_slice(b"fmt ");
+        wav.extend_from_slice(&18u32.to_le_bytes());
+        wav.extend_from_slice(&3u16.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&self.sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(self.sample_rate * 8).to_le_bytes());
+        wav.extend_from_slice(&8u16.to_le_bytes());
+        wav.extend_from_slice(&32u16.to_le_bytes());
+        wav.extend_from_slice(&0u16.to_le_bytes());
+
+        // Formats other than integer PCM also need the length in frames
+        wav.extend_from_slice(b"fact");
+        wav.extend_from_slice(&4u32.to_le_bytes());
+        wav.extend_from_slice(&(self.frames() as u32).to_le_bytes());
+
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&self.data);
+        wav
+    }
+}
+
+impl Sink<AudioFrame> for WavSink {
+    fn append(&mut self, value: AudioFrame) {
+        self.data.extend_from_slice(&value.0.to_le_bytes());
+        self.data.extend_from_slice(&value.1.to_le_bytes());
+    }
+}
+
+/// Time in centiseconds, rounded, that frame `frame` of a recording starts
+fn frame_time(frame: u64) -> u64 {
+    (frame * 100 * FRAME_CYCLES as u64 + CLOCK_RATE as u64 / 2) / CLOCK_RATE as u64
+}
+
+/// Splits an RGB frame into a palette and an index into it for each pixel. Colors past the
+/// 256 a GIF frame can have are replaced by the closest one already in the palette.
+fn index_colors(frame: &[u8]) -> (Vec<[u8; 3]>, Vec<u8>) {
+    let mut palette: Vec<[u8; 3]> = Vec::new();
+    let indices = frame
+        .chunks_exact(3)
+        .map(|pixel| {
+            let color = [pixel[0], pixel[1], pixel[2]];
+            if let Some(i) = palette.iter().position(|c| *c == color) {
+                i as u8
+            } else if palette.len() < 256 {
+                palette.push(color);
+                (palette.len() - 1) as u8
+            } else {
+                closest_color(&palette, color)
+            }
+        })
+        .collect();
+    if palette.is_empty() {
+        palette.push([0; 3]);
+    }
+    (palette, indices)
+}
+
+fn closest_color(palette: &[[u8; 3]], color: [u8; 3]) -> u8 {
+    let distance = |c: &[u8; 3]| -> u32 {
+        c.iter()
+            .zip(color)
+            .map(|(a, b)| (*a as i32 - b as i32).pow(2) as u32)
+            .sum()
+    };
+    (0..palette.len())
+        .min_by_key(|&i| distance(&palette[i]))
+        .unwrap_or(0) as u8
+}
+
+/// Compresses color indices with the variable-width LZW used by GIF, returning the packed
+/// codes before they're split into blocks
+fn lzw_compress(indices: &[u8], min_code_size: u32) -> Vec<u8> {
+    let mut out = BitWriter::default();
+    let alphabet = 1usize << min_code_size;
+    let clear = alphabet;
+    let end = clear + 1;
+    // Code for each string extended by one more index, at `code * alphabet + index`. 0 means
+    // there's no code for it yet, as codes for strings longer than one index start after `end`.
+    let mut table = vec![0u16; (MAX_CODE + 1) * alphabet];
+    let mut next = end + 1;
+    let mut code_size = min_code_size + 1;
+
+    out.write(clear, code_size);
+    let Some((&first, rest)) = indices.split_first() else {
+        out.write(end, code_size);
+        return out.finish();
+    };
+    let mut prefix = first as usize;
+    for &index in rest {
+        let entry = prefix * alphabet + index as usize;
+        if table[entry] != 0 {
+            prefix = table[entry] as usize;
+            continue;
+        }
+        out.write(prefix, code_size);
+        table[entry] = next as u16;
+        next += 1;
+        // The decoder adds each code a step behind, so it widens the codes one later too
+        if next == (1 << code_size) + 1 && code_size < 12 {
+            code_size += 1;
+        }
+        if next > MAX_CODE {
+            out.write(clear, code_size);
+            table.fill(0);
+            next = end + 1;
+            code_size = min_code_size + 1;
+        }
+        prefix = index as usize;
+    }
+    out.write(prefix, code_size);
+    out.write(end, code_size);
+    out.finish()
+}
+
+/// Packs codes into bytes least significant bit first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+    len: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: usize, size: u32) {
+        self.bits |= (code as u32) << self.len;
+        self.len += size;
+        while self.len >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.bytes.push(self.bits as u8);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod recording_tests {
+    use super::*;
+
+    /// Decodes GIF LZW data back into color indices
+    fn lzw_decompress(data: &[u8], min_code_size: u32) -> Vec<u8> {
+        let clear = 1usize << min_code_size;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let reset = |table: &mut Vec<Vec<u8>>| {
+            *table = (0..clear + 2).map(|i| vec![i as u8]).collect();
+        };
+        reset(&mut table);
+        let (mut pos, mut code_size) = (0, min_code_size + 1);
+        let mut prev: Option<Vec<u8>> = None;
+        let mut out = Vec::new();
+        loop {
+            let mut code = 0;
+            for i in 0..code_size as usize {
+                let bit = data[(pos + i) / 8] >> ((pos + i) % 8) & 1;
+                code |= (bit as usize) << i;
+            }
+            pos += code_size as usize;
+            if code == clear {
+                reset(&mut table);
+                code_size = min_code_size + 1;
+                prev = None;
+                continue;
+            }
+            if code == clear + 1 {
+                return out;
+            }
+            let string = match (table.get(code), &prev) {
+                (Some(string), _) => string.clone(),
+                (None, Some(prev)) => [&prev[..], &prev[..1]].concat(),
+                (None, None) => panic!("Undefined code {}", code),
+            };
+            out.extend_from_slice(&string);
+            if let Some(prev) = prev {
+                if table.len() <= MAX_CODE {
+                    table.push([&prev[..], &string[..1]].concat());
+                }
+            }
+            if table.len() == 1 << code_size && code_size < 12 {
+                code_size += 1;
+            }
+            prev = Some(string);
+        }
+    }
+
+    #[test]
+    fn lzw() {
+        // Long enough to fill the table and clear it a few times
+        let mut seed = 1u32;
+        let indices: Vec<u8> = (0..WIDTH * HEIGHT)
+            .map(|i| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                if i % 7 < 3 {
+                    (seed >> 16) as u8 % 4
+                } else {
+                    (i / 40 % 4) as u8
+                }
+            })
+            .collect();
+        assert_eq!(lzw_decompress(&lzw_compress(&indices, 2), 2), indices);
+        assert!(lzw_decompress(&lzw_compress(&[], 2), 2).is_empty());
+        let bytes: Vec<u8> = (0..=255).cycle().take(5000).collect();
+        assert_eq!(lzw_decompress(&lzw_compress(&bytes, 8), 8), bytes);
+    }
+
+    #[test]
+    fn timing() {
+        let frame = || vec![0xFF; WIDTH * HEIGHT * 3].into_boxed_slice();
+        let mut sink = RecordingSink::with_seconds(1);
+        for _ in 0..100 {
+            sink.append(frame());
+        }
+        assert!(sink.is_full());
+        assert_eq!(sink.frames(), 59);
+        let gif = sink.finish();
+        assert!(gif.starts_with(b"GIF89a\xA0\x00\x90\x00"));
+        assert_eq!(gif.last(), Some(&0x3B));
+
+        // The delays of the frames kept add up to the time the frames given were on screen
+        let delays: Vec<u64> = gif
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| *w == [0x21, 0xF9, 0x04, 0x04])
+            .map(|(i, _)| u16::from_le_bytes([gif[i + 4], gif[i + 5]]) as u64)
+            .collect();
+        assert!(delays.iter().all(|&delay| delay >= MIN_DELAY));
+        assert_eq!(delays.iter().sum::<u64>(), frame_time(59));
+    }
+
+    #[test]
+    fn wav() {
+        let mut sink = WavSink::new(48000);
+        sink.append((0.5, -0.25));
+        sink.append((f32::MIN_POSITIVE, 1.0));
+        assert_eq!(sink.frames(), 2);
+        let wav = sink.finish();
+        assert_eq!(wav.len(), 12 + 26 + 12 + 8 + 16);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(wav[4..8], (wav.len() as u32 - 8).to_le_bytes());
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        // Float format, stereo, 48000Hz
+        assert_eq!(wav[20..28], [3, 0, 2, 0, 0x80, 0xBB, 0, 0]);
+        assert_eq!(&wav[38..42], b"fact");
+        assert_eq!(wav[46..50], 2u32.to_le_bytes());
+        assert_eq!(&wav[50..54], b"data");
+        let samples: Vec<f32> = wav[58..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(samples, [0.5, -0.25, f32::MIN_POSITIVE, 1.0]);
+    }
+
+    #[test]
+    fn colors() {
+        let frame = [255, 255, 255, 0, 0, 0, 255, 255, 255, 250, 0, 0];
+        let (palette, indices) = index_colors(&frame);
+        assert_eq!(palette, [[255, 255, 255], [0, 0, 0], [250, 0, 0]]);
+        assert_eq!(indices, [0, 1, 0, 2]);
+        assert_eq!(closest_color(&palette, [240, 10, 10]), 2);
+    }
+}
@@ -0,0 +1,190 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+/// Ring buffer of save states, one per frame, for stepping back through recent play.
+/// Only the newest state is kept whole. Each older state is kept as the difference from the
+/// state after it, which is mostly unchanged memory and so compresses to very little.
+pub struct RewindBuffer {
+    /// Number of states kept, the oldest are dropped past it
+    capacity: usize,
+    newest: Option<Vec<u8>>,
+    /// Deltas recreating each older state from the one after it, oldest first
+    deltas: VecDeque<Delta>,
+}
+
+/// The bytes that differ between a state and the newer state it's recreated from
+struct Delta {
+    /// Length of the state recreated, which differs when e.g. the boot ROM is unmapped
+    len: usize,
+    /// Runs of unchanged bytes and the XOR of changed bytes, see `encode_delta`
+    runs: Vec<u8>,
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> Self {
+        RewindBuffer {
+            capacity,
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of states that can be rewound to
+    pub fn len(&self) -> usize {
+        self.newest.as_ref().map_or(0, |_| self.deltas.len() + 1)
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(previous) = self.newest.take() {
+            self.deltas.push_back(Delta {
+                len: previous.len(),
+                runs: encode_delta(&previous, &state),
+            });
+        }
+        self.newest = Some(state);
+        while self.len() > self.capacity {
+            self.deltas.pop_front();
+        }
+    }
+
+    /// Drops the newest `frames` states, returning the last one dropped, or `None` if there
+    /// are none. Stops at the oldest state if there are fewer than `frames`.
+    pub fn pop(&mut self, frames: usize) -> Option<Vec<u8>> {
+        let mut state = self.newest.take()?;
+        for _ in 1..frames {
+            match self.deltas.pop_back() {
+                Some(delta) => state = apply_delta(&state, &delta),
+                None => return Some(state),
+            }
+        }
+        self.newest = self
+            .deltas
+            .pop_back()
+            .map(|delta| apply_delta(&state, &delta));
+        Some(state)
+    }
+}
+
+/// Encodes the XOR of `older` and `newer`, both padded with 0s to the same length, as a
+/// series of runs: the count of unchanged bytes to skip, the count of changed bytes, then
+/// the changed bytes. Counts are LEB128 encoded.
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let len = older.len().max(newer.len());
+    let xor = |i: usize| older.get(i).unwrap_or(&0) ^ newer.get(i).unwrap_or(&0);
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < len {
+        let start = i;
+        while i < len && xor(i) == 0 {
+            i += 1;
+        }
+        if i == len {
+            break;
+        }
+        let changed = i;
+        while i < len && xor(i) != 0 {
+            i += 1;
+        }
+        write_leb128(&mut runs, changed - start);
+        write_leb128(&mut runs, i - changed);
+        runs.extend((changed..i).map(xor));
+    }
+    runs
+}
+
+/// Recreates the state `delta` was encoded from, given the newer state
+fn apply_delta(newer: &[u8], delta: &Delta) -> Vec<u8> {
+    let mut state = newer.to_vec();
+    state.resize(delta.len.max(newer.len()), 0);
+    let mut runs = &delta.runs[..];
+    let mut i = 0;
+    while !runs.is_empty() {
+        i += read_leb128(&mut runs);
+        let count = read_leb128(&mut runs);
+        for (byte, change) in state[i..i + count].iter_mut().zip(&runs[..count]) {
+            *byte ^= change;
+        }
+        runs = &runs[count..];
+        i += count;
+    }
+    state.truncate(delta.len);
+    state
+}
+
+fn write_leb128(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_leb128(data: &mut &[u8]) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[0];
+        *data = &data[1..];
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod rewind_tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trip() {
+        let older: Vec<u8> = (0..1000).map(|i| (i / 7) as u8).collect();
+        let mut newer = older.clone();
+        newer[0] ^= 1;
+        newer[500..700].fill(0xAA);
+        newer.extend_from_slice(&[1, 2, 3]);
+        let runs = encode_delta(&older, &newer);
+        assert!(runs.len() < 220);
+        let delta = Delta {
+            len: older.len(),
+            runs,
+        };
+        assert_eq!(apply_delta(&newer, &delta), older);
+
+        // States growing as well as shrinking
+        let delta = Delta {
+            len: newer.len(),
+            runs: encode_delta(&newer, &older),
+        };
+        assert_eq!(apply_delta(&older, &delta), newer);
+        assert!(encode_delta(&older, &older).is_empty());
+    }
+
+    #[test]
+    fn capacity() {
+        let mut buffer = RewindBuffer::new(3);
+        for i in 0..5u8 {
+            buffer.push(vec![i; 10 + i as usize]);
+        }
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.pop(1), Some(vec![4; 14]));
+        assert_eq!(buffer.pop(1), Some(vec![3; 13]));
+        buffer.push(vec![9; 4]);
+        // Stops at the oldest
+        assert_eq!(buffer.pop(5), Some(vec![2; 12]));
+        assert_eq!(buffer.len(), 0);
+        assert_eq!(buffer.pop(1), None);
+
+        let mut disabled = RewindBuffer::new(0);
+        disabled.push(vec![1]);
+        assert_eq!(disabled.pop(1), None);
+    }
+}
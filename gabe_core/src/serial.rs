@@ -1,6 +1,12 @@
-#![allow(dead_code)]
+use alloc::boxed::Box;
 
-use super::mmu::Memory;
+use super::gb::StateError;
+use super::link::SerialLink;
+use super::mmu::{InterruptKind, Memory};
+use super::state::{SaveState, StateReader, StateWriter};
+
+/// Cycles to shift out a byte with the DMG's internal clock, 8 bits at 8192 Hz
+const TRANSFER_CYCLES: u32 = 8 * 512;
 
 pub struct Serial {
     /// Serial transfer data: 8 Bits of data to be read/written
@@ -9,32 +15,312 @@ pub struct Serial {
     /// Bit 1 - Clock Speed (0=Normal, 1=Fast) ** CGB Mode Only **
     /// Bit 0 - Shift Clock (0=External Clock, 1=Internal Clock)
     sc: u8,
+    /// Cycles left in the transfer in progress. Only transfers using the internal clock
+    /// count down, transfers using the external clock wait on the peer instead.
+    remaining_cycles: u32,
+    /// The byte last sent using the internal clock, until taken by `take_output`
+    output: Option<u8>,
+    /// The peer on the other end of the link cable, if connected
+    link: Option<Box<dyn SerialLink>>,
+    /// Extra cycles added to transfers using the internal clock, to give the peer's reply
+    /// time to arrive over a slow link
+    latency: u32,
+    /// Holds transfers using the internal clock until the peer's reply arrives, rather than
+    /// receiving 0xFF when it's late
+    wait_for_peer: bool,
+    /// Replies to transfers that completed without them, still to arrive. They're dropped
+    /// as they arrive, so they aren't taken as the reply to a later transfer.
+    late_replies: u32,
 }
 
 impl Serial {
     pub fn power_on() -> Self {
-        Serial { sb: 0, sc: 0 }
+        Serial {
+            sb: 0,
+            sc: 0,
+            remaining_cycles: 0,
+            output: None,
+            link: None,
+            latency: 0,
+            wait_for_peer: false,
+            late_replies: 0,
+        }
+    }
+
+    /// Advances the transfer in progress. With no peer replying the bits shifted in are
+    /// all 1s, so SB reads 0xFF once it completes.
+    /// Returns an Option with an Interrupt::Serial if the transfer completed.
+    pub fn update(&mut self, cycles: u32) -> Option<InterruptKind> {
+        // Bytes arrive in the order they were sent, so late replies come before anything else
+        if let Some(link) = self.link.as_mut() {
+            while self.late_replies > 0 && link.receive().is_some() {
+                self.late_replies -= 1;
+            }
+        }
+        if self.sc & 0x80 == 0 {
+            return None;
+        }
+        let received = if self.sc & 0x01 == 0x01 {
+            self.remaining_cycles = self.remaining_cycles.saturating_sub(cycles);
+            if self.remaining_cycles > 0 {
+                return None;
+            }
+            let reply = match self.link.as_mut() {
+                Some(link) if self.late_replies == 0 => link.receive(),
+                _ => None,
+            };
+            match reply {
+                Some(byte) => byte,
+                None if self.link.is_some() && self.wait_for_peer => return None,
+                None => {
+                    // The reply is still owed, and must not be taken for the next one
+                    if self.link.is_some() {
+                        self.late_replies += 1;
+                    }
+                    0xFF
+                }
+            }
+        } else {
+            // Without a clock from the peer nothing is shifted, and the transfer never ends
+            let link = self.link.as_mut().filter(|_| self.late_replies == 0)?;
+            let byte = link.receive()?;
+            link.send(self.sb);
+            byte
+        };
+        self.sb = received;
+        self.sc &= 0x7F;
+        Some(InterruptKind::Serial)
+    }
+
+    /// Connects the link cable to a peer, or disconnects it with `None`
+    pub fn connect(&mut self, link: Option<Box<dyn SerialLink>>) {
+        self.link = link;
+        self.late_replies = 0;
+    }
+
+    pub fn set_latency(&mut self, cycles: u32) {
+        self.latency = cycles;
+    }
+
+    pub fn set_wait_for_peer(&mut self, wait: bool) {
+        self.wait_for_peer = wait;
     }
 
-    pub fn update(&mut self) {
-        // TODO
+    /// Cycles until the transfer in progress completes, or `None` if none will
+    pub fn cycles_until_complete(&self) -> Option<u32> {
+        (self.sc & 0x81 == 0x81 && self.remaining_cycles > 0).then_some(self.remaining_cycles)
+    }
+
+    /// Takes the byte last sent using the internal clock, if it hasn't been taken yet
+    pub fn take_output(&mut self) -> Option<u8> {
+        self.output.take()
     }
 }
 
 impl Memory for Serial {
     fn read_byte(&self, addr: u16) -> u8 {
+        debug_assert!(addr == 0xFF01 || addr == 0xFF02);
         match addr {
             0xFF01 => self.sb,
             0xFF02 => self.sc,
-            _ => unreachable!(),
+            _ => 0xFF,
         }
     }
 
     fn write_byte(&mut self, addr: u16, val: u8) {
+        debug_assert!(addr == 0xFF01 || addr == 0xFF02);
         match addr {
             0xFF01 => self.sb = val,
-            0xFF02 => self.sc = val,
-            _ => unreachable!(),
+            0xFF02 => {
+                self.sc = val;
+                // Clearing the start flag abandons any transfer
+                self.remaining_cycles = 0;
+                if val & 0x81 == 0x81 {
+                    self.remaining_cycles = TRANSFER_CYCLES + self.latency;
+                    self.output = Some(self.sb);
+                    if let Some(link) = self.link.as_mut() {
+                        link.send(self.sb);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl SaveState for Serial {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u32(self.remaining_cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()?;
+        self.remaining_cycles = r.u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod serial_tests {
+    use alloc::boxed::Box;
+    use alloc::collections::VecDeque;
+    use alloc::rc::Rc;
+    use core::cell::{Cell, RefCell};
+
+    use crate::link::SerialLink;
+    use crate::mmu::{InterruptKind, Memory};
+
+    use super::Serial;
+
+    /// Bytes in flight on a shared in-process link, with the cycle they arrive on
+    type Wire = Rc<RefCell<VecDeque<(u64, u8)>>>;
+
+    /// One end of an in-process link that delivers each byte `delay` cycles after sending
+    struct DelayedLink {
+        clock: Rc<Cell<u64>>,
+        delay: u64,
+        tx: Wire,
+        rx: Wire,
+    }
+
+    impl SerialLink for DelayedLink {
+        fn send(&mut self, byte: u8) {
+            let arrival = self.clock.get() + self.delay;
+            self.tx.borrow_mut().push_back((arrival, byte));
         }
+
+        fn receive(&mut self) -> Option<u8> {
+            let mut rx = self.rx.borrow_mut();
+            match rx.front() {
+                Some((arrival, _)) if *arrival <= self.clock.get() => {
+                    rx.pop_front().map(|(_, b)| b)
+                }
+                _ => None,
+            }
+        }
+    }
+
+    /// Two ends of an in-process link delaying bytes by a fixed number of cycles, one
+    /// clocking transfers and the other replying
+    struct LinkedPair {
+        clock: Rc<Cell<u64>>,
+        master: Serial,
+        slave: Serial,
+    }
+
+    impl LinkedPair {
+        fn new(delay: u64, latency: u32, wait_for_peer: bool) -> Self {
+            let clock = Rc::new(Cell::new(0));
+            let (a_to_b, b_to_a) = (Wire::default(), Wire::default());
+            let link = |tx: &Wire, rx: &Wire| {
+                Box::new(DelayedLink {
+                    clock: clock.clone(),
+                    delay,
+                    tx: tx.clone(),
+                    rx: rx.clone(),
+                })
+            };
+            let mut master = Serial::power_on();
+            master.connect(Some(link(&a_to_b, &b_to_a)));
+            master.set_latency(latency);
+            master.set_wait_for_peer(wait_for_peer);
+            let mut slave = Serial::power_on();
+            slave.connect(Some(link(&b_to_a, &a_to_b)));
+            LinkedPair {
+                clock,
+                master,
+                slave,
+            }
+        }
+
+        /// Transfers `sent` from the master to the slave replying `reply`, until both
+        /// complete or `max_cycles` more pass. Returns the bytes each side received, or
+        /// `None` for a side that didn't complete.
+        fn transfer(&mut self, sent: u8, reply: u8, max_cycles: u64) -> (Option<u8>, Option<u8>) {
+            self.slave.write_byte(0xFF01, reply);
+            self.slave.write_byte(0xFF02, 0x80);
+            self.master.write_byte(0xFF01, sent);
+            self.master.write_byte(0xFF02, 0x81);
+            let end = self.clock.get() + max_cycles;
+            let (mut master_done, mut slave_done) = (None, None);
+            while self.clock.get() < end && (master_done.is_none() || slave_done.is_none()) {
+                self.clock.set(self.clock.get() + 4);
+                if self.master.update(4) == Some(InterruptKind::Serial) {
+                    master_done = Some(self.master.read_byte(0xFF01));
+                }
+                if self.slave.update(4) == Some(InterruptKind::Serial) {
+                    slave_done = Some(self.slave.read_byte(0xFF01));
+                }
+            }
+            (master_done, slave_done)
+        }
+    }
+
+    /// Transfers 0x42 from a side clocking the transfer to one replying 0x99 over a link
+    /// delaying bytes by `delay` cycles, until both complete or `max_cycles` pass.
+    /// Returns the bytes each side received, or `None` for a side that didn't complete.
+    fn link_transfer(
+        delay: u64,
+        latency: u32,
+        wait_for_peer: bool,
+        max_cycles: u64,
+    ) -> (Option<u8>, Option<u8>) {
+        LinkedPair::new(delay, latency, wait_for_peer).transfer(0x42, 0x99, max_cycles)
+    }
+
+    #[test]
+    fn no_peer_connected() {
+        let mut serial = Serial::power_on();
+        serial.write_byte(0xFF01, 0x42);
+        serial.write_byte(0xFF02, 0x81);
+        assert_eq!(serial.take_output(), Some(0x42));
+        assert!(serial.update(4092).is_none());
+        assert_eq!(serial.read_byte(0xFF02), 0x81);
+        assert_eq!(serial.update(4), Some(InterruptKind::Serial));
+        assert_eq!(serial.read_byte(0xFF01), 0xFF);
+        assert_eq!(serial.read_byte(0xFF02), 0x01);
+        assert!(serial.update(4096).is_none());
+
+        serial.write_byte(0xFF01, 0x42);
+        serial.write_byte(0xFF02, 0x80);
+        assert_eq!(serial.take_output(), None);
+        for _ in 0..100 {
+            assert!(serial.update(4096).is_none());
+        }
+        assert_eq!(serial.read_byte(0xFF01), 0x42);
+        assert_eq!(serial.read_byte(0xFF02), 0x80);
+    }
+
+    #[test]
+    fn delayed_link() {
+        // A reply arriving within the transfer time is received as on hardware
+        assert_eq!(
+            link_transfer(1000, 0, false, 100_000),
+            (Some(0x99), Some(0x42))
+        );
+        // Later than that, the side clocking the transfer races ahead and misses it
+        let mut pair = LinkedPair::new(3000, 0, false);
+        assert_eq!(pair.transfer(0x42, 0x99, 100_000), (Some(0xFF), Some(0x42)));
+        // The missed reply arrives during the next transfer, but isn't taken as its reply
+        assert_eq!(pair.transfer(0x43, 0x98, 100_000), (Some(0xFF), Some(0x43)));
+        // Both missed replies are dropped, and the link stays in step once the side
+        // clocking transfers waits for them
+        pair.master.set_wait_for_peer(true);
+        assert_eq!(pair.transfer(0x44, 0x97, 100_000), (Some(0x97), Some(0x44)));
+        assert_eq!(pair.transfer(0x45, 0x96, 100_000), (Some(0x96), Some(0x45)));
+        // Unless given enough latency to cover the round trip
+        assert_eq!(
+            link_transfer(3000, 2000, false, 100_000),
+            (Some(0x99), Some(0x42))
+        );
+        // Or waiting for the reply, however long it takes
+        assert_eq!(
+            link_transfer(20_000, 0, true, 100_000),
+            (Some(0x99), Some(0x42))
+        );
+        assert_eq!(link_transfer(20_000, 0, true, 30_000), (None, Some(0x42)));
     }
 }
@@ -15,3 +15,75 @@ pub type VideoFrame = Box<[u8]>;
 
 /// A frame of audio data, consisting of (Left, Right) sample data of i16
 pub type AudioFrame = (f32, f32);
+
+/// A frame of audio data split by APU channel, in the order Square 1, Square 2, Wave, Noise.
+/// Each entry is that channel's (Left, Right) contribution to the mixed `AudioFrame`, so
+/// summing all four gives the same output as the mixed stream.
+pub type ChannelAudioFrame = [AudioFrame; 4];
+
+/// Adapter that mixes each `ChannelAudioFrame` down into a single `AudioFrame`
+pub struct MixedAudioSink<'a>(pub &'a mut dyn Sink<AudioFrame>);
+
+impl Sink<ChannelAudioFrame> for MixedAudioSink<'_> {
+    fn append(&mut self, value: ChannelAudioFrame) {
+        self.0.append(mix_channels(&value));
+    }
+}
+
+/// A frame of audio data as (Left, Right) signed 16-bit samples
+pub type AudioFrameI16 = (i16, i16);
+
+/// Converts a sample to a signed 16-bit sample, clamping it to the -1.0 to 1.0 range first
+pub fn sample_to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+/// Adapter that mixes each `ChannelAudioFrame` down into a single `AudioFrameI16`
+pub struct I16AudioSink<'a>(pub &'a mut dyn Sink<AudioFrameI16>);
+
+impl Sink<ChannelAudioFrame> for I16AudioSink<'_> {
+    fn append(&mut self, value: ChannelAudioFrame) {
+        let mixed = mix_channels(&value);
+        self.0
+            .append((sample_to_i16(mixed.0), sample_to_i16(mixed.1)));
+    }
+}
+
+/// Sums each channel's contribution into the final mixed frame
+fn mix_channels(frame: &ChannelAudioFrame) -> AudioFrame {
+    frame.iter().fold((0.0, 0.0), |acc, channel| {
+        (acc.0 + channel.0, acc.1 + channel.1)
+    })
+}
+
+/// Adapter that splits each `ChannelAudioFrame` into a separate `AudioFrame` Sink per channel,
+/// in the same order as the frame: Square 1, Square 2, Wave, Noise
+pub struct SplitAudioSink<'a>(pub [&'a mut dyn Sink<AudioFrame>; 4]);
+
+impl Sink<ChannelAudioFrame> for SplitAudioSink<'_> {
+    fn append(&mut self, value: ChannelAudioFrame) {
+        for (sink, frame) in self.0.iter_mut().zip(value) {
+            sink.append(frame);
+        }
+    }
+}
+
+/// A Sink that discards everything it is given, for running the emulator
+/// without presenting any video or audio
+pub struct NullSink;
+
+impl Sink<VideoFrame> for NullSink {
+    fn append(&mut self, _value: VideoFrame) {}
+}
+
+impl Sink<AudioFrame> for NullSink {
+    fn append(&mut self, _value: AudioFrame) {}
+}
+
+impl Sink<AudioFrameI16> for NullSink {
+    fn append(&mut self, _value: AudioFrameI16) {}
+}
+
+impl Sink<ChannelAudioFrame> for NullSink {
+    fn append(&mut self, _value: ChannelAudioFrame) {}
+}
@@ -0,0 +1,178 @@
+use alloc::vec::Vec;
+
+use super::gb::StateError;
+
+/// Implemented by every part of the system with state to keep in a save state.
+/// Fields are written in a fixed order and read back in the same order, so any change to
+/// what's written needs `STATE_VERSION` bumped.
+pub trait SaveState {
+    fn save_state(&self, w: &mut StateWriter);
+    /// Restores the fields written by `save_state`, failing with `StateError::Corrupt` on
+    /// data it couldn't have written. May leave the state partially loaded on failure.
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError>;
+}
+
+/// Builds a save state, storing values little-endian
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, v: i32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// Writes a block of bytes prefixed with its length
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.data.extend_from_slice(v);
+    }
+}
+
+/// Reads back a save state built by `StateWriter`
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        if self.data.len() < N {
+            return Err(StateError::Corrupt);
+        }
+        let (taken, rest) = self.data.split_at(N);
+        self.data = rest;
+        Ok(taken.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        self.take::<1>().map(|[v]| v)
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    pub fn i32(&mut self) -> Result<i32, StateError> {
+        self.take().map(i32::from_le_bytes)
+    }
+
+    /// Reads a block of bytes written by `StateWriter::bytes`
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u32()? as usize;
+        if len > self.data.len() {
+            return Err(StateError::Corrupt);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Reads a block of bytes into `dest`, which it must exactly fill
+    pub fn bytes_into(&mut self, dest: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.bytes()?;
+        if bytes.len() != dest.len() {
+            return Err(StateError::Corrupt);
+        }
+        dest.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Checks that everything has been read, as leftover data means it was misread
+    pub fn finish(&self) -> Result<(), StateError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(StateError::Corrupt)
+        }
+    }
+}
+
+#[cfg(test)]
+mod state_tests {
+    use super::*;
+
+    #[test]
+    fn read_back() {
+        let mut w = StateWriter::new();
+        w.u8(0x12);
+        w.bool(true);
+        w.u16(0x3456);
+        w.u32(0x789A_BCDE);
+        w.u64(u64::MAX - 1);
+        w.i32(-5);
+        w.bytes(&[1, 2, 3]);
+        let data = w.into_inner();
+
+        let mut r = StateReader::new(&data);
+        assert_eq!(r.u8(), Ok(0x12));
+        assert_eq!(r.bool(), Ok(true));
+        assert_eq!(r.u16(), Ok(0x3456));
+        assert_eq!(r.u32(), Ok(0x789A_BCDE));
+        assert_eq!(r.u64(), Ok(u64::MAX - 1));
+        assert_eq!(r.i32(), Ok(-5));
+        let mut dest = [0; 3];
+        assert_eq!(r.bytes_into(&mut dest), Ok(()));
+        assert_eq!(dest, [1, 2, 3]);
+        assert_eq!(r.finish(), Ok(()));
+        assert_eq!(r.u8(), Err(StateError::Corrupt));
+
+        let mut r = StateReader::new(&data[1..]);
+        assert_eq!(r.bool(), Ok(true));
+        assert_eq!(r.finish(), Err(StateError::Corrupt));
+        assert_eq!(StateReader::new(&[2]).bool(), Err(StateError::Corrupt));
+        // Length claims more bytes than remain
+        assert_eq!(
+            StateReader::new(&[4, 0, 0, 0, 1]).bytes(),
+            Err(StateError::Corrupt)
+        );
+    }
+}
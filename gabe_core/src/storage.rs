@@ -0,0 +1,43 @@
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+/// Where a game's battery-backed RAM is kept while the emulator is closed, set with
+/// `GameboyBuilder::save_storage` or `Gameboy::set_save_storage`.
+///
+/// The `Gameboy` writes the RAM back by itself shortly after the game changes it and when
+/// it's dropped, see `Gameboy::flush_save_data`, so saves aren't lost however it's closed.
+pub trait SaveStorage {
+    /// Returns the save data stored, or `None` if nothing has been saved yet
+    fn load(&mut self) -> Option<Vec<u8>>;
+    /// Replaces the save data stored with `data`. Storage that can fail is left to report
+    /// its own errors, as the game carries on either way.
+    fn store(&mut self, data: &[u8]);
+}
+
+/// Keeps save data in memory, for tests and hosts without a file system. Clones share the
+/// same data, so a clone can be kept to read what the `Gameboy` stored.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage(Rc<RefCell<Option<Vec<u8>>>>);
+
+impl MemoryStorage {
+    /// Storage holding `data`, or nothing yet with `None`
+    pub fn new(data: Option<Vec<u8>>) -> Self {
+        MemoryStorage(Rc::new(RefCell::new(data)))
+    }
+
+    /// The save data last stored
+    pub fn data(&self) -> Option<Vec<u8>> {
+        self.0.borrow().clone()
+    }
+}
+
+impl SaveStorage for MemoryStorage {
+    fn load(&mut self) -> Option<Vec<u8>> {
+        self.data()
+    }
+
+    fn store(&mut self, data: &[u8]) {
+        *self.0.borrow_mut() = Some(data.to_vec());
+    }
+}
@@ -1,9 +1,9 @@
+use super::gb::StateError;
+use super::log_target;
 use super::mmu::{InterruptKind, Memory};
+use super::state::{SaveState, StateReader, StateWriter};
 
 pub struct Timer {
-    /// 0xFF04: Divider Register
-    /// Increments at 16384 Hz, and wraps around. Resets to 0x00 when written to.
-    div: u8,
     /// 0xFF05: Timer Counter
     /// Incremented at rate indicated by TAC register. When overflowed, it resets to
     /// the value of the TMA register and a Timer Interrupt is requested.
@@ -19,86 +19,154 @@ pub struct Timer {
     ///     10: 65536 Hz
     ///     11: 16384 Hz
     tac: u8,
-    /// Tracks the current cycles before incrementing DIV, increments at 256 cycles
-    div_cycles: u32,
-    /// Tracks the current cycles before incrementing TIMA, depends on TAC frequency
-    tima_cycles: u32,
+    /// Internal 16-bit counter incremented every cycle, DIV is its upper byte.
+    /// TIMA increments whenever the counter bit selected by TAC falls from 1 to 0.
+    counter: u16,
+    /// Set when TIMA overflows from a register write, to request the interrupt on the next update
+    write_overflow: bool,
 }
 
 impl Timer {
     pub fn power_on() -> Self {
         Timer {
-            div: 0xAB,
             tima: 0x0,
             tma: 0x0,
             tac: 0xF8,
-            div_cycles: 0,
-            tima_cycles: 0,
+            counter: 0xAB00,
+            write_overflow: false,
         }
     }
 
     /// Updates all the timer registers up to the same cycles as the CPU.
     /// Returns an Option with an Interrupt::Timer if the timer overflowed.
     pub fn update(&mut self, cycles: u32) -> Option<InterruptKind> {
-        // Update DIV timer
-        self.div_cycles += cycles;
-        if self.div_cycles >= 256 {
-            self.div = self.div.wrapping_add(1);
-            self.div_cycles -= 256;
-        }
-        // Update TIMA timer
-        if !self.timer_stopped() {
-            self.tima_cycles += cycles;
-            if self.tima_cycles >= self.get_tima_freq() {
-                self.tima = self.tima.wrapping_add(1);
-                self.tima_cycles -= self.get_tima_freq();
-                if self.tima == 0x0 {
-                    self.tima = self.tma;
-                    return Some(InterruptKind::Timer);
-                }
+        let mut overflowed = core::mem::take(&mut self.write_overflow);
+        // Step a machine cycle at a time so no falling edge is skipped
+        for _ in 0..cycles / 4 {
+            let old = self.counter;
+            self.counter = self.counter.wrapping_add(4);
+            if Self::input(old, self.tac) && !Self::input(self.counter, self.tac) {
+                overflowed |= self.increment_tima();
             }
         }
-        None
+        overflowed.then_some(InterruptKind::Timer)
     }
 
-    /// Reads the value of the TAC register and returns the number of
-    /// CPU cycles needed before incrementing the TIMA register
-    fn get_tima_freq(&self) -> u32 {
-        match self.tac & 0b11 {
-            0b00 => 1024,
-            0b01 => 16,
-            0b10 => 64,
-            0b11 => 256,
-            _ => panic!(""),
+    /// The internal counter DIV is taken from. Needed along with the registers to fully
+    /// restore the timer, as it decides when DIV and TIMA next increment.
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
+    /// Sets the internal counter without the side effects of writing DIV
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    /// Cycles until TIMA next overflows and requests an interrupt, or `None` while stopped.
+    /// Assumes the registers aren't written in the meantime.
+    pub fn cycles_until_overflow(&self) -> Option<u32> {
+        if self.write_overflow {
+            return Some(0);
+        }
+        if (self.tac >> 2) & 0b1 == 0 {
+            return None;
+        }
+        // TIMA increments each time the counter passes a multiple of this period
+        let period = Self::tima_bit(self.tac) as u32 * 2;
+        let next_increment = period - (self.counter as u32 % period);
+        Some(next_increment + (0xFF - self.tima as u32) * period)
+    }
+
+    /// Increments TIMA, returning true if it overflowed and was reloaded from TMA
+    fn increment_tima(&mut self) -> bool {
+        self.tima = self.tima.wrapping_add(1);
+        if self.tima == 0x0 {
+            trace!(target: log_target::TIMER, "TIMA overflowed, reloading {:02X}", self.tma);
+            self.tima = self.tma;
+            true
+        } else {
+            false
         }
     }
 
-    fn timer_stopped(&self) -> bool {
-        ((self.tac >> 2) & 0b1) != 0b1
+    /// The signal clocking TIMA on its falling edge: the counter bit selected by `tac`,
+    /// ANDed with the timer enable bit
+    fn input(counter: u16, tac: u8) -> bool {
+        let enabled = (tac >> 2) & 0b1 == 0b1;
+        enabled && (counter & Self::tima_bit(tac)) != 0
+    }
+
+    /// Reads the value of the TAC register and returns the counter bit that clocks TIMA,
+    /// which sets the number of CPU cycles between increments
+    fn tima_bit(tac: u8) -> u16 {
+        match tac & 0b11 {
+            // 1024 cycles
+            0b00 => 1 << 9,
+            // 16 cycles
+            0b01 => 1 << 3,
+            // 64 cycles
+            0b10 => 1 << 5,
+            // 256 cycles
+            0b11 => 1 << 7,
+            _ => panic!(""),
+        }
     }
 }
 
 impl Memory for Timer {
     fn read_byte(&self, addr: u16) -> u8 {
+        debug_assert!((0xFF04..=0xFF07).contains(&addr));
         match addr {
-            0xFF04 => self.div,
+            // 0xFF04: Divider Register
+            // Increments at 16384 Hz, and wraps around. Resets to 0x00 when written to.
+            0xFF04 => (self.counter >> 8) as u8,
             0xFF05 => self.tima,
             0xFF06 => self.tma,
             0xFF07 => self.tac,
-            _ => panic!("0x{:X}: Improper Timer Address", addr),
+            _ => 0xFF,
         }
     }
 
     fn write_byte(&mut self, addr: u16, val: u8) {
+        debug_assert!((0xFF04..=0xFF07).contains(&addr));
         match addr {
             0xFF04 => {
-                self.div = 0x0;
-                self.div_cycles = 0;
+                // Resetting the counter can itself cause a falling edge
+                if Self::input(self.counter, self.tac) {
+                    self.write_overflow |= self.increment_tima();
+                }
+                self.counter = 0;
             }
             0xFF05 => self.tima = val,
             0xFF06 => self.tma = val,
-            0xFF07 => self.tac = val,
-            _ => panic!("0x{:X}: Improper Timer Address", addr),
+            0xFF07 => {
+                // As can changing the selected bit or disabling the timer
+                if Self::input(self.counter, self.tac) && !Self::input(self.counter, val) {
+                    self.write_overflow |= self.increment_tima();
+                }
+                self.tac = val;
+            }
+            _ => {}
         }
     }
 }
+
+impl SaveState for Timer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.u16(self.counter);
+        w.bool(self.write_overflow);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()?;
+        self.counter = r.u16()?;
+        self.write_overflow = r.bool()?;
+        Ok(())
+    }
+}
@@ -1 +1,2 @@
 pub mod bit;
+pub mod hash;
@@ -1,7 +1,11 @@
+use super::gb::{BackgroundLayout, OamEntry, StateError, TilePalette};
+use super::log_target;
 use super::mmu::{InterruptKind, Memory};
 use super::sink::*;
+use super::state::{SaveState, StateReader, StateWriter};
 
 use alloc::boxed::*;
+use alloc::collections::VecDeque;
 use alloc::vec::*;
 
 struct Lcdc {
@@ -47,7 +51,7 @@ impl Lcdc {
 
 impl Memory for Lcdc {
     fn read_byte(&self, addr: u16) -> u8 {
-        assert_eq!(0xFF40, addr);
+        debug_assert_eq!(0xFF40, addr);
         let mut v = 0;
         v |= (self.lcd_enable as u8) << 7;
         v |= (self.window_tile_map_select as u8) << 6;
@@ -60,7 +64,7 @@ impl Memory for Lcdc {
         v
     }
     fn write_byte(&mut self, addr: u16, val: u8) {
-        assert_eq!(0xFF40, addr);
+        debug_assert_eq!(0xFF40, addr);
         self.lcd_enable = (val & 0x80) != 0x0;
         self.window_tile_map_select = (val & 0x40) != 0x0;
         self.window_enable = (val & 0x20) != 0x0;
@@ -131,7 +135,7 @@ impl Stat {
 
 impl Memory for Stat {
     fn read_byte(&self, addr: u16) -> u8 {
-        assert_eq!(0xFF41, addr);
+        debug_assert_eq!(0xFF41, addr);
         let mut v = 0;
         v |= 1 << 7;
         v |= (self.lyc_ly_interrupt as u8) << 6;
@@ -143,19 +147,13 @@ impl Memory for Stat {
         v
     }
     fn write_byte(&mut self, addr: u16, val: u8) {
-        assert_eq!(0xFF41, addr);
+        debug_assert_eq!(0xFF41, addr);
+        // Only the interrupt enables are writable, the coincidence and mode flags
+        // are set by the LCD controller
         self.lyc_ly_interrupt = (val & 0x40) != 0x0;
         self.oam_interrupt = (val & 0x20) != 0x0;
         self.vblank_interrupt = (val & 0x10) != 0x0;
         self.hblank_interrupt = (val & 0x08) != 0x0;
-        self.lyc_ly_flag = (val & 0x04) != 0x0;
-        self.mode_flag = match val & 0x03 {
-            0b00 => LCDMode::Mode0,
-            0b01 => LCDMode::Mode1,
-            0b10 => LCDMode::Mode2,
-            0b11 => LCDMode::Mode3,
-            _ => LCDMode::Mode0,
-        };
     }
 }
 
@@ -186,9 +184,22 @@ impl PaletteData {
     }
 }
 
+impl PaletteData {
+    /// Returns the shade assigned to the given color number, 0-3
+    fn shade(&self, color_idx: u8) -> GrayShades {
+        match color_idx {
+            0 => self.color0,
+            1 => self.color1,
+            2 => self.color2,
+            3 => self.color3,
+            _ => unreachable!(),
+        }
+    }
+}
+
 impl Memory for PaletteData {
     fn read_byte(&self, addr: u16) -> u8 {
-        assert!(addr == 0xFF47 || addr == 0xFF48 || addr == 0xFF49);
+        debug_assert!(addr == 0xFF47 || addr == 0xFF48 || addr == 0xFF49);
         let mut ret: u8 = 0;
         ret |= (self.color3 as u8) << 6;
         ret |= (self.color2 as u8) << 4;
@@ -197,7 +208,7 @@ impl Memory for PaletteData {
         ret
     }
     fn write_byte(&mut self, addr: u16, val: u8) {
-        assert!(addr == 0xFF47 || addr == 0xFF48 || addr == 0xFF49);
+        debug_assert!(addr == 0xFF47 || addr == 0xFF48 || addr == 0xFF49);
         let mut colors: Vec<GrayShades> = vec![];
         for i in 0..4 {
             let v = (val >> (i * 2)) & 0b11;
@@ -217,20 +228,109 @@ impl Memory for PaletteData {
     }
 }
 
+/// Steps of the background/window tile fetcher. Each takes 2 dots, except `Push`, which
+/// waits until the BG FIFO is empty to push the fetched row of 8 pixels into it.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+enum FetchStep {
+    #[default]
+    TileIndex = 0,
+    DataLow = 1,
+    DataHigh = 2,
+    Push = 3,
+}
+
+/// Fetches rows of background or window tiles for the BG FIFO during Mode 3. Registers are
+/// read as each step happens, so changes made partway through a scanline take effect from
+/// the next tile fetched.
 #[derive(Default)]
-struct PixelInfo {
+struct Fetcher {
+    step: FetchStep,
+    /// Dots spent on the current step
+    dots: u8,
+    /// Column of the tile fetched next, counted from the left edge of the screen, or of the
+    /// window when fetching window tiles
+    tile_x: u8,
+    /// Whether window tiles are being fetched rather than background tiles
+    window: bool,
+    tile_index: u8,
+    data_low: u8,
+    data_high: u8,
+}
+
+/// A pixel of an OBJ waiting in the OBJ FIFO to be mixed with the background
+#[derive(Copy, Clone, Default)]
+struct ObjPixel {
+    /// Color number 0-3, 0 being transparent
     color_idx: u8,
-    palette: u8,
-    _sprite_prio: u8,
+    obp1: bool,
+    /// Whether BG color numbers 1-3 are drawn over this pixel
     bg_prio: bool,
 }
 
+impl ObjPixel {
+    fn to_byte(self) -> u8 {
+        self.color_idx | ((self.obp1 as u8) << 2) | ((self.bg_prio as u8) << 3)
+    }
+
+    fn from_byte(byte: u8) -> Self {
+        ObjPixel {
+            color_idx: byte & 0x3,
+            obp1: byte & 0x4 != 0,
+            bg_prio: byte & 0x8 != 0,
+        }
+    }
+}
+
+/// State of the pixel FIFOs while a scanline is drawn in Mode 3. Pixels are shifted out to
+/// the LCD one per dot as they become available, so register writes made while a scanline is
+/// drawn affect only the pixels after them, as on hardware.
+#[derive(Default)]
+struct PixelFifo {
+    /// Color numbers of background/window pixels
+    bg: VecDeque<u8>,
+    /// OBJ pixels, lined up with the front of `bg`
+    obj: VecDeque<ObjPixel>,
+    fetcher: Fetcher,
+    /// X coordinate of the next pixel sent to the LCD
+    x: u8,
+    /// Pixels still to be thrown away rather than drawn, for fine scrolling by SCX
+    discard: u8,
+    /// Dots left before the fetcher starts at the beginning of the scanline
+    stall: u8,
+    /// Whether the window has started on this scanline
+    window_active: bool,
+    /// The `obj_list` entry being fetched, and the dots left to fetch it
+    obj_fetch: Option<(u8, u8)>,
+    /// Bit per `obj_list` entry, set once the entry has been fetched on this scanline
+    fetched_objs: u64,
+}
+
 /// Type alias for the rendered screen data
 pub type FrameData = Box<[u8]>;
 
 const SCREEN_WIDTH: usize = 160;
 const SCREEN_HEIGHT: usize = 144;
 
+/// Width and height in pixels of a full 32x32 tile background map
+pub const TILEMAP_SIZE: usize = 256;
+
+/// Width in pixels of the tile data dump, 16 tiles across
+pub const TILES_WIDTH: usize = 128;
+
+/// Height in pixels of the tile data dump, 24 rows of tiles
+pub const TILES_HEIGHT: usize = 192;
+
+/// Number of OBJs the hardware draws on a single scanline
+const SPRITES_PER_LINE: usize = 10;
+
+/// Dots at the start of Mode 3 before the first tile is fetched, from the hardware fetching
+/// the first tile twice. Brings Mode 3 to its minimum of 172 dots.
+const MODE3_START_DELAY: u8 = 6;
+
+/// Dots the pixel FIFO is paused for to fetch an OBJ, after the background fetcher has
+/// finished the tile it was fetching
+const OBJ_FETCH_DOTS: u8 = 6;
+
 pub struct Vram {
     /// 0xFF40: LCD Control
     lcdc: Lcdc,
@@ -280,6 +380,15 @@ pub struct Vram {
     /// Window X = 7 and Window = 0 represents a Window position at the top left of the LCD
     window_coords: (u8, u8),
 
+    /// Internal window line counter. Selects which line of the window is drawn, and only
+    /// increments on scanlines where the window was actually drawn, so the window always starts
+    /// from its first line no matter when it is enabled. Reset at the start of each frame.
+    window_line: u8,
+
+    /// Set once LY has matched WY during the current frame, which allows the window to be drawn
+    /// on this and the following scanlines. Reset at the start of each frame.
+    window_y_triggered: bool,
+
     /// Number of cycles, or dots, that the LCD is in the current scanline. Max is 456, and value
     /// determines which Mode the LCD is in. Corresponds to CPU cycles passed in to MMU.
     scanline_cycles: u32,
@@ -290,11 +399,22 @@ pub struct Vram {
     /// Read during Mode 3 (Draw scanline)
     obj_list: Vec<u8>,
 
+    /// The scanline being drawn during Mode 3
+    fifo: PixelFifo,
+
+    /// Whether OAM search stops after the first 10 OBJs on a line, as on hardware.
+    /// Lifting the limit is an enhancement that stops games from flickering sprites.
+    sprite_limit: bool,
+
     /// Data containing the rendered scanlines. Presented as row-major, meaning that
     /// the first (top-left) pixel is represented by the first 3 values, the next pixel to the right is
     /// represented by the next 3 values, and the next row doesn't begin until the SCREEN_WIDTH * 3 value.
     screen_data: FrameData,
 
+    /// The color number, 0-3, of each pixel in `screen_data` before the BGP, OBP0 or OBP1
+    /// palette was applied. One value per pixel, in the same order.
+    screen_indices: Box<[u8]>,
+
     /// VRAM data
     memory: Box<[u8]>,
 
@@ -314,9 +434,14 @@ impl Vram {
             obp0: PaletteData::init(),
             obp1: PaletteData::init(),
             window_coords: (0x0, 0x0),
+            window_line: 0,
+            window_y_triggered: false,
             scanline_cycles: 0,
             obj_list: Vec::with_capacity(40),
+            fifo: PixelFifo::default(),
+            sprite_limit: true,
             screen_data: vec![0x0; 3 * SCREEN_WIDTH * SCREEN_HEIGHT].into_boxed_slice(),
+            screen_indices: vec![0x0; SCREEN_WIDTH * SCREEN_HEIGHT].into_boxed_slice(),
             memory: vec![0; 0x2000].into_boxed_slice(),
             oam: vec![0; 0xA0].into_boxed_slice(),
         };
@@ -346,10 +471,43 @@ impl Vram {
         // The CPU can't see VRAM (writes are ignored and reads are $FF) during mode 3, but it can during other modes.
         // The CPU can't see OAM during modes 2 and 3, but it can during blanking modes (0 and 1).
 
-        // TODO: If cycles are too high, we don't want to do it all at once. Try and make sure
-        // cycles are in groups of 4, i.e. split CPU ticks to cycle operations, not instructions
-        self.scanline_cycles += cycles;
+        // The LCD runs a dot at a time, so Mode 3 can draw pixels as they come out of the FIFO.
+        // TODO: Register writes only take effect once the CPU finishes its instruction, so
+        // mid-scanline effects can land a few dots early until CPU ticks are split into cycles.
         self.stat.lyc_ly_flag = self.ly == self.lyc;
+        let mut remaining = cycles;
+        while remaining > 0 {
+            // Outside of Mode 3 nothing happens until the next mode change, so skip to it
+            let idle = match self.stat.mode_flag {
+                LCDMode::Mode3 => 0,
+                LCDMode::Mode2 => 80 - self.scanline_cycles.min(80),
+                // The LCD was just turned on, and Mode 2 is about to start
+                _ if self.ly < 144 && self.scanline_cycles < 80 => 0,
+                _ => 455 - self.scanline_cycles,
+            }
+            .min(remaining);
+            self.scanline_cycles += idle;
+            remaining -= idle;
+            if remaining > 0 {
+                self.tick_dot(video_sink, &mut interrupts);
+                remaining -= 1;
+            }
+        }
+
+        if !interrupts.is_empty() {
+            Some(interrupts)
+        } else {
+            None
+        }
+    }
+
+    /// Runs the LCD for a single dot
+    fn tick_dot(
+        &mut self,
+        video_sink: &mut dyn Sink<VideoFrame>,
+        interrupts: &mut Vec<InterruptKind>,
+    ) {
+        self.scanline_cycles += 1;
 
         if self.scanline_cycles >= 456 {
             // Reached end of scanline, wrap around and increment LY
@@ -370,6 +528,8 @@ impl Vram {
             if self.stat.mode_flag != LCDMode::Mode1 {
                 // If we are just entering V-Blank
                 self.stat.mode_flag = LCDMode::Mode1;
+                self.window_line = 0;
+                self.window_y_triggered = false;
                 // New frame ready to be rendered
                 video_sink.append(self.screen_data.clone());
                 interrupts.push(InterruptKind::VBlank);
@@ -382,35 +542,57 @@ impl Vram {
             if self.stat.mode_flag != LCDMode::Mode2 {
                 // We are just entering Mode 2
                 self.stat.mode_flag = LCDMode::Mode2;
+                if self.ly == self.window_coords.1 {
+                    self.window_y_triggered = true;
+                }
                 // Perform the OAM Scan to collect the OBJs on this line
                 self.oam_search();
                 if self.stat.oam_interrupt && !interrupts.contains(&InterruptKind::LcdStat) {
                     interrupts.push(InterruptKind::LcdStat);
                 }
             }
-        } else if self.scanline_cycles <= (80 + 172) {
-            // TODO: Change cycle check to be non-arbitrary, the number of cycles spent in
-            // Mode 3 is variable upon sprite drawing
-            if self.stat.mode_flag != LCDMode::Mode3 {
-                // Unnecessary, but for consistency
-                self.stat.mode_flag = LCDMode::Mode3;
-            }
-        } else {
+        } else if self.stat.mode_flag == LCDMode::Mode2 {
+            // Mode 3 lasts until all 160 pixels are drawn, which takes longer with fine
+            // scrolling, the window and OBJs on the line
+            self.stat.mode_flag = LCDMode::Mode3;
+            self.start_scanline();
+            self.draw_dot();
+        } else if self.stat.mode_flag == LCDMode::Mode3 && self.draw_dot() {
             // Spend the rest of the scanline in Mode 0: H-Blank
-            if self.stat.mode_flag != LCDMode::Mode0 {
-                self.stat.mode_flag = LCDMode::Mode0;
-                if self.stat.hblank_interrupt && !interrupts.contains(&InterruptKind::LcdStat) {
-                    interrupts.push(InterruptKind::LcdStat);
-                }
-                self.draw_scanline();
+            self.stat.mode_flag = LCDMode::Mode0;
+            if self.stat.hblank_interrupt && !interrupts.contains(&InterruptKind::LcdStat) {
+                interrupts.push(InterruptKind::LcdStat);
+            }
+            if self.fifo.window_active {
+                self.window_line = self.window_line.wrapping_add(1);
             }
         }
+    }
 
-        if !interrupts.is_empty() {
-            Some(interrupts)
-        } else {
-            None
+    /// Applies the DMG OAM bug "write" corruption, triggered when the CPU puts an address in
+    /// 0xFE00-0xFEFF on the bus during Mode 2. OAM is accessed as 20 rows of 8 bytes, one row
+    /// per 4 dots of OAM scan. The first word of the row currently being scanned is replaced with
+    /// `((a ^ c) & (b ^ c)) ^ c`, where `a` is that word, and `b` and `c` are the first and third
+    /// words of the preceding row. The remaining three words are copied from the preceding row.
+    /// The first row is never corrupted.
+    pub fn oam_bug_write(&mut self) {
+        if !self.lcdc.lcd_enable || self.stat.mode_flag != LCDMode::Mode2 {
+            return;
         }
+        let row = (self.scanline_cycles / 4) as usize;
+        if row == 0 || row >= 20 {
+            return;
+        }
+        let cur = row * 8;
+        let prev = cur - 8;
+        let word = |i: usize| u16::from_le_bytes([self.oam[i], self.oam[i + 1]]);
+        let a = word(cur);
+        let b = word(prev);
+        let c = word(prev + 4);
+        let corrupted = (((a ^ c) & (b ^ c)) ^ c).to_le_bytes();
+        self.oam[cur] = corrupted[0];
+        self.oam[cur + 1] = corrupted[1];
+        self.oam.copy_within(prev + 2..prev + 8, cur + 2);
     }
 
     /// Scan the current contents of OAM to find all OBJs that are on the same scanline.
@@ -427,279 +609,420 @@ impl Vram {
             // Check if the OBJ y-pos is in the range of values that would put a line in the current ly
             if data[0] > self.ly + obj_size_adj && data[0] <= self.ly + 16 {
                 // This OBJ is in the current line, add to the list if we have < 10 OBJs already
-                if self.obj_list.len() < 10 {
+                if !self.sprite_limit || self.obj_list.len() < SPRITES_PER_LINE {
                     self.obj_list.push(i as u8);
                 }
             }
         }
     }
 
-    /// Compute and "render" the scanline into the internal LCD data state
-    fn draw_scanline(&mut self) {
-        for p in 0..SCREEN_WIDTH {
-            let bg_pixel = if self.lcdc.background_enable {
-                Some(self.get_background_pixel(p as u8))
-            } else {
-                None
+    /// Resets the pixel FIFOs at the start of Mode 3
+    fn start_scanline(&mut self) {
+        let fifo = &mut self.fifo;
+        fifo.bg.clear();
+        fifo.obj.clear();
+        fifo.fetcher = Fetcher::default();
+        fifo.x = 0;
+        fifo.discard = self.scroll_coords.0 % 8;
+        fifo.stall = MODE3_START_DELAY;
+        fifo.window_active = false;
+        fifo.obj_fetch = None;
+        fifo.fetched_objs = 0;
+    }
+
+    /// Runs the pixel FIFOs for one dot of Mode 3, sending at most one pixel to the LCD.
+    /// Returns true once all pixels on the scanline have been drawn.
+    fn draw_dot(&mut self) -> bool {
+        if self.fifo.x as usize == SCREEN_WIDTH {
+            return true;
+        }
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return false;
+        }
+
+        // The window starts once the pixels reach WX, replacing the background from then on
+        let window_visible =
+            self.lcdc.background_enable && self.lcdc.window_enable && self.window_y_triggered;
+        if !self.fifo.window_active && window_visible && self.fifo.x + 7 >= self.window_coords.0 {
+            self.fifo.window_active = true;
+            self.fifo.bg.clear();
+            self.fifo.fetcher = Fetcher {
+                window: true,
+                ..Default::default()
             };
+            // With WX below 7, the left of the window is off screen
+            self.fifo.discard = 7u8.saturating_sub(self.window_coords.0);
+        }
 
-            let sprite_pixel = if self.lcdc.obj_enable {
-                Some(self.get_sprite_pixel(p as u8))
+        if let Some((entry, dots)) = self.fifo.obj_fetch {
+            // The OBJ is fetched once the background fetcher finishes its current tile
+            if self.fifo.fetcher.step != FetchStep::Push {
+                self.tick_fetcher();
+            } else if dots > 1 {
+                self.fifo.obj_fetch = Some((entry, dots - 1));
             } else {
-                None
-            };
+                self.fifo.obj_fetch = None;
+                self.fetch_obj(entry);
+            }
+            return false;
+        }
+        if self.lcdc.obj_enable {
+            // OBJs are fetched when the pixels reach their left edge, smallest X first
+            let x = self.fifo.x;
+            let next = (0..self.obj_list.len()).find(|i| {
+                self.fifo.fetched_objs & (1 << i) == 0
+                    && self.oam[self.obj_list[*i] as usize * 4 + 1] <= x + 8
+            });
+            if let Some(i) = next {
+                self.fifo.fetched_objs |= 1 << i;
+                self.fifo.obj_fetch = Some((i as u8, OBJ_FETCH_DOTS));
+                return false;
+            }
+        }
 
-            let pixel_shade = if let (Some(b), Some(p)) = (&bg_pixel, &sprite_pixel) {
-                if p.color_idx > 0 {
-                    if b.color_idx == 0 || !p.bg_prio {
-                        match p.palette {
-                            0 => match p.color_idx {
-                                0 => self.obp0.color0,
-                                1 => self.obp0.color1,
-                                2 => self.obp0.color2,
-                                3 => self.obp0.color3,
-                                _ => unreachable!(),
-                            },
-                            1 => match p.color_idx {
-                                0 => self.obp1.color0,
-                                1 => self.obp1.color1,
-                                2 => self.obp1.color2,
-                                3 => self.obp1.color3,
-                                _ => unreachable!(),
-                            },
-                            _ => unreachable!(),
-                        }
-                    } else {
-                        match b.color_idx {
-                            0 => self.bgp.color0,
-                            1 => self.bgp.color1,
-                            2 => self.bgp.color2,
-                            3 => self.bgp.color3,
-                            _ => unreachable!(),
-                        }
-                    }
-                } else {
-                    match b.color_idx {
-                        0 => self.bgp.color0,
-                        1 => self.bgp.color1,
-                        2 => self.bgp.color2,
-                        3 => self.bgp.color3,
-                        _ => unreachable!(),
-                    }
-                }
-            } else if let (Some(b), None) = (&bg_pixel, &sprite_pixel) {
-                match b.color_idx {
-                    0 => self.bgp.color0,
-                    1 => self.bgp.color1,
-                    2 => self.bgp.color2,
-                    3 => self.bgp.color3,
-                    _ => unreachable!(),
-                }
-            } else if let (None, Some(p)) = (&bg_pixel, &sprite_pixel) {
-                match p.palette {
-                    0 => match p.color_idx {
-                        0 => self.obp0.color0,
-                        1 => self.obp0.color1,
-                        2 => self.obp0.color2,
-                        3 => self.obp0.color3,
-                        _ => unreachable!(),
-                    },
-                    1 => match p.color_idx {
-                        0 => self.obp1.color0,
-                        1 => self.obp1.color1,
-                        2 => self.obp1.color2,
-                        3 => self.obp1.color3,
-                        _ => unreachable!(),
-                    },
-                    _ => unreachable!(),
+        self.tick_fetcher();
+        let Some(color_idx) = self.fifo.bg.pop_front() else {
+            return false;
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return false;
+        }
+        let obj = self.fifo.obj.pop_front();
+        self.draw_pixel(color_idx, obj);
+        self.fifo.x += 1;
+        false
+    }
+
+    /// Advances the background/window fetcher by one dot
+    fn tick_fetcher(&mut self) {
+        let fetcher = &mut self.fifo.fetcher;
+        if fetcher.step == FetchStep::Push {
+            // Waits for the FIFO to empty, and for any OBJ being fetched
+            if self.fifo.bg.is_empty() && self.fifo.obj_fetch.is_none() {
+                for bit in (0..8).rev() {
+                    self.fifo.bg.push_back(
+                        (((fetcher.data_high >> bit) & 0x1) << 1)
+                            | ((fetcher.data_low >> bit) & 0x1),
+                    );
                 }
-            } else {
-                // Neither are present, return a White/Color 1
-                GrayShades::White
-            };
-            let pixel_rgb = Self::shade_to_rgb_u8(&pixel_shade);
-
-            self.screen_data[(self.ly as usize * (SCREEN_WIDTH * 3)) + (p * 3)] = pixel_rgb.0;
-            self.screen_data[(self.ly as usize * (SCREEN_WIDTH * 3)) + (p * 3) + 1] = pixel_rgb.1;
-            self.screen_data[(self.ly as usize * (SCREEN_WIDTH * 3)) + (p * 3) + 2] = pixel_rgb.2;
-        }
-    }
-
-    /// Check internal state to determine what horizontal scanline background
-    /// pixels should be written to `screen_data`. Includes checking if rendering
-    /// window tiles in addition to background tiles. Only called during H-Blank,
-    /// and fills the scanline as provided by `ly`, assuming we're not in V-Blank
-    fn get_background_pixel(&mut self, pixel: u8) -> PixelInfo {
-        // Get the tile data index and pixel offsets, either from the window map or the background map
-        let (mut tile_data_base, tile_pixel_x, tile_pixel_y) = if self.lcdc.window_enable
-            && pixel >= self.window_coords.0.saturating_sub(7)
-            && self.ly >= self.window_coords.1
-        {
-            // We are inside the window, so grab window tiles
-            let tile_x: u8 = (pixel - self.window_coords.0.saturating_sub(7)) / 8;
-            let tile_y: u8 = (self.ly - self.window_coords.1) / 8;
-
-            // Get the pixel coordinates for the tile
-            let tile_pixel_x: u8 = (pixel - self.window_coords.0.saturating_sub(7)) % 8;
-            let tile_pixel_y: u8 = (self.ly - self.window_coords.1) % 8;
-
-            // Get the tile map offset from what tile we are using
-            let mut tile_map_index: u16 = (tile_y as u16 * 32) + tile_x as u16;
-
-            // Add the relevant base address depending on which tile map is selected
-            // Tile Map 0: 0x9800 - 0x8000 = 0x1800
-            // Tile Map 1: 0x9C00 - 0x8000 = 0x1C00
-            if self.lcdc.window_tile_map_select {
-                tile_map_index += 0x1C00;
-            } else {
-                tile_map_index += 0x1800;
+                fetcher.tile_x = fetcher.tile_x.wrapping_add(1);
+                fetcher.step = FetchStep::TileIndex;
+            }
+            return;
+        }
+        fetcher.dots += 1;
+        if fetcher.dots < 2 {
+            return;
+        }
+        fetcher.dots = 0;
+
+        // The line within the tile map being drawn
+        let map_y = if fetcher.window {
+            self.window_line
+        } else {
+            self.scroll_coords.1.wrapping_add(self.ly)
+        };
+        match fetcher.step {
+            FetchStep::TileIndex => {
+                // Tile Map 0: 0x9800 - 0x8000 = 0x1800
+                // Tile Map 1: 0x9C00 - 0x8000 = 0x1C00
+                let (map_select, map_x) = if fetcher.window {
+                    (self.lcdc.window_tile_map_select, fetcher.tile_x)
+                } else {
+                    (
+                        self.lcdc.background_tile_map_select,
+                        (self.scroll_coords.0 / 8).wrapping_add(fetcher.tile_x),
+                    )
+                };
+                let map_base = if map_select { 0x1C00 } else { 0x1800 };
+                let map_index = ((map_y as usize / 8) * 32) + (map_x as usize % 32);
+                fetcher.tile_index = self.memory[map_base + map_index];
+                fetcher.step = FetchStep::DataLow;
+            }
+            FetchStep::DataLow => {
+                let row =
+                    self.bg_tile_data_base(self.fifo.fetcher.tile_index) + (map_y as usize % 8) * 2;
+                self.fifo.fetcher.data_low = self.memory[row];
+                self.fifo.fetcher.step = FetchStep::DataHigh;
             }
+            FetchStep::DataHigh => {
+                let row =
+                    self.bg_tile_data_base(self.fifo.fetcher.tile_index) + (map_y as usize % 8) * 2;
+                self.fifo.fetcher.data_high = self.memory[row + 1];
+                self.fifo.fetcher.step = FetchStep::Push;
+            }
+            FetchStep::Push => unreachable!(),
+        }
+    }
 
-            // Grab the tile data index
-            (
-                self.memory[tile_map_index as usize] as u16,
-                tile_pixel_x,
-                tile_pixel_y,
-            )
+    /// Fetches a row of the OBJ in `obj_list[entry]` into the OBJ FIFO. Pixels already in the
+    /// FIFO that aren't transparent keep priority, as they belong to OBJs further left or
+    /// earlier in OAM.
+    fn fetch_obj(&mut self, entry: u8) {
+        let oam_index = self.obj_list[entry as usize] as usize * 4;
+        let y_pos = self.oam[oam_index];
+        let x_pos = self.oam[oam_index + 1];
+        let tile_idx = self.oam[oam_index + 2];
+        let attribs = self.oam[oam_index + 3];
+
+        // Parse attributes
+        let bg_prio = (attribs & 0b1000_0000) != 0;
+        let y_flip = (attribs & 0b0100_0000) != 0;
+        let x_flip = (attribs & 0b0010_0000) != 0;
+        let obp1 = (attribs & 0b0001_0000) != 0;
+
+        let mut tile_pixel_y = (self.ly + 16).wrapping_sub(y_pos);
+        // Get the location of the tile data, starting at 0x8000
+        // Internally, we start at 0x0000
+        let tile_data_base = if self.lcdc.obj_size_select {
+            // 8x16
+            if (tile_pixel_y > 7 && !y_flip) || (tile_pixel_y <= 7 && y_flip) {
+                // Bottom tile
+                (tile_idx | 0x01) as usize * 16
+            } else {
+                // Top tile
+                (tile_idx & 0xFE) as usize * 16
+            }
+        } else {
+            tile_idx as usize * 16
+        };
+        if y_flip {
+            // Invert the bits and mask the lower 3 to get the new line offset
+            tile_pixel_y = !tile_pixel_y & 0x7;
         } else {
-            // No window, just grab from background map using scroll coords
-            let tile_x: u8 = self.scroll_coords.0.wrapping_add(pixel) / 8;
-            let tile_y: u8 = self.scroll_coords.1.wrapping_add(self.ly) / 8;
-
-            // Get the pixel coordinates for the tile
-            let tile_pixel_x: u8 = self.scroll_coords.0.wrapping_add(pixel) % 8;
-            let tile_pixel_y: u8 = self.scroll_coords.1.wrapping_add(self.ly) % 8;
-
-            // Get the tile map offset from what tile we are using
-            let mut tile_map_index: u16 = (tile_y as u16 * 32) + tile_x as u16;
-
-            // Add the relevant base address depending on which tile map is selected
-            // Tile Map 0: 0x9800 - 0x8000 = 0x1800
-            // Tile Map 1: 0x9C00 - 0x8000 = 0x1C00
-            if self.lcdc.background_tile_map_select {
-                tile_map_index += 0x1C00;
+            // Just mask the lower 3 bits to contain it within the given tile
+            tile_pixel_y &= 0x7
+        }
+        let tile_colors_lsb = self.memory[tile_data_base + (tile_pixel_y as usize * 2)];
+        let tile_colors_msb = self.memory[tile_data_base + (tile_pixel_y as usize * 2) + 1];
+
+        // OBJs partly off the left of the screen lose the pixels already passed
+        let skip = (self.fifo.x + 8).saturating_sub(x_pos);
+        while self.fifo.obj.len() < 8 {
+            self.fifo.obj.push_back(ObjPixel::default());
+        }
+        for tile_pixel_x in skip..8 {
+            // Which pixel in the line we shift over changes on the status of x_flip
+            let pixel_shift = if x_flip {
+                tile_pixel_x
             } else {
-                tile_map_index += 0x1800;
+                !tile_pixel_x & 0x7
+            };
+            let color_idx = (((tile_colors_msb >> pixel_shift) & 0x1) << 1)
+                | ((tile_colors_lsb >> pixel_shift) & 0x1);
+            let slot = &mut self.fifo.obj[(tile_pixel_x - skip) as usize];
+            if slot.color_idx == 0 {
+                *slot = ObjPixel {
+                    color_idx,
+                    obp1,
+                    bg_prio,
+                };
             }
+        }
+    }
 
-            // Grab the tile data index
-            (
-                self.memory[tile_map_index as usize] as u16,
-                tile_pixel_x,
-                tile_pixel_y,
-            )
+    /// Mixes a background pixel with an OBJ pixel and writes the result at the current
+    /// position of the scanline in `screen_data`
+    fn draw_pixel(&mut self, bg_color_idx: u8, obj: Option<ObjPixel>) {
+        let bg = Some(bg_color_idx).filter(|_| self.lcdc.background_enable);
+        let obj = obj.filter(|obj| self.lcdc.obj_enable && obj.color_idx != 0);
+        let (shade, color_idx) = match (bg, obj) {
+            (Some(b), Some(o)) if b == 0 || !o.bg_prio => (self.obj_shade(o), o.color_idx),
+            (Some(b), _) => (self.bgp.shade(b), b),
+            (None, Some(o)) => (self.obj_shade(o), o.color_idx),
+            // Neither are present, return a White/Color 1
+            (None, None) => (GrayShades::White, 0),
         };
+        let pixel = (self.ly as usize * SCREEN_WIDTH) + self.fifo.x as usize;
+        let pixel_rgb = Self::shade_to_rgb_u8(&shade);
+        self.screen_indices[pixel] = color_idx;
+        self.screen_data[pixel * 3] = pixel_rgb.0;
+        self.screen_data[pixel * 3 + 1] = pixel_rgb.1;
+        self.screen_data[pixel * 3 + 2] = pixel_rgb.2;
+    }
 
+    fn obj_shade(&self, obj: ObjPixel) -> GrayShades {
+        if obj.obp1 {
+            self.obp1.shade(obj.color_idx)
+        } else {
+            self.obp0.shade(obj.color_idx)
+        }
+    }
+
+    /// Offset into VRAM of a background/window tile's data, using the tile data table
+    /// currently selected by LCDC bit 4. `tile_index` is the raw value taken from the tile map.
+    fn bg_tile_data_base(&self, tile_index: u8) -> usize {
         // Add the relevant base address depending on which tile data is selected
         if !self.lcdc.tile_data_select {
             // The Tile Data index is a signed byte value when using Tile Table 1, reinterpret as an i8.
-            let tile_data_signed = i8::from_le_bytes([tile_data_base as u8]);
+            let tile_data_signed = i8::from_le_bytes([tile_index]);
             // Each Tile Data Table entry is 16 bytes, then offset by signed index.
             // Value of 0 is at 0x1000 into the VRAM, then subtracted or added to by the signed index
-            tile_data_base = (((tile_data_signed) as i16 * 16) + 0x1000) as u16;
+            ((tile_data_signed as i16 * 16) + 0x1000) as usize
         } else {
             // Each Tile Data Table entry is 16 bytes, starting at 0x0000
-            tile_data_base *= 16;
+            tile_index as usize * 16
         }
+    }
+
+    /// Looks up the color number (0-3) of a pixel inside a background/window tile, using the
+    /// tile data table currently selected by LCDC bit 4. `tile_index` is the raw value taken
+    /// from the tile map, and `tile_pixel_x`/`tile_pixel_y` are the pixel offsets within the tile.
+    fn bg_tile_color_number(&self, tile_index: u8, tile_pixel_x: u8, tile_pixel_y: u8) -> u8 {
+        let tile_data_base = self.bg_tile_data_base(tile_index);
 
         // Each set of 2 bytes represets the least and most signficant bits in the tile's color number, respectively,
         // for each line of 8 pixels in the tile.
         // Byte 0-1 is first line, Byte 2-3 is second line, etc.
         // Offset the line we're looking for by applying the tile pixel y-offset, and grab both color bytes
-        let tile_colors_lsb = self.memory[(tile_data_base + (tile_pixel_y as u16 * 2)) as usize];
-        let tile_colors_msb =
-            self.memory[(tile_data_base + (tile_pixel_y as u16 * 2) + 1) as usize];
+        let tile_colors_lsb = self.memory[tile_data_base + (tile_pixel_y as usize * 2)];
+        let tile_colors_msb = self.memory[tile_data_base + (tile_pixel_y as usize * 2) + 1];
 
         let pixel_shift = tile_pixel_x ^ 0x7;
-        let tile_color_number = (((tile_colors_msb >> pixel_shift) & 0x1) << 1)
-            | ((tile_colors_lsb >> pixel_shift) & 0x1);
-
-        PixelInfo {
-            color_idx: tile_color_number,
-            palette: 0,
-            _sprite_prio: 0,
-            bg_prio: false,
-        }
-    }
-
-    /// Called after `draw_background` fills scanline `ly` with data inside `screen_data`
-    /// with background and window tiles. Goes through OBJ memory to determine the
-    /// sprites to be drawn over the background tiles, and writes them in the same
-    /// `ly` scanline within `screen_data`.
-    fn get_sprite_pixel(&mut self, pixel: u8) -> PixelInfo {
-        let mut ret = PixelInfo::default();
-        // Once all OBJs are found, go through the line and check the valid OBJs for the current scanline pixel being placed
-        // Go in reverse so that the first valid OAM entries override past ones
-        for i in self.obj_list.iter().rev() {
-            let y_pos = self.oam[(i * 4) as usize];
-            let x_pos = self.oam[((i * 4) + 1) as usize];
-            let tile_idx = self.oam[((i * 4) + 2) as usize];
-            let attribs = self.oam[((i * 4) + 3) as usize];
-
-            // Check x-pos for this OBJ
-            if x_pos > pixel && x_pos <= pixel + 8 {
-                let tile_pixel_x = pixel + 8 - x_pos;
-                let mut tile_pixel_y = (self.ly + 16).wrapping_sub(y_pos);
-
-                // Parse attributes
-                let bg_prio = (attribs & 0b1000_0000) != 0;
-                let y_flip = (attribs & 0b0100_0000) != 0;
-                let x_flip = (attribs & 0b0010_0000) != 0;
-                let obp1 = (attribs & 0b0001_0000) != 0;
-
-                // Get the location of the tile data, starting at 0x8000
-                // Internally, we start at 0x0000
-                let tile_data_base = if self.lcdc.obj_size_select {
-                    // 8x16
-                    if (tile_pixel_y > 7 && !y_flip) || (tile_pixel_y <= 7 && y_flip) {
-                        // Bottom tile
-                        (tile_idx | 0x01) as u16 * 16
-                    } else {
-                        // Top tile
-                        (tile_idx & 0xFE) as u16 * 16
-                    }
-                } else {
-                    tile_idx as u16 * 16
-                };
+        (((tile_colors_msb >> pixel_shift) & 0x1) << 1) | ((tile_colors_lsb >> pixel_shift) & 0x1)
+    }
 
-                if y_flip {
-                    // Invert the bits and mask the lower 3 to get the new line offset
-                    tile_pixel_y = !tile_pixel_y & 0x7;
-                } else {
-                    // Just mask the lower 3 bits to contain it within the given tile
-                    tile_pixel_y &= 0x7
-                }
+    /// Enables or disables the limit of 10 OBJs per scanline. Enabled by default.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
+
+    /// The current LCD mode, 0-3, as reported in STAT bits 1-0
+    pub fn mode(&self) -> u8 {
+        self.stat.mode_flag as u8
+    }
+
+    /// The scanline currently being processed, as reported by LY
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    /// Cycles until the next V-Blank starts, or `None` while the LCD is off
+    pub fn cycles_until_vblank(&self) -> Option<u32> {
+        if !self.lcdc.lcd_enable {
+            return None;
+        }
+        // V-Blank starts as line 143 ends
+        let lines = (143 + 154 - self.ly as u32) % 154;
+        Some(lines * 456 + 456 - self.scanline_cycles)
+    }
+
+    /// The RGB screen data as drawn so far, containing the last completed frame once in V-Blank
+    pub fn screen(&self) -> &[u8] {
+        &self.screen_data
+    }
 
-                // Each set of 2 bytes represets the least and most signficant bits in the tile's color number, respectively,
-                // for each line of 8 pixels in the tile.
-                // Byte 0-1 is first line, Byte 2-3 is second line, etc.
-                // Offset the line we're looking for by applying the tile pixel y-offset, and grab both color bytes
-                let tile_colors_lsb =
-                    self.memory[(tile_data_base + (tile_pixel_y as u16 * 2)) as usize];
-                let tile_colors_msb =
-                    self.memory[(tile_data_base + (tile_pixel_y as u16 * 2) + 1) as usize];
-
-                // Which pixel in the line we shift over changes on the status of x_flip
-                let pixel_shift = if x_flip {
-                    tile_pixel_x
+    /// The color numbers of the screen before palettes were applied, see `screen_indices`
+    pub fn screen_indices(&self) -> &[u8] {
+        &self.screen_indices
+    }
+
+    /// The raw VRAM bytes at 0x8000-0x9FFF
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// The raw OAM bytes at 0xFE00-0xFE9F
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    /// Decodes the 40 OBJs in OAM
+    pub fn oam_entries(&self) -> Vec<OamEntry> {
+        self.oam
+            .chunks_exact(4)
+            .map(|entry| OamEntry {
+                y: entry[0],
+                x: entry[1],
+                tile: entry[2],
+                behind_bg: entry[3] & 0b1000_0000 != 0,
+                flip_y: entry[3] & 0b0100_0000 != 0,
+                flip_x: entry[3] & 0b0010_0000 != 0,
+                palette: if entry[3] & 0b0001_0000 != 0 {
+                    TilePalette::Obp1
                 } else {
-                    !tile_pixel_x & 0x7
-                };
+                    TilePalette::Obp0
+                },
+            })
+            .collect()
+    }
 
-                let tile_color_number = (((tile_colors_msb >> pixel_shift) & 0x1) << 1)
-                    | ((tile_colors_lsb >> pixel_shift) & 0x1);
+    /// The color numbers of the pixels of tile `index` counting from 0x8000, row by row.
+    /// Panics past the last tile, 383.
+    pub fn tile_pixels(&self, index: usize) -> [u8; 64] {
+        let tile = &self.memory[index * 16..index * 16 + 16];
+        core::array::from_fn(|i| {
+            let (x, y) = (i % 8, i / 8);
+            let pixel_shift = x ^ 0x7;
+            (((tile[y * 2 + 1] >> pixel_shift) & 0x1) << 1) | ((tile[y * 2] >> pixel_shift) & 0x1)
+        })
+    }
 
-                if tile_color_number != 0 {
-                    ret = PixelInfo {
-                        color_idx: tile_color_number,
-                        palette: obp1 as u8,
-                        _sprite_prio: 0,
-                        bg_prio,
-                    };
-                }
+    /// Where the background, window and OBJs are drawn from, going by LCDC, SCX/SCY and WX/WY
+    pub fn background_layout(&self) -> BackgroundLayout {
+        BackgroundLayout {
+            scroll_x: self.scroll_coords.0,
+            scroll_y: self.scroll_coords.1,
+            window_x: self.window_coords.0,
+            window_y: self.window_coords.1,
+            bg_enabled: self.lcdc.background_enable,
+            window_enabled: self.lcdc.window_enable,
+            objs_enabled: self.lcdc.obj_enable,
+            bg_map_select: self.lcdc.background_tile_map_select,
+            window_map_select: self.lcdc.window_tile_map_select,
+            tile_data_select: self.lcdc.tile_data_select,
+            tall_objs: self.lcdc.obj_size_select,
+        }
+    }
+
+    /// Renders the full 256x256 pixel background tile map into an RGB buffer, ignoring scroll
+    /// and window state. `map_select` chooses the map in the same way as LCDC bits 3 and 6:
+    /// false means use 0x9800-0x9BFF, true means use 0x9C00-0x9FFF.
+    /// Tile data addressing and the BG palette are taken from the current LCDC/BGP values.
+    pub fn dump_tilemap(&self, map_select: bool) -> VideoFrame {
+        let map_base: usize = if map_select { 0x1C00 } else { 0x1800 };
+        let mut data = vec![0x0; 3 * TILEMAP_SIZE * TILEMAP_SIZE];
+        for y in 0..TILEMAP_SIZE {
+            for x in 0..TILEMAP_SIZE {
+                let tile_index = self.memory[map_base + ((y / 8) * 32) + (x / 8)];
+                let color_idx = self.bg_tile_color_number(tile_index, (x % 8) as u8, (y % 8) as u8);
+                let pixel_rgb = Self::shade_to_rgb_u8(&self.bgp.shade(color_idx));
+                let offset = (y * TILEMAP_SIZE * 3) + (x * 3);
+                data[offset] = pixel_rgb.0;
+                data[offset + 1] = pixel_rgb.1;
+                data[offset + 2] = pixel_rgb.2;
             }
         }
-        ret
+        data.into_boxed_slice()
+    }
+
+    /// Renders all 384 tiles in VRAM into an RGB buffer of `TILES_WIDTH` x `TILES_HEIGHT`
+    /// pixels, 16 tiles per row in tile data order starting from 0x8000. Colors are taken from
+    /// the given palette.
+    pub fn dump_tiles(&self, palette: TilePalette) -> VideoFrame {
+        let palette = match palette {
+            TilePalette::Bgp => &self.bgp,
+            TilePalette::Obp0 => &self.obp0,
+            TilePalette::Obp1 => &self.obp1,
+        };
+        let mut data = vec![0x0; 3 * TILES_WIDTH * TILES_HEIGHT];
+        for y in 0..TILES_HEIGHT {
+            for x in 0..TILES_WIDTH {
+                let tile_base = (((y / 8) * 16) + (x / 8)) * 16;
+                let row = tile_base + ((y % 8) * 2);
+                let pixel_shift = (x % 8) ^ 0x7;
+                let color_idx = (((self.memory[row + 1] >> pixel_shift) & 0x1) << 1)
+                    | ((self.memory[row] >> pixel_shift) & 0x1);
+                let pixel_rgb = Self::shade_to_rgb_u8(&palette.shade(color_idx));
+                let offset = (y * TILES_WIDTH * 3) + (x * 3);
+                data[offset] = pixel_rgb.0;
+                data[offset + 1] = pixel_rgb.1;
+                data[offset + 2] = pixel_rgb.2;
+            }
+        }
+        data.into_boxed_slice()
     }
 
     /// Converts the given GrayShade enum value into a tuple of
@@ -732,7 +1055,7 @@ impl Memory for Vram {
             0xFF4A => self.window_coords.1,
             0xFF4B => self.window_coords.0,
             _ => {
-                error!("Unassigned read in VRAM: {:X}", addr);
+                error!(target: log_target::VRAM, "Unassigned read in VRAM: {:X}", addr);
                 0xFF
             }
         }
@@ -748,11 +1071,14 @@ impl Memory for Vram {
                     // LCD disabled, reset all LCD driver variables
                     self.ly = 0;
                     self.scanline_cycles = 0;
+                    self.window_line = 0;
+                    self.window_y_triggered = false;
                     self.stat.mode_flag = LCDMode::Mode0;
                     for i in 0..self.screen_data.len() {
                         // Clear all screen data to white
                         self.screen_data[i] = 255;
                     }
+                    self.screen_indices.fill(0);
                 }
             }
             0xFF41 => self.stat.write_byte(addr, val),
@@ -766,12 +1092,121 @@ impl Memory for Vram {
             0xFF4A => self.window_coords.1 = val,
             0xFF4B => self.window_coords.0 = val,
             _ => {
-                error!("Unassigned write in VRAM: {:X}", addr);
+                error!(target: log_target::VRAM, "Unassigned write in VRAM: {:X}", addr);
             }
         }
     }
 }
 
+impl SaveState for Vram {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.lcdc.read_byte(0xFF40));
+        w.u8(self.stat.read_byte(0xFF41));
+        w.u8(self.scroll_coords.0);
+        w.u8(self.scroll_coords.1);
+        w.u8(self.ly);
+        w.u8(self.lyc);
+        w.u8(self.bgp.read_byte(0xFF47));
+        w.u8(self.obp0.read_byte(0xFF48));
+        w.u8(self.obp1.read_byte(0xFF49));
+        w.u8(self.window_coords.0);
+        w.u8(self.window_coords.1);
+        w.u8(self.window_line);
+        w.bool(self.window_y_triggered);
+        w.u32(self.scanline_cycles);
+        w.bytes(&self.obj_list);
+        let fifo = &self.fifo;
+        w.bytes(&fifo.bg.iter().copied().collect::<Vec<u8>>());
+        let obj: Vec<u8> = fifo.obj.iter().map(|p| p.to_byte()).collect();
+        w.bytes(&obj);
+        w.u8(fifo.fetcher.step as u8);
+        w.u8(fifo.fetcher.dots);
+        w.u8(fifo.fetcher.tile_x);
+        w.bool(fifo.fetcher.window);
+        w.u8(fifo.fetcher.tile_index);
+        w.u8(fifo.fetcher.data_low);
+        w.u8(fifo.fetcher.data_high);
+        w.u8(fifo.x);
+        w.u8(fifo.discard);
+        w.u8(fifo.stall);
+        w.bool(fifo.window_active);
+        let (entry, dots) = fifo.obj_fetch.unwrap_or((0xFF, 0));
+        w.u8(entry);
+        w.u8(dots);
+        w.u64(fifo.fetched_objs);
+        w.bytes(&self.screen_data);
+        w.bytes(&self.screen_indices);
+        w.bytes(&self.memory);
+        w.bytes(&self.oam);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.lcdc.write_byte(0xFF40, r.u8()?);
+        // Only the interrupt enables are written, the flags are restored separately
+        let stat = r.u8()?;
+        self.stat.write_byte(0xFF41, stat);
+        self.stat.lyc_ly_flag = stat & 0x04 != 0;
+        self.stat.mode_flag = match stat & 0x03 {
+            0b00 => LCDMode::Mode0,
+            0b01 => LCDMode::Mode1,
+            0b10 => LCDMode::Mode2,
+            _ => LCDMode::Mode3,
+        };
+        self.scroll_coords = (r.u8()?, r.u8()?);
+        self.ly = r.u8()?;
+        self.lyc = r.u8()?;
+        self.bgp.write_byte(0xFF47, r.u8()?);
+        self.obp0.write_byte(0xFF48, r.u8()?);
+        self.obp1.write_byte(0xFF49, r.u8()?);
+        self.window_coords = (r.u8()?, r.u8()?);
+        self.window_line = r.u8()?;
+        self.window_y_triggered = r.bool()?;
+        self.scanline_cycles = r.u32()?;
+        let obj_list = r.bytes()?;
+        if obj_list.len() > 40 || obj_list.iter().any(|obj| *obj >= 40) {
+            return Err(StateError::Corrupt);
+        }
+        self.obj_list = obj_list.to_vec();
+        let bg = r.bytes()?;
+        let obj = r.bytes()?;
+        if bg.len() > 8 || obj.len() > 8 {
+            return Err(StateError::Corrupt);
+        }
+        self.fifo.bg = bg.iter().copied().collect();
+        self.fifo.obj = obj.iter().map(|p| ObjPixel::from_byte(*p)).collect();
+        self.fifo.fetcher.step = match r.u8()? {
+            0 => FetchStep::TileIndex,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            3 => FetchStep::Push,
+            _ => return Err(StateError::Corrupt),
+        };
+        self.fifo.fetcher.dots = r.u8()?;
+        self.fifo.fetcher.tile_x = r.u8()?;
+        self.fifo.fetcher.window = r.bool()?;
+        self.fifo.fetcher.tile_index = r.u8()?;
+        self.fifo.fetcher.data_low = r.u8()?;
+        self.fifo.fetcher.data_high = r.u8()?;
+        self.fifo.x = r.u8()?;
+        self.fifo.discard = r.u8()?;
+        self.fifo.stall = r.u8()?;
+        self.fifo.window_active = r.bool()?;
+        self.fifo.obj_fetch = match (r.u8()?, r.u8()?) {
+            (0xFF, _) => None,
+            (entry, dots) if (entry as usize) < self.obj_list.len() => Some((entry, dots)),
+            _ => return Err(StateError::Corrupt),
+        };
+        self.fifo.fetched_objs = r.u64()?;
+        if self.fifo.x as usize > SCREEN_WIDTH {
+            return Err(StateError::Corrupt);
+        }
+        r.bytes_into(&mut self.screen_data)?;
+        r.bytes_into(&mut self.screen_indices)?;
+        r.bytes_into(&mut self.memory)?;
+        r.bytes_into(&mut self.oam)
+    }
+}
+
 #[cfg(test)]
 mod vram_tests {
     use super::*;
@@ -799,6 +1234,19 @@ mod vram_tests {
         };
         let v = lcdc.read_byte(0xFF40);
         assert_eq!(0b0110_1001, v);
+        // Every bit is used, so any value reads back as written
+        for v in 0..=0xFF {
+            lcdc.write_byte(0xFF40, v);
+            assert_eq!(lcdc.read_byte(0xFF40), v);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn register_range_checked() {
+        let lcdc = Lcdc::power_on();
+        lcdc.read_byte(0xFF41);
     }
 
     #[test]
@@ -809,7 +1257,10 @@ mod vram_tests {
         assert!(stat.oam_interrupt);
         assert!(!stat.vblank_interrupt);
         assert!(!stat.hblank_interrupt);
-        assert!(stat.lyc_ly_flag);
+        // The flag bits are read-only
+        assert!(!stat.lyc_ly_flag);
+        assert_eq!(LCDMode::Mode1, stat.mode_flag);
+        stat.write_byte(0xFF41, 0b0000_0010);
         assert_eq!(LCDMode::Mode1, stat.mode_flag);
         stat = Stat {
             lyc_ly_interrupt: false,
@@ -823,6 +1274,33 @@ mod vram_tests {
         assert_eq!(0b1010_1110, v);
     }
 
+    #[test]
+    fn stat_flags_read_only() {
+        let mut vram = Vram::power_on();
+        // LYC of 0 matches the first line
+        vram.write_byte(0xFF40, 0b1000_0000);
+        let mut sink = FrameSink(None);
+        while vram.mode() != LCDMode::Mode3 as u8 {
+            vram.update(4, &mut sink);
+        }
+        vram.write_byte(0xFF41, 0x00);
+        assert_eq!(vram.read_byte(0xFF41), 0b1000_0111);
+        vram.write_byte(0xFF41, 0xF8);
+        assert_eq!(vram.read_byte(0xFF41), 0b1111_1111);
+
+        // Reads follow the real mode and coincidence, not what was written
+        while vram.mode() != LCDMode::Mode0 as u8 {
+            vram.update(4, &mut sink);
+        }
+        assert_eq!(vram.read_byte(0xFF41), 0b1111_1100);
+        vram.write_byte(0xFF45, 0x40);
+        while vram.mode() != LCDMode::Mode2 as u8 {
+            vram.update(4, &mut sink);
+        }
+        vram.write_byte(0xFF41, 0xFF);
+        assert_eq!(vram.read_byte(0xFF41), 0b1111_1010);
+    }
+
     #[test]
     fn palette_read_write() {
         let mut p = PaletteData::init();
@@ -833,4 +1311,247 @@ mod vram_tests {
         assert_eq!(GrayShades::Black, p.color3);
         assert_eq!(0b1101_1000, p.read_byte(0xFF47));
     }
+
+    #[test]
+    fn oam_bug_corruption() {
+        let mut vram = Vram::power_on();
+        for i in 0..0xA0u16 {
+            vram.write_byte(0xFE00 + i, i as u8);
+        }
+        let original = vram.oam.clone();
+
+        // Outside of Mode 2 nothing happens
+        vram.stat.mode_flag = LCDMode::Mode0;
+        vram.oam_bug_write();
+        assert_eq!(vram.oam, original);
+
+        // Third row being scanned
+        vram.stat.mode_flag = LCDMode::Mode2;
+        vram.scanline_cycles = 8;
+        vram.oam_bug_write();
+        // a = 0x1110, b = 0x0908, c = 0x0D0C
+        // ((a ^ c) & (b ^ c)) ^ c = (0x1C1C & 0x0404) ^ 0x0D0C = 0x0908
+        assert_eq!(
+            &vram.oam[16..24],
+            &[0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F]
+        );
+        // Other rows are untouched
+        assert_eq!(&vram.oam[..16], &original[..16]);
+        assert_eq!(&vram.oam[24..], &original[24..]);
+
+        // First row is never corrupted
+        vram.scanline_cycles = 2;
+        let before = vram.oam.clone();
+        vram.oam_bug_write();
+        assert_eq!(vram.oam, before);
+    }
+
+    struct FrameSink(Option<VideoFrame>);
+
+    impl Sink<VideoFrame> for FrameSink {
+        fn append(&mut self, value: VideoFrame) {
+            self.0 = Some(value);
+        }
+    }
+
+    #[test]
+    fn window_line_counter() {
+        let mut vram = Vram::power_on();
+        // BGP: 0 -> White, 1 -> LightGray, 2 -> DarkGray, 3 -> Black
+        vram.write_byte(0xFF47, 0b1110_0100);
+        // Tile 1 is solid color 3, tile 2 is solid color 1
+        for i in 0..16 {
+            vram.write_byte(0x8010 + i, 0xFF);
+            vram.write_byte(0x8020 + i, if i % 2 == 0 { 0xFF } else { 0x00 });
+        }
+        // Window map at 0x9C00: first tile row uses tile 1, second uses tile 2
+        for i in 0..32 {
+            vram.write_byte(0x9C00 + i, 0x01);
+            vram.write_byte(0x9C20 + i, 0x02);
+        }
+        // Window at the top left, BG map all tile 0
+        vram.write_byte(0xFF4A, 0);
+        vram.write_byte(0xFF4B, 7);
+        // LCD on, window map 0x9C00, window off, tile data 0x8000, BG on
+        vram.write_byte(0xFF40, 0b1101_0001);
+
+        let mut sink = FrameSink(None);
+        // Run the top half of the screen without the window
+        while vram.ly < 72 {
+            vram.update(4, &mut sink);
+        }
+        // Turn the window on for the bottom half
+        vram.write_byte(0xFF40, 0b1111_0001);
+        while sink.0.is_none() {
+            vram.update(4, &mut sink);
+        }
+        let frame = sink.0.unwrap();
+        let pixel = |x: usize, y: usize| {
+            let offset = (y * SCREEN_WIDTH * 3) + (x * 3);
+            (frame[offset], frame[offset + 1], frame[offset + 2])
+        };
+        assert_eq!(pixel(0, 71), (255, 255, 255));
+        // Window starts from its first line where it was enabled
+        assert_eq!(pixel(0, 72), (0, 0, 0));
+        assert_eq!(pixel(159, 79), (0, 0, 0));
+        assert_eq!(pixel(0, 80), (170, 170, 170));
+        assert_eq!(pixel(159, 87), (170, 170, 170));
+    }
+
+    #[test]
+    fn mode3_length() {
+        let mode3_dots = |scx: u8, objs: &[u8]| {
+            let mut vram = Vram::power_on();
+            vram.write_byte(0xFF43, scx);
+            for (i, x) in objs.iter().enumerate() {
+                vram.write_byte(0xFE00 + i as u16 * 4, 16);
+                vram.write_byte(0xFE01 + i as u16 * 4, *x);
+            }
+            // LCD on, OBJ on, BG on
+            vram.write_byte(0xFF40, 0b1000_0011);
+            let mut sink = FrameSink(None);
+            let mut dots = 0;
+            while vram.ly == 0 {
+                vram.update(1, &mut sink);
+                if vram.mode() == 3 {
+                    dots += 1;
+                }
+            }
+            dots
+        };
+        assert_eq!(mode3_dots(0, &[]), 172);
+        // Fine scrolling throws away pixels at the start of the line
+        assert_eq!(mode3_dots(5, &[]), 177);
+        // Each OBJ pauses the FIFO while it's fetched
+        let one = mode3_dots(0, &[48]);
+        assert!(one > 172 + OBJ_FETCH_DOTS as u32 - 1 && one <= 172 + 11);
+        assert!(mode3_dots(0, &[48, 80]) > one);
+        // OBJs entirely off screen are skipped
+        assert_eq!(mode3_dots(0, &[200]), 172);
+    }
+
+    #[test]
+    fn mid_scanline_scroll() {
+        let mut vram = Vram::power_on();
+        // BGP: 0 -> White, 1 -> LightGray, 2 -> DarkGray, 3 -> Black
+        vram.write_byte(0xFF47, 0b1110_0100);
+        // Tile 1 is solid color 3
+        for i in 0..16 {
+            vram.write_byte(0x8010 + i, 0xFF);
+        }
+        // Alternate black and white columns of tiles
+        for i in 0..32 * 32 {
+            vram.write_byte(0x9800 + i, (i % 2) as u8 ^ 1);
+        }
+        // LCD on, tile data 0x8000, BG on
+        vram.write_byte(0xFF40, 0b1001_0001);
+        let mut sink = FrameSink(None);
+        // Scroll by a tile halfway through drawing the first line
+        while vram.mode() != 3 || vram.fifo.x < 80 {
+            vram.update(1, &mut sink);
+        }
+        vram.write_byte(0xFF43, 8);
+        while sink.0.is_none() {
+            vram.update(4, &mut sink);
+        }
+        let frame = sink.0.unwrap();
+        let pixel = |x: usize, y: usize| frame[(y * SCREEN_WIDTH * 3) + (x * 3)];
+        assert_eq!(pixel(0, 0), 0);
+        assert_eq!(pixel(8, 0), 255);
+        // Tiles fetched after the write are shifted over
+        assert_eq!(pixel(120, 0), 0);
+        assert_eq!(pixel(128, 0), 255);
+        assert_eq!(pixel(0, 1), 255);
+        assert_eq!(pixel(120, 1), 0);
+    }
+
+    #[test]
+    fn sprite_limit() {
+        let drawn_sprites = |limit: bool| {
+            let mut vram = Vram::power_on();
+            vram.set_sprite_limit(limit);
+            // OBP0: 0 -> White, 1 -> LightGray, 2 -> DarkGray, 3 -> Black
+            vram.write_byte(0xFF48, 0b1110_0100);
+            // Tile 1 is solid color 3
+            for i in 0..16 {
+                vram.write_byte(0x8010 + i, 0xFF);
+            }
+            // 12 OBJs side by side on the first line
+            for i in 0..12 {
+                vram.write_byte(0xFE00 + i * 4, 16);
+                vram.write_byte(0xFE01 + i * 4, 8 + i as u8 * 8);
+                vram.write_byte(0xFE02 + i * 4, 0x01);
+            }
+            // LCD on, OBJ on, BG off
+            vram.write_byte(0xFF40, 0b1000_0010);
+
+            let mut sink = FrameSink(None);
+            while sink.0.is_none() {
+                vram.update(4, &mut sink);
+            }
+            let frame = sink.0.unwrap();
+            (0..12).filter(|i| frame[i * 8 * 3] == 0).count()
+        };
+        assert_eq!(drawn_sprites(true), 10);
+        assert_eq!(drawn_sprites(false), 12);
+    }
+
+    #[test]
+    fn tiles_dump() {
+        let mut vram = Vram::power_on();
+        // OBP1: 0 -> Black, 1 -> DarkGray, 2 -> LightGray, 3 -> White
+        vram.write_byte(0xFF49, 0b0001_1011);
+        // Tile 17 (second row, second column): first line is colors 0, 1, 2, 3, 0, 0, 0, 0
+        vram.write_byte(0x8110, 0b0101_0000);
+        vram.write_byte(0x8111, 0b0011_0000);
+        // Last tile, last pixel is color 3
+        vram.write_byte(0x97FE, 0x01);
+        vram.write_byte(0x97FF, 0x01);
+
+        let tiles = vram.dump_tiles(TilePalette::Obp1);
+        assert_eq!(tiles.len(), 3 * TILES_WIDTH * TILES_HEIGHT);
+        let pixel = |x: usize, y: usize| {
+            let offset = (y * TILES_WIDTH * 3) + (x * 3);
+            (tiles[offset], tiles[offset + 1], tiles[offset + 2])
+        };
+        assert_eq!(pixel(8, 8), (0, 0, 0));
+        assert_eq!(pixel(9, 8), (85, 85, 85));
+        assert_eq!(pixel(10, 8), (170, 170, 170));
+        assert_eq!(pixel(11, 8), (255, 255, 255));
+        assert_eq!(pixel(12, 8), (0, 0, 0));
+        assert_eq!(pixel(TILES_WIDTH - 1, TILES_HEIGHT - 1), (255, 255, 255));
+        assert_eq!(pixel(TILES_WIDTH - 2, TILES_HEIGHT - 1), (0, 0, 0));
+    }
+
+    #[test]
+    fn tilemap_dump() {
+        let mut vram = Vram::power_on();
+        // BGP: 0 -> White, 1 -> LightGray, 2 -> DarkGray, 3 -> Black
+        vram.write_byte(0xFF47, 0b1110_0100);
+        // Tile 1: first row is all color 3, remaining rows color 1
+        vram.write_byte(0x8010, 0xFF);
+        vram.write_byte(0x8011, 0xFF);
+        for row in 1..8 {
+            vram.write_byte(0x8010 + (row * 2), 0xFF);
+        }
+        // Place tile 1 at the second tile of the first row in each map
+        vram.write_byte(0x9801, 0x01);
+        vram.write_byte(0x9C21, 0x01);
+
+        let low = vram.dump_tilemap(false);
+        assert_eq!(low.len(), 3 * TILEMAP_SIZE * TILEMAP_SIZE);
+        // Tile 0 is empty, pixel (0, 0) is White
+        assert_eq!(&low[0..3], &[255, 255, 255]);
+        // Pixel (8, 0) is the first row of tile 1, Black
+        assert_eq!(&low[8 * 3..8 * 3 + 3], &[0, 0, 0]);
+        // Pixel (9, 1) is the second row of tile 1, LightGray
+        let offset = (TILEMAP_SIZE * 3) + (9 * 3);
+        assert_eq!(&low[offset..offset + 3], &[170, 170, 170]);
+
+        let high = vram.dump_tilemap(true);
+        // The high map has tile 1 at tile (1, 1) instead
+        assert_eq!(&high[8 * 3..8 * 3 + 3], &[255, 255, 255]);
+        let offset = (8 * TILEMAP_SIZE * 3) + (8 * 3);
+        assert_eq!(&high[offset..offset + 3], &[0, 0, 0]);
+    }
 }
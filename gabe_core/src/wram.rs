@@ -1,4 +1,7 @@
+use super::gb::StateError;
+use super::log_target;
 use super::mmu::Memory;
+use super::state::{SaveState, StateReader, StateWriter};
 use alloc::vec::*;
 
 pub struct Wram {
@@ -15,21 +18,57 @@ impl Wram {
 
 impl Memory for Wram {
     fn read_byte(&self, addr: u16) -> u8 {
-        assert!((0xC000..=0xFDFF).contains(&addr));
-        if addr >= 0xE000 {
-            warn!("Reading WRAM echo memory at 0x{:04X}", addr);
-            self.memory[(addr - 0xE000) as usize]
-        } else {
-            self.memory[(addr - 0xC000) as usize]
+        debug_assert!((0xC000..=0xFDFF).contains(&addr));
+        match addr {
+            0xC000..=0xDFFF => self.memory[(addr - 0xC000) as usize],
+            0xE000..=0xFDFF => {
+                warn!(target: log_target::WRAM, "Reading WRAM echo memory at 0x{:04X}", addr);
+                self.memory[(addr - 0xE000) as usize]
+            }
+            _ => 0xFF,
         }
     }
     fn write_byte(&mut self, addr: u16, val: u8) {
-        assert!((0xC000..=0xFDFF).contains(&addr));
-        if addr >= 0xE000 {
-            warn!("Writing to WRAM echo memory at 0x{:04X}", addr);
-            self.memory[(addr - 0xE000) as usize] = val;
-        } else {
-            self.memory[(addr - 0xC000) as usize] = val;
+        debug_assert!((0xC000..=0xFDFF).contains(&addr));
+        match addr {
+            0xC000..=0xDFFF => self.memory[(addr - 0xC000) as usize] = val,
+            0xE000..=0xFDFF => {
+                warn!(target: log_target::WRAM, "Writing to WRAM echo memory at 0x{:04X}", addr);
+                self.memory[(addr - 0xE000) as usize] = val;
+            }
+            _ => {}
         }
     }
 }
+
+impl SaveState for Wram {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.memory);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        r.bytes_into(&mut self.memory)
+    }
+}
+
+#[cfg(test)]
+mod wram_tests {
+    use super::*;
+
+    #[test]
+    fn echo_memory() {
+        let mut wram = Wram::power_on();
+        wram.write_byte(0xC123, 0x12);
+        assert_eq!(wram.read_byte(0xE123), 0x12);
+        wram.write_byte(0xFDFF, 0x34);
+        assert_eq!(wram.read_byte(0xDDFF), 0x34);
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn range_unchecked() {
+        let mut wram = Wram::power_on();
+        wram.write_byte(0xFE00, 0x12);
+        assert_eq!(wram.read_byte(0xFE00), 0xFF);
+    }
+}
@@ -42,16 +42,6 @@ impl Sink<VideoFrame> for MostRecentSink {
     }
 }
 
-pub struct NullSink;
-
-impl Sink<VideoFrame> for NullSink {
-    fn append(&mut self, _value: VideoFrame) {}
-}
-
-impl Sink<AudioFrame> for NullSink {
-    fn append(&mut self, _value: AudioFrame) {}
-}
-
 pub fn get_rom_data(path: impl AsRef<Path>) -> std::io::Result<Box<[u8]>> {
     let mut f = File::open(path)?;
     let mut rom_data = vec![];
@@ -88,3 +78,61 @@ pub fn run_dmg_sound_case(gb: &mut gabe_core::gb::Gameboy) -> bool {
         }
     }
 }
+
+/// Outcome of running a test ROM with `run_test_rom`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RomResult {
+    Passed,
+    /// Failed, with the ROM's output or result code
+    Failed(String),
+    /// Neither passed nor failed within the cycle limit
+    Timeout,
+}
+
+/// Runs a test ROM until it reports a result or `max_cycles` elapse. Understands the two
+/// ways Blargg's test ROMs report: text over serial for the CPU tests, and a result code
+/// at $A000 tagged with $DE, $B0, $61 at $A001-$A003 for the rest.
+pub fn run_test_rom(path: impl AsRef<Path>, max_cycles: u64) -> std::io::Result<RomResult> {
+    // Only check memory once per frame, reading it every step is slow
+    const CHECK_INTERVAL: u64 = 70224;
+    let rom_data = get_rom_data(path)?;
+    let mut gb = match gabe_core::gb::Gameboy::power_on(rom_data, None) {
+        Ok(gb) => gb,
+        Err(e) => return Ok(RomResult::Failed(e.to_string())),
+    };
+    let mut video_sink = NullSink;
+    let mut audio_sink = NullSink;
+    let mut serial = String::new();
+    let mut running = false;
+    let mut cycles = 0;
+    let mut next_check = CHECK_INTERVAL;
+    while cycles < max_cycles {
+        cycles += gb.step(&mut video_sink, &mut audio_sink) as u64;
+        if let Some(v) = gb.poll_serial() {
+            serial.push(v as char);
+            if serial.contains("Passed") {
+                return Ok(RomResult::Passed);
+            }
+            if serial.contains("Failed") {
+                return Ok(RomResult::Failed(serial));
+            }
+        }
+        if cycles >= next_check {
+            next_check += CHECK_INTERVAL;
+            let data = gb.get_memory_range(0xA000..0xA004);
+            if data[1..] != [0xDE, 0xB0, 0x61] {
+                continue;
+            }
+            // $80 while running. Wait to see it first, the result isn't valid before then.
+            match data[0] {
+                0x80 => running = true,
+                0x00 if running => return Ok(RomResult::Passed),
+                code if running => {
+                    return Ok(RomResult::Failed(format!("Result code {:02X}", code)))
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(RomResult::Timeout)
+}
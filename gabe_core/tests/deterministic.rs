@@ -0,0 +1,52 @@
+mod common;
+
+use gabe_core::gb::{GameboyBuilder, GameboyError};
+use gabe_core::sink::*;
+
+struct FrameCounter(u32);
+
+impl Sink<VideoFrame> for FrameCounter {
+    fn append(&mut self, _value: VideoFrame) {
+        self.0 += 1;
+    }
+}
+
+/// Runs a deterministic instance for the given number of frames, returning the hash of each frame
+fn frame_hashes(rom_path: &str, frames: u32) -> Vec<u64> {
+    let rom_data = common::get_rom_data(rom_path).unwrap();
+    let mut gb = GameboyBuilder::new(rom_data)
+        .deterministic()
+        .build()
+        .unwrap();
+    let mut video_sink = FrameCounter(0);
+    let mut audio_sink = NullSink;
+    let mut hashes = vec![];
+    while video_sink.0 < frames {
+        let prev = video_sink.0;
+        gb.step(&mut video_sink, &mut audio_sink);
+        if video_sink.0 != prev {
+            hashes.push(gb.frame_hash());
+        }
+    }
+    hashes
+}
+
+#[test]
+fn deterministic_frame_hashes() {
+    let first = frame_hashes("tests/roms/cpu_instrs/cpu_instrs.gb", 60);
+    let second = frame_hashes("tests/roms/cpu_instrs/cpu_instrs.gb", 60);
+    assert_eq!(first.len(), 60);
+    assert_eq!(first, second);
+    // Make sure the ROM drew something, so the comparison is meaningful
+    assert!(first.iter().any(|h| *h != first[0]));
+}
+
+#[test]
+fn deterministic_rejects_save_data() {
+    let rom_data = common::get_rom_data("tests/roms/cpu_instrs/cpu_instrs.gb").unwrap();
+    let result = GameboyBuilder::new(rom_data)
+        .save_data(vec![0xA5; 0x2000].into_boxed_slice())
+        .deterministic()
+        .build();
+    assert_eq!(result.err(), Some(GameboyError::DeterministicSaveData));
+}
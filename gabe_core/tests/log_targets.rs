@@ -0,0 +1,79 @@
+use std::sync::Mutex;
+
+use gabe_core::gb::Gameboy;
+use gabe_core::log_target;
+use gabe_core::sink::NullSink;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Keeps the records for a single target, as `RUST_LOG=<target>=trace` would
+struct TargetLogger {
+    target: Mutex<&'static str>,
+    records: Mutex<Vec<(String, Level, String)>>,
+}
+
+impl Log for TargetLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target() == *self.target.lock().unwrap()
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if self.enabled(record.metadata()) {
+            self.records.lock().unwrap().push((
+                record.target().to_string(),
+                record.level(),
+                record.args().to_string(),
+            ));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: TargetLogger = TargetLogger {
+    target: Mutex::new(""),
+    records: Mutex::new(vec![]),
+};
+
+/// Powers on and runs a few instructions of a program writing to echo RAM, returning the
+/// records logged for `target`
+fn run_logging(target: &'static str) -> Vec<(String, Level, String)> {
+    *LOGGER.target.lock().unwrap() = target;
+    LOGGER.records.lock().unwrap().clear();
+    // LD A, $5A; LD ($E000), A; JR -2
+    let program = [0x3E, 0x5A, 0xEA, 0x00, 0xE0, 0x18, 0xFE];
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    let mut gb = Gameboy::power_on(rom.into_boxed_slice(), None).unwrap();
+    for _ in 0..4 {
+        gb.step(&mut NullSink, &mut NullSink);
+    }
+    core::mem::take(&mut *LOGGER.records.lock().unwrap())
+}
+
+#[test]
+fn filter_by_target() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let cpu = run_logging(log_target::CPU);
+    assert_eq!(cpu.len(), 4);
+    assert!(cpu
+        .iter()
+        .all(|(target, level, _)| target == "gabe::cpu" && *level == Level::Trace));
+    assert_eq!(cpu[0].2, "0100: 3E");
+    assert_eq!(cpu[1].2, "0102: EA");
+
+    let wram = run_logging(log_target::WRAM);
+    assert_eq!(wram.len(), 1);
+    assert_eq!(wram[0].0, "gabe::wram");
+    assert!(wram[0].2.contains("echo"));
+
+    let mmu = run_logging(log_target::MMU);
+    assert!(!mmu.is_empty());
+    assert!(mmu.iter().all(|(target, ..)| target == "gabe::mmu"));
+    assert!(mmu
+        .iter()
+        .any(|(.., message)| message.contains("Cartridge Info")));
+
+    assert!(run_logging("gabe_core::cpu").is_empty());
+}
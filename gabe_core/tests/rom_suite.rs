@@ -0,0 +1,94 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use common::RomResult;
+
+const ROM_DIR: &str = "tests/roms";
+
+/// Enough for the longest ROM, the combined cpu_instrs, to finish
+const MAX_CYCLES: u64 = 4_194_304 * 60;
+
+/// ROMs that currently pass, relative to `ROM_DIR`. These fail the suite if they regress,
+/// the rest are only reported. Add to this as accuracy improves.
+const EXPECTED_PASS: &[&str] = &[
+    "cpu_instrs/01-special.gb",
+    "cpu_instrs/02-interrupts.gb",
+    "cpu_instrs/03-op_sp_hl.gb",
+    "cpu_instrs/04-op_r_imm.gb",
+    "cpu_instrs/05-op_rp.gb",
+    "cpu_instrs/06-ld_r_r.gb",
+    "cpu_instrs/07-jr_jp_call_ret_rst.gb",
+    "cpu_instrs/08-misc_instrs.gb",
+    "cpu_instrs/09-op_r_r.gb",
+    "cpu_instrs/10-bit_ops.gb",
+    "cpu_instrs/11-op_a_(hl).gb",
+    "cpu_instrs/cpu_instrs.gb",
+    "dmg_sound/01-registers.gb",
+    "dmg_sound/02-len_ctr.gb",
+    "dmg_sound/03-trigger.gb",
+    "dmg_sound/04-sweep.gb",
+    "dmg_sound/06-overflow_on_trigger.gb",
+    "dmg_sound/07-len_sweep_period_sync.gb",
+    "dmg_sound/08-len_ctr_during_power.gb",
+    "dmg_sound/11-regs_after_power.gb",
+    "oam_bug/3-non_causes.gb",
+    "oam_bug/6-timing_no_bug.gb",
+];
+
+/// Collects every .gb file under `dir`, sorted so results are reported in a stable order
+fn discover(dir: &Path, roms: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            discover(&path, roms)?;
+        } else if path.extension().map_or(false, |ext| ext == "gb") {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+    Ok(())
+}
+
+/// Runs every ROM found in `ROM_DIR`, printing each result. Skipped if the ROMs aren't present.
+#[test]
+fn rom_suite() {
+    let mut roms = vec![];
+    if let Err(e) = discover(Path::new(ROM_DIR), &mut roms) {
+        println!("{}: Skipping test ROMs.", e);
+        return;
+    }
+    let mut regressions = vec![];
+    let mut passed = 0;
+    for rom in &roms {
+        let name = rom.strip_prefix(ROM_DIR).unwrap().to_string_lossy();
+        let name = name.replace('\\', "/");
+        let result = common::run_test_rom(rom, MAX_CYCLES).unwrap();
+        match &result {
+            RomResult::Passed => {
+                passed += 1;
+                println!("PASS {}", name);
+            }
+            RomResult::Failed(output) => println!("FAIL {}: {}", name, output.trim()),
+            RomResult::Timeout => println!("TIMEOUT {}", name),
+        }
+        if result != RomResult::Passed && EXPECTED_PASS.contains(&name.as_str()) {
+            regressions.push(name);
+        }
+    }
+    println!("{}/{} test ROMs passed", passed, roms.len());
+    assert!(regressions.is_empty(), "Regressed: {:?}", regressions);
+}
+
+#[test]
+fn rom_suite_sample() {
+    let rom = Path::new(ROM_DIR).join("cpu_instrs/01-special.gb");
+    if !rom.exists() {
+        println!("{} not found, skipping.", rom.display());
+        return;
+    }
+    assert_eq!(
+        common::run_test_rom(rom, MAX_CYCLES).unwrap(),
+        RomResult::Passed
+    );
+}
@@ -1,16 +1,58 @@
 use std::{
     collections::VecDeque,
-    fs::{File, OpenOptions},
-    io::{Read, Seek, Write},
+    io::Read,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
 };
 
-use egui::{load::SizedTexture, ColorImage, Image, Key, TextureHandle, TextureOptions, Vec2};
-use gabe_core::gb::{Gameboy, GbKeys};
+use egui::{
+    load::SizedTexture, Align2, Color32, ColorImage, FontId, Image, Key, Rect, TextureHandle,
+    TextureOptions, Ui, Vec2, ViewportCommand,
+};
+use gabe_core::cartridge::CartridgeHeader;
+use gabe_core::gb::{CgbSupport, Gameboy, GameboyError, TilePalette, FRAME_CYCLES};
+use gabe_core::recording::{RecordingSink, WavSink};
 use gabe_core::sink::{AudioFrame, Sink};
+use gabe_core::storage::SaveStorage;
+
+#[cfg(feature = "gamepad")]
+use crate::gamepad::Gamepads;
+#[cfg(feature = "lua")]
+use crate::lua::LuaScript;
 
-use crate::{audio_driver::AudioDriver, video_sinks};
+use crate::{
+    apu_scope::{scope_line, ChannelScopes, ScopeSink, CHANNEL_NAMES},
+    audio_driver::AudioDriver,
+    auto_pause::AutoPause,
+    dither::{dither, COLOR_DEPTHS, FULL_COLOR_DEPTH},
+    fast_forward::{
+        cycle_budget, frame_delay, FastForward, FastForwardMode, FRAME_TIME, SLOW_MOTION_SPEEDS,
+    },
+    file_storage::FileStorage,
+    headless::BUTTON_NAMES,
+    input_config::{InputConfig, BINDINGS, KEYMAP_PATH},
+    input_overlay::{overlay_buttons, OVERLAY_SIZE},
+    input_pipeline::{turbo_held, InputPipeline, MAX_TURBO_RATE},
+    memory_dump::{dump_memory, parse_address},
+    memory_viewer::{ascii_row, MemoryViewer, REGIONS, ROW_BYTES},
+    palette::{cgb_palette_index, Palette, CGB_DEFAULT_PALETTE, PALETTES},
+    screenshot::{save_screenshot, timestamped_name, FRAME_WIDTH, MAX_SCREENSHOT_SCALE},
+    session::{Action, Session},
+    settings::{Settings, SETTINGS_PATH},
+    tcp_link::TcpLink,
+    video_sinks,
+    vram_viewer::{
+        background_viewport, obj_row, tile_at, window_viewport, VramView, TILES_SIZE, TILE_PALETTES,
+    },
+    window_scale::{fit_scale, scaled_size, snap_scale, MAX_WINDOW_SCALE},
+};
 
-const CYCLE_TIME_NS: f32 = 238.41858;
+/// Seconds since the Unix epoch, for the cartridge's clock
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
 
 struct SimpleAudioSink {
     inner: VecDeque<AudioFrame>,
@@ -23,38 +65,797 @@ impl Sink<AudioFrame> for SimpleAudioSink {
 }
 
 pub struct GabeApp {
-    emu: Option<gabe_core::gb::Gameboy>,
+    /// The loaded game, changed through `dispatch`
+    session: Session,
     emulated_cycles: u64,
-    start_time: u64,
-    save_file: Option<File>,
+    /// Cycles to catch up to, which run ahead of real time while fast-forwarding
+    /// and behind it in slow motion
+    target_emu_cycles: f64,
+    last_time: u64,
     audio_driver: AudioDriver,
     framebuffer: TextureHandle,
+    /// Main settings, as saved to the settings file
+    settings: Settings,
+    /// Contents of the loaded game's settings file, layered over the main settings
+    game_settings: String,
+    /// The `[keys]` section of the loaded game's settings file, layered over the main key
+    /// bindings
+    game_keys: String,
+    fast_forward: FastForward,
+    auto_pause: AutoPause,
+    palette: Palette,
+    /// The CGB built-in palette picked for the loaded game, used by the `cgb-auto` palette
+    cgb_palette: usize,
+    /// Show the pressed buttons over the game even when the overlay key isn't held
+    input_overlay: bool,
+    screenshot_scale: u32,
+    color_correction: bool,
+    /// Bits per color channel the display is dithered down to
+    color_depth: u8,
+    /// Start and end addresses entered in the memory dump window, which is open when set
+    dump_range: Option<(String, String)>,
+    show_apu_registers: bool,
+    show_audio_channels: bool,
+    /// Recent samples of each channel, kept while the audio channels window is open
+    scopes: ChannelScopes,
+    /// Kept while the window is closed, so its bookmarks aren't lost
+    memory_viewer: MemoryViewer,
+    show_memory_viewer: bool,
+    show_vram_viewer: bool,
+    vram_view: VramView,
+    /// Palette tiles are shown in by the VRAM viewer
+    tile_palette: TilePalette,
+    /// The image shown by the VRAM viewer, once first opened
+    vram_texture: Option<TextureHandle>,
+    /// Main key bindings, as saved to the keymap file and edited in the controls window
+    input_config: InputConfig,
+    /// Key bindings in effect, the main ones overridden by any in `game_keys`
+    active_input_config: InputConfig,
+    /// Where changes to the key bindings are saved
+    keymap_path: PathBuf,
+    show_controls: bool,
+    /// The binding waiting for a key to be pressed to bind to it in the controls window,
+    /// as an index into `BINDINGS`
+    rebinding: Option<usize>,
+    /// Turbo and macros, between the inputs held and the buttons the game sees. The macro
+    /// last recorded is played back with F6.
+    input: InputPipeline,
+    integer_scaling: bool,
+    /// Space the window uses around the game, such as the menu bar
+    chrome_size: Vec2,
+    /// Size of the game area last frame, and when it last changed, for snapping the window
+    /// once a resize has finished
+    last_game_size: Vec2,
+    game_resized: Option<Instant>,
+    /// Link cable to another copy of gabe, connected to each game opened
+    link: Option<TcpLink>,
+    /// GIF being recorded of the game as shown, and where it's saved once finished
+    recording: Option<(RecordingSink, PathBuf)>,
+    /// All audio produced by the game, and where it's saved as the window closes
+    audio_dump: Option<(WavSink, PathBuf)>,
+    /// Lua script loaded into each game opened
+    #[cfg(feature = "lua")]
+    lua_path: Option<PathBuf>,
+    #[cfg(feature = "lua")]
+    lua: Option<LuaScript>,
+    /// Connected gamepads, if the platform supports them
+    #[cfg(feature = "gamepad")]
+    gamepads: Option<Gamepads>,
 }
 
+/// How long the window has to keep the same size before it's snapped to a whole scale,
+/// so it doesn't fight the user while they're still dragging
+const SNAP_DELAY: Duration = Duration::from_millis(300);
+
+/// Frames kept for rewinding with R, 10 seconds of play
+const REWIND_FRAMES: usize = 60 * 10;
+/// Most instructions run looking for the frame after rewinding, in case the LCD is off
+const REWIND_STEP_LIMIT: usize = 70224;
+
 impl GabeApp {
     /// Called once before the first frame.
-    pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
+    /// `audio_latency_ms` sets the size of the audio buffer. With `record_video`, frames are
+    /// recorded to a GIF at the given path until the given number of seconds, if any, have
+    /// been recorded or the window is closed. With `dump_audio`, the audio is saved to a WAV
+    /// at the given path once the window is closed.
+    pub fn new(
+        cc: &eframe::CreationContext<'_>,
+        audio_latency_ms: u32,
+        link: Option<TcpLink>,
+        record_video: Option<(PathBuf, Option<u32>)>,
+        dump_audio: Option<PathBuf>,
+    ) -> Self {
         // This is also where you can customize the look and feel of egui using
         // `cc.egui_ctx.set_visuals` and `cc.egui_ctx.set_fonts`.
-        Self {
-            emu: None,
+        let mut app = Self {
+            session: Session::default(),
             emulated_cycles: 0,
-            start_time: 0,
-            save_file: None,
-            audio_driver: AudioDriver::new(gabe_core::SAMPLE_RATE, 100),
+            target_emu_cycles: 0.0,
+            last_time: 0,
+            audio_driver: AudioDriver::new(gabe_core::SAMPLE_RATE, audio_latency_ms),
             framebuffer: cc.egui_ctx.load_texture(
                 "framebuffer",
                 ColorImage::default(),
                 Default::default(),
             ),
+            settings: Settings::load(SETTINGS_PATH.as_ref()),
+            game_settings: String::new(),
+            game_keys: String::new(),
+            fast_forward: FastForward::new(FastForwardMode::Hold),
+            auto_pause: AutoPause::new(true),
+            palette: Palette::Grey,
+            cgb_palette: CGB_DEFAULT_PALETTE,
+            input_overlay: false,
+            screenshot_scale: 1,
+            color_correction: false,
+            color_depth: FULL_COLOR_DEPTH,
+            dump_range: None,
+            show_apu_registers: false,
+            show_audio_channels: false,
+            scopes: ChannelScopes::default(),
+            memory_viewer: MemoryViewer::default(),
+            show_memory_viewer: false,
+            show_vram_viewer: false,
+            vram_view: VramView::Tiles,
+            tile_palette: TilePalette::Bgp,
+            vram_texture: None,
+            input_config: InputConfig::load(KEYMAP_PATH.as_ref()),
+            active_input_config: InputConfig::default(),
+            keymap_path: KEYMAP_PATH.into(),
+            show_controls: false,
+            rebinding: None,
+            input: InputPipeline::default(),
+            integer_scaling: false,
+            chrome_size: Vec2::ZERO,
+            last_game_size: Vec2::ZERO,
+            game_resized: None,
+            link,
+            recording: record_video.map(|(path, seconds)| {
+                let sink = seconds.map_or_else(RecordingSink::new, RecordingSink::with_seconds);
+                (sink, path)
+            }),
+            audio_dump: dump_audio.map(|path| (WavSink::new(gabe_core::SAMPLE_RATE), path)),
+            #[cfg(feature = "lua")]
+            lua_path: None,
+            #[cfg(feature = "lua")]
+            lua: None,
+            #[cfg(feature = "gamepad")]
+            gamepads: Gamepads::new(),
+        };
+        app.apply_settings();
+        app
+    }
+
+    /// Runs the Lua script at `path` in each game opened
+    #[cfg(feature = "lua")]
+    pub fn with_lua_script(mut self, path: PathBuf) -> Self {
+        self.lua_path = Some(path);
+        self
+    }
+
+    /// Uses the key bindings in the file at `path` instead of the usual keymap file,
+    /// saving any changes made to them there
+    pub fn with_keymap(mut self, path: PathBuf) -> Self {
+        self.input_config = InputConfig::load(&path);
+        self.keymap_path = path;
+        self.apply_input_config();
+        self
+    }
+
+    /// Loads the Lua script, if any, into the game just opened
+    #[cfg(feature = "lua")]
+    fn load_lua_script(&mut self) {
+        let (Some(path), Some(emu)) = (&self.lua_path, &mut self.session.emu) else {
+            return;
+        };
+        self.lua = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|source| {
+                LuaScript::load(&source, &path.display().to_string(), emu)
+                    .map_err(|e| e.to_string())
+            })
+            .map_err(|e| println!("{}: Lua script not loaded.", e))
+            .ok();
+    }
+
+    /// Carries out `action` on the session, then starts or stops audio playback to match
+    /// whether the game is running and shows the resulting frame
+    fn dispatch(&mut self, action: Action) -> Result<(), GameboyError> {
+        let was_running = self.session.is_running();
+        let result = self.session.dispatch(action);
+        if self.session.is_running() != was_running {
+            if was_running {
+                self.audio_driver.stop();
+            } else {
+                self.audio_driver.play();
+                // Don't try to catch up on the time spent paused
+                self.last_time = self.audio_driver.time_source().time_ns();
+            }
+        }
+        if self.session.last_frame.is_empty() {
+            self.framebuffer
+                .set(ColorImage::default(), Default::default());
+        } else {
+            set_frame(
+                &mut self.framebuffer,
+                self.palette,
+                self.color_correction,
+                self.color_depth,
+                &self.session.last_frame,
+            );
+        }
+        result
+    }
+
+    /// Pauses or resumes the running game, stopping audio playback while paused
+    pub fn set_paused(&mut self, paused: bool) {
+        if self.session.emu.is_some() && paused != self.session.paused {
+            let _ = self.dispatch(Action::TogglePause);
+        }
+    }
+
+    /// Shows the memory dump window while it's open, asking where to save the range when confirmed
+    fn show_dump_window(&mut self, ctx: &egui::Context) {
+        let (Some(emu), Some((start, end))) = (&self.session.emu, &mut self.dump_range) else {
+            return;
+        };
+        let mut open = true;
+        let mut save = false;
+        egui::Window::new("Dump Memory")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Start");
+                    ui.text_edit_singleline(start);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("End");
+                    ui.text_edit_singleline(end);
+                });
+                save = ui.button("Save...").clicked();
+            });
+        if save {
+            match (parse_address(start), parse_address(end)) {
+                (Ok(start), Ok(end)) => {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .set_file_name(format!("{:04X}-{:04X}.bin", start, end))
+                        .save_file()
+                    {
+                        if let Err(e) = dump_memory(emu, start, end, &path) {
+                            println!("{}: Memory not dumped.", e);
+                        }
+                    }
+                }
+                (Err(e), _) | (_, Err(e)) => println!("{}", e),
+            }
+        }
+        if !open {
+            self.dump_range = None;
+        }
+    }
+
+    /// Shows the APU registers and channel states while the window is open
+    fn show_apu_window(&mut self, ctx: &egui::Context) {
+        let Some(emu) = &self.session.emu else {
+            return;
+        };
+        egui::Window::new("APU Registers")
+            .open(&mut self.show_apu_registers)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.monospace(emu.get_debug_state().apu_data.to_string());
+            });
+    }
+
+    /// Shows the keys bound to each button while the window is open. Keys are added by
+    /// clicking Add and pressing one, removed by clicking them, and saved as they change.
+    fn show_controls_window(&mut self, ctx: &egui::Context) {
+        let mut changed = false;
+        if let Some(binding) = self.rebinding {
+            let pressed = ctx.input(|i| {
+                i.events.iter().find_map(|event| match event {
+                    egui::Event::Key {
+                        key,
+                        pressed: true,
+                        repeat: false,
+                        ..
+                    } => Some(*key),
+                    _ => None,
+                })
+            });
+            match pressed {
+                // Escape cancels rather than being bound
+                Some(Key::Escape) => self.rebinding = None,
+                Some(key) => {
+                    self.input_config.bind(binding, key);
+                    self.rebinding = None;
+                    changed = true;
+                }
+                None => {}
+            }
+        }
+        let mut rebinding = self.rebinding;
+        let config = &mut self.input_config;
+        #[cfg(feature = "gamepad")]
+        let gamepads = &self.gamepads;
+        egui::Window::new("Controls")
+            .open(&mut self.show_controls)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("controls").striped(true).show(ui, |ui| {
+                    for (_, label, binding) in BINDINGS {
+                        ui.strong(label);
+                        ui.horizontal(|ui| {
+                            for key in config.keys(binding).to_vec() {
+                                let response = ui.button(key.name()).on_hover_text("Remove");
+                                if response.clicked() {
+                                    config.unbind(binding, key);
+                                    changed = true;
+                                }
+                            }
+                            if rebinding == Some(binding) {
+                                ui.label("Press a key, or Escape to cancel");
+                            } else if ui.button("Add").clicked() {
+                                rebinding = Some(binding);
+                            }
+                        });
+                        ui.end_row();
+                    }
+                });
+                ui.separator();
+                if ui.button("Reset to Defaults").clicked() {
+                    config.reset_keys();
+                    changed = true;
+                }
+                // Gamepad bindings are edited in the keymap file, where a gamepad can be
+                // given its own profile to tell it apart from the rest
+                #[cfg(feature = "gamepad")]
+                if let Some(pads) = gamepads {
+                    ui.separator();
+                    ui.strong("Gamepads");
+                    for name in pads.names() {
+                        ui.horizontal(|ui| {
+                            ui.label(&name);
+                            if config.gamepads.contains_key(&name) {
+                                ui.label("(own profile)");
+                            } else if ui
+                                .button("Own Profile")
+                                .on_hover_text("Copy the default gamepad bindings for this gamepad")
+                                .clicked()
+                            {
+                                config.gamepads.insert(name.clone(), config.gamepad.clone());
+                                changed = true;
+                            }
+                        });
+                    }
+                }
+            });
+        self.rebinding = rebinding.filter(|_| self.show_controls);
+        if changed {
+            self.apply_input_config();
+            if let Err(e) = self.input_config.save(&self.keymap_path) {
+                println!("{}: Key bindings not saved.", e);
+            }
+        }
+    }
+
+    /// Shows each sound channel's state and a scope of its waveform while the window is
+    /// open, with controls to mute channels or hear one on its own
+    fn show_audio_channels(&mut self, ctx: &egui::Context) {
+        let Some(emu) = &mut self.session.emu else {
+            return;
+        };
+        const SCOPE_SIZE: Vec2 = Vec2::new(256.0, 48.0);
+        let state = emu.apu_debug_state();
+        let scopes = &self.scopes;
+        egui::Window::new("Audio Channels")
+            .open(&mut self.show_audio_channels)
+            .resizable(false)
+            .show(ctx, |ui| {
+                for (i, channel) in state.channels.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        let mut enabled = emu.channel_enabled(i);
+                        let name = format!("CH{} {}", i + 1, CHANNEL_NAMES[i]);
+                        if ui.checkbox(&mut enabled, name).changed() {
+                            emu.set_channel_enabled(i, enabled);
+                        }
+                        if ui.button("Solo").clicked() {
+                            for other in 0..4 {
+                                emu.set_channel_enabled(other, other == i);
+                            }
+                        }
+                    });
+                    ui.monospace(format!(
+                        "{:<3}  Volume: {:>2}  {:>6} Hz",
+                        if channel.enabled { "on" } else { "off" },
+                        channel.volume,
+                        channel.frequency
+                    ));
+                    let (response, painter) = ui.allocate_painter(SCOPE_SIZE, egui::Sense::hover());
+                    let rect = response.rect;
+                    painter.rect_filled(rect, 0.0, Color32::BLACK);
+                    let points = scope_line(scopes.samples(i), rect.width(), rect.height())
+                        .into_iter()
+                        .map(|[x, y]| rect.min + Vec2::new(x, y))
+                        .collect();
+                    painter.add(egui::Shape::line(points, (1.0, Color32::GREEN)));
+                    ui.separator();
+                }
+                if ui.button("Unmute All").clicked() {
+                    for channel in 0..4 {
+                        emu.set_channel_enabled(channel, true);
+                    }
+                }
+            });
+    }
+
+    /// Shows a live hex dump of a range of memory while the window is open, with bytes
+    /// outside ROM editable by clicking them
+    fn show_memory_viewer(&mut self, ctx: &egui::Context) {
+        let Some(emu) = &mut self.session.emu else {
+            return;
+        };
+        let viewer = &mut self.memory_viewer;
+        let memory = emu.get_memory_range(viewer.start as usize..viewer.end as usize + 1);
+        let mut write = None;
+        egui::Window::new("Memory Viewer")
+            .open(&mut self.show_memory_viewer)
+            .default_height(400.0)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    for (name, start, end) in REGIONS {
+                        let shown = (viewer.start, viewer.end) == (start, end);
+                        if ui.selectable_label(shown, name).clicked() {
+                            viewer.view(start, end);
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Go to");
+                    let response = ui.text_edit_singleline(&mut viewer.goto);
+                    if response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter)) {
+                        viewer.go_to(&memory);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Find");
+                    let response = ui.text_edit_singleline(&mut viewer.search);
+                    let entered = response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter));
+                    if ui.button("Next").clicked() || entered {
+                        viewer.find_next(&memory);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Bookmark");
+                    ui.text_edit_singleline(&mut viewer.bookmark_name);
+                    if ui.button("Add").clicked() {
+                        viewer.add_bookmark();
+                    }
+                });
+                ui.horizontal_wrapped(|ui| {
+                    let mut shown = None;
+                    let mut removed = None;
+                    for (i, bookmark) in viewer.bookmarks.iter().enumerate() {
+                        let response = ui
+                            .button(&bookmark.name)
+                            .on_hover_text("Right-click to remove");
+                        if response.clicked() {
+                            shown = Some((bookmark.start, bookmark.end));
+                        }
+                        if response.secondary_clicked() {
+                            removed = Some(i);
+                        }
+                    }
+                    if let Some((start, end)) = shown {
+                        viewer.view(start, end);
+                    }
+                    if let Some(i) = removed {
+                        viewer.bookmarks.remove(i);
+                    }
+                });
+                if let Some(addr) = viewer.selected {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{:04X}", addr));
+                        let response = ui.text_edit_singleline(&mut viewer.value);
+                        let entered =
+                            response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter));
+                        if ui.button("Write").clicked() || entered {
+                            match viewer.edit() {
+                                Ok(edit) => write = Some(edit),
+                                Err(e) => viewer.status = e,
+                            }
+                        }
+                    });
+                }
+                ui.label(&viewer.status);
+                ui.separator();
+
+                let row_height = ui.spacing().interact_size.y;
+                let mut scroll = egui::ScrollArea::vertical().auto_shrink([false, false]);
+                if let Some(row) = viewer.scroll_to.take() {
+                    let spacing = ui.spacing().item_spacing.y;
+                    scroll = scroll.vertical_scroll_offset(row as f32 * (row_height + spacing));
+                }
+                let rows = (memory.len() + ROW_BYTES - 1) / ROW_BYTES;
+                scroll.show_rows(ui, row_height, rows, |ui, range| {
+                    for row in range {
+                        let offset = row * ROW_BYTES;
+                        let bytes = &memory[offset..(offset + ROW_BYTES).min(memory.len())];
+                        ui.horizontal(|ui| {
+                            let row_addr = viewer.start as usize + offset;
+                            ui.monospace(format!("{:04X}", row_addr));
+                            for (i, &byte) in bytes.iter().enumerate() {
+                                let addr = (row_addr + i) as u16;
+                                let text = egui::RichText::new(format!("{:02X}", byte)).monospace();
+                                if ui
+                                    .selectable_label(viewer.selected == Some(addr), text)
+                                    .clicked()
+                                {
+                                    viewer.select(addr, byte);
+                                }
+                            }
+                            ui.monospace(ascii_row(bytes));
+                        });
+                    }
+                });
+            });
+        if let Some((addr, value)) = write {
+            emu.write_memory(addr, value);
+            self.memory_viewer.status = format!("Wrote {:02X} to {:04X}", value, addr);
+        }
+    }
+
+    /// Shows the tiles in VRAM, the background and window tile maps with the parts on screen
+    /// outlined, or the OBJs in OAM, while the window is open
+    fn show_vram_viewer(&mut self, ctx: &egui::Context) {
+        let Some(emu) = &self.session.emu else {
+            return;
+        };
+        // Images are shown at twice their size so single pixels can be made out
+        const SCALE: f32 = 2.0;
+        let mut view = self.vram_view;
+        let mut tile_palette = self.tile_palette;
+        let layout = emu.background_layout();
+        let image = match view {
+            VramView::Tiles => emu
+                .dump_tiles(0, tile_palette)
+                .map(|tiles| (TILES_SIZE, tiles)),
+            VramView::Background => Some(([256, 256], emu.dump_tilemap(layout.bg_map_select))),
+            VramView::Window => Some(([256, 256], emu.dump_tilemap(layout.window_map_select))),
+            VramView::Objs => None,
+        };
+        if let Some((size, pixels)) = image {
+            let image =
+                ColorImage::from_rgb(size, &self.palette.render(&pixels, self.color_correction));
+            match &mut self.vram_texture {
+                Some(texture) => texture.set(image, TextureOptions::NEAREST),
+                None => {
+                    self.vram_texture =
+                        Some(ctx.load_texture("vram", image, TextureOptions::NEAREST))
+                }
+            }
+        }
+        let texture = &self.vram_texture;
+        egui::Window::new("VRAM Viewer")
+            .open(&mut self.show_vram_viewer)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    for option in VramView::ALL {
+                        ui.selectable_value(&mut view, option, option.name());
+                    }
+                });
+                ui.separator();
+                if view == VramView::Objs {
+                    egui::ScrollArea::vertical()
+                        .max_height(400.0)
+                        .show(ui, |ui| {
+                            egui::Grid::new("objs").striped(true).show(ui, |ui| {
+                                for heading in ["#", "Position", "Tile", "Palette", "Attributes"] {
+                                    ui.strong(heading);
+                                }
+                                ui.end_row();
+                                for (i, obj) in emu.oam_entries().iter().enumerate() {
+                                    for cell in obj_row(i, obj) {
+                                        ui.label(cell);
+                                    }
+                                    ui.end_row();
+                                }
+                            });
+                        });
+                    ui.label(if layout.tall_objs {
+                        "8x16 OBJs"
+                    } else {
+                        "8x8 OBJs"
+                    });
+                    return;
+                }
+                if view == VramView::Tiles {
+                    ui.horizontal(|ui| {
+                        for (palette, name) in TILE_PALETTES {
+                            ui.radio_value(&mut tile_palette, palette, name);
+                        }
+                    });
+                } else {
+                    let select = match view {
+                        VramView::Background => layout.bg_map_select,
+                        _ => layout.window_map_select,
+                    };
+                    ui.label(format!("Map at {}", if select { "9C00" } else { "9800" }));
+                }
+                let Some(texture) = texture else {
+                    return;
+                };
+                let size = texture.size_vec2() * SCALE;
+                let response =
+                    ui.add(Image::new(SizedTexture::from_handle(texture)).fit_to_exact_size(size));
+                let viewport = match view {
+                    VramView::Background => background_viewport(&layout),
+                    VramView::Window => window_viewport(&layout).into_iter().collect(),
+                    _ => vec![],
+                };
+                for [x, y, width, height] in viewport {
+                    let min = response.rect.min + Vec2::new(x as f32, y as f32) * SCALE;
+                    let size = Vec2::new(width as f32, height as f32) * SCALE;
+                    ui.painter().rect_stroke(
+                        Rect::from_min_size(min, size),
+                        0.0,
+                        (2.0, Color32::RED),
+                    );
+                }
+                if view == VramView::Tiles {
+                    if let Some(pos) = response.hover_pos() {
+                        let pos = (pos - response.rect.min) / SCALE;
+                        if let Some((index, addr)) = tile_at(pos.x as usize, pos.y as usize) {
+                            response.on_hover_text(format!(
+                                "Tile {} ({:02X}) at {:04X}",
+                                index, index as u8, addr
+                            ));
+                        }
+                    }
+                }
+            });
+        self.vram_view = view;
+        self.tile_palette = tile_palette;
+    }
+
+    /// Replaces the main settings and saves them to the settings file
+    fn set_settings(&mut self, settings: Settings) {
+        self.settings = settings;
+        if let Err(e) = self.settings.save(SETTINGS_PATH.as_ref()) {
+            println!("{}: Settings not saved.", e);
+        }
+        self.apply_settings();
+    }
+
+    /// Puts the main settings, overridden by any of the loaded game's settings, into effect
+    fn apply_settings(&mut self) {
+        let settings = self.settings.merge(&self.game_settings);
+        if settings.fast_forward_mode != self.fast_forward.mode() {
+            self.fast_forward.set_mode(settings.fast_forward_mode);
+        }
+        self.fast_forward
+            .set_fast_speed(settings.fast_forward_speed);
+        self.auto_pause.enabled = settings.pause_on_focus_loss;
+        self.palette = settings.palette.resolve(self.cgb_palette);
+        self.input_overlay = settings.input_overlay;
+        self.screenshot_scale = settings.screenshot_scale;
+        self.color_correction = settings.color_correction;
+        self.color_depth = settings.color_depth;
+        if settings.integer_scaling && !self.integer_scaling {
+            // Snap the window straight away rather than on the next resize
+            self.game_resized = Some(Instant::now());
+        }
+        self.integer_scaling = settings.integer_scaling;
+        self.input.turbo_rate = settings.turbo_rate;
+        if let Some(emu) = &mut self.session.emu {
+            emu.set_sprite_limit(!settings.no_sprite_limit);
+        }
+        self.apply_input_config();
+    }
+
+    /// Puts the main key bindings, overridden by any in the loaded game's settings, into
+    /// effect
+    fn apply_input_config(&mut self) {
+        self.active_input_config = self.input_config.merge(&self.game_keys);
+    }
+
+    /// Tracks the size of the game area, `game_size`, and resizes the window to the nearest
+    /// whole multiple of the game's resolution once it's stopped changing, if integer scaling
+    /// is on
+    fn snap_window(&mut self, ctx: &egui::Context, game_size: Vec2) {
+        self.chrome_size = ctx.screen_rect().size() - game_size;
+        if game_size != self.last_game_size {
+            self.last_game_size = game_size;
+            self.game_resized = Some(Instant::now());
+        }
+        if !self.integer_scaling {
+            return;
+        }
+        let Some(resized) = self.game_resized else {
+            return;
+        };
+        let waited = resized.elapsed();
+        if waited < SNAP_DELAY {
+            ctx.request_repaint_after(SNAP_DELAY - waited);
+            return;
+        }
+        self.game_resized = None;
+        let (width, height) = scaled_size(snap_scale(game_size.x, game_size.y));
+        let snapped = Vec2::new(width, height);
+        if snapped != game_size {
+            ctx.send_viewport_cmd(ViewportCommand::InnerSize(self.chrome_size + snapped));
+        }
+    }
+
+    /// Asks where to save a screenshot of the last frame, in the current palette and scale
+    fn save_screenshot(&self) {
+        if self.session.last_frame.is_empty() {
+            return;
+        }
+        if let Some(path) = rfd::FileDialog::new()
+            .add_filter("PNG", &["png"])
+            .set_file_name("screenshot.png")
+            .save_file()
+        {
+            self.write_screenshot(&path);
+        }
+    }
+
+    /// Saves a screenshot of the last frame to the working directory, named after the time
+    fn quick_screenshot(&self) {
+        if self.session.last_frame.is_empty() {
+            return;
+        }
+        let path = PathBuf::from(timestamped_name(unix_time()));
+        if self.write_screenshot(&path) {
+            println!("Screenshot saved to {}", path.display());
+        }
+    }
+
+    /// Writes the last frame to `path` as a PNG, returning whether it was written
+    fn write_screenshot(&self, path: &Path) -> bool {
+        let frame = self
+            .palette
+            .render(&self.session.last_frame, self.color_correction);
+        let frame = dither(&frame, FRAME_WIDTH, self.color_depth);
+        match save_screenshot(path, &frame, self.screenshot_scale) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("{}: Screenshot not saved.", e);
+                false
+            }
         }
     }
 }
 
 impl eframe::App for GabeApp {
+    /// Called once as the window closes
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        if let Some(emu) = &mut self.session.emu {
+            emu.sync_rtc(unix_time());
+            emu.flush_save_data();
+        }
+        finish_recording(&mut self.recording);
+        if let Some((wav, path)) = self.audio_dump.take() {
+            match std::fs::write(&path, wav.finish()) {
+                Ok(()) => println!("Audio saved to {}", path.display()),
+                Err(e) => println!("{}: Audio not saved.", e),
+            }
+        }
+    }
+
     /// Called each time the UI needs repainting, which may be many times per second.
     /// Put your widgets into a `SidePanel`, `TopPanel`, `CentralPanel`, `Window` or `Area`.
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        if let Some(paused) = self
+            .auto_pause
+            .update(ctx.input(|i| i.focused), self.session.paused)
+        {
+            self.set_paused(paused);
+        }
+
         // Menu Bar UI
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             // The top panel is often a good place for a menu bar:
@@ -63,63 +864,285 @@ impl eframe::App for GabeApp {
                     if ui.button("Open File...").clicked() {
                         if let Some(mut path) = rfd::FileDialog::new().pick_file() {
                             let mut rom_file = std::fs::File::open(&path).unwrap();
+                            let text = std::fs::read_to_string(Settings::game_settings_path(&path))
+                                .unwrap_or_default();
+                            let (game_settings, game_keys) = Settings::split_game_keys(&text);
+                            self.game_settings = game_settings.to_string();
+                            self.game_keys = game_keys.to_string();
                             path.set_extension("sav");
-                            let mut save_file = OpenOptions::new()
-                                .write(true)
-                                .read(true)
-                                .create(true)
-                                .open(path)
-                                .unwrap();
                             let mut rom_data = vec![];
                             rom_file.read_to_end(&mut rom_data).unwrap();
-                            let mut save_data = vec![];
-                            save_file.read_to_end(&mut save_data).unwrap();
-                            self.emu = Some(gabe_core::gb::Gameboy::power_on(
-                                rom_data.into_boxed_slice(),
-                                Some(save_data.into_boxed_slice()),
-                            ));
-                            self.save_file = Some(save_file);
-                            self.audio_driver.play();
-                            self.start_time = self.audio_driver.time_source().time_ns();
+                            self.cgb_palette = cgb_palette_index(&rom_data);
+                            // Save the game being replaced with its clock's time first, in
+                            // case it's the same game and its save is about to be read
+                            if let Some(emu) = &mut self.session.emu {
+                                emu.sync_rtc(unix_time());
+                                emu.flush_save_data();
+                            }
+                            let mut storage = FileStorage::new(path);
+                            let save_data = storage.load().map(Vec::into_boxed_slice);
+                            let boot_rom = self
+                                .settings
+                                .merge(&self.game_settings)
+                                .boot_rom
+                                .and_then(|path| {
+                                    std::fs::read(path)
+                                        .map_err(|e| println!("{}: Boot ROM not loaded.", e))
+                                        .ok()
+                                })
+                                .map(Vec::into_boxed_slice);
+                            let header = CartridgeHeader::parse(&rom_data);
+                            if let Some(header) = header.as_ref().filter(|h| !h.is_supported()) {
+                                println!(
+                                    "{} uses an unsupported cartridge type: {}",
+                                    header.title,
+                                    header.cartridge_type_name().unwrap_or("Unknown")
+                                );
+                            }
+                            let open = Action::OpenRom {
+                                rom: rom_data.into_boxed_slice(),
+                                save_data,
+                                boot_rom,
+                            };
+                            match self.dispatch(open) {
+                                Ok(()) => {
+                                    let emu = self.session.emu.as_mut().unwrap();
+                                    ui.ctx().send_viewport_cmd(ViewportCommand::Title(format!(
+                                        "Gabe Emulator - {}",
+                                        emu.cartridge_header().title
+                                    )));
+                                    // Catch the cartridge's clock up on time spent closed
+                                    emu.sync_rtc(unix_time());
+                                    emu.set_rewind_frames(REWIND_FRAMES);
+                                    if let Some(link) = &self.link {
+                                        match link.try_clone() {
+                                            Ok(clone) => {
+                                                emu.connect_serial(Some(Box::new(clone)));
+                                                // Network latency is far longer than a
+                                                // transfer, so run in lockstep with the peer
+                                                emu.set_serial_wait_for_peer(true);
+                                            }
+                                            Err(e) => println!("{}: Link cable not connected.", e),
+                                        }
+                                    }
+                                    if emu.cgb_support() == CgbSupport::Only {
+                                        println!("CGB-only game, it may not run on the DMG.");
+                                    }
+                                    #[cfg(feature = "lua")]
+                                    self.load_lua_script();
+                                    // Only games with a battery keep their RAM, so others
+                                    // don't get a save file
+                                    if emu.has_battery() {
+                                        emu.set_save_storage(Some(Box::new(storage)));
+                                    }
+                                    self.last_time = self.audio_driver.time_source().time_ns();
+                                    self.emulated_cycles = 0;
+                                    self.target_emu_cycles = 0.0;
+                                }
+                                Err(e @ GameboyError::SaveData(_)) => {
+                                    // The save file is left alone, rather than written over
+                                    // with blank RAM by the storage
+                                    println!("{}: ROM not loaded, save file kept.", e);
+                                }
+                                Err(e) => {
+                                    println!("{}: ROM not loaded.", e);
+                                    let supported: Vec<&str> =
+                                        gabe_core::cartridge::supported_types()
+                                            .iter()
+                                            .map(|(_, name)| *name)
+                                            .collect();
+                                    println!("Supported cartridge types: {}", supported.join(", "));
+                                }
+                            }
+                            self.apply_settings();
                         }
                         ui.close_menu();
                     }
+                    if ui
+                        .add_enabled(
+                            self.session.emu.is_some(),
+                            egui::Button::new("Save Screenshot..."),
+                        )
+                        .clicked()
+                    {
+                        self.save_screenshot();
+                        ui.close_menu();
+                    }
+                    if ui
+                        .add_enabled(
+                            self.session.emu.is_some(),
+                            egui::Button::new("Quick Screenshot (F12)"),
+                        )
+                        .clicked()
+                    {
+                        self.quick_screenshot();
+                        ui.close_menu();
+                    }
                 });
                 ui.menu_button("Emulation", |ui| {
-                    ui.add_enabled_ui(self.emu.is_some(), |ui| {
+                    ui.add_enabled_ui(self.session.emu.is_some(), |ui| {
+                        let mut paused = self.session.paused;
+                        if ui.checkbox(&mut paused, "Pause").clicked() {
+                            let _ = self.dispatch(Action::TogglePause);
+                            ui.close_menu();
+                        }
+                        ui.add_enabled_ui(self.session.paused, |ui| {
+                            if ui.button("Step Frame").clicked() {
+                                let _ = self.dispatch(Action::StepFrame);
+                            }
+                            if ui.button("Step Scanline").clicked() {
+                                let _ = self.dispatch(Action::StepScanline);
+                            }
+                        });
+                        ui.menu_button("Speed", |ui| {
+                            let mut speed = self.fast_forward.normal_speed();
+                            for option in SLOW_MOTION_SPEEDS.into_iter().chain([1.0]) {
+                                ui.radio_value(&mut speed, option, format!("{}x", option));
+                            }
+                            if speed != self.fast_forward.normal_speed() {
+                                self.fast_forward.set_normal_speed(speed);
+                            }
+                        });
+                        let recording = self.input.is_recording();
+                        let label = if recording {
+                            "Stop Recording Macro (F5)"
+                        } else {
+                            "Record Macro (F5)"
+                        };
+                        if ui.button(label).clicked() {
+                            self.input.toggle_recording();
+                            ui.close_menu();
+                        }
+                        let can_play = !recording && !self.input.input_macro.is_empty();
+                        let play = egui::Button::new("Play Macro (hold F6 to repeat)");
+                        if ui.add_enabled(can_play, play).clicked() {
+                            self.input.play_macro();
+                            ui.close_menu();
+                        }
+                        ui.checkbox(&mut self.show_apu_registers, "APU Registers");
+                        ui.checkbox(&mut self.show_audio_channels, "Audio Channels");
+                        ui.checkbox(&mut self.show_memory_viewer, "Memory Viewer");
+                        ui.checkbox(&mut self.show_vram_viewer, "VRAM Viewer");
+                        if ui.button("Dump Memory...").clicked() {
+                            self.dump_range = Some(("C000".to_string(), "DFFF".to_string()));
+                            ui.close_menu();
+                        }
                         if ui.button("Stop").clicked() {
-                            if let Some(emu) = &mut self.emu {
+                            if let Some(emu) = &mut self.session.emu {
+                                // The clock's time goes in the save file, written as the
+                                // game is dropped on stopping
+                                emu.sync_rtc(unix_time());
                                 // Stop all emulation, reset state
-                                self.audio_driver.stop();
-                                // Save the data to the save file, if valid
-                                if let (Some(data), Some(save_file)) =
-                                    (emu.get_save_data(), &mut self.save_file)
-                                {
-                                    if let Err(e) = save_file.rewind() {
-                                        println! {"{}: No save file written.", e};
-                                    }
-                                    if let Err(e) = save_file.write_all(&data) {
-                                        println! {"{}: Corrupt save file written.", e};
-                                    }
-                                }
-                                // Setting to None drops the Gameboy object
-                                self.emu = None;
+                                let _ = self.dispatch(Action::Stop);
+                                self.game_settings.clear();
+                                self.game_keys.clear();
+                                self.cgb_palette = CGB_DEFAULT_PALETTE;
+                                self.apply_settings();
                                 self.emulated_cycles = 0;
-                                // Clear framebuffer
-                                self.framebuffer
-                                    .set(ColorImage::default(), Default::default());
+                                self.target_emu_cycles = 0.0;
+                                self.input.stop();
                             }
                             ui.close_menu();
                         }
                     })
                 });
+                ui.menu_button("Settings", |ui| {
+                    // Edits the main settings, a game's own settings file still takes precedence
+                    let mut settings = self.settings.clone();
+                    ui.label("Fast Forward (Tab)");
+                    let mode = &mut settings.fast_forward_mode;
+                    ui.radio_value(mode, FastForwardMode::Hold, "Hold");
+                    ui.radio_value(mode, FastForwardMode::Toggle, "Toggle");
+                    ui.separator();
+                    ui.checkbox(&mut settings.pause_on_focus_loss, "Pause when unfocused");
+                    ui.checkbox(&mut settings.input_overlay, "Show input overlay (F1)");
+                    ui.separator();
+                    ui.menu_button("Palette", |ui| {
+                        for palette in PALETTES {
+                            ui.radio_value(&mut settings.palette, palette, palette.name());
+                        }
+                    });
+                    ui.checkbox(&mut settings.color_correction, "CGB color correction");
+                    ui.menu_button("Color Depth", |ui| {
+                        for bits in COLOR_DEPTHS {
+                            let label = if bits == FULL_COLOR_DEPTH {
+                                "Full".to_string()
+                            } else {
+                                format!("{}-bit dithered", bits * 3)
+                            };
+                            ui.radio_value(&mut settings.color_depth, bits, label);
+                        }
+                    });
+                    ui.checkbox(&mut settings.no_sprite_limit, "No sprite flicker");
+                    ui.checkbox(&mut settings.integer_scaling, "Integer scaling");
+                    ui.menu_button("Turbo Rate", |ui| {
+                        for rate in [5, 10, 15, 20, MAX_TURBO_RATE] {
+                            let label = format!("{} Hz", rate);
+                            ui.radio_value(&mut settings.turbo_rate, rate, label);
+                        }
+                    });
+                    ui.menu_button("Window Size", |ui| {
+                        for scale in 1..=MAX_WINDOW_SCALE {
+                            if ui.button(format!("{}x", scale)).clicked() {
+                                let (width, height) = scaled_size(scale);
+                                let size = self.chrome_size + Vec2::new(width, height);
+                                ctx.send_viewport_cmd(ViewportCommand::InnerSize(size));
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                    ui.menu_button("Screenshot Scale", |ui| {
+                        for scale in 1..=MAX_SCREENSHOT_SCALE {
+                            let label = format!("{}x", scale);
+                            ui.radio_value(&mut settings.screenshot_scale, scale, label);
+                        }
+                    });
+                    ui.separator();
+                    if ui.button("Controls...").clicked() {
+                        self.show_controls = true;
+                        ui.close_menu();
+                    }
+                    if settings != self.settings {
+                        self.set_settings(settings);
+                    }
+                });
             });
         });
 
+        self.show_dump_window(ctx);
+        self.show_memory_viewer(ctx);
+        self.show_vram_viewer(ctx);
+        self.show_apu_window(ctx);
+        self.show_audio_channels(ctx);
+        self.show_controls_window(ctx);
+
+        if self.session.is_running() {
+            let (record, replay) = ctx.input(|i| (i.key_pressed(Key::F5), i.key_down(Key::F6)));
+            if record {
+                self.input.toggle_recording();
+            }
+            self.input.hold_replay(replay);
+        }
+        if self.session.emu.is_some() && ctx.input(|i| i.key_pressed(Key::F12)) {
+            self.quick_screenshot();
+        }
+
+        let show_overlay = self.input_overlay || ctx.input(|i| i.key_down(Key::F1));
+
         // Main Render Panel
         egui::CentralPanel::default().show(ctx, |ui| {
-            if let Some(emu) = &mut self.emu {
+            self.snap_window(ctx, ui.available_size());
+            if self.session.emu.is_some() && self.session.paused {
+                // Keep showing the last frame while paused
+                let rect = draw_framebuffer(ui, &self.framebuffer, self.integer_scaling);
+                if show_overlay {
+                    let pressed = self.session.emu.as_ref().map_or(0, Gameboy::pressed_keys);
+                    draw_input_overlay(ui, rect, pressed);
+                }
+            } else if let Some(emu) = &mut self.session.emu {
                 // Currently running a game
+                let update_start = Instant::now();
+                self.audio_driver.recover();
                 let mut video_sink = video_sinks::BlendVideoSink::new();
                 let mut audio_sink = SimpleAudioSink {
                     inner: VecDeque::new(),
@@ -127,28 +1150,145 @@ impl eframe::App for GabeApp {
                 let time_source = self.audio_driver.time_source();
                 let mut audio_buffer_sink = self.audio_driver.sink();
 
-                let target_emu_time_ns = time_source.time_ns() - self.start_time;
-                let target_emu_cycles = (target_emu_time_ns as f32 / CYCLE_TIME_NS).floor() as u64;
-                while self.emulated_cycles < target_emu_cycles {
-                    self.emulated_cycles += emu.step(&mut video_sink, &mut audio_sink) as u64;
-
+                self.fast_forward
+                    .update(ctx.input(|i| i.key_down(Key::Tab)));
+                let now = time_source.time_ns();
+                self.target_emu_cycles += cycle_budget(
+                    now.saturating_sub(self.last_time),
+                    self.fast_forward.speed(),
+                );
+                self.last_time = now;
+                let target_emu_cycles = self.target_emu_cycles.floor() as u64;
+                let held = held_keys(ctx, &self.active_input_config);
+                #[cfg(feature = "gamepad")]
+                let held = held
+                    | self
+                        .gamepads
+                        .as_mut()
+                        .map_or(0, |pads| pads.held(&self.active_input_config));
+                let rewinding = ctx.input(|i| i.key_down(Key::R));
+                if rewinding {
+                    // Back two frames then forward one to show it, so play runs in reverse
+                    if emu.rewind(2) > 0 {
+                        for _ in 0..REWIND_STEP_LIMIT {
+                            emu.step(&mut video_sink, &mut audio_sink);
+                            if let Some(frame) = video_sink.get_frame() {
+                                set_frame(
+                                    &mut self.framebuffer,
+                                    self.palette,
+                                    self.color_correction,
+                                    self.color_depth,
+                                    &frame,
+                                );
+                                self.session.last_frame = frame.to_vec();
+                                break;
+                            }
+                        }
+                    }
+                    audio_sink.inner.clear();
+                    self.target_emu_cycles = self.emulated_cycles as f64;
+                }
+                // Fast-forward runs whole frames at a time, showing only the last and thinning
+                // the audio out to keep pace. Macro playback and turbo need every frame and
+                // audio dumps every sample, so none of them do, and nor do scripts, which run
+                // each frame.
+                #[cfg(feature = "lua")]
+                let scripted = self.lua.is_some();
+                #[cfg(not(feature = "lua"))]
+                let scripted = false;
+                let skipping = !rewinding
+                    && self.fast_forward.is_active()
+                    && !self.input.is_playing()
+                    && !turbo_held(held)
+                    && self.audio_dump.is_none()
+                    && !scripted;
+                if skipping {
+                    let frames = (target_emu_cycles.saturating_sub(self.emulated_cycles)
+                        / FRAME_CYCLES as u64) as u32;
+                    if frames > 0 {
+                        update_key_states(emu, self.input.keys(held));
+                        self.emulated_cycles +=
+                            emu.emulate_frames(frames, true, &mut video_sink, &mut audio_sink);
+                        if let Some(frame) = video_sink.get_frame() {
+                            set_frame(
+                                &mut self.framebuffer,
+                                self.palette,
+                                self.color_correction,
+                                self.color_depth,
+                                &frame,
+                            );
+                            self.session.last_frame = frame.to_vec();
+                            record_frame(
+                                &mut self.recording,
+                                self.palette,
+                                self.color_correction,
+                                self.color_depth,
+                                &frame,
+                            );
+                        }
+                        for _ in 0..frames {
+                            self.input.next_frame(held);
+                        }
+                    }
+                }
+                while !rewinding && !skipping && self.emulated_cycles < target_emu_cycles {
+                    let mut scope_sink = ScopeSink {
+                        mixed: &mut audio_sink,
+                        scopes: self.show_audio_channels.then_some(&mut self.scopes),
+                    };
+                    self.emulated_cycles +=
+                        emu.step_channels(&mut video_sink, &mut scope_sink) as u64;
+                    #[cfg(feature = "lua")]
+                    if let Some(script) = &mut self.lua {
+                        if let Err(e) = script.after_step(emu) {
+                            println!("{}: Lua script stopped.", e);
+                            if let Some(script) = self.lua.take() {
+                                script.unload(emu);
+                            }
+                        }
+                    }
                     if let Some(frame) = video_sink.get_frame() {
-                        self.framebuffer.set(
-                            ColorImage::from_rgb([160, 144], &frame),
-                            TextureOptions {
-                                magnification: egui::TextureFilter::Nearest,
-                                minification: egui::TextureFilter::Nearest,
-                            },
+                        set_frame(
+                            &mut self.framebuffer,
+                            self.palette,
+                            self.color_correction,
+                            self.color_depth,
+                            &frame,
+                        );
+                        self.session.last_frame = frame.to_vec();
+                        record_frame(
+                            &mut self.recording,
+                            self.palette,
+                            self.color_correction,
+                            self.color_depth,
+                            &frame,
                         );
+                        // Turbo and macros advance a frame at a time, like the game
+                        self.input.next_frame(held);
                     }
-                    update_key_states(ctx, emu);
+                    let keys = self.input.keys(held);
+                    #[cfg(feature = "lua")]
+                    let keys = keys | self.lua.as_ref().map_or(0, LuaScript::held_buttons);
+                    update_key_states(emu, keys);
                 }
-                audio_buffer_sink.append(audio_sink.inner.as_slices().0);
-                ui.add(
-                    Image::new(SizedTexture::from_handle(&self.framebuffer))
-                        .fit_to_fraction(Vec2::new(1.0, 1.0)),
-                );
-                ctx.request_repaint();
+                if let Some((wav, _)) = &mut self.audio_dump {
+                    for frame in &audio_sink.inner {
+                        wav.append(*frame);
+                    }
+                }
+                // Audio would overrun the buffer while fast-forwarding without skipping and
+                // underrun it in slow motion, so it's muted instead
+                let real_time =
+                    !self.fast_forward.is_active() && self.fast_forward.normal_speed() == 1.0;
+                if skipping || real_time {
+                    audio_buffer_sink.append(audio_sink.inner.as_slices().0);
+                }
+                let rect = draw_framebuffer(ui, &self.framebuffer, self.integer_scaling);
+                if show_overlay {
+                    draw_input_overlay(ui, rect, emu.pressed_keys());
+                }
+                // Sleep until the next frame is due rather than repainting as fast as possible
+                ctx.request_repaint_after(frame_delay(FRAME_TIME, update_start.elapsed()));
             } else {
                 ui.heading("Use File->Open File to select and run a valid ROM file.");
             }
@@ -156,15 +1296,115 @@ impl eframe::App for GabeApp {
     }
 }
 
-fn update_key_states(ctx: &egui::Context, gb: &mut Gameboy) {
-    ctx.input(|i| {
-        gb.update_key_state(GbKeys::A, i.key_down(Key::X));
-        gb.update_key_state(GbKeys::B, i.key_down(Key::Z));
-        gb.update_key_state(GbKeys::Start, i.key_down(Key::Enter));
-        gb.update_key_state(GbKeys::Select, i.key_down(Key::Backspace));
-        gb.update_key_state(GbKeys::Up, i.key_down(Key::ArrowUp));
-        gb.update_key_state(GbKeys::Down, i.key_down(Key::ArrowDown));
-        gb.update_key_state(GbKeys::Left, i.key_down(Key::ArrowLeft));
-        gb.update_key_state(GbKeys::Right, i.key_down(Key::ArrowRight));
-    });
+/// Draws the game as large as fits in `ui`, at a whole multiple of its resolution when
+/// `integer_scaling` is set. Returns where it was drawn.
+fn draw_framebuffer(ui: &mut Ui, framebuffer: &TextureHandle, integer_scaling: bool) -> Rect {
+    let image = Image::new(SizedTexture::from_handle(framebuffer));
+    let image = if integer_scaling {
+        let available = ui.available_size();
+        let (width, height) = scaled_size(fit_scale(available.x, available.y));
+        image.fit_to_exact_size(Vec2::new(width, height))
+    } else {
+        image.fit_to_fraction(Vec2::new(1.0, 1.0))
+    };
+    ui.add(image).rect
+}
+
+/// Displays a frame from the core in the given palette and color depth
+fn set_frame(
+    framebuffer: &mut TextureHandle,
+    palette: Palette,
+    color_correction: bool,
+    color_depth: u8,
+    frame: &[u8],
+) {
+    let frame = dither(
+        &palette.render(frame, color_correction),
+        FRAME_WIDTH,
+        color_depth,
+    );
+    framebuffer.set(
+        ColorImage::from_rgb([160, 144], &frame),
+        TextureOptions {
+            magnification: egui::TextureFilter::Nearest,
+            minification: egui::TextureFilter::Nearest,
+        },
+    );
+}
+
+/// Adds a frame to the GIF being recorded, as it's displayed, saving the GIF once it's full
+fn record_frame(
+    recording: &mut Option<(RecordingSink, PathBuf)>,
+    palette: Palette,
+    color_correction: bool,
+    color_depth: u8,
+    frame: &[u8],
+) {
+    let Some((sink, _)) = recording else {
+        return;
+    };
+    let frame = dither(
+        &palette.render(frame, color_correction),
+        FRAME_WIDTH,
+        color_depth,
+    );
+    sink.append(frame.into_boxed_slice());
+    if sink.is_full() {
+        finish_recording(recording);
+    }
+}
+
+/// Saves the GIF being recorded, if any
+fn finish_recording(recording: &mut Option<(RecordingSink, PathBuf)>) {
+    if let Some((sink, path)) = recording.take() {
+        match std::fs::write(&path, sink.finish()) {
+            Ok(()) => println!("Video saved to {}", path.display()),
+            Err(e) => println!("{}: Video not saved.", e),
+        }
+    }
+}
+
+/// Draws the button diagram from `input_overlay` over the bottom left of the game's `rect`,
+/// with the pressed buttons lit
+fn draw_input_overlay(ui: &Ui, rect: Rect, pressed: u8) {
+    let unit = rect.width() / 24.0;
+    let origin = rect.left_bottom() + Vec2::new(unit * 0.5, -unit * (OVERLAY_SIZE.1 + 0.5));
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(
+        Rect::from_min_size(origin, Vec2::new(OVERLAY_SIZE.0, OVERLAY_SIZE.1) * unit),
+        unit * 0.25,
+        Color32::from_black_alpha(96),
+    );
+    for button in overlay_buttons(pressed) {
+        let center = origin + Vec2::new(button.pos.0, button.pos.1) * unit;
+        let (fill, text) = if button.lit {
+            (Color32::from_rgb(230, 60, 60), Color32::WHITE)
+        } else {
+            (
+                Color32::from_white_alpha(48),
+                Color32::from_white_alpha(160),
+            )
+        };
+        painter.circle_filled(center, unit * 0.45, fill);
+        painter.text(
+            center,
+            Align2::CENTER_CENTER,
+            button.label,
+            FontId::proportional(unit * 0.4),
+            text,
+        );
+    }
+}
+
+/// The inputs held on the keyboard with the keys bound in `config`, as a mask indexed
+/// like `BINDINGS`
+fn held_keys(ctx: &egui::Context, config: &InputConfig) -> u16 {
+    ctx.input(|i| config.held(|key| i.key_down(key)))
+}
+
+/// Presses the buttons in `keys`, a mask indexed by `GbKeys`, and releases the rest
+fn update_key_states(gb: &mut Gameboy, keys: u8) {
+    for (_, button) in BUTTON_NAMES {
+        gb.update_key_state(button, keys & (1 << button as u8) != 0);
+    }
 }
@@ -0,0 +1,112 @@
+use std::collections::VecDeque;
+
+use gabe_core::sink::{AudioFrame, ChannelAudioFrame, MixedAudioSink, Sink};
+
+/// Samples shown by each channel's scope, about 10ms at the default sample rate
+pub const SCOPE_SAMPLES: usize = 512;
+
+/// Names of channels 1-4, in order
+pub const CHANNEL_NAMES: [&str; 4] = ["Square 1", "Square 2", "Wave", "Noise"];
+
+/// The latest samples of each channel, for drawing their waveforms
+#[derive(Debug, Clone, Default)]
+pub struct ChannelScopes {
+    samples: [VecDeque<f32>; 4],
+}
+
+impl ChannelScopes {
+    /// Adds a frame's samples, dropping the oldest once `SCOPE_SAMPLES` are kept. Channels
+    /// are a quarter of the mix each, so they're scaled back up to fill the -1.0 to 1.0 range.
+    pub fn push(&mut self, frame: ChannelAudioFrame) {
+        for (samples, (left, right)) in self.samples.iter_mut().zip(frame) {
+            if samples.len() == SCOPE_SAMPLES {
+                samples.pop_front();
+            }
+            samples.push_back((left + right) * 2.0);
+        }
+    }
+
+    /// The samples kept for `channel`, 0-3 for channels 1-4, oldest first
+    pub fn samples(&self, channel: usize) -> &VecDeque<f32> {
+        &self.samples[channel]
+    }
+}
+
+/// Mixes each channel's audio into `mixed` like `MixedAudioSink`, keeping the samples in
+/// `scopes` on the way when given, so the scopes cost nothing while they aren't shown
+pub struct ScopeSink<'a> {
+    pub mixed: &'a mut dyn Sink<AudioFrame>,
+    pub scopes: Option<&'a mut ChannelScopes>,
+}
+
+impl Sink<ChannelAudioFrame> for ScopeSink<'_> {
+    fn append(&mut self, value: ChannelAudioFrame) {
+        if let Some(scopes) = &mut self.scopes {
+            scopes.push(value);
+        }
+        MixedAudioSink(&mut *self.mixed).append(value);
+    }
+}
+
+/// Points of the line tracing `samples` across a scope `width` by `height`, relative to its
+/// top left corner, with silence along the middle
+pub fn scope_line(samples: &VecDeque<f32>, width: f32, height: f32) -> Vec<[f32; 2]> {
+    let step = width / (SCOPE_SAMPLES - 1) as f32;
+    samples
+        .iter()
+        .enumerate()
+        .map(|(i, sample)| {
+            let y = height / 2.0 * (1.0 - sample.clamp(-1.0, 1.0));
+            [i as f32 * step, y]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod apu_scope_tests {
+    use super::*;
+
+    struct FrameSink(Vec<AudioFrame>);
+
+    impl Sink<AudioFrame> for FrameSink {
+        fn append(&mut self, value: AudioFrame) {
+            self.0.push(value);
+        }
+    }
+
+    #[test]
+    fn scope_sink() {
+        let frame = [(0.25, 0.25), (0.0, 0.125), (-0.25, -0.25), (0.0, 0.0)];
+        let mut mixed = FrameSink(vec![]);
+        let mut scopes = ChannelScopes::default();
+        let mut sink = ScopeSink {
+            mixed: &mut mixed,
+            scopes: Some(&mut scopes),
+        };
+        for _ in 0..SCOPE_SAMPLES + 10 {
+            sink.append(frame);
+        }
+        assert_eq!(mixed.0.len(), SCOPE_SAMPLES + 10);
+        assert_eq!(mixed.0[0], (0.0, 0.125));
+        assert_eq!(scopes.samples(0).len(), SCOPE_SAMPLES);
+        assert_eq!(scopes.samples(0)[0], 1.0);
+        assert_eq!(scopes.samples(1)[0], 0.25);
+        assert_eq!(scopes.samples(2)[0], -1.0);
+
+        let mut sink = ScopeSink {
+            mixed: &mut mixed,
+            scopes: None,
+        };
+        sink.append(frame);
+        assert_eq!(mixed.0.len(), SCOPE_SAMPLES + 11);
+    }
+
+    #[test]
+    fn line() {
+        let samples = VecDeque::from([1.0, 0.0, -2.0]);
+        assert_eq!(
+            scope_line(&samples, (SCOPE_SAMPLES - 1) as f32, 40.0),
+            [[0.0, 0.0], [1.0, 20.0], [2.0, 40.0]]
+        );
+    }
+}
@@ -1,13 +1,44 @@
 use super::time_source::*;
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    Sample, SampleFormat,
+    Sample, SampleFormat, StreamError,
 };
 use gabe_core::sink::*;
 use log::*;
 
 use std::sync::*;
 
+/// Audio latency used when none is given on the command line, in milliseconds
+pub const DEFAULT_AUDIO_LATENCY_MS: u32 = 100;
+
+/// Accepted range for the audio latency, in milliseconds. Lower values risk buffer
+/// underruns on some host audio stacks, higher values make audio lag noticeably.
+pub const AUDIO_LATENCY_RANGE_MS: std::ops::RangeInclusive<u32> = 20..=500;
+
+/// Parses an audio latency argument in milliseconds, checking it's within
+/// `AUDIO_LATENCY_RANGE_MS`
+pub fn parse_audio_latency(value: &str) -> Result<u32, String> {
+    let latency_ms: u32 = value
+        .parse()
+        .map_err(|_| format!("Invalid audio latency \"{}\", expected milliseconds", value))?;
+    if AUDIO_LATENCY_RANGE_MS.contains(&latency_ms) {
+        Ok(latency_ms)
+    } else {
+        Err(format!(
+            "Audio latency of {} ms is out of range, must be {}-{} ms",
+            latency_ms,
+            AUDIO_LATENCY_RANGE_MS.start(),
+            AUDIO_LATENCY_RANGE_MS.end()
+        ))
+    }
+}
+
+/// Returns the size of the sample ring buffer needed to hold `latency_ms` of
+/// interleaved stereo audio at `sample_rate`
+fn buffer_samples(sample_rate: u32, latency_ms: u32) -> usize {
+    (sample_rate as u64 * latency_ms as u64 / 1000 * 2) as usize
+}
+
 /// A ring buffer of audio samples
 /// Tracks sample count in order to provide a time source
 struct SampleBuffer {
@@ -94,109 +125,197 @@ impl SinkRef<[AudioFrame]> for AudioDriverSink {
 
 pub struct AudioDriver {
     buffer: Arc<Mutex<SampleBuffer>>,
-    stream: cpal::Stream,
+    /// The output stream, or `None` while there's no device to play on
+    stream: Option<cpal::Stream>,
+    /// Last error reported on the output stream, taken by `recover`
+    stream_error: Arc<Mutex<Option<StreamError>>>,
+    sample_rate: u32,
+    playing: bool,
+}
+
+/// What to do about the output stream after checking for errors on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Recovery {
+    /// Keep using the current stream
+    Keep,
+    /// The device is gone but there's nothing to move to yet, check again later
+    Wait,
+    /// Build a new stream on the default device
+    Rebuild,
+}
+
+/// Decides how to recover from the last `error` on the output stream, given whether there's
+/// an output device available. Only a lost device stops the stream, other errors are
+/// backend specific and left alone.
+fn recovery(error: Option<&StreamError>, device_available: bool) -> Recovery {
+    match error {
+        Some(StreamError::DeviceNotAvailable) if device_available => Recovery::Rebuild,
+        Some(StreamError::DeviceNotAvailable) => Recovery::Wait,
+        _ => Recovery::Keep,
+    }
+}
+
+/// Opens an output stream on the default device that plays from `audio_buffer`, resampled
+/// from `sample_rate`. Errors on the stream are stored in `stream_error`.
+fn open_stream(
+    sample_rate: u32,
+    audio_buffer: &Arc<Mutex<SampleBuffer>>,
+    stream_error: &Arc<Mutex<Option<StreamError>>>,
+) -> Result<cpal::Stream, String> {
+    // Set up audio device, use default device.
+    let host = cpal::default_host();
+    let device = host
+        .default_output_device()
+        .ok_or("No audio output device available.")?;
+
+    let supported_configs_range = device
+        .supported_output_configs()
+        .map_err(|e| format!("{}: Error while querying configs.", e))?;
+
+    // Use the provided cmp_default_heuristics to find the best config supported
+    // Prioritizes 2 channels, gets highest sample rate.
+    let best_config = supported_configs_range
+        .max_by(|x, y| x.cmp_default_heuristics(y))
+        .ok_or("No supported output configs for device.")?;
+
+    let max_sample = best_config.max_sample_rate();
+    let selected_config = best_config.with_sample_rate(max_sample);
+
+    let err_fn = {
+        let stream_error = stream_error.clone();
+        move |err: StreamError| {
+            error!("An error occurred on the output audio stream: {}", err);
+            *stream_error.lock().unwrap() = Some(err);
+        }
+    };
+    let sample_format = selected_config.sample_format();
+    info!("Sound: ");
+    info!("\t Device: {:?}", device.name().unwrap_or_default());
+    info!("\t Device sample format: {:?}", sample_format);
+    info!(
+        "\t Device sample rate: {:?}",
+        selected_config.sample_rate().0
+    );
+    info!("\t Device channels: {:?}", selected_config.channels());
+
+    let config = selected_config.config();
+
+    // Resample from requested sample rate to the config's sample rate
+    let mut resampler = LinearResampler::new(sample_rate, config.sample_rate.0);
+
+    let read_audio_buffer = audio_buffer.clone();
+    match sample_format {
+        SampleFormat::F32 => device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                let mut buffer = read_audio_buffer.lock().unwrap();
+                for frame in data.chunks_mut(2) {
+                    for sample in frame.iter_mut() {
+                        *sample = resampler.next(&mut *buffer).to_sample();
+                    }
+                }
+            },
+            err_fn,
+            None,
+        ),
+        SampleFormat::I16 => device.build_output_stream(
+            &config,
+            move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
+                let mut buffer = read_audio_buffer.lock().unwrap();
+                for frame in data.chunks_mut(2) {
+                    for sample in frame.iter_mut() {
+                        *sample = resampler.next(&mut *buffer).to_sample();
+                    }
+                }
+            },
+            err_fn,
+            None,
+        ),
+        SampleFormat::U16 => device.build_output_stream(
+            &config,
+            move |data: &mut [u16], _: &cpal::OutputCallbackInfo| {
+                let mut buffer = read_audio_buffer.lock().unwrap();
+                for frame in data.chunks_mut(2) {
+                    for sample in frame.iter_mut() {
+                        *sample = resampler.next(&mut *buffer).to_sample();
+                    }
+                }
+            },
+            err_fn,
+            None,
+        ),
+        _ => return Err(format!("Unsupported sample format {:?}.", sample_format)),
+    }
+    .map_err(|e| format!("{}: Couldn't build the output stream.", e))
 }
 
 impl AudioDriver {
+    /// Opens a stream on the default output device. Without one, starts silent and leaves
+    /// `recover` to open the stream once a device is available.
     pub fn new(sample_rate: u32, latency_ms: u32) -> Self {
-        // Set up audio device, use default device.
-        let host = cpal::default_host();
-        let device = host
-            .default_output_device()
-            .expect("No audio output device available.");
-
-        let supported_configs_range = device
-            .supported_output_configs()
-            .expect("error while querying configs");
-
-        // Use the provided cmp_default_heuristics to find the best config supported
-        // Prioritizes 2 channels, gets highest sample rate.
-        let best_config = supported_configs_range
-            .max_by(|x, y| x.cmp_default_heuristics(y))
-            .expect("No supported output configs for device.");
-
-        let max_sample = best_config.max_sample_rate();
-        let selected_config = best_config.with_sample_rate(max_sample);
-
-        let err_fn = |err| error!("An error occurred on the output audio stream: {}", err);
-        let sample_format = selected_config.sample_format();
-        let buffer_samples = (sample_rate * latency_ms / 1000 * 2) as usize;
-        info!("Sound: ");
-        info!("\t Device: {:?}", device.name().unwrap());
-        info!("\t Device sample format: {:?}", sample_format);
-        info!(
-            "\t Device sample rate: {:?}",
-            selected_config.sample_rate().0
-        );
-        info!("\t Device channels: {:?}", selected_config.channels());
-
-        let config = selected_config.config();
         let audio_buffer = Arc::new(Mutex::new(SampleBuffer {
-            inner: vec![0.0; buffer_samples].into_boxed_slice(),
+            inner: vec![0.0; buffer_samples(sample_rate, latency_ms)].into_boxed_slice(),
             samples_read: 0,
             sample_rate,
             count: 0,
             write_index: 0,
             read_index: 0,
         }));
-
-        // Resample from requested sample rate to the config's sample rate
-        let mut resampler = LinearResampler::new(sample_rate, config.sample_rate.0);
-
-        let read_audio_buffer = audio_buffer.clone();
-        let stream = match sample_format {
-            SampleFormat::F32 => device.build_output_stream(
-                &config,
-                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                    let mut buffer = read_audio_buffer.lock().unwrap();
-                    for frame in data.chunks_mut(2) {
-                        for sample in frame.iter_mut() {
-                            *sample = resampler.next(&mut *buffer).to_sample();
-                        }
-                    }
-                },
-                err_fn,
-                None,
-            ),
-            SampleFormat::I16 => device.build_output_stream(
-                &config,
-                move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
-                    let mut buffer = read_audio_buffer.lock().unwrap();
-                    for frame in data.chunks_mut(2) {
-                        for sample in frame.iter_mut() {
-                            *sample = resampler.next(&mut *buffer).to_sample();
-                        }
-                    }
-                },
-                err_fn,
-                None,
-            ),
-            SampleFormat::U16 => device.build_output_stream(
-                &config,
-                move |data: &mut [u16], _: &cpal::OutputCallbackInfo| {
-                    let mut buffer = read_audio_buffer.lock().unwrap();
-                    for frame in data.chunks_mut(2) {
-                        for sample in frame.iter_mut() {
-                            *sample = resampler.next(&mut *buffer).to_sample();
-                        }
-                    }
-                },
-                err_fn,
-                None,
-            ),
-            _ => panic!("Test"),
-        }
-        .unwrap();
+        let stream_error = Arc::new(Mutex::new(None));
+        let stream = match open_stream(sample_rate, &audio_buffer, &stream_error) {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                warn!("{}: Running without audio until a device is available.", e);
+                // Recovered the same way as losing the device
+                *stream_error.lock().unwrap() = Some(StreamError::DeviceNotAvailable);
+                None
+            }
+        };
 
         AudioDriver {
             buffer: audio_buffer,
             stream,
+            stream_error,
+            sample_rate,
+            playing: false,
+        }
+    }
+
+    /// Moves playback to the new default device if the current one was lost, e.g. by
+    /// unplugging headphones, or opens the stream once there's a device if there was none
+    /// at startup. The sample buffer is kept, so playback carries on where it was.
+    /// Call regularly, it does nothing while the stream is healthy.
+    pub fn recover(&mut self) {
+        let error = self.stream_error.lock().unwrap().take();
+        let device_available =
+            error.is_some() && cpal::default_host().default_output_device().is_some();
+        match recovery(error.as_ref(), device_available) {
+            Recovery::Keep => {}
+            Recovery::Wait => *self.stream_error.lock().unwrap() = error,
+            Recovery::Rebuild => {
+                match open_stream(self.sample_rate, &self.buffer, &self.stream_error) {
+                    Ok(stream) => {
+                        info!("Audio output moved to the default device.");
+                        self.stream = Some(stream);
+                        if self.playing {
+                            self.play();
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{}: Retrying.", e);
+                        *self.stream_error.lock().unwrap() = error;
+                    }
+                }
+            }
         }
     }
 
     /// Begins audio playback and consumption of SampleBuffer
     pub fn play(&mut self) {
-        self.stream.play().unwrap();
+        self.playing = true;
+        if let Some(stream) = &self.stream {
+            stream.play().unwrap();
+        }
     }
 
     /// Stops all playback and resets internal buffer state.
@@ -207,8 +326,11 @@ impl AudioDriver {
             let mut buffer = self.buffer.lock().unwrap();
             buffer.clear();
         }
+        self.playing = false;
         // TODO: There's slight chirps after resuming stream with play(), as it consumes the remaining OS driver buffer
-        self.stream.pause().unwrap();
+        if let Some(stream) = &self.stream {
+            stream.pause().unwrap();
+        }
     }
 
     /// Returns an AudioSink that receives audio frames to be passed along to the device.
@@ -309,3 +431,41 @@ impl LinearResampler {
         ret
     }
 }
+
+#[cfg(test)]
+mod audio_driver_tests {
+    use super::*;
+
+    #[test]
+    fn latency_buffer_size() {
+        // 100 ms of stereo audio
+        assert_eq!(buffer_samples(48000, 100), 9600);
+        assert_eq!(buffer_samples(262_144, 100), 52428);
+        // Large rates don't overflow
+        assert_eq!(buffer_samples(4_194_304, 500), 4_194_304);
+    }
+
+    #[test]
+    fn device_loss_recovery() {
+        let lost = StreamError::DeviceNotAvailable;
+        assert_eq!(recovery(Some(&lost), true), Recovery::Rebuild);
+        assert_eq!(recovery(Some(&lost), false), Recovery::Wait);
+        assert_eq!(recovery(None, true), Recovery::Keep);
+        let backend = StreamError::BackendSpecific {
+            err: cpal::BackendSpecificError {
+                description: "underrun".to_string(),
+            },
+        };
+        assert_eq!(recovery(Some(&backend), true), Recovery::Keep);
+    }
+
+    #[test]
+    fn latency_parsing() {
+        assert_eq!(parse_audio_latency("50"), Ok(50));
+        assert_eq!(parse_audio_latency("20"), Ok(20));
+        assert_eq!(parse_audio_latency("500"), Ok(500));
+        assert!(parse_audio_latency("5").is_err());
+        assert!(parse_audio_latency("1000").is_err());
+        assert!(parse_audio_latency("fast").is_err());
+    }
+}
@@ -0,0 +1,57 @@
+/// Decides when to pause and resume emulation as the window loses and regains focus.
+/// Only resumes emulation that it paused itself, so a manual pause survives a focus change.
+pub struct AutoPause {
+    pub enabled: bool,
+    focused: bool,
+    paused_by_focus: bool,
+}
+
+impl AutoPause {
+    pub fn new(enabled: bool) -> Self {
+        AutoPause {
+            enabled,
+            focused: true,
+            paused_by_focus: false,
+        }
+    }
+
+    /// Updates with the window's current focus and whether emulation is paused,
+    /// returning the new paused state if it should change
+    pub fn update(&mut self, focused: bool, paused: bool) -> Option<bool> {
+        let was_focused = self.focused;
+        self.focused = focused;
+        if was_focused && !focused && self.enabled && !paused {
+            self.paused_by_focus = true;
+            Some(true)
+        } else if !was_focused && focused && self.paused_by_focus {
+            self.paused_by_focus = false;
+            // Nothing to do if it was already resumed some other way
+            paused.then_some(false)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod auto_pause_tests {
+    use super::*;
+
+    #[test]
+    fn focus_changes() {
+        let mut auto_pause = AutoPause::new(true);
+        assert_eq!(auto_pause.update(true, false), None);
+        assert_eq!(auto_pause.update(false, false), Some(true));
+        assert_eq!(auto_pause.update(false, true), None);
+        assert_eq!(auto_pause.update(true, true), Some(false));
+        assert_eq!(auto_pause.update(true, false), None);
+
+        // Manually paused before losing focus, so focus doesn't resume it
+        assert_eq!(auto_pause.update(false, true), None);
+        assert_eq!(auto_pause.update(true, true), None);
+
+        let mut auto_pause = AutoPause::new(false);
+        assert_eq!(auto_pause.update(false, false), None);
+        assert_eq!(auto_pause.update(true, false), None);
+    }
+}
@@ -0,0 +1,110 @@
+use std::io::Write;
+use std::ops::Range;
+
+use gabe_core::disassemble::disassemble_block_with_data;
+
+const BANK_SIZE: usize = 0x4000;
+
+/// Disassembles every ROM bank as it would be mapped into the CPU's address space,
+/// one instruction per line prefixed with its bank and address.
+/// Decoding restarts at the 0x100 entry point so it always lines up with the first instruction.
+/// Bytes within `data_regions`, given as offsets into the ROM file, are output as `db` directives.
+pub fn disassemble_rom(
+    rom: &[u8],
+    data_regions: &[Range<usize>],
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    for (bank, data) in rom.chunks(BANK_SIZE).enumerate() {
+        writeln!(out, "; Bank {:02X}", bank)?;
+        let base = if bank == 0 { 0 } else { BANK_SIZE };
+        let bank_start = bank * BANK_SIZE;
+        // Move the regions within this bank to where the bank is mapped
+        let bank_regions: Vec<Range<u16>> = data_regions
+            .iter()
+            .filter(|r| r.start < bank_start + data.len() && r.end > bank_start)
+            .map(|r| {
+                let start = r.start.max(bank_start) - bank_start + base;
+                let end = r.end.min(bank_start + data.len()) - bank_start + base;
+                start as u16..end as u16
+            })
+            .collect();
+        let blocks = if bank == 0 {
+            let (vectors, code) = data.split_at(data.len().min(0x100));
+            vec![(vectors, 0x0000), (code, 0x0100)]
+        } else {
+            vec![(data, BANK_SIZE as u16)]
+        };
+        for (block, pc) in blocks {
+            for (addr, line) in disassemble_block_with_data(block, pc, &bank_regions) {
+                writeln!(out, "{:02X}:{:04X} {}", bank, addr, line)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the disassembly of `rom` to the file at `path`, or to stdout if `path` is "-"
+pub fn disassemble_to_path(
+    rom: &[u8],
+    data_regions: &[Range<usize>],
+    path: &str,
+) -> std::io::Result<()> {
+    if path == "-" {
+        disassemble_rom(rom, data_regions, &mut std::io::stdout().lock())
+    } else {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        disassemble_rom(rom, data_regions, &mut file)?;
+        file.flush()
+    }
+}
+
+/// Parses a data region given as an inclusive range of hex ROM offsets, e.g. `4000-40FF`
+pub fn parse_data_region(value: &str) -> Result<Range<usize>, String> {
+    let invalid = || format!("Invalid data region \"{}\", expected <start>-<end>", value);
+    let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+    let start = usize::from_str_radix(start.trim(), 16).map_err(|_| invalid())?;
+    let end = usize::from_str_radix(end.trim(), 16).map_err(|_| invalid())?;
+    if end < start {
+        return Err(invalid());
+    }
+    Ok(start..end + 1)
+}
+
+#[cfg(test)]
+mod disassemble_tests {
+    use super::*;
+    use crate::test_util::test_rom;
+
+    #[test]
+    fn output_path() {
+        let rom = std::fs::read("../gabe_core/tests/roms/cpu_instrs/cpu_instrs.gb").unwrap();
+        let path = std::env::temp_dir().join("gabe_disassemble_test.asm");
+        let path = path.to_str().unwrap();
+        disassemble_to_path(&rom, &[], path).unwrap();
+        let asm = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        // NOP; JP $0637 at the entry point
+        let mut entry = asm.lines().skip_while(|l| !l.starts_with("00:0100"));
+        assert!(entry.next().unwrap().contains("nop"));
+        assert!(entry.next().unwrap().starts_with("00:0101"));
+        assert!(asm.contains("; Bank 03"));
+    }
+
+    #[test]
+    fn rom_data_regions() {
+        let mut rom = test_rom(&[]);
+        // Bytes that would decode as LD BC,$3E3E in bank 1
+        rom[0x4010..0x4013].copy_from_slice(&[0x01, 0x3E, 0x3E]);
+        let mut out = vec![];
+        disassemble_rom(&rom, &[parse_data_region("4010-4012").unwrap()], &mut out).unwrap();
+        let asm = String::from_utf8(out).unwrap();
+        assert!(asm.contains("01:4010 01:\t db $01"));
+        assert!(asm.contains("01:4012 3E:\t db $3E"));
+        assert!(asm.contains("01:4013 00:\tnop"));
+        assert!(!asm.contains("ld bc"));
+
+        assert_eq!(parse_data_region("100-103"), Ok(0x100..0x104));
+        assert!(parse_data_region("103-100").is_err());
+        assert!(parse_data_region("4000").is_err());
+    }
+}
@@ -0,0 +1,77 @@
+/// Bits per channel of frames from the core, which need no dithering
+pub const FULL_COLOR_DEPTH: u8 = 8;
+
+/// Color depths offered in the settings, in bits per channel
+pub const COLOR_DEPTHS: [u8; 6] = [FULL_COLOR_DEPTH, 5, 4, 3, 2, 1];
+
+/// Thresholds in 16ths for each pixel of a repeating 4x4 block, spread so every
+/// fraction of a block lights up as evenly as possible
+const BAYER_4X4: [[u32; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Reduces an RGB frame `width` pixels wide to `bits` bits per channel, for an authentic
+/// low color look. Ordered dithering turns values between two levels into a fixed pattern
+/// of both, lighting the brighter level for a share of each 4x4 block matching how close
+/// the value is to it, rather than banding smooth gradients.
+pub fn dither(rgb: &[u8], width: usize, bits: u8) -> Vec<u8> {
+    if bits >= FULL_COLOR_DEPTH {
+        return rgb.to_vec();
+    }
+    let levels = (1 << bits) - 1;
+    let mut ret = Vec::with_capacity(rgb.len());
+    for (i, pixel) in rgb.chunks(3).enumerate() {
+        let threshold = BAYER_4X4[(i / width) % 4][(i % width) % 4];
+        for c in pixel {
+            // Position between the two nearest levels, in 16ths
+            let position = *c as u32 * levels * 16 / 255;
+            let level = ((position + threshold) / 16).min(levels);
+            ret.push((level * 255 / levels) as u8);
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod dither_tests {
+    use super::*;
+
+    /// Dithers a 4x4 block of a single grey value to 1 bit, returning which pixels are lit
+    fn one_bit_block(value: u8) -> [[bool; 4]; 4] {
+        let out = dither(&[value; 4 * 4 * 3], 4, 1);
+        let mut lit = [[false; 4]; 4];
+        for (i, pixel) in out.chunks(3).enumerate() {
+            assert!(pixel == [0; 3] || pixel == [255; 3]);
+            lit[i / 4][i % 4] = pixel[0] == 255;
+        }
+        lit
+    }
+
+    #[test]
+    fn ordered_pattern() {
+        let lit_count = |value| {
+            one_bit_block(value)
+                .iter()
+                .flatten()
+                .filter(|l| **l)
+                .count()
+        };
+        let gradient = [0, 64, 128, 192, 255].map(lit_count);
+        assert_eq!(gradient, [0, 4, 8, 12, 16]);
+
+        // Halfway between the levels makes a checkerboard
+        let checkerboard = [
+            [false, true, false, true],
+            [true, false, true, false],
+            [false, true, false, true],
+            [true, false, true, false],
+        ];
+        assert_eq!(one_bit_block(128), checkerboard);
+    }
+
+    #[test]
+    fn levels_unchanged() {
+        let levels = [0, 0, 0, 85, 85, 85, 170, 170, 170, 255, 255, 255];
+        assert_eq!(dither(&levels, 4, 2), levels);
+        let gradient: Vec<u8> = (0..=255).flat_map(|v| [v; 3]).collect();
+        assert_eq!(dither(&gradient, 16, FULL_COLOR_DEPTH), gradient);
+    }
+}
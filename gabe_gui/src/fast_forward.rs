@@ -0,0 +1,216 @@
+use std::time::Duration;
+
+/// Default emulation speed multiplier while fast-forwarding
+pub const FAST_FORWARD_SPEED: f64 = 4.0;
+
+/// Speed multipliers offered for slow motion, alongside normal speed
+pub const SLOW_MOTION_SPEEDS: [f64; 2] = [0.25, 0.5];
+
+/// Length of a single CPU clock cycle
+const CYCLE_TIME_NS: f64 = 238.41858;
+
+/// Number of cycles to emulate for `elapsed_ns` of real time at the given speed multiplier
+pub fn cycle_budget(elapsed_ns: u64, speed: f64) -> f64 {
+    elapsed_ns as f64 * speed / CYCLE_TIME_NS
+}
+
+/// Real time taken by a frame at normal speed, 70224 cycles
+pub const FRAME_TIME: Duration = Duration::from_nanos(16_742_706);
+
+/// How long to wait before the next update so updates happen once every `frame_time`,
+/// given how long the current update has taken. An overdue update happens right away.
+pub fn frame_delay(frame_time: Duration, elapsed: Duration) -> Duration {
+    frame_time.saturating_sub(elapsed)
+}
+
+/// How the fast-forward key controls fast-forwarding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FastForwardMode {
+    /// Fast-forward only while the key is held down
+    Hold,
+    /// Each press of the key turns fast-forward on or off
+    Toggle,
+}
+
+impl FastForwardMode {
+    /// Name of the mode as stored in the settings file
+    pub fn name(&self) -> &'static str {
+        match self {
+            FastForwardMode::Hold => "hold",
+            FastForwardMode::Toggle => "toggle",
+        }
+    }
+
+    pub fn from_name(value: &str) -> Option<Self> {
+        match value {
+            "hold" => Some(FastForwardMode::Hold),
+            "toggle" => Some(FastForwardMode::Toggle),
+            _ => None,
+        }
+    }
+}
+
+/// Tracks whether fast-forward is active from the state of the fast-forward key
+pub struct FastForward {
+    mode: FastForwardMode,
+    active: bool,
+    key_held: bool,
+    fast_speed: f64,
+    /// Speed while not fast-forwarding, below 1 for slow motion
+    normal_speed: f64,
+}
+
+impl FastForward {
+    pub fn new(mode: FastForwardMode) -> Self {
+        FastForward {
+            mode,
+            active: false,
+            key_held: false,
+            fast_speed: FAST_FORWARD_SPEED,
+            normal_speed: 1.0,
+        }
+    }
+
+    /// Sets the speed multiplier used while fast-forwarding
+    pub fn set_fast_speed(&mut self, speed: f64) {
+        self.fast_speed = speed;
+    }
+
+    /// Sets the speed multiplier used while not fast-forwarding, for slow motion
+    pub fn set_normal_speed(&mut self, speed: f64) {
+        self.normal_speed = speed;
+    }
+
+    pub fn normal_speed(&self) -> f64 {
+        self.normal_speed
+    }
+
+    pub fn mode(&self) -> FastForwardMode {
+        self.mode
+    }
+
+    /// Changes the mode, turning fast-forward off
+    pub fn set_mode(&mut self, mode: FastForwardMode) {
+        self.mode = mode;
+        self.active = false;
+    }
+
+    /// Updates the state from whether the key is currently held, acting only on
+    /// presses and releases so held-key repeats don't retrigger a toggle
+    pub fn update(&mut self, key_held: bool) {
+        if key_held && !self.key_held {
+            self.key_down();
+        } else if !key_held && self.key_held {
+            self.key_up();
+        }
+        self.key_held = key_held;
+    }
+
+    pub fn key_down(&mut self) {
+        self.active = match self.mode {
+            FastForwardMode::Hold => true,
+            FastForwardMode::Toggle => !self.active,
+        };
+    }
+
+    pub fn key_up(&mut self) {
+        if self.mode == FastForwardMode::Hold {
+            self.active = false;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// The current emulation speed multiplier. Fast-forwarding overrides slow motion.
+    pub fn speed(&self) -> f64 {
+        if self.active {
+            self.fast_speed
+        } else {
+            self.normal_speed
+        }
+    }
+}
+
+#[cfg(test)]
+mod fast_forward_tests {
+    use super::*;
+
+    #[test]
+    fn hold_mode() {
+        let mut ff = FastForward::new(FastForwardMode::Hold);
+        assert!(!ff.is_active());
+        ff.update(true);
+        assert!(ff.is_active());
+        assert_eq!(ff.speed(), FAST_FORWARD_SPEED);
+        ff.update(true);
+        assert!(ff.is_active());
+        ff.update(false);
+        assert!(!ff.is_active());
+        assert_eq!(ff.speed(), 1.0);
+    }
+
+    #[test]
+    fn toggle_mode() {
+        let mut ff = FastForward::new(FastForwardMode::Toggle);
+        ff.update(true);
+        assert!(ff.is_active());
+        // Holding the key doesn't toggle it back off
+        ff.update(true);
+        assert!(ff.is_active());
+        ff.update(false);
+        assert!(ff.is_active());
+        ff.update(true);
+        assert!(!ff.is_active());
+        ff.update(false);
+        assert!(!ff.is_active());
+
+        ff.update(true);
+        ff.set_mode(FastForwardMode::Hold);
+        assert!(!ff.is_active());
+        ff.update(false);
+        assert!(!ff.is_active());
+    }
+
+    #[test]
+    fn slow_motion() {
+        let mut ff = FastForward::new(FastForwardMode::Hold);
+        ff.set_normal_speed(0.5);
+        assert_eq!(ff.speed(), 0.5);
+        ff.update(true);
+        assert_eq!(ff.speed(), FAST_FORWARD_SPEED);
+        ff.update(false);
+        assert_eq!(ff.speed(), 0.5);
+
+        // One frame's worth of time
+        let elapsed_ns = 16_742_706;
+        let normal = cycle_budget(elapsed_ns, 1.0);
+        assert!((normal - 70224.0).abs() < 1.0);
+        assert_eq!(cycle_budget(elapsed_ns, 0.5), normal / 2.0);
+        assert_eq!(cycle_budget(elapsed_ns, 0.25), normal / 4.0);
+    }
+
+    #[test]
+    fn frame_pacing() {
+        assert_eq!(
+            frame_delay(FRAME_TIME, Duration::from_millis(4)),
+            Duration::from_nanos(12_742_706)
+        );
+        assert_eq!(frame_delay(FRAME_TIME, Duration::ZERO), FRAME_TIME);
+        // Running behind, don't wait at all
+        assert_eq!(
+            frame_delay(FRAME_TIME, Duration::from_millis(20)),
+            Duration::ZERO
+        );
+        assert!((cycle_budget(FRAME_TIME.as_nanos() as u64, 1.0) - 70224.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn mode_names() {
+        for mode in [FastForwardMode::Hold, FastForwardMode::Toggle] {
+            assert_eq!(FastForwardMode::from_name(mode.name()), Some(mode));
+        }
+        assert_eq!(FastForwardMode::from_name("turbo"), None);
+    }
+}
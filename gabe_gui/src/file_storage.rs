@@ -0,0 +1,48 @@
+use std::path::PathBuf;
+
+use gabe_core::storage::SaveStorage;
+
+/// Keeps a game's battery-backed RAM in a save file next to its ROM
+pub struct FileStorage {
+    path: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(path: PathBuf) -> Self {
+        FileStorage { path }
+    }
+}
+
+impl SaveStorage for FileStorage {
+    fn load(&mut self) -> Option<Vec<u8>> {
+        // A missing or newly created save file has nothing to load
+        std::fs::read(&self.path)
+            .ok()
+            .filter(|data| !data.is_empty())
+    }
+
+    fn store(&mut self, data: &[u8]) {
+        // Written alongside then moved over the old file, so it's never left half written
+        let temp = self.path.with_extension("sav.tmp");
+        let result = std::fs::write(&temp, data).and_then(|()| std::fs::rename(&temp, &self.path));
+        if let Err(e) = result {
+            println!("{}: Save file not written.", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod file_storage_tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!("gabe-storage-{}.sav", std::process::id()));
+        let mut storage = FileStorage::new(path.clone());
+        assert_eq!(storage.load(), None);
+        storage.store(&[1, 2, 3]);
+        storage.store(&[4, 5]);
+        assert_eq!(storage.load(), Some(vec![4, 5]));
+        std::fs::remove_file(path).unwrap();
+    }
+}
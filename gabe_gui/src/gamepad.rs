@@ -0,0 +1,75 @@
+use gilrs::{Axis, Button, EventType, Gilrs};
+
+use crate::input_config::{InputConfig, PadButton};
+
+/// The connected gamepads, which can be plugged in and out while running
+pub struct Gamepads {
+    gilrs: Gilrs,
+}
+
+impl Gamepads {
+    /// Starts watching for gamepads, or `None` if the platform doesn't support them
+    pub fn new() -> Option<Self> {
+        match Gilrs::new() {
+            Ok(gilrs) => {
+                for (_, pad) in gilrs.gamepads() {
+                    println!("Gamepad \"{}\" connected.", pad.name());
+                }
+                Some(Gamepads { gilrs })
+            }
+            Err(e) => {
+                println!("{}: Gamepads not available.", e);
+                None
+            }
+        }
+    }
+
+    /// The inputs held on any gamepad with the buttons bound in `config`, as a mask
+    /// indexed like `BINDINGS`. Each gamepad uses its own profile, if it has one.
+    pub fn held(&mut self, config: &InputConfig) -> u16 {
+        // Events have to be drained for the gamepads' state to update
+        while let Some(event) = self.gilrs.next_event() {
+            let name = self.gilrs.gamepad(event.id).name().to_string();
+            match event.event {
+                EventType::Connected => println!("Gamepad \"{}\" connected.", name),
+                EventType::Disconnected => println!("Gamepad \"{}\" disconnected.", name),
+                _ => {}
+            }
+        }
+        self.gilrs
+            .gamepads()
+            .map(|(_, pad)| {
+                let profile = config.gamepad_profile(pad.name());
+                let stick = (pad.value(Axis::LeftStickX), pad.value(Axis::LeftStickY));
+                profile.held(|button| pad.is_pressed(gilrs_button(button)), stick)
+            })
+            .fold(0, |held, pad_held| held | pad_held)
+    }
+
+    /// Names of the connected gamepads, for choosing which profile they use
+    pub fn names(&self) -> Vec<String> {
+        self.gilrs
+            .gamepads()
+            .map(|(_, pad)| pad.name().to_string())
+            .collect()
+    }
+}
+
+fn gilrs_button(button: PadButton) -> Button {
+    match button {
+        PadButton::South => Button::South,
+        PadButton::East => Button::East,
+        PadButton::North => Button::North,
+        PadButton::West => Button::West,
+        PadButton::LeftTrigger => Button::LeftTrigger,
+        PadButton::LeftTrigger2 => Button::LeftTrigger2,
+        PadButton::RightTrigger => Button::RightTrigger,
+        PadButton::RightTrigger2 => Button::RightTrigger2,
+        PadButton::Select => Button::Select,
+        PadButton::Start => Button::Start,
+        PadButton::DPadUp => Button::DPadUp,
+        PadButton::DPadDown => Button::DPadDown,
+        PadButton::DPadLeft => Button::DPadLeft,
+        PadButton::DPadRight => Button::DPadRight,
+    }
+}
@@ -0,0 +1,257 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use gabe_core::gb::{Gameboy, GbKeys};
+use gabe_core::sink::NullSink;
+
+use crate::memory_dump::{dump_memory, parse_address};
+use crate::screenshot::save_screenshot;
+
+/// Describes the commands a headless script can use, one per line
+pub const SCRIPT_HELP: &str = "\
+Script commands, one per line, with addresses and values in hex and # starting a comment:
+  run <frames>                Run the given number of frames
+  press <button>...           Hold buttons down: a, b, start, select, up, down, left, right
+  release <button>...         Let go of buttons
+  dump <start> <end> [file]   Print memory from start through end, or write it to a file
+  screenshot <file.png>       Save the screen as a PNG
+  expect <addr> <value>       Exit with code 1 unless the byte at addr has the value
+  exit <code>                 Exit with the given code";
+
+/// Names buttons are given in scripts, matched ignoring case
+pub const BUTTON_NAMES: [(&str, GbKeys); 8] = [
+    ("a", GbKeys::A),
+    ("b", GbKeys::B),
+    ("start", GbKeys::Start),
+    ("select", GbKeys::Select),
+    ("up", GbKeys::Up),
+    ("down", GbKeys::Down),
+    ("left", GbKeys::Left),
+    ("right", GbKeys::Right),
+];
+
+/// A line of a headless script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run(u32),
+    Press(Vec<GbKeys>),
+    Release(Vec<GbKeys>),
+    Dump {
+        start: u16,
+        end: u16,
+        path: Option<PathBuf>,
+    },
+    Screenshot(PathBuf),
+    Expect {
+        addr: u16,
+        value: u8,
+    },
+    Exit(i32),
+}
+
+/// Parses a headless script, see `SCRIPT_HELP`
+pub fn parse_script(text: &str) -> Result<Vec<Command>, String> {
+    let mut script = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, args)) = words.split_first() else {
+            continue;
+        };
+        let command = parse_command(name, args).map_err(|e| format!("Line {}: {}", i + 1, e))?;
+        script.push(command);
+    }
+    Ok(script)
+}
+
+fn parse_command(name: &str, args: &[&str]) -> Result<Command, String> {
+    let command = match (name, args) {
+        ("run", [frames]) => Command::Run(
+            frames
+                .parse()
+                .map_err(|_| format!("Invalid frame count \"{}\"", frames))?,
+        ),
+        ("press", [_, ..]) => Command::Press(parse_buttons(args)?),
+        ("release", [_, ..]) => Command::Release(parse_buttons(args)?),
+        ("dump", [start, end]) | ("dump", [start, end, _]) => Command::Dump {
+            start: parse_address(start)?,
+            end: parse_address(end)?,
+            path: args.get(2).map(PathBuf::from),
+        },
+        ("screenshot", [path]) => Command::Screenshot(PathBuf::from(path)),
+        ("expect", [addr, value]) => Command::Expect {
+            addr: parse_address(addr)?,
+            value: u8::from_str_radix(value, 16)
+                .map_err(|_| format!("Invalid value \"{}\"", value))?,
+        },
+        ("exit", [code]) => Command::Exit(
+            code.parse()
+                .map_err(|_| format!("Invalid exit code \"{}\"", code))?,
+        ),
+        ("run" | "press" | "release" | "dump" | "screenshot" | "expect" | "exit", _) => {
+            return Err(format!("Wrong arguments for \"{}\"", name))
+        }
+        _ => return Err(format!("Unknown command \"{}\"", name)),
+    };
+    Ok(command)
+}
+
+fn parse_buttons(names: &[&str]) -> Result<Vec<GbKeys>, String> {
+    names
+        .iter()
+        .map(|name| {
+            BUTTON_NAMES
+                .iter()
+                .find(|(button, _)| name.eq_ignore_ascii_case(button))
+                .map(|(_, key)| *key)
+                .ok_or_else(|| format!("Unknown button \"{}\"", name))
+        })
+        .collect()
+}
+
+/// Runs `script` on `gb`, printing dumps and failed expectations to `out`. Returns the code
+/// to exit with, which is 0 if the script ends without an `exit` or failed `expect`.
+pub fn run_script(
+    gb: &mut Gameboy,
+    script: &[Command],
+    out: &mut dyn Write,
+) -> Result<i32, String> {
+    for command in script {
+        match command {
+            Command::Run(frames) => {
+                gb.emulate_frames(*frames, true, &mut NullSink, &mut NullSink);
+            }
+            Command::Press(keys) | Command::Release(keys) => {
+                let pressed = matches!(command, Command::Press(_));
+                for key in keys {
+                    gb.update_key_state(*key, pressed);
+                }
+            }
+            Command::Dump {
+                start,
+                end,
+                path: Some(path),
+            } => dump_memory(gb, *start, *end, path).map_err(|e| e.to_string())?,
+            Command::Dump {
+                start,
+                end,
+                path: None,
+            } => {
+                if end < start {
+                    return Err(format!("End {:04X} is before start {:04X}", end, start));
+                }
+                let bytes = gb.get_memory_range(*start as usize..*end as usize + 1);
+                write_hex_dump(out, *start, &bytes).map_err(|e| e.to_string())?;
+            }
+            Command::Screenshot(path) => {
+                save_screenshot(path, gb.frame_buffer(), 1).map_err(|e| e.to_string())?
+            }
+            Command::Expect { addr, value } => {
+                let found = gb.get_memory_range(*addr as usize..*addr as usize + 1)[0];
+                if found != *value {
+                    writeln!(
+                        out,
+                        "Expected {:02X} at {:04X}, found {:02X}",
+                        value, addr, found
+                    )
+                    .map_err(|e| e.to_string())?;
+                    return Ok(1);
+                }
+            }
+            Command::Exit(code) => return Ok(*code),
+        }
+    }
+    Ok(0)
+}
+
+/// Prints `bytes` 16 to a line, each line starting with the address of its first byte
+fn write_hex_dump(out: &mut dyn Write, start: u16, bytes: &[u8]) -> std::io::Result<()> {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02X}", b)).collect();
+        writeln!(out, "{:04X}: {}", start as usize + i * 16, hex.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Runs the ROM at `rom_path` without a window or audio, driven by the script at
+/// `script_path`. Returns the code to exit with.
+pub fn run_headless(rom_path: &Path, script_path: &Path) -> Result<i32, String> {
+    let script = std::fs::read_to_string(script_path)
+        .map_err(|e| format!("{}: Script not read.", e))
+        .and_then(|text| parse_script(&text))?;
+    let rom = std::fs::read(rom_path).map_err(|e| format!("{}: ROM not read.", e))?;
+    let mut gb = Gameboy::power_on(rom.into_boxed_slice(), None)
+        .map_err(|e| format!("{}: ROM not loaded.", e))?;
+    run_script(&mut gb, &script, &mut std::io::stdout().lock())
+}
+
+#[cfg(test)]
+mod headless_tests {
+    use super::*;
+    use crate::test_util::test_rom;
+
+    #[test]
+    fn parse() {
+        let script = parse_script(
+            "# Get past the title screen\n\
+             run 60\n\
+             press Start a  # both at once\n\
+             \n\
+             dump C000 c00f out.bin\n\
+             expect $FF80 0a\n\
+             exit 3",
+        )
+        .unwrap();
+        assert_eq!(
+            script,
+            [
+                Command::Run(60),
+                Command::Press(vec![GbKeys::Start, GbKeys::A]),
+                Command::Dump {
+                    start: 0xC000,
+                    end: 0xC00F,
+                    path: Some(PathBuf::from("out.bin")),
+                },
+                Command::Expect {
+                    addr: 0xFF80,
+                    value: 0x0A
+                },
+                Command::Exit(3),
+            ]
+        );
+
+        assert_eq!(
+            parse_script("run 1\npress x"),
+            Err("Line 2: Unknown button \"x\"".to_string())
+        );
+        assert_eq!(
+            parse_script("run"),
+            Err("Line 1: Wrong arguments for \"run\"".to_string())
+        );
+        assert_eq!(
+            parse_script("jump 100"),
+            Err("Line 1: Unknown command \"jump\"".to_string())
+        );
+    }
+
+    #[test]
+    fn run() {
+        // LD A, $5A; LD ($C001), A; JR -2
+        let program = [0x3E, 0x5A, 0xEA, 0x01, 0xC0, 0x18, 0xFE];
+        let mut gb = Gameboy::power_on(test_rom(&program), None).unwrap();
+
+        let script = parse_script("run 1\ndump C000 C011\nexpect C001 5A").unwrap();
+        let mut out = vec![];
+        assert_eq!(run_script(&mut gb, &script, &mut out), Ok(0));
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("C000: 00 5A 00"));
+        assert_eq!(out.lines().nth(1), Some("C010: 00 00"));
+
+        let script = parse_script("expect C001 00\nexit 3").unwrap();
+        let mut out = vec![];
+        assert_eq!(run_script(&mut gb, &script, &mut out), Ok(1));
+        assert_eq!(out, b"Expected 00 at C001, found 5A\n");
+        let script = parse_script("press start\nexit 3").unwrap();
+        assert_eq!(run_script(&mut gb, &script, &mut vec![]), Ok(3));
+    }
+}
@@ -0,0 +1,448 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use egui::Key;
+use gabe_core::gb::GbKeys;
+use log::*;
+
+/// Location of the key bindings file, relative to the working directory
+pub const KEYMAP_PATH: &str = "gabe_keys.toml";
+
+/// Index of turbo A in the bindings, after the buttons indexed by `GbKeys`
+pub const TURBO_A: usize = 8;
+/// Index of turbo B in the bindings
+pub const TURBO_B: usize = 9;
+
+/// Everything keys and gamepad buttons can be bound to, as (name in the keymap file,
+/// label, index). The buttons are indexed by `GbKeys`, followed by the turbo buttons,
+/// which press A or B over and over while held.
+pub const BINDINGS: [(&str, &str, usize); 10] = [
+    ("a", "A", GbKeys::A as usize),
+    ("b", "B", GbKeys::B as usize),
+    ("start", "Start", GbKeys::Start as usize),
+    ("select", "Select", GbKeys::Select as usize),
+    ("up", "Up", GbKeys::Up as usize),
+    ("down", "Down", GbKeys::Down as usize),
+    ("left", "Left", GbKeys::Left as usize),
+    ("right", "Right", GbKeys::Right as usize),
+    ("turbo_a", "Turbo A", TURBO_A),
+    ("turbo_b", "Turbo B", TURBO_B),
+];
+
+/// How far a stick is pushed, from 0.0 to 1.0, before it presses a direction by default
+pub const DEFAULT_DEAD_ZONE: f32 = 0.5;
+
+/// Gamepad buttons, named by where they sit on the pad like gilrs does, so South is the
+/// bottom face button whatever the pad labels it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftTrigger,
+    LeftTrigger2,
+    RightTrigger,
+    RightTrigger2,
+    Select,
+    Start,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+impl PadButton {
+    pub const ALL: [PadButton; 14] = [
+        PadButton::South,
+        PadButton::East,
+        PadButton::North,
+        PadButton::West,
+        PadButton::LeftTrigger,
+        PadButton::LeftTrigger2,
+        PadButton::RightTrigger,
+        PadButton::RightTrigger2,
+        PadButton::Select,
+        PadButton::Start,
+        PadButton::DPadUp,
+        PadButton::DPadDown,
+        PadButton::DPadLeft,
+        PadButton::DPadRight,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PadButton::South => "South",
+            PadButton::East => "East",
+            PadButton::North => "North",
+            PadButton::West => "West",
+            PadButton::LeftTrigger => "LeftTrigger",
+            PadButton::LeftTrigger2 => "LeftTrigger2",
+            PadButton::RightTrigger => "RightTrigger",
+            PadButton::RightTrigger2 => "RightTrigger2",
+            PadButton::Select => "Select",
+            PadButton::Start => "Start",
+            PadButton::DPadUp => "DPadUp",
+            PadButton::DPadDown => "DPadDown",
+            PadButton::DPadLeft => "DPadLeft",
+            PadButton::DPadRight => "DPadRight",
+        }
+    }
+
+    /// The button called `name`, ignoring case
+    pub fn from_name(name: &str) -> Option<Self> {
+        PadButton::ALL
+            .into_iter()
+            .find(|button| button.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Gamepad buttons bound to each button, and how the left stick acts as the D-pad, for
+/// one model of controller or as the default for any other
+#[derive(Debug, Clone, PartialEq)]
+pub struct GamepadProfile {
+    /// Gamepad buttons for each binding, indexed like `BINDINGS`
+    bindings: [Vec<PadButton>; 10],
+    /// How far the left stick is pushed, from 0.0 to 1.0, before it presses a direction
+    pub dead_zone: f32,
+}
+
+impl Default for GamepadProfile {
+    fn default() -> Self {
+        // A and B sit side by side on the right, like the Game Boy's
+        let bindings = [
+            (GbKeys::A as usize, PadButton::East),
+            (GbKeys::B as usize, PadButton::South),
+            (GbKeys::Start as usize, PadButton::Start),
+            (GbKeys::Select as usize, PadButton::Select),
+            (GbKeys::Up as usize, PadButton::DPadUp),
+            (GbKeys::Down as usize, PadButton::DPadDown),
+            (GbKeys::Left as usize, PadButton::DPadLeft),
+            (GbKeys::Right as usize, PadButton::DPadRight),
+            (TURBO_A, PadButton::North),
+            (TURBO_B, PadButton::West),
+        ];
+        let mut profile = GamepadProfile {
+            bindings: Default::default(),
+            dead_zone: DEFAULT_DEAD_ZONE,
+        };
+        for (binding, pad_button) in bindings {
+            profile.bindings[binding].push(pad_button);
+        }
+        profile
+    }
+}
+
+impl GamepadProfile {
+    /// The bindings held, as a mask indexed like `BINDINGS`, given whether each gamepad
+    /// button is down and the left stick's position, with up and right positive
+    #[cfg_attr(not(feature = "gamepad"), allow(dead_code))]
+    pub fn held(&self, pressed: impl Fn(PadButton) -> bool, stick: (f32, f32)) -> u16 {
+        let (x, y) = stick;
+        let mut held = held_mask(&self.bindings, pressed);
+        for (direction, pushed) in [
+            (GbKeys::Right, x > self.dead_zone),
+            (GbKeys::Left, x < -self.dead_zone),
+            (GbKeys::Up, y > self.dead_zone),
+            (GbKeys::Down, y < -self.dead_zone),
+        ] {
+            if pushed {
+                held |= 1 << direction as u8;
+            }
+        }
+        held
+    }
+
+    fn serialize(&self) -> String {
+        let mut text = serialize_bindings(&self.bindings, PadButton::name);
+        text.push_str(&format!("dead_zone = {}\n", self.dead_zone));
+        text
+    }
+}
+
+/// Keyboard keys and gamepad buttons bound to each button, where any of a button's keys
+/// holds it down.
+///
+/// Stored as `name = ["Key", ...]` lines, named as in `BINDINGS` and using egui's key
+/// names, followed by a `[gamepad]` section of lines like these for gamepads, using
+/// `PadButton` names, and a `[gamepad."Name"]` section for each controller with its own
+/// profile. Buttons left out keep their default keys, and unknown buttons and keys are
+/// ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputConfig {
+    /// Keys for each binding, indexed like `BINDINGS`
+    bindings: [Vec<Key>; 10],
+    /// Profile for gamepads without one of their own
+    pub gamepad: GamepadProfile,
+    /// Profiles for gamepads by the name they report
+    pub gamepads: BTreeMap<String, GamepadProfile>,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        let mut config = InputConfig {
+            bindings: Default::default(),
+            gamepad: GamepadProfile::default(),
+            gamepads: BTreeMap::new(),
+        };
+        for (binding, key) in [
+            (GbKeys::A as usize, Key::X),
+            (GbKeys::B as usize, Key::Z),
+            (GbKeys::Start as usize, Key::Enter),
+            (GbKeys::Select as usize, Key::Backspace),
+            (GbKeys::Up as usize, Key::ArrowUp),
+            (GbKeys::Down as usize, Key::ArrowDown),
+            (GbKeys::Left as usize, Key::ArrowLeft),
+            (GbKeys::Right as usize, Key::ArrowRight),
+            // Above the keys for A and B
+            (TURBO_A, Key::S),
+            (TURBO_B, Key::A),
+        ] {
+            config.bind(binding, key);
+        }
+        config
+    }
+}
+
+impl InputConfig {
+    /// Loads key bindings from `path`, falling back to the defaults if it can't be read
+    pub fn load(path: &Path) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(text) => InputConfig::default().merge(&text),
+            Err(e) => {
+                info!("{}: Using default key bindings.", e);
+                InputConfig::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, self.serialize())
+    }
+
+    /// Returns these bindings with the buttons given in `text` bound to the keys given
+    /// for them instead
+    pub fn merge(&self, text: &str) -> Self {
+        let mut config = self.clone();
+        let mut section = Section::Keys;
+        for line in text.lines() {
+            let line = line.trim();
+            if let Some(header) = line.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+                section = match header.trim().split_once('.') {
+                    None if header.trim() == "gamepad" => Section::Gamepad(None),
+                    Some(("gamepad", name)) => {
+                        let name = name.trim().trim_matches('"').to_string();
+                        config.gamepads.entry(name.clone()).or_default();
+                        Section::Gamepad(Some(name))
+                    }
+                    _ => {
+                        warn!("Unknown section \"{}\", ignoring.", header);
+                        Section::Unknown
+                    }
+                };
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=') else {
+                continue;
+            };
+            let name = name.trim();
+            let profile = match &section {
+                Section::Keys => {
+                    parse_bindings(&mut config.bindings, name, value, Key::from_name);
+                    continue;
+                }
+                Section::Gamepad(None) => &mut config.gamepad,
+                Section::Gamepad(Some(pad)) => config.gamepads.get_mut(pad).unwrap(),
+                Section::Unknown => continue,
+            };
+            if name == "dead_zone" {
+                match value.trim().parse() {
+                    Ok(dead_zone @ 0.0..=1.0) => profile.dead_zone = dead_zone,
+                    _ => warn!("Invalid dead_zone \"{}\", ignoring.", value.trim()),
+                }
+            } else {
+                parse_bindings(&mut profile.bindings, name, value, PadButton::from_name);
+            }
+        }
+        config
+    }
+
+    fn serialize(&self) -> String {
+        let mut text = serialize_bindings(&self.bindings, Key::name);
+        text.push_str("\n[gamepad]\n");
+        text.push_str(&self.gamepad.serialize());
+        for (name, profile) in &self.gamepads {
+            text.push_str(&format!("\n[gamepad.\"{}\"]\n", name));
+            text.push_str(&profile.serialize());
+        }
+        text
+    }
+
+    /// The keys bound to `binding`, an index into `BINDINGS`
+    pub fn keys(&self, binding: usize) -> &[Key] {
+        &self.bindings[binding]
+    }
+
+    /// Adds `key` to the keys for `binding`, alongside any it already has
+    pub fn bind(&mut self, binding: usize, key: Key) {
+        let keys = &mut self.bindings[binding];
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    pub fn unbind(&mut self, binding: usize, key: Key) {
+        self.bindings[binding].retain(|&k| k != key);
+    }
+
+    /// Goes back to the default keys, leaving the gamepad profiles alone
+    pub fn reset_keys(&mut self) {
+        self.bindings = InputConfig::default().bindings;
+    }
+
+    /// The bindings held, as a mask indexed like `BINDINGS`, given whether each key is down
+    pub fn held(&self, key_down: impl Fn(Key) -> bool) -> u16 {
+        held_mask(&self.bindings, key_down)
+    }
+
+    /// The profile for the gamepad called `name`, or the default one if it has none
+    #[cfg_attr(not(feature = "gamepad"), allow(dead_code))]
+    pub fn gamepad_profile(&self, name: &str) -> &GamepadProfile {
+        self.gamepads.get(name).unwrap_or(&self.gamepad)
+    }
+}
+
+/// Part of the keymap file being read
+enum Section {
+    /// The keyboard bindings at the start
+    Keys,
+    /// The default gamepad profile, or the one for the gamepad named
+    Gamepad(Option<String>),
+    Unknown,
+}
+
+/// The bindings with any of their inputs down, as a mask indexed like `BINDINGS`
+fn held_mask<T: Copy>(bindings: &[Vec<T>; 10], down: impl Fn(T) -> bool) -> u16 {
+    (0..bindings.len())
+        .filter(|&binding| bindings[binding].iter().any(|&input| down(input)))
+        .fold(0, |held, binding| held | 1 << binding)
+}
+
+/// Binds the binding called `name` to the list of inputs in `value`, like `["X", "K"]`
+fn parse_bindings<T: Copy + PartialEq>(
+    bindings: &mut [Vec<T>; 10],
+    name: &str,
+    value: &str,
+    from_name: impl Fn(&str) -> Option<T>,
+) {
+    let Some(&(_, _, binding)) = BINDINGS.iter().find(|(n, _, _)| *n == name) else {
+        warn!("Unknown button \"{}\", ignoring.", name);
+        return;
+    };
+    let mut inputs = vec![];
+    let value = value.trim().trim_start_matches('[').trim_end_matches(']');
+    for input in value.split(',').map(|input| input.trim().trim_matches('"')) {
+        match from_name(input) {
+            Some(input) if !inputs.contains(&input) => inputs.push(input),
+            Some(_) => {}
+            None if input.is_empty() => {}
+            None => warn!("Invalid input \"{}\" for {}, ignoring.", input, name),
+        }
+    }
+    bindings[binding] = inputs;
+}
+
+fn serialize_bindings<T: Copy>(
+    bindings: &[Vec<T>; 10],
+    name: impl Fn(T) -> &'static str,
+) -> String {
+    BINDINGS
+        .iter()
+        .map(|&(binding_name, _, binding)| {
+            let inputs: Vec<_> = bindings[binding]
+                .iter()
+                .map(|&input| format!("\"{}\"", name(input)))
+                .collect();
+            format!("{} = [{}]\n", binding_name, inputs.join(", "))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod input_config_tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut config = InputConfig::default();
+        config.bind(GbKeys::A as usize, Key::K);
+        config.bind(GbKeys::A as usize, Key::K);
+        config.unbind(GbKeys::Select as usize, Key::Backspace);
+        assert_eq!(config.keys(GbKeys::A as usize), [Key::X, Key::K]);
+        assert!(config.serialize().contains("a = [\"X\", \"K\"]\n"));
+        assert!(config.serialize().contains("select = []\n"));
+        config.gamepad.dead_zone = 0.25;
+        config.gamepads.insert(
+            "Pro Controller".to_string(),
+            GamepadProfile {
+                dead_zone: 0.75,
+                ..Default::default()
+            },
+        );
+        assert_eq!(InputConfig::default().merge(&config.serialize()), config);
+        assert_eq!(InputConfig::default().merge(""), InputConfig::default());
+    }
+
+    #[test]
+    fn merge() {
+        let config = InputConfig::default()
+            .merge("b = [\"J\", \"Nope\", \"J\"]\nturbo = [\"T\"]\nstart = \"Space\"");
+        assert_eq!(config.keys(GbKeys::B as usize), [Key::J]);
+        assert_eq!(config.keys(GbKeys::Start as usize), [Key::Space]);
+        assert_eq!(config.keys(GbKeys::A as usize), [Key::X]);
+        assert_eq!(config.keys(TURBO_A), [Key::S]);
+
+        let held = config.held(|key| key == Key::J || key == Key::ArrowUp);
+        assert_eq!(held, 1 << GbKeys::B as u8 | 1 << GbKeys::Up as u8);
+        assert_eq!(config.held(|key| key == Key::Z), 0);
+        assert_eq!(
+            config.merge("turbo_b = [\"T\"]").held(|key| key == Key::T),
+            1 << TURBO_B
+        );
+    }
+
+    #[test]
+    fn gamepad_profiles() {
+        let config = InputConfig::default().merge(
+            "a = [\"K\"]\n\n[gamepad]\na = [\"south\"]\ndead_zone = 2\n\n\
+             [gamepad.\"Pro Controller\"]\na = [\"East\", \"RightTrigger\"]\ndead_zone = 0.2\n",
+        );
+        assert_eq!(config.keys(GbKeys::A as usize), [Key::K]);
+        assert_eq!(
+            config.gamepad.bindings[GbKeys::A as usize],
+            [PadButton::South]
+        );
+        assert_eq!(config.gamepad.dead_zone, DEFAULT_DEAD_ZONE);
+        let pro = config.gamepad_profile("Pro Controller");
+        assert_eq!(
+            pro.bindings[GbKeys::A as usize],
+            [PadButton::East, PadButton::RightTrigger]
+        );
+        assert_eq!(pro.bindings[GbKeys::B as usize], [PadButton::South]);
+        assert_eq!(config.gamepad_profile("Other Pad"), &config.gamepad);
+        let skipped = config.merge("[turbo]\na = [\"J\"]");
+        assert_eq!(skipped, config);
+
+        let a = 1 << GbKeys::A as u8;
+        let up = 1 << GbKeys::Up as u8;
+        let left = 1 << GbKeys::Left as u8;
+        assert_eq!(pro.held(|b| b == PadButton::RightTrigger, (0.0, 0.0)), a);
+        assert_eq!(
+            config.gamepad.held(|b| b == PadButton::North, (0.0, 0.0)),
+            1 << TURBO_A
+        );
+        assert_eq!(pro.held(|b| b == PadButton::DPadUp, (0.0, 0.0)), up);
+        assert_eq!(pro.held(|_| false, (-0.5, 0.3)), up | left);
+        assert_eq!(config.gamepad.held(|_| false, (-0.4, 0.3)), 0);
+    }
+}
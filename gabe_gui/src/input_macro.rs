@@ -0,0 +1,123 @@
+/// A short recorded button sequence, for repeating menu inputs or grinding. Each step is a
+/// `(delta, keys)` pair, where `keys` is a mask of buttons indexed by `GbKeys` that starts
+/// being held `delta` frames after the previous step started. The last step marks the end
+/// of the macro, its keys are never held.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputMacro {
+    pub steps: Vec<(u32, u8)>,
+}
+
+impl InputMacro {
+    pub fn is_empty(&self) -> bool {
+        self.steps.len() < 2
+    }
+}
+
+/// Builds an `InputMacro` from the keys held each frame
+#[derive(Debug, Default)]
+pub struct MacroRecorder {
+    steps: Vec<(u32, u8)>,
+    /// Frames since the last step started
+    frames: u32,
+}
+
+impl MacroRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a frame with `keys` held, starting a new step if they changed
+    pub fn record_frame(&mut self, keys: u8) {
+        if self.steps.last().map(|(_, last)| *last) != Some(keys) {
+            self.steps.push((self.frames, keys));
+            self.frames = 0;
+        }
+        self.frames += 1;
+    }
+
+    /// Ends the macro after the last recorded frame
+    pub fn finish(mut self) -> InputMacro {
+        if !self.steps.is_empty() {
+            self.steps.push((self.frames, 0));
+        }
+        InputMacro { steps: self.steps }
+    }
+}
+
+/// Plays back an `InputMacro` a frame at a time
+#[derive(Debug)]
+pub struct MacroPlayer {
+    steps: Vec<(u32, u8)>,
+    /// Index of the next step to start
+    index: usize,
+    /// Frames until the next step starts
+    wait: u32,
+    keys: u8,
+}
+
+impl MacroPlayer {
+    pub fn new(input_macro: &InputMacro) -> Self {
+        MacroPlayer {
+            steps: input_macro.steps.clone(),
+            index: 0,
+            wait: input_macro.steps.first().map_or(0, |(delta, _)| *delta),
+            keys: 0,
+        }
+    }
+
+    /// Keys to hold for the next frame, or `None` once the macro has finished
+    pub fn next_frame(&mut self) -> Option<u8> {
+        while self.wait == 0 {
+            let (_, keys) = *self.steps.get(self.index)?;
+            self.keys = keys;
+            self.index += 1;
+            // The last step only marks the end
+            let (delta, _) = *self.steps.get(self.index)?;
+            self.wait = delta;
+        }
+        self.wait -= 1;
+        Some(self.keys)
+    }
+}
+
+#[cfg(test)]
+mod input_macro_tests {
+    use super::*;
+    use gabe_core::gb::GbKeys;
+
+    const A: u8 = 1 << GbKeys::A as u8;
+    const RIGHT: u8 = 1 << GbKeys::Right as u8;
+
+    #[test]
+    fn playback_schedule() {
+        let input_macro = InputMacro {
+            steps: vec![(2, A), (3, A | RIGHT), (2, 0), (4, 0)],
+        };
+        let mut player = MacroPlayer::new(&input_macro);
+        let frames: Vec<u8> = std::iter::from_fn(|| player.next_frame()).collect();
+        assert_eq!(frames, [0, 0, A, A, A, A | RIGHT, A | RIGHT, 0, 0, 0, 0]);
+        assert_eq!(player.next_frame(), None);
+
+        assert_eq!(MacroPlayer::new(&InputMacro::default()).next_frame(), None);
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let held = [0, A, A, A, RIGHT, RIGHT, A | RIGHT, 0, 0];
+        let mut recorder = MacroRecorder::new();
+        for keys in held {
+            recorder.record_frame(keys);
+        }
+        let input_macro = recorder.finish();
+        assert_eq!(
+            input_macro.steps,
+            [(0, 0), (1, A), (3, RIGHT), (2, A | RIGHT), (1, 0), (2, 0)]
+        );
+
+        let mut player = MacroPlayer::new(&input_macro);
+        let replayed: Vec<u8> = std::iter::from_fn(|| player.next_frame()).collect();
+        assert_eq!(replayed, held);
+
+        assert!(MacroRecorder::new().finish().is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+use gabe_core::gb::GbKeys;
+
+/// A button drawn in the input overlay
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OverlayButton {
+    pub key: GbKeys,
+    pub label: &'static str,
+    /// Center of the button, in units of button widths from the overlay's top left
+    pub pos: (f32, f32),
+    /// Whether the button is currently held down
+    pub lit: bool,
+}
+
+/// Size of the overlay diagram, in units of button widths
+pub const OVERLAY_SIZE: (f32, f32) = (8.0, 3.0);
+
+/// Layout of the buttons, roughly matching the DMG's face
+const LAYOUT: [(GbKeys, &str, (f32, f32)); 8] = [
+    (GbKeys::Up, "^", (1.0, 0.5)),
+    (GbKeys::Left, "<", (0.5, 1.5)),
+    (GbKeys::Right, ">", (1.5, 1.5)),
+    (GbKeys::Down, "v", (1.0, 2.5)),
+    (GbKeys::Select, "Sel", (3.25, 2.5)),
+    (GbKeys::Start, "Sta", (4.75, 2.5)),
+    (GbKeys::B, "B", (6.5, 1.5)),
+    (GbKeys::A, "A", (7.5, 1.0)),
+];
+
+/// Maps the pressed keys, as returned by `Gameboy::pressed_keys`, to the buttons of the overlay
+pub fn overlay_buttons(pressed: u8) -> [OverlayButton; 8] {
+    LAYOUT.map(|(key, label, pos)| OverlayButton {
+        key,
+        label,
+        pos,
+        lit: pressed & (1 << key as u8) != 0,
+    })
+}
+
+#[cfg(test)]
+mod input_overlay_tests {
+    use super::*;
+
+    #[test]
+    fn lit_buttons() {
+        let lit = |pressed| {
+            overlay_buttons(pressed)
+                .iter()
+                .filter(|b| b.lit)
+                .map(|b| b.key)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(lit(0), []);
+        assert_eq!(lit(0xFF).len(), 8);
+        assert_eq!(
+            lit((1 << GbKeys::A as u8) | (1 << GbKeys::Left as u8)),
+            [GbKeys::Left, GbKeys::A]
+        );
+        assert_eq!(
+            lit((1 << GbKeys::Start as u8) | (1 << GbKeys::Down as u8)),
+            [GbKeys::Down, GbKeys::Start]
+        );
+        for button in overlay_buttons(0) {
+            assert!(button.pos.0 < OVERLAY_SIZE.0 && button.pos.1 < OVERLAY_SIZE.1);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use gabe_core::gb::GbKeys;
+
+use crate::fast_forward::FRAME_TIME;
+use crate::input_config::{TURBO_A, TURBO_B};
+use crate::input_macro::{InputMacro, MacroPlayer, MacroRecorder};
+
+/// Presses per second turbo buttons fire at by default
+pub const DEFAULT_TURBO_RATE: u32 = 10;
+
+/// Fastest turbo rate, pressing and letting go on alternate frames
+pub const MAX_TURBO_RATE: u32 = 30;
+
+/// Turns the inputs held on the keyboard and gamepads into the buttons the game sees,
+/// firing turbo buttons and recording and playing back macros. It moves on a frame at a
+/// time, like the game, so turbo presses and macros line up with frames.
+#[derive(Debug)]
+pub struct InputPipeline {
+    /// Presses per second turbo buttons fire at
+    pub turbo_rate: u32,
+    /// Frames turbo buttons have been held for, so the first press lands straight away
+    turbo_frames: u32,
+    /// The macro being recorded, while recording
+    recorder: Option<MacroRecorder>,
+    /// The macro last recorded, for playing back
+    pub input_macro: InputMacro,
+    player: Option<MacroPlayer>,
+    /// Whether the macro being played starts over when it ends, while its key is held
+    looping: bool,
+    /// Buttons held by the macro being played, for the current frame
+    macro_keys: u8,
+}
+
+impl Default for InputPipeline {
+    fn default() -> Self {
+        InputPipeline {
+            turbo_rate: DEFAULT_TURBO_RATE,
+            turbo_frames: 0,
+            recorder: None,
+            input_macro: InputMacro::default(),
+            player: None,
+            looping: false,
+            macro_keys: 0,
+        }
+    }
+}
+
+impl InputPipeline {
+    /// The buttons for the game to see during the current frame with the inputs in `held`
+    /// held, a mask indexed like `BINDINGS`, as a mask indexed by `GbKeys`
+    pub fn keys(&self, held: u16) -> u8 {
+        let mut keys = held as u8 | self.macro_keys;
+        if self.turbo_pressed() {
+            keys |= turbo_buttons(held);
+        }
+        keys
+    }
+
+    /// Moves on to the next frame, with the inputs in `held` held through the frame just
+    /// finished
+    pub fn next_frame(&mut self, held: u16) {
+        // Macros are recorded from what the player pressed, turbo included
+        let keys = self.keys(held);
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record_frame(keys);
+        }
+        if !turbo_held(held) {
+            self.turbo_frames = 0;
+        } else {
+            self.turbo_frames += 1;
+        }
+        self.macro_keys = match &mut self.player {
+            Some(player) => match player.next_frame() {
+                Some(keys) => keys,
+                None if self.looping => {
+                    let mut player = MacroPlayer::new(&self.input_macro);
+                    let keys = player.next_frame().unwrap_or(0);
+                    self.player = Some(player);
+                    keys
+                }
+                None => {
+                    self.player = None;
+                    0
+                }
+            },
+            None => 0,
+        };
+    }
+
+    /// Whether turbo buttons are pressed rather than let go for the current frame. Each
+    /// press lasts half a period at the turbo rate, going by the frames held so far.
+    fn turbo_pressed(&self) -> bool {
+        let elapsed = self.turbo_frames as u128 * FRAME_TIME.as_nanos();
+        let half_periods = elapsed * 2 * self.turbo_rate as u128 / 1_000_000_000;
+        half_periods & 1 == 0
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.player.is_some()
+    }
+
+    /// Starts recording a macro, or finishes recording and keeps it for playback.
+    /// Starting stops any macro being played, so it doesn't get recorded.
+    pub fn toggle_recording(&mut self) {
+        match self.recorder.take() {
+            Some(recorder) => self.input_macro = recorder.finish(),
+            None => {
+                self.stop_macro();
+                self.recorder = Some(MacroRecorder::new());
+            }
+        }
+    }
+
+    /// Plays the last recorded macro through once, starting with the current frame
+    pub fn play_macro(&mut self) {
+        if self.recorder.is_none() && !self.input_macro.is_empty() {
+            let mut player = MacroPlayer::new(&self.input_macro);
+            self.macro_keys = player.next_frame().unwrap_or(0);
+            self.player = Some(player);
+            self.looping = false;
+        }
+    }
+
+    /// Plays the last recorded macro over and over while `held`, stopping as soon as it's
+    /// let go. Called every update with whether the replay key is down.
+    pub fn hold_replay(&mut self, held: bool) {
+        if held && !self.looping {
+            self.play_macro();
+            self.looping = self.player.is_some();
+        } else if !held && self.looping {
+            self.stop_macro();
+        }
+    }
+
+    pub fn stop_macro(&mut self) {
+        self.player = None;
+        self.looping = false;
+        self.macro_keys = 0;
+    }
+
+    /// Stops recording and playing back, keeping the last recorded macro
+    pub fn stop(&mut self) {
+        self.recorder = None;
+        self.stop_macro();
+        self.turbo_frames = 0;
+    }
+}
+
+/// Whether any turbo inputs are held in `held`, a mask indexed like `BINDINGS`
+pub fn turbo_held(held: u16) -> bool {
+    turbo_buttons(held) != 0
+}
+
+/// The buttons turbo fires while the turbo inputs in `held` are held, as a mask indexed
+/// by `GbKeys`
+fn turbo_buttons(held: u16) -> u8 {
+    let mut buttons = 0;
+    if held & 1 << TURBO_A != 0 {
+        buttons |= 1 << GbKeys::A as u8;
+    }
+    if held & 1 << TURBO_B != 0 {
+        buttons |= 1 << GbKeys::B as u8;
+    }
+    buttons
+}
+
+#[cfg(test)]
+mod input_pipeline_tests {
+    use super::*;
+
+    const A: u8 = 1 << GbKeys::A as u8;
+    const B: u8 = 1 << GbKeys::B as u8;
+    const RIGHT: u8 = 1 << GbKeys::Right as u8;
+
+    /// The buttons seen on each of `frames` frames with `held` held
+    fn run(pipeline: &mut InputPipeline, held: u16, frames: usize) -> Vec<u8> {
+        (0..frames)
+            .map(|_| {
+                let keys = pipeline.keys(held);
+                pipeline.next_frame(held);
+                keys
+            })
+            .collect()
+    }
+
+    #[test]
+    fn turbo() {
+        let mut pipeline = InputPipeline {
+            turbo_rate: MAX_TURBO_RATE,
+            ..Default::default()
+        };
+        let turbo_a = 1 << TURBO_A | RIGHT as u16;
+        assert!(turbo_held(turbo_a));
+        assert!(!turbo_held(RIGHT as u16));
+        assert_eq!(
+            run(&mut pipeline, turbo_a, 4),
+            [A | RIGHT, RIGHT, A | RIGHT, RIGHT]
+        );
+        // Letting go and pressing again fires straight away
+        assert_eq!(run(&mut pipeline, 0, 1), [0]);
+        assert_eq!(run(&mut pipeline, 1 << TURBO_B, 1), [B]);
+
+        // At 10 Hz, pressed for 3 frames then let go for 3
+        let mut pipeline = InputPipeline::default();
+        let frames = run(&mut pipeline, 1 << TURBO_A, 12);
+        assert_eq!(frames, [A, A, A, 0, 0, 0, A, A, A, 0, 0, 0]);
+        // Holding the button itself as well holds it throughout
+        assert_eq!(run(&mut pipeline, 1 << TURBO_A | A as u16, 6), [A; 6]);
+    }
+
+    #[test]
+    fn macros() {
+        let mut pipeline = InputPipeline::default();
+        pipeline.toggle_recording();
+        assert!(pipeline.is_recording());
+        run(&mut pipeline, A as u16, 2);
+        run(&mut pipeline, RIGHT as u16 | 1 << TURBO_B, 1);
+        pipeline.toggle_recording();
+        assert!(!pipeline.is_recording());
+
+        pipeline.play_macro();
+        let played = run(&mut pipeline, 0, 5);
+        assert_eq!(played, [A, A, RIGHT | B, 0, 0]);
+        assert!(!pipeline.is_playing());
+
+        // Held, it starts over each time it ends until let go
+        pipeline.hold_replay(true);
+        let replayed = run(&mut pipeline, 0, 8);
+        assert_eq!(replayed, [A, A, RIGHT | B, A, A, RIGHT | B, A, A]);
+        pipeline.hold_replay(true);
+        assert!(pipeline.is_playing());
+        pipeline.hold_replay(false);
+        assert!(!pipeline.is_playing());
+        assert_eq!(pipeline.keys(0), 0);
+    }
+}
@@ -1,7 +1,41 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod app;
+mod apu_scope;
 mod audio_driver;
+mod auto_pause;
+mod disassemble;
+mod dither;
+mod fast_forward;
+mod file_storage;
+#[cfg(feature = "gamepad")]
+mod gamepad;
+mod headless;
+mod input_config;
+mod input_macro;
+mod input_overlay;
+mod input_pipeline;
+#[cfg(feature = "lua")]
+mod lua;
+mod memory_dump;
+mod memory_viewer;
+mod palette;
+mod screenshot;
+mod session;
+mod settings;
+mod tcp_link;
+#[cfg(test)]
+mod test_util;
+mod tilemap_dump;
 mod time_source;
 mod video_sinks;
+mod vram_viewer;
+mod window_scale;
 pub use app::GabeApp;
+pub use audio_driver::{parse_audio_latency, DEFAULT_AUDIO_LATENCY_MS};
+pub use disassemble::{disassemble_to_path, parse_data_region};
+pub use headless::{run_headless, SCRIPT_HELP};
+#[cfg(feature = "lua")]
+pub use lua::LuaScript;
+pub use tcp_link::TcpLink;
+pub use tilemap_dump::{dump_tilemap_to_path, parse_tilemap_args, TILEMAP_USAGE};
@@ -0,0 +1,347 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, VecDeque};
+use std::rc::Rc;
+
+use gabe_core::gb::{Gameboy, Hooks};
+use mlua::{Function, Lua, RegistryKey, Thread, ThreadStatus};
+
+use crate::headless::BUTTON_NAMES;
+
+/// The API scripts use, built on the `_host` functions which are only valid while the script
+/// is running and so are looked up on each call
+const PRELUDE: &str = r#"
+local callbacks = { frame = {}, read = {}, write = {} }
+
+memory = {}
+function memory.read(addr) return _host.read(addr) end
+function memory.write(addr, value) _host.write(addr, value) end
+
+joypad = {}
+-- Holds or lets go of each button in a table like { a = true, start = false }
+function joypad.set(buttons)
+  for name, pressed in pairs(buttons) do _host.setbutton(name, pressed) end
+end
+function joypad.get() return _host.getbuttons() end
+
+emu = {}
+emu.frameadvance = coroutine.yield
+function emu.framecount() return _host.framecount() end
+
+event = {}
+function event.onframeend(fn) table.insert(callbacks.frame, fn) end
+local function on_memory(kind, fn, addr)
+  callbacks[kind][addr] = callbacks[kind][addr] or {}
+  table.insert(callbacks[kind][addr], fn)
+  _watch(kind, addr)
+end
+function event.onmemoryread(fn, addr) on_memory("read", fn, addr) end
+function event.onmemorywrite(fn, addr) on_memory("write", fn, addr) end
+
+function _dispatch(kind, addr, value)
+  local fns = kind == "frame" and callbacks.frame or callbacks[kind][addr] or {}
+  for _, fn in ipairs(fns) do fn(addr, value) end
+end
+"#;
+
+/// Something a script asked to be told about, in the order it happened
+enum Event {
+    Read(u16, u8),
+    Write(u16, u8),
+    Frame,
+}
+
+#[derive(Default)]
+struct HookState {
+    /// Addresses with `event.onmemoryread` callbacks
+    reads: BTreeSet<u16>,
+    /// Addresses with `event.onmemorywrite` callbacks
+    writes: BTreeSet<u16>,
+    events: VecDeque<Event>,
+}
+
+/// Records the events a script watches for as the game runs, to be handled after the step
+struct ScriptHooks(Rc<RefCell<HookState>>);
+
+impl Hooks for ScriptHooks {
+    fn on_read(&mut self, addr: u16, value: u8) {
+        let mut state = self.0.borrow_mut();
+        if state.reads.contains(&addr) {
+            state.events.push_back(Event::Read(addr, value));
+        }
+    }
+
+    fn on_write(&mut self, addr: u16, value: u8) {
+        let mut state = self.0.borrow_mut();
+        if state.writes.contains(&addr) {
+            state.events.push_back(Event::Write(addr, value));
+        }
+    }
+
+    fn on_frame(&mut self) {
+        self.0.borrow_mut().events.push_back(Event::Frame);
+    }
+}
+
+/// A Lua script driving the game, with the `lua` feature. The script's body runs as a
+/// coroutine a frame at a time, pausing at each `emu.frameadvance()`, and can register
+/// callbacks with `event.onframeend`, `event.onmemoryread` and `event.onmemorywrite`.
+/// It reads and writes memory with `memory.read` and `memory.write`, and holds buttons with
+/// `joypad.set` on top of those the player holds, see `held_buttons`.
+pub struct LuaScript {
+    lua: Lua,
+    /// The script's body, until it finishes
+    main: Option<RegistryKey>,
+    state: Rc<RefCell<HookState>>,
+    /// Frames run since the script was loaded
+    frames: u64,
+    /// Buttons held with `joypad.set`, one bit per button indexed by `GbKeys`
+    buttons: Cell<u8>,
+}
+
+impl LuaScript {
+    /// Loads a script into `gb` and runs its body up to the first `emu.frameadvance()`.
+    /// `name` identifies the script in error messages. The script's hooks are only added to
+    /// `gb` once it loads, so a failed load leaves `gb` as it was.
+    pub fn load(source: &str, name: &str, gb: &mut Gameboy) -> mlua::Result<Self> {
+        let lua = Lua::new();
+        let state = Rc::new(RefCell::new(HookState::default()));
+        let main = {
+            let watch_state = state.clone();
+            let watch = lua.create_function(move |_, (kind, addr): (String, u16)| {
+                let mut state = watch_state.borrow_mut();
+                match kind.as_str() {
+                    "read" => state.reads.insert(addr),
+                    _ => state.writes.insert(addr),
+                };
+                Ok(())
+            })?;
+            lua.globals().set("_watch", watch)?;
+            lua.load(PRELUDE).set_name("prelude").exec()?;
+            let body = lua.load(source).set_name(name).into_function()?;
+            let thread = lua.create_thread(body)?;
+            lua.create_registry_value(thread)?
+        };
+        let hooks = ScriptHooks(state.clone());
+        let mut script = LuaScript {
+            lua,
+            main: Some(main),
+            state,
+            frames: 0,
+            buttons: Cell::new(0),
+        };
+        script.resume(gb)?;
+        gb.set_hooks(Some(Box::new(hooks)));
+        Ok(script)
+    }
+
+    /// Buttons the script is holding, one bit per button indexed by `GbKeys`. Hosts set
+    /// the buttons every step, so the script's are combined with the player's there.
+    pub fn held_buttons(&self) -> u8 {
+        self.buttons.get()
+    }
+
+    /// Removes the script's hooks from `gb`
+    pub fn unload(self, gb: &mut Gameboy) {
+        gb.set_hooks(None);
+    }
+
+    /// Handles what happened during the last step, in order, calling the script's callbacks
+    /// and running its body on to the next frame at the end of each frame
+    pub fn after_step(&mut self, gb: &mut Gameboy) -> mlua::Result<()> {
+        loop {
+            let event = self.state.borrow_mut().events.pop_front();
+            match event {
+                None => return Ok(()),
+                Some(Event::Read(addr, value)) => self.dispatch(gb, "read", addr, value)?,
+                Some(Event::Write(addr, value)) => self.dispatch(gb, "write", addr, value)?,
+                Some(Event::Frame) => {
+                    self.frames += 1;
+                    self.dispatch(gb, "frame", 0, 0)?;
+                    self.resume(gb)?;
+                }
+            }
+        }
+    }
+
+    /// Calls the callbacks registered for an event
+    fn dispatch(&self, gb: &mut Gameboy, kind: &str, addr: u16, value: u8) -> mlua::Result<()> {
+        self.with_host(gb, |lua| {
+            let dispatch: Function<'_> = lua.globals().get("_dispatch")?;
+            dispatch.call((kind, addr, value))
+        })
+    }
+
+    /// Runs the script's body until it next calls `emu.frameadvance()` or finishes
+    fn resume(&mut self, gb: &mut Gameboy) -> mlua::Result<()> {
+        let Some(main) = &self.main else {
+            return Ok(());
+        };
+        let finished = self.with_host(gb, |lua| {
+            let thread: Thread<'_> = lua.registry_value(main)?;
+            thread.resume::<_, mlua::MultiValue<'_>>(())?;
+            Ok(thread.status() != ThreadStatus::Resumable)
+        })?;
+        if finished {
+            if let Some(main) = self.main.take() {
+                self.lua.remove_registry_value(main)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f` with the `_host` functions set up to work on `gb`
+    fn with_host<R>(
+        &self,
+        gb: &mut Gameboy,
+        f: impl FnOnce(&Lua) -> mlua::Result<R>,
+    ) -> mlua::Result<R> {
+        let gb = RefCell::new(gb);
+        let frames = self.frames;
+        self.lua.scope(|scope| {
+            let host = self.lua.create_table()?;
+            host.set(
+                "read",
+                scope.create_function(|_, addr: u16| {
+                    Ok(gb
+                        .borrow()
+                        .get_memory_range(addr as usize..addr as usize + 1)[0])
+                })?,
+            )?;
+            host.set(
+                "write",
+                scope.create_function(|_, (addr, value): (u16, u8)| {
+                    gb.borrow_mut().write_memory(addr, value);
+                    Ok(())
+                })?,
+            )?;
+            host.set(
+                "setbutton",
+                scope.create_function(|_, (name, pressed): (String, bool)| {
+                    let (_, key) = BUTTON_NAMES
+                        .iter()
+                        .find(|(button, _)| name.eq_ignore_ascii_case(button))
+                        .ok_or_else(|| {
+                            mlua::Error::RuntimeError(format!("Unknown button \"{}\"", name))
+                        })?;
+                    let bit = 1 << *key as u8;
+                    let buttons = self.buttons.get();
+                    self.buttons.set(if pressed {
+                        buttons | bit
+                    } else {
+                        buttons & !bit
+                    });
+                    Ok(())
+                })?,
+            )?;
+            host.set(
+                "getbuttons",
+                scope.create_function(|lua, ()| {
+                    let pressed = gb.borrow().pressed_keys();
+                    let buttons = lua.create_table()?;
+                    for (name, key) in BUTTON_NAMES {
+                        buttons.set(name, pressed & (1 << key as u8) != 0)?;
+                    }
+                    Ok(buttons)
+                })?,
+            )?;
+            host.set(
+                "framecount",
+                scope.create_function(move |_, ()| Ok(frames))?,
+            )?;
+            self.lua.globals().set("_host", host)?;
+            f(&self.lua)
+        })
+    }
+}
+
+#[cfg(test)]
+mod lua_tests {
+    use super::*;
+    use crate::test_util::test_rom;
+    use gabe_core::gb::GbKeys;
+    use gabe_core::sink::NullSink;
+
+    /// Steps `gb` with the script until the script has seen the end of a frame
+    fn run_frame(script: &mut LuaScript, gb: &mut Gameboy) {
+        let frames = script.frames;
+        while script.frames == frames {
+            gb.step(&mut NullSink, &mut NullSink);
+            script.after_step(gb).unwrap();
+        }
+    }
+
+    fn read(gb: &Gameboy, addr: u16) -> u8 {
+        gb.get_memory_range(addr as usize..addr as usize + 1)[0]
+    }
+
+    #[test]
+    fn frame_advance() {
+        // JR -2
+        let mut gb = Gameboy::power_on(test_rom(&[0x18, 0xFE]), None).unwrap();
+        let source = r#"
+            ends = 0
+            event.onframeend(function() ends = ends + 1 end)
+            while true do
+              memory.write(0xC000, emu.framecount())
+              emu.frameadvance()
+            end
+        "#;
+        let mut script = LuaScript::load(source, "test", &mut gb).unwrap();
+        // The body ran up to its first frameadvance and no further
+        assert_eq!(read(&gb, 0xC000), 0);
+        for frame in 1..=3 {
+            run_frame(&mut script, &mut gb);
+            assert_eq!(read(&gb, 0xC000), frame);
+            let ends: u8 = script.lua.globals().get("ends").unwrap();
+            assert_eq!(ends, frame);
+        }
+    }
+
+    #[test]
+    fn memory_callbacks() {
+        // LD A, $5A; LD ($C001), A; LD A, ($C002); JR -8
+        let program = [0x3E, 0x5A, 0xEA, 0x01, 0xC0, 0xFA, 0x02, 0xC0, 0x18, 0xF8];
+        let mut gb = Gameboy::power_on(test_rom(&program), None).unwrap();
+        let source = r#"
+            event.onmemorywrite(function(addr, value) written = value end, 0xC001)
+            event.onmemoryread(function(addr, value) read_from = addr end, 0xC002)
+        "#;
+        let mut script = LuaScript::load(source, "test", &mut gb).unwrap();
+        for _ in 0..4 {
+            gb.step(&mut NullSink, &mut NullSink);
+            script.after_step(&mut gb).unwrap();
+        }
+        let written: u8 = script.lua.globals().get("written").unwrap();
+        assert_eq!(written, 0x5A);
+        let read_from: u16 = script.lua.globals().get("read_from").unwrap();
+        assert_eq!(read_from, 0xC002);
+
+        script.unload(&mut gb);
+        assert!(gb.set_hooks(None).is_none());
+    }
+
+    #[test]
+    fn held_buttons() {
+        let mut gb = Gameboy::power_on(test_rom(&[0x18, 0xFE]), None).unwrap();
+        let source = r#"
+            joypad.set({ a = true, start = true })
+            joypad.set({ start = false })
+        "#;
+        let script = LuaScript::load(source, "test", &mut gb).unwrap();
+        assert_eq!(script.held_buttons(), 1 << GbKeys::A as u8);
+    }
+
+    #[test]
+    fn failed_load() {
+        let mut gb = Gameboy::power_on(test_rom(&[0x18, 0xFE]), None).unwrap();
+        // A syntax error, and an error while running up to the first frame
+        for source in [
+            "while",
+            "event.onframeend(print)\njoypad.set({ turbo = true })",
+        ] {
+            assert!(LuaScript::load(source, "test", &mut gb).is_err());
+            // No hooks are left behind for a script that isn't running
+            assert!(gb.set_hooks(None).is_none());
+        }
+    }
+}
@@ -1,6 +1,14 @@
 #![warn(clippy::all, rust_2018_idioms)]
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 
+/// Data regions are inclusive ranges of hex offsets into the ROM file, output as bytes
+#[cfg(not(target_arch = "wasm32"))]
+const DISASSEMBLE_USAGE: &str =
+    "gabe_gui --disassemble <out.asm|-> <rom> [--data <start>-<end>]...";
+
+#[cfg(not(target_arch = "wasm32"))]
+const HEADLESS_USAGE: &str = "gabe_gui --headless <rom> <script>";
+
 // When compiling natively:
 // Error if trying to do web
 #[cfg(not(target_arch = "wasm32"))]
@@ -8,6 +16,160 @@ fn main() {
     // Log to stdout (if you run with `RUST_LOG=debug`).
     tracing_subscriber::fmt::init();
 
+    let mut audio_latency_ms = gabe_gui::DEFAULT_AUDIO_LATENCY_MS;
+    let mut link = None;
+    let mut record_video = None;
+    let mut record_seconds = None;
+    let mut dump_audio = None;
+    let mut keymap = None;
+    #[cfg(feature = "lua")]
+    let mut lua_script = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--audio-latency" => {
+                let value = args.next().unwrap_or_default();
+                match gabe_gui::parse_audio_latency(&value) {
+                    Ok(latency_ms) => audio_latency_ms = latency_ms,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--link-listen" => {
+                let Some(port) = args.next().and_then(|port| port.parse().ok()) else {
+                    eprintln!("Usage: gabe_gui --link-listen <port>");
+                    std::process::exit(1);
+                };
+                println!("Waiting for the link cable peer on port {}...", port);
+                link = Some(gabe_gui::TcpLink::listen(port));
+            }
+            "--link-connect" => {
+                let Some(addr) = args.next() else {
+                    eprintln!("Usage: gabe_gui --link-connect <host:port>");
+                    std::process::exit(1);
+                };
+                link = Some(gabe_gui::TcpLink::connect(addr));
+            }
+            "--record-video" => {
+                let Some(path) = args.next() else {
+                    eprintln!("Usage: gabe_gui --record-video <out.gif> [--record-seconds <n>]");
+                    std::process::exit(1);
+                };
+                record_video = Some(std::path::PathBuf::from(path));
+            }
+            "--record-seconds" => {
+                let Some(seconds) = args.next().and_then(|seconds| seconds.parse().ok()) else {
+                    eprintln!("Usage: gabe_gui --record-video <out.gif> --record-seconds <n>");
+                    std::process::exit(1);
+                };
+                record_seconds = Some(seconds);
+            }
+            "--dump-audio" => {
+                let Some(path) = args.next() else {
+                    eprintln!("Usage: gabe_gui --dump-audio <out.wav>");
+                    std::process::exit(1);
+                };
+                dump_audio = Some(std::path::PathBuf::from(path));
+            }
+            "--keymap" => {
+                let Some(path) = args.next() else {
+                    eprintln!("Usage: gabe_gui --keymap <keys.toml>");
+                    std::process::exit(1);
+                };
+                keymap = Some(std::path::PathBuf::from(path));
+            }
+            #[cfg(feature = "lua")]
+            "--lua" => {
+                let Some(path) = args.next() else {
+                    eprintln!("Usage: gabe_gui --lua <script.lua>");
+                    std::process::exit(1);
+                };
+                lua_script = Some(std::path::PathBuf::from(path));
+            }
+            "--headless" => {
+                // Run the script and exit without opening a window or audio device
+                let (Some(rom_path), Some(script_path)) = (args.next(), args.next()) else {
+                    eprintln!("Usage: {}", HEADLESS_USAGE);
+                    eprintln!("{}", gabe_gui::SCRIPT_HELP);
+                    std::process::exit(1);
+                };
+                match gabe_gui::run_headless(rom_path.as_ref(), script_path.as_ref()) {
+                    Ok(code) => std::process::exit(code),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--disassemble" => {
+                // Disassemble the ROM and exit without opening a window
+                let (Some(out_path), Some(rom_path)) = (args.next(), args.next()) else {
+                    eprintln!("Usage: {}", DISASSEMBLE_USAGE);
+                    std::process::exit(1);
+                };
+                let mut data_regions = vec![];
+                while let Some(arg) = args.next() {
+                    let region = match (arg.as_str(), args.next()) {
+                        ("--data", Some(value)) => gabe_gui::parse_data_region(&value),
+                        _ => Err(format!("Usage: {}", DISASSEMBLE_USAGE)),
+                    };
+                    match region {
+                        Ok(region) => data_regions.push(region),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                let result = std::fs::read(&rom_path)
+                    .and_then(|rom| gabe_gui::disassemble_to_path(&rom, &data_regions, &out_path));
+                if let Err(e) = result {
+                    eprintln!("{}: Disassembly failed.", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+            "--dump-tilemap" => {
+                // Dump the background tile map and exit without opening a window
+                let result = gabe_gui::parse_tilemap_args(&mut args)
+                    .and_then(|dump| gabe_gui::dump_tilemap_to_path(&dump));
+                if let Err(e) = result {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+            _ => {
+                eprintln!("Unknown argument \"{}\"", arg);
+                eprintln!("Usage: gabe_gui [--audio-latency <ms>] [--link-listen <port> | --link-connect <host:port>]");
+                eprintln!("                [--record-video <out.gif> [--record-seconds <n>]] [--dump-audio <out.wav>]");
+                eprintln!("                [--keymap <keys.toml>]");
+                #[cfg(feature = "lua")]
+                eprintln!("                [--lua <script.lua>]");
+                eprintln!("       {}", DISASSEMBLE_USAGE);
+                eprintln!("       {}", HEADLESS_USAGE);
+                eprintln!("       {}", gabe_gui::TILEMAP_USAGE);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let link = match link.transpose() {
+        Ok(link) => link,
+        Err(e) => {
+            eprintln!("{}: Link cable not connected.", e);
+            std::process::exit(1);
+        }
+    };
+
+    if record_seconds.is_some() && record_video.is_none() {
+        eprintln!("--record-seconds needs --record-video <out.gif>");
+        std::process::exit(1);
+    }
+    let record_video = record_video.map(|path| (path, record_seconds));
+
     let native_options = eframe::NativeOptions {
         vsync: false,
         ..Default::default()
@@ -16,7 +178,19 @@ fn main() {
     eframe::run_native(
         "Gabe Emulator",
         native_options,
-        Box::new(|cc| Box::new(gabe_gui::GabeApp::new(cc))),
+        Box::new(move |cc| {
+            let app = gabe_gui::GabeApp::new(cc, audio_latency_ms, link, record_video, dump_audio);
+            let app = match keymap {
+                Some(path) => app.with_keymap(path),
+                None => app,
+            };
+            #[cfg(feature = "lua")]
+            let app = match lua_script {
+                Some(path) => app.with_lua_script(path),
+                None => app,
+            };
+            Box::new(app)
+        }),
     )
     .unwrap();
 }
@@ -0,0 +1,57 @@
+use std::path::Path;
+
+use gabe_core::gb::Gameboy;
+
+/// Parses an address given in hex, with or without a `0x` or `$` prefix
+pub fn parse_address(value: &str) -> Result<u16, String> {
+    let value = value.trim();
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix('$'))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid address \"{}\"", value))
+}
+
+/// Writes the bytes from `start` through `end` inclusive to the file at `path`.
+/// Memory is read as the CPU sees it, so the range can span devices and banked regions
+/// contain whichever bank is currently mapped.
+pub fn dump_memory(gb: &Gameboy, start: u16, end: u16, path: &Path) -> std::io::Result<()> {
+    if end < start {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("End {:04X} is before start {:04X}", end, start),
+        ));
+    }
+    std::fs::write(path, gb.get_memory_range(start as usize..end as usize + 1))
+}
+
+#[cfg(test)]
+mod memory_dump_tests {
+    use super::*;
+    use crate::test_util::test_rom;
+    use gabe_core::sink::NullSink;
+
+    #[test]
+    fn dump_wram() {
+        // LD A, $5A; LD ($C001), A; LD ($DFFF), A; JR -2
+        let program = [0x3E, 0x5A, 0xEA, 0x01, 0xC0, 0xEA, 0xFF, 0xDF, 0x18, 0xFE];
+        let mut gb = Gameboy::power_on(test_rom(&program), None).unwrap();
+        for _ in 0..4 {
+            gb.step(&mut NullSink, &mut NullSink);
+        }
+
+        let path = std::env::temp_dir().join("gabe_memory_dump_test.bin");
+        dump_memory(&gb, 0xC000, 0xDFFF, &path).unwrap();
+        let dump = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dump.len(), 0x2000);
+        assert_eq!(*gb.get_memory_range(0xC000..0xE000), dump[..]);
+        assert_eq!(dump[0x0001], 0x5A);
+        assert_eq!(dump[0x1FFF], 0x5A);
+
+        assert!(dump_memory(&gb, 0xD000, 0xC000, &path).is_err());
+        assert_eq!(parse_address("0xC000"), Ok(0xC000));
+        assert_eq!(parse_address("$ff80"), Ok(0xFF80));
+        assert!(parse_address("10000").is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use crate::memory_dump::parse_address;
+
+/// Parts of the memory map the viewer has shortcuts for, as (name, first address, last address)
+pub const REGIONS: [(&str, u16, u16); 7] = [
+    ("ROM", 0x0000, 0x7FFF),
+    ("VRAM", 0x8000, 0x9FFF),
+    ("Cart RAM", 0xA000, 0xBFFF),
+    ("WRAM", 0xC000, 0xDFFF),
+    ("OAM", 0xFE00, 0xFE9F),
+    ("IO", 0xFF00, 0xFF7F),
+    ("HRAM", 0xFF80, 0xFFFF),
+];
+
+/// Bytes shown on each row of the viewer
+pub const ROW_BYTES: usize = 16;
+
+/// A range of memory saved to come back to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bookmark {
+    pub name: String,
+    pub start: u16,
+    pub end: u16,
+}
+
+/// What's entered and shown in the memory viewer window. Memory is read as the CPU sees
+/// it, so banked regions show whichever bank is mapped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryViewer {
+    /// First address shown
+    pub start: u16,
+    /// Last address shown
+    pub end: u16,
+    /// Address of the byte picked for editing, and where searches continue from
+    pub selected: Option<u16>,
+    /// New value entered for the selected byte, in hex
+    pub value: String,
+    /// Address entered to jump to, in hex
+    pub goto: String,
+    /// Bytes entered to search for, in hex
+    pub search: String,
+    pub bookmarks: Vec<Bookmark>,
+    /// Name entered for the next bookmark
+    pub bookmark_name: String,
+    /// Row to scroll to on the next frame
+    pub scroll_to: Option<usize>,
+    /// Result of the last search or edit, shown under the controls
+    pub status: String,
+}
+
+impl Default for MemoryViewer {
+    fn default() -> Self {
+        MemoryViewer {
+            start: 0xC000,
+            end: 0xDFFF,
+            selected: None,
+            value: String::new(),
+            goto: String::new(),
+            search: String::new(),
+            bookmarks: vec![],
+            bookmark_name: String::new(),
+            scroll_to: None,
+            status: String::new(),
+        }
+    }
+}
+
+impl MemoryViewer {
+    /// Shows the memory from `start` through `end`, from the top
+    pub fn view(&mut self, start: u16, end: u16) {
+        self.start = start;
+        self.end = end;
+        self.selected = None;
+        self.scroll_to = Some(0);
+        self.status.clear();
+    }
+
+    /// Picks the byte at `addr` holding `value` for editing
+    pub fn select(&mut self, addr: u16, value: u8) {
+        self.selected = Some(addr);
+        self.value = format!("{:02X}", value);
+    }
+
+    /// Shows the region holding the address entered to jump to, scrolled to and selecting it
+    pub fn go_to(&mut self, memory: &[u8]) {
+        let addr = match parse_address(&self.goto) {
+            Ok(addr) => addr,
+            Err(e) => {
+                self.status = e;
+                return;
+            }
+        };
+        if !(self.start..=self.end).contains(&addr) {
+            let (_, start, end) = REGIONS
+                .iter()
+                .find(|(_, start, end)| (*start..=*end).contains(&addr))
+                .copied()
+                .unwrap_or(("", addr, addr));
+            self.view(start, end);
+            // The memory shown is for the old range, so the value is filled in once clicked
+            self.selected = Some(addr);
+            self.value.clear();
+        } else {
+            self.select(addr, memory[(addr - self.start) as usize]);
+        }
+        self.scroll_to = Some((addr - self.start) as usize / ROW_BYTES);
+        self.status.clear();
+    }
+
+    /// Selects the next match for the bytes entered to search for in `memory`, the range
+    /// shown, starting after the selected byte and wrapping around to the start
+    pub fn find_next(&mut self, memory: &[u8]) {
+        let needle = match parse_bytes(&self.search) {
+            Ok(needle) => needle,
+            Err(e) => {
+                self.status = e;
+                return;
+            }
+        };
+        let from = self
+            .selected
+            .map_or(0, |addr| (addr - self.start) as usize + 1);
+        match find_bytes(memory, &needle, from) {
+            Some(offset) => {
+                self.select(self.start + offset as u16, memory[offset]);
+                self.scroll_to = Some(offset / ROW_BYTES);
+                self.status = format!("Found at {:04X}", self.start as usize + offset);
+            }
+            None => self.status = "Not found".to_string(),
+        }
+    }
+
+    /// The selected address and new value entered for it, if it can be written. ROM can't
+    /// be, as writes there go to the cartridge's bank switching registers instead.
+    pub fn edit(&self) -> Result<(u16, u8), String> {
+        let addr = self.selected.ok_or("No byte selected")?;
+        if addr < 0x8000 {
+            return Err("ROM can't be edited".to_string());
+        }
+        let value = u8::from_str_radix(self.value.trim(), 16)
+            .map_err(|_| format!("Invalid value \"{}\"", self.value.trim()))?;
+        Ok((addr, value))
+    }
+
+    /// Bookmarks the range shown, under the name entered
+    pub fn add_bookmark(&mut self) {
+        let name = match self.bookmark_name.trim() {
+            "" => format!("{:04X}-{:04X}", self.start, self.end),
+            name => name.to_string(),
+        };
+        self.bookmarks.push(Bookmark {
+            name,
+            start: self.start,
+            end: self.end,
+        });
+        self.bookmark_name.clear();
+    }
+}
+
+/// Parses bytes given in hex, like "3E 5A" or "3E5A"
+pub fn parse_bytes(text: &str) -> Result<Vec<u8>, String> {
+    let digits: String = text.split_whitespace().collect();
+    if digits.is_empty() || digits.len() % 2 == 1 || !digits.is_ascii() {
+        return Err(format!("Invalid bytes \"{}\"", text.trim()));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|_| format!("Invalid bytes \"{}\"", text.trim()))
+        })
+        .collect()
+}
+
+/// Finds the first occurrence of `needle` in `memory` at or after `from`, wrapping around
+/// to search from the start, returning its offset
+pub fn find_bytes(memory: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    let matches = |offset: &usize| memory[*offset..].starts_with(needle);
+    let from = from.min(memory.len());
+    (from..memory.len())
+        .find(matches)
+        .or_else(|| (0..from).find(matches))
+}
+
+/// The bytes of a row as text, with anything but printable ASCII shown as '.'
+pub fn ascii_row(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod memory_viewer_tests {
+    use super::*;
+
+    #[test]
+    fn search() {
+        assert_eq!(parse_bytes("3e 5A"), Ok(vec![0x3E, 0x5A]));
+        assert_eq!(parse_bytes("3E5A"), Ok(vec![0x3E, 0x5A]));
+        assert!(parse_bytes("3E5").is_err());
+        assert!(parse_bytes("").is_err());
+        assert!(parse_bytes("XY").is_err());
+
+        let memory = [0x00, 0x3E, 0x5A, 0x00, 0x3E, 0x5A];
+        assert_eq!(find_bytes(&memory, &[0x3E, 0x5A], 0), Some(1));
+        assert_eq!(find_bytes(&memory, &[0x3E, 0x5A], 2), Some(4));
+        assert_eq!(find_bytes(&memory, &[0x3E, 0x5A], 5), Some(1));
+        assert_eq!(find_bytes(&memory, &[0x5A, 0x3E], 0), None);
+
+        let mut viewer = MemoryViewer {
+            search: "3E 5A".to_string(),
+            ..Default::default()
+        };
+        viewer.find_next(&memory);
+        assert_eq!(viewer.selected, Some(0xC001));
+        viewer.find_next(&memory);
+        assert_eq!(
+            (viewer.selected, viewer.value.as_str()),
+            (Some(0xC004), "3E")
+        );
+        assert_eq!(viewer.status, "Found at C004");
+    }
+
+    #[test]
+    fn navigate() {
+        let memory = [0; 0x2000];
+        let mut viewer = MemoryViewer {
+            goto: "C123".to_string(),
+            ..Default::default()
+        };
+        viewer.go_to(&memory);
+        assert_eq!(viewer.selected, Some(0xC123));
+        assert_eq!(viewer.scroll_to, Some(0x12));
+
+        viewer.goto = "FF44".to_string();
+        viewer.go_to(&memory);
+        assert_eq!((viewer.start, viewer.end), (0xFF00, 0xFF7F));
+        assert_eq!(viewer.scroll_to, Some(4));
+
+        viewer.bookmark_name = "Registers".to_string();
+        viewer.add_bookmark();
+        viewer.view(0x8000, 0x9FFF);
+        viewer.add_bookmark();
+        assert_eq!(
+            viewer.bookmarks,
+            [
+                Bookmark {
+                    name: "Registers".to_string(),
+                    start: 0xFF00,
+                    end: 0xFF7F
+                },
+                Bookmark {
+                    name: "8000-9FFF".to_string(),
+                    start: 0x8000,
+                    end: 0x9FFF
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn edit() {
+        let mut viewer = MemoryViewer::default();
+        assert!(viewer.edit().is_err());
+        viewer.select(0xC010, 0x12);
+        viewer.value = "ab".to_string();
+        assert_eq!(viewer.edit(), Ok((0xC010, 0xAB)));
+        viewer.value = "100".to_string();
+        assert!(viewer.edit().is_err());
+        viewer.select(0x4000, 0x12);
+        assert_eq!(viewer.edit(), Err("ROM can't be edited".to_string()));
+        assert_eq!(ascii_row(b"Hi\x00!"), "Hi.!");
+    }
+}
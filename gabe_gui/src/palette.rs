@@ -0,0 +1,522 @@
+/// Colors used to display the four DMG shades, from lightest to darkest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Palette {
+    /// Plain greyscale, as output by the core
+    Grey,
+    /// The green tint of the original DMG screen
+    Green,
+    /// The neutral tones of the Game Boy Pocket screen
+    Pocket,
+    /// The CGB built-in palette picked for the running game, see `cgb_palette_index`
+    CgbAuto,
+    /// One of the CGB built-in palettes, by index into `CGB_PALETTES`
+    Cgb(usize),
+}
+
+pub const PALETTES: [Palette; 16] = [
+    Palette::Grey,
+    Palette::Green,
+    Palette::Pocket,
+    Palette::CgbAuto,
+    Palette::Cgb(0),
+    Palette::Cgb(1),
+    Palette::Cgb(2),
+    Palette::Cgb(3),
+    Palette::Cgb(4),
+    Palette::Cgb(5),
+    Palette::Cgb(6),
+    Palette::Cgb(7),
+    Palette::Cgb(8),
+    Palette::Cgb(9),
+    Palette::Cgb(10),
+    Palette::Cgb(11),
+];
+
+/// The CGB's built-in palettes for DMG games. The first 12 are named after the button
+/// combination that selects them during the boot animation, and the rest are only given to
+/// particular games, see `CGB_TITLE_PALETTES`. Only the background colors are used, as frames
+/// from the core don't say which layer each pixel came from.
+pub const CGB_PALETTES: [(&str, [[u8; 3]; 4]); 23] = [
+    (
+        "cgb-up",
+        [[255, 255, 255], [255, 173, 99], [132, 49, 0], [0, 0, 0]],
+    ),
+    (
+        "cgb-up-a",
+        [[255, 255, 255], [255, 132, 132], [148, 58, 58], [0, 0, 0]],
+    ),
+    (
+        "cgb-up-b",
+        [
+            [255, 231, 197],
+            [204, 156, 133],
+            [132, 107, 41],
+            [90, 49, 8],
+        ],
+    ),
+    (
+        "cgb-left",
+        [[255, 255, 255], [99, 165, 255], [0, 0, 255], [0, 0, 0]],
+    ),
+    (
+        "cgb-left-a",
+        [[255, 255, 255], [140, 140, 222], [82, 82, 140], [0, 0, 0]],
+    ),
+    (
+        "cgb-left-b",
+        [[255, 255, 255], [165, 165, 165], [82, 82, 82], [0, 0, 0]],
+    ),
+    (
+        "cgb-down",
+        [[255, 255, 165], [255, 148, 148], [148, 148, 255], [0, 0, 0]],
+    ),
+    (
+        "cgb-down-a",
+        [[255, 255, 255], [255, 255, 0], [255, 0, 0], [0, 0, 0]],
+    ),
+    (
+        "cgb-down-b",
+        [[255, 255, 255], [255, 255, 0], [123, 74, 0], [0, 0, 0]],
+    ),
+    (
+        "cgb-right",
+        [[255, 255, 255], [82, 255, 0], [255, 66, 0], [0, 0, 0]],
+    ),
+    (
+        "cgb-right-a",
+        [[255, 255, 255], [123, 255, 49], [0, 99, 197], [0, 0, 0]],
+    ),
+    (
+        "cgb-right-b",
+        [[0, 0, 0], [0, 132, 132], [255, 222, 0], [255, 255, 255]],
+    ),
+    (
+        "cgb-title-1",
+        [[255, 255, 255], [123, 255, 49], [0, 132, 0], [0, 0, 0]],
+    ),
+    (
+        "cgb-title-2",
+        [[255, 255, 255], [123, 255, 0], [181, 115, 0], [0, 0, 0]],
+    ),
+    (
+        "cgb-title-3",
+        [[255, 255, 255], [173, 173, 132], [66, 115, 123], [0, 0, 0]],
+    ),
+    (
+        "cgb-title-4",
+        [[165, 156, 255], [255, 255, 0], [0, 99, 0], [0, 0, 0]],
+    ),
+    (
+        "cgb-title-5",
+        [
+            [255, 255, 206],
+            [99, 239, 239],
+            [156, 132, 49],
+            [90, 90, 90],
+        ],
+    ),
+    (
+        "cgb-title-6",
+        [[181, 181, 255], [255, 255, 148], [173, 90, 66], [0, 0, 0]],
+    ),
+    (
+        "cgb-title-7",
+        [
+            [255, 255, 156],
+            [148, 181, 255],
+            [99, 148, 115],
+            [0, 58, 58],
+        ],
+    ),
+    (
+        "cgb-title-8",
+        [[107, 255, 0], [255, 255, 255], [255, 82, 74], [0, 0, 0]],
+    ),
+    (
+        "cgb-title-9",
+        [[82, 222, 0], [255, 132, 0], [255, 255, 0], [255, 255, 255]],
+    ),
+    (
+        "cgb-title-10",
+        [[255, 255, 255], [255, 156, 0], [255, 0, 0], [0, 0, 0]],
+    ),
+    (
+        "cgb-title-11",
+        [[255, 255, 255], [255, 206, 0], [156, 99, 0], [0, 0, 0]],
+    ),
+];
+
+/// Palette used for games the CGB doesn't recognize, Right + A
+pub const CGB_DEFAULT_PALETTE: usize = 10;
+
+/// The CGB's table of the games it recognizes, as the sum of their title bytes, the 4th letter
+/// of the title for sums shared by more than one game, and the index of their palette in
+/// `CGB_PALETTES`. Games are named where known. The CGB also gives games their own sprite
+/// palettes, which aren't covered here.
+const CGB_TITLE_PALETTES: [(u8, Option<u8>, usize); 93] = [
+    // ALLEY WAY
+    (0x88, None, 15),
+    // YAKUMAN
+    (0x16, None, 0),
+    // BASEBALL, GAME&WATCH 2
+    (0x36, None, 20),
+    // TENNIS
+    (0xD1, None, 19),
+    // TETRIS
+    (0xDB, None, 7),
+    // QIX
+    (0xF2, None, 7),
+    // DR.MARIO
+    (0x3C, None, 3),
+    // RADARMISSION
+    (0x8C, None, 14),
+    // F1RACE
+    (0x92, None, 0),
+    // YOSSY NO TAMAGO
+    (0x3D, None, 9),
+    (0x5C, None, 15),
+    // X
+    (0x58, None, 5),
+    // MARIOLAND2
+    (0xC9, None, 16),
+    // YOSSY NO COOKIE
+    (0x3E, None, 21),
+    // ZELDA
+    (0x70, None, 1),
+    (0x1D, None, 15),
+    (0x59, None, 14),
+    // TETRIS FLASH
+    (0x69, None, 7),
+    // DONKEY KONG
+    (0x19, None, 21),
+    // MARIO'S PICROSS
+    (0x35, None, 0),
+    (0xA8, None, 18),
+    // POKEMON RED, GAMEBOYCAMERA G
+    (0x14, None, 1),
+    // POKEMON GREEN
+    (0xAA, None, 10),
+    // PICROSS 2
+    (0x75, None, 0),
+    // YOSSY NO PANEPON
+    (0x95, None, 9),
+    // KIRAKIRA KIDS
+    (0x99, None, 0),
+    // GAMEBOY GALLERY
+    (0x34, None, 13),
+    // POCKETCAMERA
+    (0x6F, None, 22),
+    (0x15, None, 7),
+    // BALLOON KID
+    (0xFF, None, 21),
+    // KINGOFTHEZOO
+    (0x97, None, 0),
+    // DMG FOOTBALL
+    (0x4B, None, 12),
+    // WORLD CUP
+    (0x90, None, 12),
+    // OTHELLO
+    (0x17, None, 12),
+    // SUPER RC PRO-AM
+    (0x10, None, 0),
+    // DYNABLASTER
+    (0x39, None, 0),
+    // BOY AND BLOB GB2
+    (0xF7, None, 0),
+    // MEGAMAN
+    (0xF6, None, 0),
+    // STAR WARS-NOA
+    (0xA2, None, 0),
+    (0x49, None, 15),
+    // WAVERACE
+    (0x4E, None, 3),
+    (0x43, None, 0),
+    // LOLO2
+    (0x68, None, 0),
+    // YOSHI'S COOKIE
+    (0xE0, None, 21),
+    // MYSTIC QUEST
+    (0x8B, None, 12),
+    (0xF0, None, 19),
+    // TOPRANKINGTENNIS
+    (0xCE, None, 19),
+    // MANSELL
+    (0x0C, None, 0),
+    // MEGAMAN3
+    (0x29, None, 0),
+    // SPACE INVADERS
+    (0xE8, None, 11),
+    // GAME&WATCH
+    (0xB7, None, 0),
+    // DONKEYKONGLAND95
+    (0x86, None, 18),
+    // ASTEROIDS/MISCMD
+    (0x9A, None, 12),
+    // STREET FIGHTER 2
+    (0x52, None, 0),
+    // DEFENDER/JOUST
+    (0x01, None, 0),
+    // KILLERINSTINCT95
+    (0x9D, None, 4),
+    // TETRIS BLAST
+    (0x71, None, 21),
+    // PINOCCHIO
+    (0x9C, None, 4),
+    (0xBD, None, 12),
+    // BA.TOSHINDEN
+    (0x5D, None, 0),
+    // NETTOU KOF 95
+    (0x6D, None, 0),
+    (0x67, None, 0),
+    // TETRIS PLUS
+    (0x3F, None, 10),
+    // DONKEYKONGLAND 3
+    (0x6B, None, 4),
+    (0xB3, Some(b'B'), 15),
+    // SUPER MARIOLAND
+    (0x46, Some(b'E'), 17),
+    // GOLF
+    (0x28, Some(b'F'), 12),
+    // SOLARSTRIKER
+    (0xA5, Some(b'A'), 11),
+    // GBWARS
+    (0xC6, Some(b'A'), 14),
+    // KAERUNOTAMENI
+    (0xD3, Some(b'R'), 4),
+    (0x27, Some(b'B'), 15),
+    // POKEMON BLUE
+    (0x61, Some(b'E'), 3),
+    // DONKEYKONGLAND
+    (0x18, Some(b'K'), 4),
+    // GAMEBOY GALLERY2
+    (0x66, Some(b'E'), 13),
+    // DONKEYKONGLAND 2
+    (0x6A, Some(b'K'), 4),
+    // KID ICARUS
+    (0xBF, Some(b' '), 4),
+    // TETRIS2
+    (0x0D, Some(b'R'), 7),
+    (0xF4, Some(b'-'), 10),
+    // MOGURANYA
+    (0xB3, Some(b'U'), 14),
+    (0x46, Some(b'R'), 3),
+    // GALAGA&GALAXIAN
+    (0x28, Some(b'A'), 11),
+    // BT2RAGNAROKWORLD
+    (0xA5, Some(b'R'), 0),
+    // KEN GRIFFEY JR
+    (0xC6, Some(b' '), 10),
+    (0xD3, Some(b'I'), 14),
+    // MAGNETIC SOCCER
+    (0x27, Some(b'N'), 12),
+    // VEGAS STAKES
+    (0x61, Some(b'A'), 12),
+    (0x18, Some(b'I'), 10),
+    // MILLI/CENTI/PEDE
+    (0x66, Some(b'L'), 10),
+    // MARIO & YOSHI
+    (0x6A, Some(b'I'), 9),
+    // SOCCER
+    (0xBF, Some(b'C'), 19),
+    // POKEBOM
+    (0x0D, Some(b'E'), 4),
+    // G&W GALLERY
+    (0xF4, Some(b' '), 13),
+    // TETRIS ATTACK
+    (0xB3, Some(b'R'), 9),
+];
+
+/// Picks the built-in palette to colorize a DMG game with, for the games in
+/// `CGB_TITLE_PALETTES`, matching them by the sum of their title bytes and, where needed, its
+/// 4th letter like the CGB. Only games published by Nintendo are matched, and all others get
+/// `CGB_DEFAULT_PALETTE`.
+pub fn cgb_palette_index(rom: &[u8]) -> usize {
+    if rom.len() < 0x150 {
+        return CGB_DEFAULT_PALETTE;
+    }
+    let nintendo = match rom[0x14B] {
+        0x01 => true,
+        // Use the new licensee code instead
+        0x33 => &rom[0x144..0x146] == b"01",
+        _ => false,
+    };
+    if !nintendo {
+        return CGB_DEFAULT_PALETTE;
+    }
+    let checksum = rom[0x134..=0x143]
+        .iter()
+        .fold(0u8, |acc, b| acc.wrapping_add(*b));
+    CGB_TITLE_PALETTES
+        .iter()
+        .find(|(c, letter, _)| *c == checksum && letter.iter().all(|&l| l == rom[0x137]))
+        .map_or(CGB_DEFAULT_PALETTE, |(_, _, index)| *index)
+}
+
+/// Approximates how the CGB's LCD displays raw RGB colors, which come out darker and with
+/// the channels bleeding into each other. Uses the channel mixing matrix from Gambatte and
+/// higan on the CGB's 5-bit channels, with the brightest output capped at 240.
+pub fn color_correct(rgb: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(rgb.len());
+    for pixel in rgb.chunks(3) {
+        let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(|c| (c >> 3) as u32);
+        for mixed in [
+            r * 26 + g * 4 + b * 2,
+            g * 24 + b * 8,
+            r * 6 + g * 4 + b * 22,
+        ] {
+            ret.push((mixed.min(960) >> 2) as u8);
+        }
+    }
+    ret
+}
+
+impl Palette {
+    /// Name of the palette as stored in the settings file
+    pub fn name(&self) -> &'static str {
+        match self {
+            Palette::Grey => "grey",
+            Palette::Green => "green",
+            Palette::Pocket => "pocket",
+            Palette::CgbAuto => "cgb-auto",
+            Palette::Cgb(index) => CGB_PALETTES[*index].0,
+        }
+    }
+
+    /// Resolves `CgbAuto` to the CGB palette with the given index, picked for the running game
+    pub fn resolve(self, cgb_index: usize) -> Palette {
+        match self {
+            Palette::CgbAuto => Palette::Cgb(cgb_index),
+            palette => palette,
+        }
+    }
+
+    pub fn from_name(value: &str) -> Option<Self> {
+        PALETTES.iter().copied().find(|p| p.name() == value)
+    }
+
+    /// RGB values for each shade, lightest first
+    fn colors(&self) -> [[u8; 3]; 4] {
+        match self {
+            Palette::Grey => [[255, 255, 255], [170, 170, 170], [85, 85, 85], [0, 0, 0]],
+            Palette::Green => [[155, 188, 15], [139, 172, 15], [48, 98, 48], [15, 56, 15]],
+            Palette::Pocket => [[196, 207, 161], [139, 149, 109], [77, 83, 60], [31, 31, 31]],
+            Palette::CgbAuto => CGB_PALETTES[CGB_DEFAULT_PALETTE].1,
+            Palette::Cgb(index) => CGB_PALETTES[*index].1,
+        }
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Palette::CgbAuto | Palette::Cgb(_))
+    }
+
+    /// Colorizes a frame for display, applying `color_correct` on top for CGB palettes
+    /// when `color_correction` is set
+    pub fn render(&self, frame: &[u8], color_correction: bool) -> Vec<u8> {
+        let colorized = self.colorize(frame);
+        if color_correction && self.is_cgb() {
+            color_correct(&colorized)
+        } else {
+            colorized
+        }
+    }
+
+    /// Maps a greyscale RGB frame from the core onto the palette. Blended frames can contain
+    /// levels between the four shades, so those are interpolated between neighboring colors.
+    pub fn colorize(&self, frame: &[u8]) -> Vec<u8> {
+        if *self == Palette::Grey {
+            return frame.to_vec();
+        }
+        let colors = self.colors();
+        let mut ret = Vec::with_capacity(frame.len());
+        for pixel in frame.chunks(3) {
+            // Darkness from 0 (white) to 255 (black), split into 3 spans of 85
+            let darkness = 255 - pixel[0] as u16;
+            let index = (darkness / 85).min(2) as usize;
+            let frac = darkness - index as u16 * 85;
+            for (from, to) in colors[index].iter().zip(colors[index + 1].iter()) {
+                ret.push(((*from as u16 * (85 - frac) + *to as u16 * frac) / 85) as u8);
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod palette_tests {
+    use super::*;
+    use crate::test_util::test_rom;
+
+    #[test]
+    fn colorize_shades() {
+        let frame = [255, 255, 255, 170, 170, 170, 85, 85, 85, 0, 0, 0];
+        assert_eq!(Palette::Grey.colorize(&frame), frame);
+        assert_eq!(
+            Palette::Green.colorize(&frame),
+            [155, 188, 15, 139, 172, 15, 48, 98, 48, 15, 56, 15]
+        );
+        // Halfway between the two darkest shades
+        let blended = Palette::Green.colorize(&[43, 43, 43]);
+        assert!(blended[1] > 56 && blended[1] < 98);
+
+        for palette in PALETTES {
+            assert_eq!(Palette::from_name(palette.name()), Some(palette));
+        }
+    }
+
+    #[test]
+    fn cgb_title_palettes() {
+        let rom = |title: &[u8], licensee: u8| {
+            let mut rom = test_rom(&[]);
+            rom[0x134..0x134 + title.len()].copy_from_slice(title);
+            rom[0x14B] = licensee;
+            rom
+        };
+        assert_eq!(cgb_palette_index(&rom(b"POKEMON RED", 0x01)), 1);
+        assert_eq!(cgb_palette_index(&rom(b"POKEMON GREEN", 0x01)), 10);
+        assert_eq!(cgb_palette_index(&rom(b"TETRIS", 0x01)), 7);
+        let mut new_licensee = rom(b"POKEMON BLUE", 0x33);
+        new_licensee[0x144..0x146].copy_from_slice(b"01");
+        assert_eq!(cgb_palette_index(&new_licensee), 3);
+        // Told apart from POKEMON BLUE by the 4th letter
+        assert_eq!(cgb_palette_index(&rom(b"VEGAS STAKES", 0x01)), 12);
+        assert_eq!(cgb_palette_index(&rom(b"KID ICARUS", 0x01)), 4);
+        assert_eq!(cgb_palette_index(&rom(b"SOCCER", 0x01)), 19);
+        // Same sum as POKEMON BLUE, but a 4th letter the CGB doesn't know
+        assert_eq!(
+            cgb_palette_index(&rom(b"POKOMEN BLUE", 0x01)),
+            CGB_DEFAULT_PALETTE
+        );
+        // Only Nintendo's games are recognized
+        assert_eq!(
+            cgb_palette_index(&rom(b"POKEMON RED", 0x00)),
+            CGB_DEFAULT_PALETTE
+        );
+        assert_eq!(
+            cgb_palette_index(&rom(b"CPU_INSTRS", 0x01)),
+            CGB_DEFAULT_PALETTE
+        );
+
+        assert_eq!(Palette::CgbAuto.resolve(1), Palette::Cgb(1));
+        assert_eq!(Palette::Green.resolve(1), Palette::Green);
+    }
+
+    #[test]
+    fn cgb_color_correction() {
+        assert_eq!(color_correct(&[255, 255, 255]), [240, 240, 240]);
+        assert_eq!(color_correct(&[255, 0, 0]), [201, 0, 46]);
+        assert_eq!(color_correct(&[0, 0, 0]), [0, 0, 0]);
+
+        let frame = [255, 255, 255, 170, 170, 170, 85, 85, 85, 0, 0, 0];
+        let cgb = Palette::Cgb(9);
+        assert_eq!(cgb.render(&frame, false), cgb.colorize(&frame));
+        assert_eq!(
+            cgb.render(&frame, true),
+            color_correct(&cgb.colorize(&frame))
+        );
+        // Only CGB palettes are corrected
+        assert_eq!(
+            Palette::Green.render(&frame, true),
+            Palette::Green.colorize(&frame)
+        );
+    }
+}
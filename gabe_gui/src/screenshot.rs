@@ -0,0 +1,179 @@
+use std::path::Path;
+
+/// Width of frames from the core
+pub const FRAME_WIDTH: usize = 160;
+/// Height of frames from the core
+pub const FRAME_HEIGHT: usize = 144;
+
+/// Largest scale factor offered for screenshots
+pub const MAX_SCREENSHOT_SCALE: u32 = 8;
+
+/// Scales an RGB image up by an integer `factor` with nearest-neighbor sampling,
+/// so each source pixel becomes a `factor` x `factor` block
+pub fn upscale_rgb(rgb: &[u8], width: usize, height: usize, factor: usize) -> Vec<u8> {
+    assert_eq!(rgb.len(), width * height * 3);
+    let mut ret = Vec::with_capacity(rgb.len() * factor * factor);
+    for row in rgb.chunks(width * 3) {
+        let mut scaled_row = Vec::with_capacity(row.len() * factor);
+        for pixel in row.chunks(3) {
+            for _ in 0..factor {
+                scaled_row.extend_from_slice(pixel);
+            }
+        }
+        for _ in 0..factor {
+            ret.extend_from_slice(&scaled_row);
+        }
+    }
+    ret
+}
+
+/// Saves an RGB frame from the core as a PNG, scaled up by `scale`
+pub fn save_screenshot(path: &Path, frame: &[u8], scale: u32) -> std::io::Result<()> {
+    let scale = scale as usize;
+    let rgb = upscale_rgb(frame, FRAME_WIDTH, FRAME_HEIGHT, scale);
+    std::fs::write(
+        path,
+        encode_png(&rgb, FRAME_WIDTH * scale, FRAME_HEIGHT * scale),
+    )
+}
+
+/// File name for a screenshot taken `secs` seconds after the Unix epoch, with the time in
+/// UTC, like `gabe-20240229-235959.png`
+pub fn timestamped_name(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "gabe-{:04}{:02}{:02}-{:02}{:02}{:02}.png",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) date in the Gregorian calendar,
+/// counting from March so leap days fall at the end of each year
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+/// Encodes an RGB image as a PNG. The image data is stored without compression,
+/// which keeps this simple at the cost of larger files.
+pub fn encode_png(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, RGB, default compression, filtering and no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // Each scanline is prefixed with its filter type, 0 for none
+    let mut raw = Vec::with_capacity(rgb.len() + height);
+    for row in rgb.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps `data` in a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // 32K window, no preset dictionary, checksum of the header bytes divisible by 31
+    let mut ret = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        // An empty stream still needs a final block
+        ret.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        ret.push(is_final as u8);
+        let len = block.len() as u16;
+        ret.extend_from_slice(&len.to_le_bytes());
+        ret.extend_from_slice(&(!len).to_le_bytes());
+        ret.extend_from_slice(block);
+    }
+    ret.extend_from_slice(&adler32(data).to_be_bytes());
+    ret
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod screenshot_tests {
+    use super::*;
+
+    #[test]
+    fn upscale_2x() {
+        // Red, green / blue, white
+        let image = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+        let (r, g, b, w) = ([255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]);
+        let expected: Vec<u8> = [[r, r, g, g], [r, r, g, g], [b, b, w, w], [b, b, w, w]]
+            .concat()
+            .concat();
+        assert_eq!(upscale_rgb(&image, 2, 2, 2), expected);
+        assert_eq!(upscale_rgb(&image, 2, 2, 1), image);
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamped_name(0), "gabe-19700101-000000.png");
+        assert_eq!(timestamped_name(951868800), "gabe-20000301-000000.png");
+        assert_eq!(timestamped_name(1709251199), "gabe-20240229-235959.png");
+    }
+
+    #[test]
+    fn png_encoding() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+
+        let png = encode_png(&[0; 4 * 2 * 3], 4, 2);
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR"));
+        assert_eq!(&png[16..24], [0, 0, 0, 4, 0, 0, 0, 2]);
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+    }
+}
@@ -0,0 +1,183 @@
+use gabe_core::gb::{Gameboy, GameboyBuilder, GameboyError, FRAME_CYCLES};
+use gabe_core::sink::NullSink;
+
+use crate::video_sinks::MostRecentSink;
+
+/// Something the user asked the emulator to do. Kept apart from the menus that ask for it,
+/// so what each does can be tested without a window.
+#[derive(Debug)]
+pub enum Action {
+    /// Starts a game from its ROM and any save data, replacing the current one. The game
+    /// starts after the boot ROM, if given one.
+    OpenRom {
+        rom: Box<[u8]>,
+        save_data: Option<Box<[u8]>>,
+        boot_rom: Option<Box<[u8]>>,
+    },
+    /// Pauses the running game, or resumes the paused one
+    TogglePause,
+    /// Runs the paused game to the end of its next frame
+    StepFrame,
+    /// Runs the paused game until the next scanline, for debugging the PPU
+    StepScanline,
+    /// Stops the game
+    Stop,
+}
+
+/// The emulator and the state the GUI keeps about it, changed only through `Action`s
+/// and by running the game
+#[derive(Default)]
+pub struct Session {
+    pub emu: Option<Gameboy>,
+    pub paused: bool,
+    /// The frame last shown, as output by the core
+    pub last_frame: Vec<u8>,
+}
+
+impl Session {
+    /// Whether a game is loaded and not paused
+    pub fn is_running(&self) -> bool {
+        self.emu.is_some() && !self.paused
+    }
+
+    /// Carries out `action`. Only `OpenRom` does anything without a game loaded, and steps
+    /// only run while paused. Audio from steps is discarded.
+    /// Returns an error if the ROM can't be loaded, leaving the current game as it was.
+    pub fn dispatch(&mut self, action: Action) -> Result<(), GameboyError> {
+        match action {
+            Action::OpenRom {
+                rom,
+                save_data,
+                boot_rom,
+            } => {
+                let mut builder = GameboyBuilder::new(rom);
+                if let Some(save_data) = save_data {
+                    builder = builder.save_data(save_data);
+                }
+                if let Some(boot_rom) = boot_rom {
+                    builder = builder.boot_rom(boot_rom);
+                }
+                self.emu = Some(builder.build()?);
+                self.paused = false;
+                self.last_frame.clear();
+            }
+            Action::TogglePause => {
+                if self.emu.is_some() {
+                    self.paused = !self.paused;
+                }
+            }
+            Action::StepFrame => {
+                let Some(emu) = self.emu.as_mut().filter(|_| self.paused) else {
+                    return Ok(());
+                };
+                let mut video_sink = MostRecentSink::new();
+                // No frames come while the LCD is off, so give up after two frames' worth
+                let mut cycles = 0;
+                while !video_sink.has_frame() && cycles < FRAME_CYCLES * 2 {
+                    cycles += emu.step(&mut video_sink, &mut NullSink);
+                }
+                if let Some(frame) = video_sink.get_frame() {
+                    self.last_frame = frame.to_vec();
+                }
+            }
+            Action::StepScanline => {
+                let Some(emu) = self.emu.as_mut().filter(|_| self.paused) else {
+                    return Ok(());
+                };
+                let mut video_sink = MostRecentSink::new();
+                emu.step_scanline(&mut video_sink, &mut NullSink);
+                if let Some(frame) = video_sink.get_frame() {
+                    self.last_frame = frame.to_vec();
+                }
+            }
+            Action::Stop => {
+                // Setting to None drops the Gameboy object
+                self.emu = None;
+                self.paused = false;
+                self.last_frame.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use super::*;
+    use crate::test_util::test_rom;
+
+    fn rom() -> Box<[u8]> {
+        // JR -2
+        test_rom(&[0x18, 0xFE])
+    }
+
+    #[test]
+    fn open_rom() {
+        let mut session = Session::default();
+        session.dispatch(Action::TogglePause).unwrap();
+        assert!(!session.paused);
+
+        session
+            .dispatch(Action::OpenRom {
+                rom: rom(),
+                save_data: None,
+                boot_rom: None,
+            })
+            .unwrap();
+        assert!(session.is_running());
+        assert_eq!(session.emu.as_ref().unwrap().get_pc(), 0x100);
+
+        // An unsupported cartridge type leaves the current game running
+        let mut bad_rom = rom();
+        bad_rom[0x147] = 0xFD;
+        assert!(session
+            .dispatch(Action::OpenRom {
+                rom: bad_rom,
+                save_data: None,
+                boot_rom: None,
+            })
+            .is_err());
+        assert!(session.is_running());
+
+        session
+            .dispatch(Action::OpenRom {
+                rom: rom(),
+                save_data: None,
+                boot_rom: Some(vec![0; 0x100].into_boxed_slice()),
+            })
+            .unwrap();
+        assert_eq!(session.emu.as_ref().unwrap().get_pc(), 0x0);
+
+        session.dispatch(Action::Stop).unwrap();
+        assert!(session.emu.is_none());
+        assert!(!session.paused);
+    }
+
+    #[test]
+    fn pause_and_step() {
+        let mut session = Session::default();
+        session
+            .dispatch(Action::OpenRom {
+                rom: rom(),
+                save_data: None,
+                boot_rom: None,
+            })
+            .unwrap();
+
+        // Steps do nothing while running
+        session.dispatch(Action::StepFrame).unwrap();
+        assert!(session.last_frame.is_empty());
+
+        session.dispatch(Action::TogglePause).unwrap();
+        assert!(session.paused);
+        session.dispatch(Action::StepFrame).unwrap();
+        assert_eq!(session.last_frame.len(), 160 * 144 * 3);
+        // Frames are sent as V-Blank starts
+        assert_eq!(session.emu.as_ref().unwrap().current_scanline(), 144);
+        session.dispatch(Action::StepScanline).unwrap();
+        assert_eq!(session.emu.as_ref().unwrap().current_scanline(), 145);
+
+        session.dispatch(Action::TogglePause).unwrap();
+        assert!(session.is_running());
+    }
+}
@@ -0,0 +1,301 @@
+use std::path::{Path, PathBuf};
+
+use log::*;
+
+use crate::dither::FULL_COLOR_DEPTH;
+use crate::fast_forward::{FastForwardMode, FAST_FORWARD_SPEED};
+use crate::input_pipeline::{DEFAULT_TURBO_RATE, MAX_TURBO_RATE};
+use crate::palette::Palette;
+use crate::screenshot::MAX_SCREENSHOT_SCALE;
+
+/// Location of the settings file, relative to the working directory
+pub const SETTINGS_PATH: &str = "gabe_gui.toml";
+
+/// Directory next to the settings file holding per-game settings, see `game_settings_path`
+pub const GAME_SETTINGS_DIR: &str = "gabe_games";
+
+/// Frontend settings persisted between runs.
+/// Stored in a flat subset of TOML: `key = value` lines and `#` comment lines, without tables,
+/// arrays or comments after values. Unknown keys and invalid values are ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub fast_forward_mode: FastForwardMode,
+    /// Emulation speed multiplier while fast-forwarding
+    pub fast_forward_speed: f64,
+    pub pause_on_focus_loss: bool,
+    pub palette: Palette,
+    /// Always show the input overlay, rather than only while its key is held
+    pub input_overlay: bool,
+    /// Integer factor screenshots are scaled up by
+    pub screenshot_scale: u32,
+    /// Adjust CGB palette colors to look like they would on the CGB's screen
+    pub color_correction: bool,
+    /// Draw every sprite on a line instead of the hardware's 10, which stops sprite flicker
+    /// at the cost of accuracy
+    pub no_sprite_limit: bool,
+    /// Only draw the game at whole multiples of its resolution, snapping the window to fit
+    pub integer_scaling: bool,
+    /// Bits per color channel to dither the display down to, see `dither`
+    pub color_depth: u8,
+    /// DMG boot ROM to run before each game, none if empty
+    pub boot_rom: Option<PathBuf>,
+    /// Presses per second turbo buttons fire at
+    pub turbo_rate: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            fast_forward_mode: FastForwardMode::Hold,
+            fast_forward_speed: FAST_FORWARD_SPEED,
+            pause_on_focus_loss: true,
+            palette: Palette::Grey,
+            input_overlay: false,
+            screenshot_scale: 1,
+            color_correction: false,
+            no_sprite_limit: false,
+            integer_scaling: false,
+            color_depth: FULL_COLOR_DEPTH,
+            boot_rom: None,
+            turbo_rate: DEFAULT_TURBO_RATE,
+        }
+    }
+}
+
+impl Settings {
+    /// Loads settings from `path`, falling back to the defaults if it can't be read
+    pub fn load(path: &Path) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Settings::default().merge(&text),
+            Err(e) => {
+                info!("{}: Using default settings.", e);
+                Settings::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, self.serialize())
+    }
+
+    /// Location of the per-game settings for the ROM at `rom_path`, a `<rom-stem>.toml` in
+    /// `GAME_SETTINGS_DIR`, kept apart from the main settings file and keymap. Key bindings
+    /// for the game can follow its settings under a `[keys]` section, see `split_game_keys`.
+    pub fn game_settings_path(rom_path: &Path) -> PathBuf {
+        let stem = rom_path.file_stem().unwrap_or_default().to_string_lossy();
+        Path::new(SETTINGS_PATH)
+            .with_file_name(GAME_SETTINGS_DIR)
+            .join(format!("{}.toml", stem))
+    }
+
+    /// Splits the per-game settings in `text` into the settings themselves and the key
+    /// bindings under its `[keys]` section, if any, which are in the keymap file's format
+    pub fn split_game_keys(text: &str) -> (&str, &str) {
+        let mut start = 0;
+        for line in text.split_inclusive('\n') {
+            if line.trim() == "[keys]" {
+                return (&text[..start], &text[start + line.len()..]);
+            }
+            start += line.len();
+        }
+        (text, "")
+    }
+
+    /// Returns these settings with any settings given in `text` taking precedence,
+    /// for layering per-game settings over the main ones
+    pub fn merge(&self, text: &str) -> Self {
+        let mut settings = self.clone();
+        for line in text.lines() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(quoted) => unescape(quoted),
+                None => value.to_string(),
+            };
+            let value = value.as_str();
+            match key.trim() {
+                "fast_forward_mode" => match FastForwardMode::from_name(value) {
+                    Some(mode) => settings.fast_forward_mode = mode,
+                    None => warn!("Invalid fast_forward_mode \"{}\", ignoring.", value),
+                },
+                "fast_forward_speed" => match value.parse::<f64>() {
+                    Ok(speed) if speed >= 1.0 && speed.is_finite() => {
+                        settings.fast_forward_speed = speed
+                    }
+                    _ => warn!("Invalid fast_forward_speed \"{}\", ignoring.", value),
+                },
+                "pause_on_focus_loss" => match value.parse() {
+                    Ok(enabled) => settings.pause_on_focus_loss = enabled,
+                    Err(_) => warn!("Invalid pause_on_focus_loss \"{}\", ignoring.", value),
+                },
+                "palette" => match Palette::from_name(value) {
+                    Some(palette) => settings.palette = palette,
+                    None => warn!("Invalid palette \"{}\", ignoring.", value),
+                },
+                "input_overlay" => match value.parse() {
+                    Ok(enabled) => settings.input_overlay = enabled,
+                    Err(_) => warn!("Invalid input_overlay \"{}\", ignoring.", value),
+                },
+                "screenshot_scale" => match value.parse() {
+                    Ok(scale @ 1..=MAX_SCREENSHOT_SCALE) => settings.screenshot_scale = scale,
+                    _ => warn!("Invalid screenshot_scale \"{}\", ignoring.", value),
+                },
+                "color_correction" => match value.parse() {
+                    Ok(enabled) => settings.color_correction = enabled,
+                    Err(_) => warn!("Invalid color_correction \"{}\", ignoring.", value),
+                },
+                "no_sprite_limit" => match value.parse() {
+                    Ok(enabled) => settings.no_sprite_limit = enabled,
+                    Err(_) => warn!("Invalid no_sprite_limit \"{}\", ignoring.", value),
+                },
+                "integer_scaling" => match value.parse() {
+                    Ok(enabled) => settings.integer_scaling = enabled,
+                    Err(_) => warn!("Invalid integer_scaling \"{}\", ignoring.", value),
+                },
+                "color_depth" => match value.parse() {
+                    Ok(bits @ 1..=FULL_COLOR_DEPTH) => settings.color_depth = bits,
+                    _ => warn!("Invalid color_depth \"{}\", ignoring.", value),
+                },
+                "turbo_rate" => match value.parse() {
+                    Ok(rate @ 1..=MAX_TURBO_RATE) => settings.turbo_rate = rate,
+                    _ => warn!("Invalid turbo_rate \"{}\", ignoring.", value),
+                },
+                "boot_rom" => settings.boot_rom = (!value.is_empty()).then(|| value.into()),
+                key => warn!("Unknown setting \"{}\", ignoring.", key),
+            }
+        }
+        settings
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "fast_forward_mode = \"{}\"\nfast_forward_speed = {}\npause_on_focus_loss = {}\npalette = \"{}\"\ninput_overlay = {}\nscreenshot_scale = {}\ncolor_correction = {}\nno_sprite_limit = {}\ninteger_scaling = {}\ncolor_depth = {}\nboot_rom = \"{}\"\nturbo_rate = {}\n",
+            self.fast_forward_mode.name(),
+            self.fast_forward_speed,
+            self.pause_on_focus_loss,
+            self.palette.name(),
+            self.input_overlay,
+            self.screenshot_scale,
+            self.color_correction,
+            self.no_sprite_limit,
+            self.integer_scaling,
+            self.color_depth,
+            escape(&self.boot_rom.as_deref().unwrap_or(Path::new("")).to_string_lossy()),
+            self.turbo_rate
+        )
+    }
+}
+
+/// Escapes backslashes and quotes for a TOML basic string
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Undoes `escape`. Other backslashes are kept as they are, so hand-written Windows paths
+/// still work.
+fn unescape(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(&next @ ('\\' | '"'))) => {
+                ret.push(next);
+                chars.next();
+            }
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod settings_tests {
+    use super::*;
+    use crate::input_config::InputConfig;
+    use egui::Key;
+    use gabe_core::gb::GbKeys;
+
+    #[test]
+    fn round_trip() {
+        let settings = Settings {
+            fast_forward_mode: FastForwardMode::Toggle,
+            fast_forward_speed: 2.5,
+            pause_on_focus_loss: false,
+            palette: Palette::Pocket,
+            input_overlay: true,
+            screenshot_scale: 3,
+            color_correction: true,
+            no_sprite_limit: true,
+            integer_scaling: true,
+            color_depth: 3,
+            boot_rom: Some("roms/dmg_boot.bin".into()),
+            turbo_rate: 15,
+        };
+        assert_eq!(Settings::default().merge(&settings.serialize()), settings);
+        // Paths are escaped as TOML strings
+        let settings = Settings {
+            boot_rom: Some(r#"C:\roms\"boot".bin"#.into()),
+            ..settings
+        };
+        let text = settings.serialize();
+        assert!(text.contains(r#"boot_rom = "C:\\roms\\\"boot\".bin""#));
+        assert_eq!(Settings::default().merge(&text), settings);
+        assert_eq!(
+            Settings::default()
+                .merge(r#"boot_rom = "C:\roms\boot.bin""#)
+                .boot_rom,
+            Some(r#"C:\roms\boot.bin"#.into())
+        );
+        assert_eq!(Settings::default().merge(""), Settings::default());
+        assert_eq!(
+            Settings::default()
+                .merge("screenshot_scale = 0\nscreenshot_scale = 100\nturbo_rate = 31"),
+            Settings::default()
+        );
+        assert_eq!(
+            Settings::default()
+                .merge("fast_forward_mode = \"sometimes\"\nvolume = 3\ncolor_depth = 0"),
+            Settings::default()
+        );
+    }
+
+    #[test]
+    fn game_overrides() {
+        let global =
+            Settings::default().merge("# Shared\npalette = \"green\"\nfast_forward_speed = 8");
+        let game = global.merge("palette = \"pocket\"");
+        assert_eq!(game.palette, Palette::Pocket);
+        assert_eq!(game.fast_forward_speed, 8.0);
+        assert_eq!(game.fast_forward_mode, global.fast_forward_mode);
+        assert_eq!(global.palette, Palette::Green);
+
+        assert_eq!(
+            Settings::game_settings_path(Path::new("roms/Tetris (World).gb")),
+            Path::new("gabe_games/Tetris (World).toml")
+        );
+    }
+
+    #[test]
+    fn game_keys() {
+        let text = "palette = \"pocket\"\n\n[keys]\na = [\"Space\"]\n\n[gamepad]\nb = [\"East\"]\n";
+        let (settings, keys) = Settings::split_game_keys(text);
+        assert_eq!(settings, "palette = \"pocket\"\n\n");
+        assert_eq!(Settings::default().merge(settings).palette, Palette::Pocket);
+
+        let global = InputConfig::default().merge("a = [\"K\"]\nb = [\"J\"]");
+        let game = global.merge(keys);
+        assert_eq!(game.keys(GbKeys::A as usize), [Key::Space]);
+        assert_eq!(game.keys(GbKeys::B as usize), [Key::J]);
+        assert_eq!(global.keys(GbKeys::A as usize), [Key::K]);
+
+        assert_eq!(
+            Settings::split_game_keys("palette = \"green\""),
+            ("palette = \"green\"", "")
+        );
+    }
+}
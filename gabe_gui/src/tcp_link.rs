@@ -0,0 +1,175 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use gabe_core::link::SerialLink;
+use log::*;
+
+/// Sent by both sides on connecting, so a peer that isn't gabe is caught early
+const HANDSHAKE: &[u8; 8] = b"GABELINK";
+
+/// A link cable to another copy of gabe over TCP. Serial bytes are sent as they are, with
+/// no framing, once the handshake is done. TCP delivers them in order, so both ends stay in
+/// step as long as the side clocking transfers waits for each reply, see
+/// `Gameboy::set_serial_wait_for_peer`.
+pub struct TcpLink {
+    stream: TcpStream,
+    connected: bool,
+}
+
+impl TcpLink {
+    /// Waits for a peer to connect on `port`
+    pub fn listen(port: u16) -> io::Result<Self> {
+        Self::accept(&TcpListener::bind(("0.0.0.0", port))?)
+    }
+
+    pub fn accept(listener: &TcpListener) -> io::Result<Self> {
+        let (stream, _) = listener.accept()?;
+        Self::handshake(stream)
+    }
+
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::handshake(TcpStream::connect(addr)?)
+    }
+
+    fn handshake(mut stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        stream.write_all(HANDSHAKE)?;
+        let mut reply = [0; HANDSHAKE.len()];
+        stream.read_exact(&mut reply)?;
+        if &reply != HANDSHAKE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Peer isn't a gabe link cable",
+            ));
+        }
+        stream.set_nonblocking(true)?;
+        Ok(TcpLink {
+            stream,
+            connected: true,
+        })
+    }
+
+    /// Another handle to the same connection, for the next game opened
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(TcpLink {
+            stream: self.stream.try_clone()?,
+            connected: self.connected,
+        })
+    }
+
+    fn disconnect(&mut self, e: io::Error) {
+        warn!("{}: Link cable disconnected.", e);
+        self.connected = false;
+    }
+}
+
+impl SerialLink for TcpLink {
+    fn send(&mut self, byte: u8) {
+        if self.connected {
+            if let Err(e) = self.stream.write_all(&[byte]) {
+                self.disconnect(e);
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        if !self.connected {
+            return None;
+        }
+        let mut byte = [0];
+        match self.stream.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            Ok(_) => {
+                self.disconnect(io::ErrorKind::UnexpectedEof.into());
+                None
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => {
+                self.disconnect(e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tcp_link_tests {
+    use super::*;
+    use crate::test_util::test_rom;
+    use std::time::Duration;
+
+    /// Polls `link` until a byte arrives
+    fn receive_blocking(link: &mut TcpLink) -> Option<u8> {
+        for _ in 0..1000 {
+            if let Some(byte) = link.receive() {
+                return Some(byte);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    #[test]
+    fn loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = std::thread::spawn(move || TcpLink::connect(addr).unwrap());
+        let mut host = TcpLink::accept(&listener).unwrap();
+        let mut peer = peer.join().unwrap();
+
+        assert_eq!(host.receive(), None);
+        host.send(0x42);
+        assert_eq!(receive_blocking(&mut peer), Some(0x42));
+        peer.try_clone().unwrap().send(0x99);
+        assert_eq!(receive_blocking(&mut host), Some(0x99));
+
+        drop(peer);
+        assert_eq!(receive_blocking(&mut host), None);
+        assert!(!host.connected);
+    }
+
+    /// Sends `sent` over the serial port, clocking the transfer with `sc`, then stores the
+    /// byte received at 0xC000 and 0x01 at 0xC001
+    fn transfer_rom(sent: u8, sc: u8) -> Box<[u8]> {
+        let program = [
+            0x3E, sent, 0xE0, 0x01, // LD A, sent; LDH ($01), A
+            0x3E, sc, 0xE0, 0x02, // LD A, sc; LDH ($02), A
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, // LDH A, ($02); BIT 7, A; JR NZ, -6
+            0xF0, 0x01, 0xEA, 0x00, 0xC0, // LDH A, ($01); LD ($C000), A
+            0x3E, 0x01, 0xEA, 0x01, 0xC0, // LD A, $01; LD ($C001), A
+            0x18, 0xFE, // JR -2
+        ];
+        test_rom(&program)
+    }
+
+    #[test]
+    fn gameboys_linked() {
+        use gabe_core::gb::Gameboy;
+        use gabe_core::sink::NullSink;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = std::thread::spawn(move || TcpLink::connect(addr).unwrap());
+        let host = TcpLink::accept(&listener).unwrap();
+        let peer = peer.join().unwrap();
+
+        // Each runs a frame at a time like the GUI, far ahead of the network, so the side
+        // clocking the transfer has to wait for the reply
+        let mut master = Gameboy::power_on(transfer_rom(0x42, 0x81), None).unwrap();
+        master.connect_serial(Some(Box::new(host)));
+        master.set_serial_wait_for_peer(true);
+        let mut slave = Gameboy::power_on(transfer_rom(0x99, 0x80), None).unwrap();
+        slave.connect_serial(Some(Box::new(peer)));
+        let done = |gb: &Gameboy| gb.get_memory_range(0xC001..0xC002)[0] == 0x01;
+        for _ in 0..1000 {
+            if done(&master) && done(&slave) {
+                break;
+            }
+            master.emulate_frames(1, true, &mut NullSink, &mut NullSink);
+            std::thread::sleep(Duration::from_millis(1));
+            slave.emulate_frames(1, true, &mut NullSink, &mut NullSink);
+        }
+        assert_eq!(master.get_memory_range(0xC000..0xC002)[..], [0x99, 0x01]);
+        assert_eq!(slave.get_memory_range(0xC000..0xC002)[..], [0x42, 0x01]);
+    }
+}
@@ -0,0 +1,8 @@
+//! Helpers shared by the unit tests
+
+/// Builds a 32 KiB ROM-only cartridge with `program` placed at the entry point
+pub fn test_rom(program: &[u8]) -> Box<[u8]> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(program);
+    rom.into_boxed_slice()
+}
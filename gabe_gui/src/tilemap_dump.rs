@@ -0,0 +1,130 @@
+use std::path::PathBuf;
+
+use gabe_core::gb::Gameboy;
+use gabe_core::sink::NullSink;
+
+use crate::screenshot::encode_png;
+
+pub const TILEMAP_USAGE: &str =
+    "gabe_gui --dump-tilemap <out.png> <rom> [--frames <n>] [--map 9800|9C00]";
+
+/// Frames run before dumping by default, enough for most games to set up their graphics
+pub const DEFAULT_TILEMAP_FRAMES: u32 = 60;
+
+/// Width and height of a dumped background tile map
+const TILEMAP_SIZE: usize = 256;
+
+/// What `--dump-tilemap` should dump, and where
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TilemapDump {
+    pub out_path: PathBuf,
+    pub rom_path: PathBuf,
+    /// Frames to run the ROM for before dumping
+    pub frames: u32,
+    /// Dumps the map at 0x9C00 rather than 0x9800, like LCDC's map select bits
+    pub map_select: bool,
+}
+
+/// Parses the arguments following `--dump-tilemap`, see `TILEMAP_USAGE`
+pub fn parse_tilemap_args(mut args: impl Iterator<Item = String>) -> Result<TilemapDump, String> {
+    let usage = || format!("Usage: {}", TILEMAP_USAGE);
+    let (Some(out_path), Some(rom_path)) = (args.next(), args.next()) else {
+        return Err(usage());
+    };
+    let mut dump = TilemapDump {
+        out_path: PathBuf::from(out_path),
+        rom_path: PathBuf::from(rom_path),
+        frames: DEFAULT_TILEMAP_FRAMES,
+        map_select: false,
+    };
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--frames", Some(value)) => {
+                dump.frames = value
+                    .parse()
+                    .map_err(|_| format!("Invalid frame count \"{}\"", value))?;
+            }
+            ("--map", Some(value)) => {
+                dump.map_select = match value.to_ascii_uppercase().as_str() {
+                    "9800" => false,
+                    "9C00" => true,
+                    _ => {
+                        return Err(format!(
+                            "Invalid tile map \"{}\", expected 9800 or 9C00",
+                            value
+                        ))
+                    }
+                };
+            }
+            _ => return Err(usage()),
+        }
+    }
+    Ok(dump)
+}
+
+/// Runs `gb` for `frames` frames, then renders the chosen background tile map as RGB
+pub fn render_tilemap(gb: &mut Gameboy, frames: u32, map_select: bool) -> Vec<u8> {
+    gb.emulate_frames(frames, true, &mut NullSink, &mut NullSink);
+    gb.dump_tilemap(map_select).into_vec()
+}
+
+/// Loads the ROM for `dump` and writes its background tile map to a PNG
+pub fn dump_tilemap_to_path(dump: &TilemapDump) -> Result<(), String> {
+    let rom = std::fs::read(&dump.rom_path).map_err(|e| format!("{}: ROM not read.", e))?;
+    let mut gb = Gameboy::power_on(rom.into_boxed_slice(), None)
+        .map_err(|e| format!("{}: ROM not loaded.", e))?;
+    let rgb = render_tilemap(&mut gb, dump.frames, dump.map_select);
+    std::fs::write(&dump.out_path, encode_png(&rgb, TILEMAP_SIZE, TILEMAP_SIZE))
+        .map_err(|e| format!("{}: Tile map not written.", e))
+}
+
+#[cfg(test)]
+mod tilemap_dump_tests {
+    use super::*;
+    use crate::test_util::test_rom;
+
+    fn args(line: &str) -> impl Iterator<Item = String> + '_ {
+        line.split_whitespace().map(String::from)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_tilemap_args(args("map.png game.gb")),
+            Ok(TilemapDump {
+                out_path: PathBuf::from("map.png"),
+                rom_path: PathBuf::from("game.gb"),
+                frames: DEFAULT_TILEMAP_FRAMES,
+                map_select: false,
+            })
+        );
+        let dump = parse_tilemap_args(args("map.png game.gb --map 9c00 --frames 300")).unwrap();
+        assert!(dump.map_select);
+        assert_eq!(dump.frames, 300);
+        assert!(parse_tilemap_args(args("map.png")).is_err());
+        assert!(parse_tilemap_args(args("map.png game.gb --map 9A00")).is_err());
+        assert!(parse_tilemap_args(args("map.png game.gb --frames")).is_err());
+        assert!(parse_tilemap_args(args("map.png game.gb --scale 2")).is_err());
+    }
+
+    #[test]
+    fn chosen_map() {
+        let rom = test_rom(&[
+            0x3E, 0x10, // LD A, $10
+            0xE0, 0x40, // LDH (LCDC), A, turning the LCD off with tile data at 0x8000
+            0x3E, 0xFF, // LD A, $FF
+            0xEA, 0x10, 0x80, // LD ($8010), A
+            0xEA, 0x11, 0x80, // LD ($8011), A, darkening the top row of tile 1
+            0x3E, 0x01, // LD A, $01
+            0xEA, 0x00, 0x9C, // LD ($9C00), A, at the top left of the 0x9C00 map
+            0x18, 0xFE, // JR -2
+        ]);
+        let mut gb = Gameboy::power_on(rom, None).unwrap();
+        let rgb = render_tilemap(&mut gb, 1, true);
+        assert_eq!(rgb.len(), TILEMAP_SIZE * TILEMAP_SIZE * 3);
+        assert_eq!(rgb, gb.dump_tilemap(true).into_vec());
+        assert_ne!(rgb, gb.dump_tilemap(false).into_vec());
+        // The top left pixel is darker in the chosen map
+        assert!(rgb[0] < gb.dump_tilemap(false)[0]);
+    }
+}
@@ -0,0 +1,188 @@
+use gabe_core::gb::{BackgroundLayout, OamEntry, TilePalette};
+
+/// Width and height in pixels of a background tile map
+pub const MAP_SIZE: u16 = 256;
+
+/// Size in pixels of the tile data dump, 16 tiles across and 24 down
+pub const TILES_SIZE: [usize; 2] = [128, 192];
+
+/// Size of the screen in pixels
+const SCREEN_SIZE: [u16; 2] = [160, 144];
+
+/// What the VRAM viewer window shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VramView {
+    /// All 384 tiles in tile data order
+    Tiles,
+    /// The tile map the background is drawn from, with the part on screen outlined
+    Background,
+    /// The tile map the window is drawn from, with the part on screen outlined
+    Window,
+    /// The 40 OBJs in OAM
+    Objs,
+}
+
+impl VramView {
+    pub const ALL: [VramView; 4] = [
+        VramView::Tiles,
+        VramView::Background,
+        VramView::Window,
+        VramView::Objs,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VramView::Tiles => "Tiles",
+            VramView::Background => "BG Map",
+            VramView::Window => "Window Map",
+            VramView::Objs => "OBJs",
+        }
+    }
+}
+
+/// The palettes tiles can be shown in, with their names
+pub const TILE_PALETTES: [(TilePalette, &str); 3] = [
+    (TilePalette::Bgp, "BGP"),
+    (TilePalette::Obp0, "OBP0"),
+    (TilePalette::Obp1, "OBP1"),
+];
+
+/// The tile at pixel (`x`, `y`) of the tile data dump and its address, for hovering over it
+pub fn tile_at(x: usize, y: usize) -> Option<(usize, u16)> {
+    if x >= TILES_SIZE[0] || y >= TILES_SIZE[1] {
+        return None;
+    }
+    let index = (y / 8) * 16 + x / 8;
+    Some((index, 0x8000 + index as u16 * 16))
+}
+
+/// The part of the background map on screen, as rectangles of (x, y, width, height) in map
+/// pixels. The background wraps around, so the screen can take up to four.
+pub fn background_viewport(layout: &BackgroundLayout) -> Vec<[u16; 4]> {
+    let split = |start: u8, length: u16| -> Vec<(u16, u16)> {
+        let start = start as u16;
+        if start + length <= MAP_SIZE {
+            vec![(start, length)]
+        } else {
+            vec![(start, MAP_SIZE - start), (0, start + length - MAP_SIZE)]
+        }
+    };
+    let mut rects = vec![];
+    for (y, height) in split(layout.scroll_y, SCREEN_SIZE[1]) {
+        for &(x, width) in &split(layout.scroll_x, SCREEN_SIZE[0]) {
+            rects.push([x, y, width, height]);
+        }
+    }
+    rects
+}
+
+/// The part of the window map on screen, as (x, y, width, height) in map pixels, or `None`
+/// if the window is off or placed off screen. The window always starts from the map's top
+/// left corner.
+pub fn window_viewport(layout: &BackgroundLayout) -> Option<[u16; 4]> {
+    let left = (layout.window_x as u16).saturating_sub(7);
+    let top = layout.window_y as u16;
+    if !layout.window_enabled || left >= SCREEN_SIZE[0] || top >= SCREEN_SIZE[1] {
+        return None;
+    }
+    Some([0, 0, SCREEN_SIZE[0] - left, SCREEN_SIZE[1] - top])
+}
+
+/// A row of the OBJ list: index, position on screen, tile and attributes
+pub fn obj_row(index: usize, obj: &OamEntry) -> [String; 5] {
+    let palette = match obj.palette {
+        TilePalette::Obp1 => "OBP1",
+        _ => "OBP0",
+    };
+    let mut flags = vec![];
+    if obj.flip_x {
+        flags.push("X flip");
+    }
+    if obj.flip_y {
+        flags.push("Y flip");
+    }
+    if obj.behind_bg {
+        flags.push("Behind BG");
+    }
+    [
+        index.to_string(),
+        format!("{}, {}", obj.x as i16 - 8, obj.y as i16 - 16),
+        format!("{:02X}", obj.tile),
+        palette.to_string(),
+        flags.join(", "),
+    ]
+}
+
+#[cfg(test)]
+mod vram_viewer_tests {
+    use super::*;
+
+    fn layout() -> BackgroundLayout {
+        BackgroundLayout {
+            scroll_x: 0,
+            scroll_y: 0,
+            window_x: 7,
+            window_y: 0,
+            bg_enabled: true,
+            window_enabled: true,
+            objs_enabled: true,
+            bg_map_select: false,
+            window_map_select: true,
+            tile_data_select: true,
+            tall_objs: false,
+        }
+    }
+
+    #[test]
+    fn viewports() {
+        let mut layout = layout();
+        assert_eq!(background_viewport(&layout), [[0, 0, 160, 144]]);
+        layout.scroll_x = 200;
+        layout.scroll_y = 100;
+        assert_eq!(
+            background_viewport(&layout),
+            [[200, 100, 56, 144], [0, 100, 104, 144]]
+        );
+        layout.scroll_y = 150;
+        assert_eq!(
+            background_viewport(&layout),
+            [
+                [200, 150, 56, 106],
+                [0, 150, 104, 106],
+                [200, 0, 56, 38],
+                [0, 0, 104, 38]
+            ]
+        );
+
+        assert_eq!(window_viewport(&layout), Some([0, 0, 160, 144]));
+        layout.window_x = 87;
+        layout.window_y = 100;
+        assert_eq!(window_viewport(&layout), Some([0, 0, 80, 44]));
+        layout.window_x = 167;
+        assert_eq!(window_viewport(&layout), None);
+        layout.window_x = 7;
+        layout.window_enabled = false;
+        assert_eq!(window_viewport(&layout), None);
+    }
+
+    #[test]
+    fn tiles_and_objs() {
+        assert_eq!(tile_at(0, 0), Some((0, 0x8000)));
+        assert_eq!(tile_at(127, 191), Some((383, 0x97F0)));
+        assert_eq!(tile_at(128, 0), None);
+
+        let obj = OamEntry {
+            y: 0x18,
+            x: 0x04,
+            tile: 0x2A,
+            behind_bg: true,
+            flip_y: false,
+            flip_x: true,
+            palette: TilePalette::Obp1,
+        };
+        assert_eq!(
+            obj_row(3, &obj),
+            ["3", "-4, 8", "2A", "OBP1", "X flip, Behind BG"].map(String::from)
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use crate::screenshot::{FRAME_HEIGHT, FRAME_WIDTH};
+
+/// Largest scale offered as a preset window size
+pub const MAX_WINDOW_SCALE: u32 = 6;
+
+/// The integer scale whose frame size is nearest to a game area of `width` x `height`,
+/// for snapping the window after it's resized. Never below 1x.
+pub fn snap_scale(width: f32, height: f32) -> u32 {
+    let scale = (width / FRAME_WIDTH as f32).min(height / FRAME_HEIGHT as f32);
+    (scale.round() as u32).max(1)
+}
+
+/// The largest integer scale whose frame fits within a game area of `width` x `height`.
+/// Never below 1x, even if the frame doesn't fit.
+pub fn fit_scale(width: f32, height: f32) -> u32 {
+    let scale = (width / FRAME_WIDTH as f32).min(height / FRAME_HEIGHT as f32);
+    (scale.floor() as u32).max(1)
+}
+
+/// Size of the frame at the given integer scale
+pub fn scaled_size(scale: u32) -> (f32, f32) {
+    (
+        (FRAME_WIDTH as u32 * scale) as f32,
+        (FRAME_HEIGHT as u32 * scale) as f32,
+    )
+}
+
+#[cfg(test)]
+mod window_scale_tests {
+    use super::*;
+
+    #[test]
+    fn snapping() {
+        assert_eq!(scaled_size(4), (640.0, 576.0));
+        assert_eq!(snap_scale(640.0, 576.0), 4);
+        // Dragged a little past 3x and a little short of 4x
+        assert_eq!(snap_scale(500.0, 450.0), 3);
+        assert_eq!(snap_scale(620.0, 560.0), 4);
+        // The narrower dimension decides
+        assert_eq!(snap_scale(1000.0, 300.0), 2);
+        assert_eq!(snap_scale(10.0, 10.0), 1);
+
+        assert_eq!(fit_scale(620.0, 560.0), 3);
+        assert_eq!(fit_scale(640.0, 576.0), 4);
+        assert_eq!(fit_scale(10.0, 10.0), 1);
+    }
+}